@@ -0,0 +1,167 @@
+// Stable codes for the errors frontends most often need to handle. Errors still travel as strings,
+// rendered as "<Code>: <message>", so clients can split off the code and look it up in a catalog.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorCode {
+    MaintenanceMode,
+    UserNotRegistered,
+    CallerNotLinked,
+    UserNotFound,
+    DiscussionNotFound,
+    GroupNotFound,
+    AttachmentNotFound,
+    NotCreator,
+    VotingClosed,
+    NotGroupMember,
+    AlreadyGroupMember,
+}
+
+// Locales with a translated catalog, the first one is the fallback
+pub(crate) const LOCALES: &[&str] = &["en", "es", "fr", "de"];
+
+pub(crate) const ALL_CODES: &[ErrorCode] = &[
+    ErrorCode::MaintenanceMode,
+    ErrorCode::UserNotRegistered,
+    ErrorCode::CallerNotLinked,
+    ErrorCode::UserNotFound,
+    ErrorCode::DiscussionNotFound,
+    ErrorCode::GroupNotFound,
+    ErrorCode::AttachmentNotFound,
+    ErrorCode::NotCreator,
+    ErrorCode::VotingClosed,
+    ErrorCode::NotGroupMember,
+    ErrorCode::AlreadyGroupMember,
+];
+
+impl ErrorCode {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ErrorCode::MaintenanceMode => "MaintenanceMode",
+            ErrorCode::UserNotRegistered => "UserNotRegistered",
+            ErrorCode::CallerNotLinked => "CallerNotLinked",
+            ErrorCode::UserNotFound => "UserNotFound",
+            ErrorCode::DiscussionNotFound => "DiscussionNotFound",
+            ErrorCode::GroupNotFound => "GroupNotFound",
+            ErrorCode::AttachmentNotFound => "AttachmentNotFound",
+            ErrorCode::NotCreator => "NotCreator",
+            ErrorCode::VotingClosed => "VotingClosed",
+            ErrorCode::NotGroupMember => "NotGroupMember",
+            ErrorCode::AlreadyGroupMember => "AlreadyGroupMember",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<ErrorCode> {
+        ALL_CODES.iter().copied().find(|code| code.name() == name)
+    }
+
+    // Message in one of LOCALES, English for anything else
+    pub(crate) fn message(self, locale: &str) -> &'static str {
+        let [en, es, fr, de] = self.translations();
+        match locale {
+            "es" => es,
+            "fr" => fr,
+            "de" => de,
+            _ => en,
+        }
+    }
+
+    fn translations(self) -> [&'static str; 4] {
+        match self {
+            ErrorCode::MaintenanceMode => [
+                "the canister is read-only",
+                "el canister es de solo lectura",
+                "le canister est en lecture seule",
+                "der Canister ist schreibgeschützt",
+            ],
+            ErrorCode::UserNotRegistered => [
+                "User is not registered",
+                "El usuario no está registrado",
+                "L'utilisateur n'est pas inscrit",
+                "Der Benutzer ist nicht registriert",
+            ],
+            ErrorCode::CallerNotLinked => [
+                "Caller is not linked to this account",
+                "El llamante no está vinculado a esta cuenta",
+                "L'appelant n'est pas lié à ce compte",
+                "Der Aufrufer ist nicht mit diesem Konto verknüpft",
+            ],
+            ErrorCode::UserNotFound => [
+                "User not found",
+                "Usuario no encontrado",
+                "Utilisateur introuvable",
+                "Benutzer nicht gefunden",
+            ],
+            ErrorCode::DiscussionNotFound => [
+                "Discussion not found",
+                "Discusión no encontrada",
+                "Discussion introuvable",
+                "Diskussion nicht gefunden",
+            ],
+            ErrorCode::GroupNotFound => [
+                "Group not found",
+                "Grupo no encontrado",
+                "Groupe introuvable",
+                "Gruppe nicht gefunden",
+            ],
+            ErrorCode::AttachmentNotFound => [
+                "Attachment not found",
+                "Archivo adjunto no encontrado",
+                "Pièce jointe introuvable",
+                "Anhang nicht gefunden",
+            ],
+            ErrorCode::NotCreator => [
+                "Only the creator can manage the discussion",
+                "Solo el creador puede gestionar la discusión",
+                "Seul le créateur peut gérer la discussion",
+                "Nur der Ersteller kann die Diskussion verwalten",
+            ],
+            ErrorCode::VotingClosed => [
+                "Voting on this discussion has closed",
+                "La votación de esta discusión ha terminado",
+                "Le vote sur cette discussion est clos",
+                "Die Abstimmung über diese Diskussion ist beendet",
+            ],
+            ErrorCode::NotGroupMember => [
+                "User is not a member of this group",
+                "El usuario no es miembro de este grupo",
+                "L'utilisateur n'est pas membre de ce groupe",
+                "Der Benutzer ist kein Mitglied dieser Gruppe",
+            ],
+            ErrorCode::AlreadyGroupMember => [
+                "User is already a member of this group",
+                "El usuario ya es miembro de este grupo",
+                "L'utilisateur est déjà membre de ce groupe",
+                "Der Benutzer ist bereits Mitglied dieser Gruppe",
+            ],
+        }
+    }
+}
+
+impl From<ErrorCode> for String {
+    fn from(code: ErrorCode) -> Self {
+        format!("{}: {}", code.name(), code.message(LOCALES[0]))
+    }
+}
+
+// Helper function to pick a supported locale from an Accept-Language style list such as "fr-CH, fr;q=0.9, en;q=0.8".
+// Entries are taken in the order given, quality values are not weighed.
+pub(crate) fn negotiate_locale(accept_language: &str) -> &'static str {
+    accept_language.split(',')
+        .filter_map(|entry| entry.split(';').next())
+        .map(|tag| tag.trim().split('-').next().unwrap_or_default().to_ascii_lowercase())
+        .find_map(|language| LOCALES.iter().copied().find(|locale| *locale == language))
+        .unwrap_or(LOCALES[0])
+}
+
+// Helper function to translate an error rendered from an ErrorCode, keeping any detail appended after the
+// message. Errors without a known code are returned as they are.
+pub(crate) fn localize(error: &str, locale: &str) -> String {
+    let Some((name, rest)) = error.split_once(": ") else {
+        return error.to_string();
+    };
+    let Some(code) = ErrorCode::from_name(name) else {
+        return error.to_string();
+    };
+
+    let detail = rest.strip_prefix(code.message(LOCALES[0])).unwrap_or_default();
+    format!("{}: {}{}", name, code.message(locale), detail)
+}
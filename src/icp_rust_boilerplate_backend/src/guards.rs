@@ -0,0 +1,44 @@
+use candid::Principal;
+use ic_cdk::api::{caller, is_controller};
+
+use crate::{CanisterRole, ADMINS_STORAGE, CANISTER_ROLE};
+
+// Guard for entrypoints that only canister controllers may call
+pub(crate) fn caller_is_controller() -> Result<(), String> {
+    if is_controller(&caller()) {
+        Ok(())
+    } else {
+        Err("Caller is not a controller".to_string())
+    }
+}
+
+// Guard for administrative entrypoints: controllers and principals granted admin rights
+pub(crate) fn caller_is_admin() -> Result<(), String> {
+    if is_admin(&caller()) {
+        Ok(())
+    } else {
+        Err("Caller is not an admin".to_string())
+    }
+}
+
+// Helper function to check a principal against the controllers and the stored admin set
+pub(crate) fn is_admin(principal: &Principal) -> bool {
+    is_controller(principal)
+        || ADMINS_STORAGE.with(|storage| storage.borrow().iter().any(|(_, admin)| admin.principal == *principal))
+}
+
+// Guard for the endpoints a shard exposes to the canister that created it
+pub(crate) fn caller_is_router() -> Result<(), String> {
+    match CANISTER_ROLE.with(|role| role.borrow().get().clone()) {
+        CanisterRole::Shard { router } if router == caller() => Ok(()),
+        _ => Err("Caller is not this shard's router".to_string()),
+    }
+}
+
+// Guard for the endpoints an archive, search or group canister exposes to its hub
+pub(crate) fn caller_is_hub() -> Result<(), String> {
+    match CANISTER_ROLE.with(|role| role.borrow().get().clone()) {
+        CanisterRole::Archive { hub } | CanisterRole::SearchIndex { hub } | CanisterRole::Group { hub, .. } if hub == caller() => Ok(()),
+        _ => Err("Caller is not this canister's hub".to_string()),
+    }
+}
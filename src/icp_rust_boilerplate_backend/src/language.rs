@@ -0,0 +1,60 @@
+// Common short words of the Latin-script languages the heuristic can tell apart
+const STOPWORDS: &[(&str, &[&str])] = &[
+    ("en", &["the", "and", "is", "of", "to", "in", "that", "it", "for", "you", "with", "this", "are", "be"]),
+    ("es", &["el", "la", "de", "que", "y", "en", "los", "las", "es", "por", "para", "con", "una", "del"]),
+    ("fr", &["le", "la", "les", "de", "et", "est", "des", "un", "une", "pour", "que", "dans", "pas", "du"]),
+    ("de", &["der", "die", "das", "und", "ist", "nicht", "ein", "eine", "zu", "mit", "den", "von", "ich", "auf"]),
+    ("it", &["il", "di", "che", "e", "la", "per", "non", "un", "una", "sono", "gli", "con", "del", "della"]),
+    ("pt", &["o", "de", "que", "e", "do", "da", "em", "um", "uma", "para", "com", "não", "os", "por"]),
+    ("nl", &["de", "het", "een", "en", "van", "is", "niet", "dat", "op", "te", "zijn", "met", "voor", "ik"]),
+];
+
+// Latin-script text needs at least this many stopword hits before a language is guessed
+const MIN_STOPWORD_HITS: usize = 2;
+
+// Helper function to guess the ISO 639-1 language of a short text: by script for non-Latin text,
+// otherwise by counting common words. Returns None when there is no clear signal.
+pub(crate) fn detect_language(text: &str) -> Option<&'static str> {
+    if let Some(language) = detect_by_script(text) {
+        return Some(language);
+    }
+
+    let words: Vec<String> = text.split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+
+    let mut scores: Vec<(&'static str, usize)> = STOPWORDS.iter()
+        .map(|(language, stopwords)| (*language, words.iter().filter(|word| stopwords.contains(&word.as_str())).count()))
+        .collect();
+    scores.sort_by_key(|score| std::cmp::Reverse(score.1));
+
+    match scores.as_slice() {
+        [(language, best), (_, second), ..] if *best >= MIN_STOPWORD_HITS && best > second => Some(language),
+        _ => None,
+    }
+}
+
+// Helper function to check if a creator-supplied tag looks like an ISO 639-1 code
+pub(crate) fn is_language_code(code: &str) -> bool {
+    code.len() == 2 && code.chars().all(|c| c.is_ascii_lowercase())
+}
+
+fn detect_by_script(text: &str) -> Option<&'static str> {
+    // Kana outranks Han, Japanese mixes both
+    if text.chars().any(|c| matches!(c, '\u{3040}'..='\u{30FF}')) {
+        return Some("ja");
+    }
+
+    text.chars().find_map(|c| match c {
+        '\u{0400}'..='\u{04FF}' => Some("ru"),
+        '\u{0370}'..='\u{03FF}' => Some("el"),
+        '\u{0590}'..='\u{05FF}' => Some("he"),
+        '\u{0600}'..='\u{06FF}' => Some("ar"),
+        '\u{0900}'..='\u{097F}' => Some("hi"),
+        '\u{0E00}'..='\u{0E7F}' => Some("th"),
+        '\u{AC00}'..='\u{D7AF}' => Some("ko"),
+        '\u{4E00}'..='\u{9FFF}' => Some("zh"),
+        _ => None,
+    })
+}
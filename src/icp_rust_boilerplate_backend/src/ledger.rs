@@ -0,0 +1,131 @@
+use candid::{Nat, Principal};
+
+// ICRC-1 account: an owner principal and an optional 32-byte subaccount
+#[derive(candid::CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Account {
+    pub(crate) owner: Principal,
+    pub(crate) subaccount: Option<Vec<u8>>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Debug, Serialize, Deserialize)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(candid::CandidType, Debug, Serialize, Deserialize)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// Helper function to read an account's balance on an ICRC-1 ledger
+pub(crate) async fn balance_of(ledger: Principal, account: Account) -> Result<Nat, String> {
+    let (balance,): (Nat,) = ic_cdk::call(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|(_, message)| format!("Ledger call failed: {}", message))?;
+
+    Ok(balance)
+}
+
+// Why a transfer did not complete
+pub(crate) enum TransferFailure {
+    // The ledger refused the transfer, so nothing moved
+    Rejected(String),
+    // No answer came back, or the ledger can no longer tell; the transfer may have gone through
+    Unknown(String),
+}
+
+impl std::fmt::Display for TransferFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferFailure::Rejected(message) | TransferFailure::Unknown(message) => f.write_str(message),
+        }
+    }
+}
+
+// Helper function to send tokens from one of this canister's subaccounts, returns the block index. Sending
+// again with the same memo and `created_at_time` within the ledger's deduplication window returns the block
+// of the first transfer instead of paying twice.
+pub(crate) async fn transfer(
+    ledger: Principal,
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: u64,
+    memo: Option<Vec<u8>>,
+    created_at_time: u64,
+) -> Result<Nat, TransferFailure> {
+    let arg = TransferArg {
+        from_subaccount,
+        to,
+        amount: Nat::from(amount),
+        fee: None,
+        memo,
+        created_at_time: Some(created_at_time),
+    };
+
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (arg,))
+        .await
+        .map_err(|(_, message)| TransferFailure::Unknown(format!("Ledger call failed: {}", message)))?;
+
+    match result {
+        Ok(block_index) => Ok(block_index),
+        Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+        // Past the deduplication window the ledger can't say whether an earlier attempt went through
+        Err(TransferError::TooOld) => Err(TransferFailure::Unknown("Transfer failed: TooOld, check the ledger for the memo".to_string())),
+        Err(error) => Err(TransferFailure::Rejected(format!("Transfer failed: {:?}", error))),
+    }
+}
+
+// Helper function to pull tokens a user approved (ICRC-2) into one of this canister's accounts, returns the block index
+pub(crate) async fn transfer_from(ledger: Principal, from: Account, to: Account, amount: u64, memo: Option<Vec<u8>>) -> Result<Nat, String> {
+    let arg = TransferFromArgs {
+        spender_subaccount: None,
+        from,
+        to,
+        amount: Nat::from(amount),
+        fee: None,
+        memo,
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+
+    let (result,): (Result<Nat, TransferFromError>,) = ic_cdk::call(ledger, "icrc2_transfer_from", (arg,))
+        .await
+        .map_err(|(_, message)| format!("Ledger call failed: {}", message))?;
+
+    result.map_err(|error| format!("Transfer failed: {:?}", error))
+}
@@ -1,350 +1,15233 @@
+#![recursion_limit = "256"]
+
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use candid::types::value::IDLField;
+use candid::{Decode, Encode, IDLArgs, IDLValue, Principal};
+use ic_cdk::api::{caller, time};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use flate2::{write::GzEncoder, Compression};
+use std::{borrow::Cow, cell::RefCell, collections::{BTreeMap, BTreeSet}, io::Write, ops::Bound, thread::LocalKey, time::Duration};
+
+mod errors;
+mod guards;
+mod language;
+mod ledger;
+mod logging;
+mod markdown;
+mod merkle;
+mod metrics;
+mod outbox;
+mod permissions;
+mod sanitize;
+mod schema;
+mod search;
+mod signing;
+use errors::ErrorCode;
+use ledger::Account;
+use logging::LogLevel;
+use merkle::MerkleStep;
+use outbox::{EventKind, OutboxEntry, SubscriberTarget, Subscription};
+use permissions::{Action, TrustLevel};
+use signing::CanisterSignature;
+use guards::{caller_is_admin, caller_is_controller, caller_is_hub, caller_is_router, is_admin};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+type SettingsCell = Cell<Settings, Memory>;
+type MaintenanceCell = Cell<MaintenanceMode, Memory>;
+type GcCell = Cell<GcState, Memory>;
+type RoleCell = Cell<CanisterRole, Memory>;
+type SearchIndexCell = Cell<SearchIndexConfig, Memory>;
+type DaoCell = Cell<DaoConfig, Memory>;
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct User {
     username: String,
     id: u64,
     created_at: u64,
+    // Lowercase 0x-prefixed address for accounts onboarded with Sign-In-With-Ethereum
+    eth_address: Option<String>,
+    // Refreshed whenever the permissions module consults it
+    trust_level: TrustLevel,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct Discussion {
-    id: u64,
-    topic: String,
-    created_by: String,
-    created_at: u64,
-    upvotes: u64,
-    downvotes: u64,
+struct PrivacySettings {
+    // Only the user can list their votes
+    hide_vote_history: bool,
+    // Leave the user out of get_users
+    hide_profile: bool,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct Vote {
+struct ContentPreferences {
+    // Include discussions flagged sensitive in listings
+    show_sensitive: bool,
+    // ISO 639-1 codes listings are limited to, empty for every language
+    languages: Vec<String>,
+}
+
+// Maps a principal to the account it may act as
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PrincipalLink {
     id: u64,
-    by: String,
-    discussion_id: u64,
-    vote_type: VoteType,
-    created_at: u64,
+    principal: Principal,
+    username: String,
+    linked_at: u64,
 }
 
+// One-time code issued by a linked principal for a new principal to confirm
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-enum VoteType {
-    Upvote,
-    Downvote,
+struct LinkChallenge {
+    id: u64,
+    username: String,
+    code: String,
+    issued_by: Principal,
+    expires_at: u64,
+    // Issued by an admin for an account without any linked principal, redeemed with claim_account
+    claim: bool,
+}
+
+// Tunable behaviour, stored in stable memory so admins can change it without a redeploy
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Settings {
+    max_topic_length: u32,
+    max_report_reason_length: u32,
+    // Discussions a user may start per rate-limit window, 0 disables the limit
+    max_discussions_per_window: u32,
+    rate_limit_window_seconds: u64,
+    recovery_delay_seconds: u64,
+    upvotes_enabled: bool,
+    downvotes_enabled: bool,
+    // Canister answering `is_human : (principal) -> (bool)`, None disables verification
+    personhood_provider: Option<Principal>,
+    // How long a successful personhood check is trusted
+    personhood_ttl_seconds: u64,
+    // Leading zero bits required from a registration proof of work, 0 disables the challenge
+    registration_pow_difficulty: u8,
+    // When set, web links in user text are rewritten to `<prefix><percent-encoded url>`
+    link_redirect_prefix: Option<String>,
+    // Accounts one principal / everyone may create per registration window, 0 disables a quota. Anonymous
+    // callers only count toward the global one.
+    max_registrations_per_principal: u32,
+    max_registrations_global: u32,
+    registration_window_seconds: u64,
+    // Bytes of discussions and attachments one user may store, 0 disables the quota
+    storage_quota_bytes: u64,
+    // Discussions without votes for this many days are moved to an archive canister, 0 disables archiving
+    archive_after_days: u32,
+    // ICRC-1 ledger holding the community treasury, None disables the treasury
+    treasury_ledger: Option<Principal>,
+    // Prices of premium features in the treasury ledger's smallest unit, 0 takes a feature off sale
+    pin_price: u64,
+    storage_boost_price: u64,
+    // Downvotes must say why
+    require_downvote_reason: bool,
+    // Karma gates, None leaves the action open to everyone
+    min_karma_to_downvote: Option<i64>,
+    min_karma_to_post_links: Option<i64>,
+    // Discussions a user may start per day before min_karma_for_extra_discussions applies
+    discussions_per_day_before_karma: u32,
+    min_karma_for_extra_discussions: Option<i64>,
+    // A principal sending more than flood_max_updates updates within flood_window_seconds is blocked for
+    // flood_backoff_seconds, doubled for every earlier incident that day. 0 disables flood protection.
+    flood_max_updates: u32,
+    flood_window_seconds: u64,
+    flood_backoff_seconds: u64,
+    // Wait between two discussions by the same user, 0 disables the cooldown
+    discussion_cooldown_seconds: u64,
+    // Users with at least this much karma skip the cooldown, as do admins
+    cooldown_waiver_karma: Option<i64>,
+    // Days a deleted discussion stays in the trash before it is purged
+    trash_retention_days: u32,
+    // Warnings count as strikes for strike_window_days. Reaching strikes_to_mute mutes the user for mute_hours,
+    // reaching strikes_to_suspend suspends them for suspension_days, doubled for every strike beyond it.
+    // A threshold of 0 disables that step.
+    strike_window_days: u32,
+    strikes_to_mute: u32,
+    mute_hours: u32,
+    strikes_to_suspend: u32,
+    suspension_days: u32,
+    // Distinct open reports that hide a discussion and mute its author until moderators review them,
+    // and distinct reporters across an author's discussions that mute the author. 0 disables either.
+    auto_hide_report_threshold: u32,
+    auto_mute_report_threshold: u32,
+    // Accounts younger than new_account_days cannot downvote or send direct messages and may start
+    // new_account_discussions_per_day discussions a day. 0 days disables the window.
+    new_account_days: u32,
+    new_account_discussions_per_day: u32,
+    // Lowest trust level that may edit wiki discussions started by someone else
+    wiki_edit_trust_level: TrustLevel,
+    // Days without activity before a forum discussion is locked, 0 never. Groups may set their own.
+    auto_lock_after_days: u32,
+    // Votes older than this stop counting toward tallies and scores, 0 keeps every vote counting.
+    // Discussions whose results are final keep their tally.
+    vote_expiry_days: u32,
+    // Accounts with no discussion, vote or edit for karma_decay_after_days lose karma_decay_percent of their
+    // positive karma every karma_decay_interval_days until they are active again. 0 days disables decay.
+    karma_decay_after_days: u32,
+    karma_decay_percent: u32,
+    karma_decay_interval_days: u32,
+    // Karma taken from a user with each formal warning, 0 leaves karma alone
+    warning_karma_penalty: u32,
+    // Threshold ECDSA key the canister signs attestations with: dfx_test_key locally, test_key_1 or key_1 on mainnet
+    signing_key_name: String,
+    // vetKD master key direct message keys derive from, named like signing_key_name. Empty in settings stored
+    // before it was added, see dm_key_id.
+    vetkd_key_name: String,
 }
 
-impl Default for VoteType {
+impl Default for Settings {
     fn default() -> Self {
-        VoteType::Upvote
+        Settings {
+            max_topic_length: 300,
+            max_report_reason_length: 500,
+            max_discussions_per_window: 10,
+            rate_limit_window_seconds: 60 * 60,
+            recovery_delay_seconds: 48 * 60 * 60,
+            upvotes_enabled: true,
+            downvotes_enabled: true,
+            personhood_provider: None,
+            personhood_ttl_seconds: 30 * 24 * 60 * 60,
+            registration_pow_difficulty: 0,
+            link_redirect_prefix: None,
+            max_registrations_per_principal: 3,
+            max_registrations_global: 500,
+            registration_window_seconds: 60 * 60,
+            storage_quota_bytes: 25 * 1024 * 1024,
+            archive_after_days: 0,
+            treasury_ledger: None,
+            pin_price: 0,
+            storage_boost_price: 0,
+            require_downvote_reason: false,
+            min_karma_to_downvote: None,
+            min_karma_to_post_links: None,
+            discussions_per_day_before_karma: 3,
+            min_karma_for_extra_discussions: None,
+            flood_max_updates: 30,
+            flood_window_seconds: 10,
+            flood_backoff_seconds: 60,
+            discussion_cooldown_seconds: 0,
+            cooldown_waiver_karma: None,
+            trash_retention_days: 30,
+            strike_window_days: 90,
+            strikes_to_mute: 3,
+            mute_hours: 24,
+            strikes_to_suspend: 5,
+            suspension_days: 7,
+            auto_hide_report_threshold: 5,
+            auto_mute_report_threshold: 10,
+            new_account_days: 3,
+            new_account_discussions_per_day: 1,
+            wiki_edit_trust_level: TrustLevel::Member,
+            auto_lock_after_days: 0,
+            vote_expiry_days: 0,
+            karma_decay_after_days: 0,
+            karma_decay_percent: 5,
+            karma_decay_interval_days: 30,
+            warning_karma_penalty: 0,
+            signing_key_name: "dfx_test_key".to_string(),
+            vetkd_key_name: "dfx_test_key".to_string(),
+        }
     }
 }
 
-impl Storable for User {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// One page of a listing query; pass `next_cursor` back to fetch the following page
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Page<T> {
+    items: Vec<T>,
+    next_cursor: Option<String>,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Progress and totals of the background garbage collector
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct GcState {
+    phase: GcPhase,
+    // Next key to scan in the current phase
+    cursor: u64,
+    last_tick_at: u64,
+    completed_passes: u64,
+    removed_votes: u64,
+    removed_group_members: u64,
+    removed_join_requests: u64,
+    removed_invites: u64,
+    removed_reports: u64,
+    removed_messages: u64,
+    removed_challenges: u64,
+    removed_idempotency_records: u64,
+    removed_views: u64,
+    removed_attachments: u64,
 }
 
-impl BoundedStorable for User {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Storage maps visited by the garbage collector, in order
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+enum GcPhase {
+    #[default]
+    Votes,
+    GroupMembers,
+    JoinRequests,
+    Invites,
+    Reports,
+    Messages,
+    LinkChallenges,
+    SiweChallenges,
+    RegistrationChallenges,
+    IdempotencyRecords,
+    Views,
+    Attachments,
 }
 
-impl Storable for Discussion {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// First successful response of a mutating call, replayed when the same caller retries with the same key
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IdempotencyRecord {
+    // Derived from (caller, method, key) by idempotency_slot
+    id: u64,
+    caller: Principal,
+    method: String,
+    key: String,
+    // Candid-encoded Ok value of the original call
+    response: Vec<u8>,
+    expires_at: u64,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Everything the discussion screen needs, fetched in one call
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DiscussionPage {
+    discussion: Discussion,
+    upvotes: u64,
+    downvotes: u64,
+    // The viewer's own vote, if any
+    my_vote: Option<Vote>,
+    group: Option<Group>,
+    // Newest other discussions from the same group, or the forum for ungrouped ones
+    related: Vec<Discussion>,
+    link_preview: Option<LinkPreview>,
+    // Normalized, HTML-safe Markdown of the topic
+    topic_markdown: Option<String>,
+    // Discussions forked from this one that the viewer can see, newest first
+    forks: Vec<Discussion>,
 }
 
-impl BoundedStorable for Discussion {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Encoding applied to the payload of a CompressedBlob
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum Codec {
+    // CBOR-encoded value, then gzip-compressed
+    GzipCbor,
 }
 
-impl Storable for Vote {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// A bulk query result packed to fit the message limit; decode `data` according to `codec`
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CompressedBlob {
+    codec: Codec,
+    uncompressed_size: u64,
+    data: Vec<u8>,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Result sets that can be snapshotted and fetched in chunks
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum StreamSource {
+    Discussions,
+    Users,
+    // Admins only
+    Backup,
 }
 
-impl BoundedStorable for Vote {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// A discussion's results rendered to CSV once for one download, held in the heap until it expires
+struct ResultsCsvSnapshot {
+    csv: Vec<u8>,
+    expires_at: u64,
 }
 
-// Thread-local storage for the memory manager and data storage
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0).expect("Cannot create a counter")
-    );
-    static USERS_STORAGE: RefCell<StableBTreeMap<u64, User, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))))
-    );
-    static DISCUSSIONS_STORAGE: RefCell<StableBTreeMap<u64, Discussion, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))))
-    );
-    static VOTES_STORAGE: RefCell<StableBTreeMap<u64, Vote, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))))
-    );
+// A frozen, encoded result set held in the heap until it expires
+struct StreamSnapshot {
+    owner: Principal,
+    source: StreamSource,
+    blob: CompressedBlob,
+    expires_at: u64,
+    // Built for an owner who opted in to sensitive discussions, so never served over HTTP
+    includes_sensitive: bool,
+    created_at: u64,
+    // Kept once signed, so the snapshot is only signed once
+    manifest: Option<SignedManifest>,
 }
 
-// Function to register a user
-#[ic_cdk::update]
-fn register_user(username: String) -> Result<User, String> {
-    if username.is_empty() {
-        return Err("Username is required".to_string());
-    }
+// What a signed export covers: the canister, the stream and the SHA-256 of each chunk in order
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ExportManifest {
+    canister_id: Principal,
+    handle: u64,
+    source: StreamSource,
+    codec: Codec,
+    total_size: u64,
+    chunk_size: u64,
+    chunk_hashes: Vec<Vec<u8>>,
+    created_at: u64,
+}
 
-    if is_user_registered(&username) {
-        return Err("Username already exists".to_string());
-    }
+// An export manifest signed by the canister. The signature is over the SHA-256 of `manifest_cbor`, the
+// CBOR encoding of `manifest`; a consumer checks it, then checks every chunk against its hash.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SignedManifest {
+    manifest: ExportManifest,
+    manifest_cbor: Vec<u8>,
+    signature: CanisterSignature,
+}
 
-    let id = ID_COUNTER.with(|counter| {
-        let current_value = *counter.borrow().get();
-        counter.borrow_mut().set(current_value + 1)
-    }).expect("Cannot increment ID counter");
+// Handle and layout of an opened stream
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StreamInfo {
+    handle: u64,
+    codec: Codec,
+    total_size: u64,
+    chunk_count: u32,
+    expires_at: u64,
+}
 
-    let new_user = User {
-        username: username.clone(),
-        id,
-        created_at: time(),
-    };
+// One sequenced piece of a stream; concatenate all chunks in order, then decode by the stream's codec
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StreamChunk {
+    handle: u64,
+    chunk_index: u32,
+    chunk_count: u32,
+    data: Vec<u8>,
+}
 
-    USERS_STORAGE.with(|storage| storage.borrow_mut().insert(id, new_user.clone()));
+#[derive(candid::CandidType, Deserialize)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
 
-    Ok(new_user)
+#[derive(candid::CandidType, Deserialize)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    streaming_strategy: Option<StreamingStrategy>,
+    // Asks the gateway to send the request again to http_request_update
+    upgrade: Option<bool>,
 }
 
-// Helper function to check if a user is registered
-fn is_user_registered(username: &String) -> bool {
-    USERS_STORAGE.with(|storage| {
-        storage.borrow().iter().any(|(_, user)| user.username == *username)
-    })
+#[derive(candid::CandidType, Clone, Deserialize)]
+struct StreamingToken {
+    handle: u64,
+    chunk_index: u32,
 }
 
-// Function to create a new discussion with user validation
-#[ic_cdk::update]
-fn create_discussion(topic: String, username: String) -> Result<Discussion, String> {
-    if topic.is_empty() {
-        return Err("Topic is required".to_string());
-    }
+candid::define_function!(StreamingCallback : (StreamingToken) -> (StreamingCallbackHttpResponse) query);
 
-    // Validate if user is registered
-    if !is_user_registered(&username) {
-        return Err("User is not registered".to_string());
-    }
+#[derive(candid::CandidType, Deserialize)]
+enum StreamingStrategy {
+    Callback { callback: StreamingCallback, token: StreamingToken },
+}
 
-    let id = ID_COUNTER.with(|counter| {
-        let current_value = *counter.borrow().get();
-        counter.borrow_mut().set(current_value + 1)
-    }).expect("Cannot increment ID counter");
+#[derive(candid::CandidType, Deserialize)]
+struct StreamingCallbackHttpResponse {
+    body: Vec<u8>,
+    token: Option<StreamingToken>,
+}
 
-    let discussion = Discussion {
-        id,
-        topic,
-        created_by: username,
-        created_at: time(),
-        upvotes: 0,
-        downvotes: 0,
-    };
+// Result of cross-checking the storage maps against each other
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct IntegrityReport {
+    checked_discussions: u64,
+    checked_votes: u64,
+    tally_mismatches: Vec<TallyMismatch>,
+    // Votes whose discussion or voter no longer exists
+    orphaned_votes: Vec<u64>,
+    // Second and later votes by the same user on the same discussion
+    duplicate_votes: Vec<u64>,
+    orphaned_group_members: Vec<u64>,
+    orphaned_principal_links: Vec<u64>,
+    // Largest stored ID is at or above the ID counter, so new records could overwrite it
+    id_counter_behind: bool,
+}
 
-    DISCUSSIONS_STORAGE.with(|storage| storage.borrow_mut().insert(id, discussion.clone()));
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct TallyMismatch {
+    discussion_id: u64,
+    stored_upvotes: u64,
+    stored_downvotes: u64,
+    counted_upvotes: u64,
+    counted_downvotes: u64,
+}
 
-    Ok(discussion)
+// What a destructive operation changed, or would change when run as a dry run
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ChangeReport {
+    dry_run: bool,
+    // Maps with at least one change, in the order they are touched
+    changes: Vec<MapChange>,
 }
 
-// New function to allow discussion topic edit (only by creator)
-#[ic_cdk::update]
-fn edit_discussion(discussion_id: u64, new_topic: String, username: String) -> Result<String, String> {
-    if new_topic.is_empty() {
-        return Err("New topic cannot be empty".to_string());
-    }
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct MapChange {
+    map: String,
+    removed: Vec<u64>,
+    updated: Vec<u64>,
+    // IDs of new entries are only allocated when the change is applied
+    inserted: u64,
+}
 
-    let mut discussion = DISCUSSIONS_STORAGE.with(|storage| {
-        storage.borrow().get(&discussion_id).map(|d| d.clone())
-    }).ok_or("Discussion not found")?;
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Metrics {
+    users: u64,
+    discussions: u64,
+    votes: u64,
+    groups: u64,
+    gc: GcState,
+    discussion_cache: CacheStats,
+    // Instructions per update entrypoint, heaviest first
+    endpoints: Vec<metrics::EndpointMetrics>,
+}
 
-    if discussion.created_by != username {
-        return Err("Only the creator can edit the discussion".to_string());
-    }
+// Stable memory used by one map or cell
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct MapStats {
+    name: String,
+    memory_id: u8,
+    entries: u64,
+    // Entries times the average encoded size of a sample, keys included
+    approx_bytes: u64,
+    // Pages the memory manager allocated to this MemoryId, in buckets
+    pages: u64,
+}
 
-    discussion.topic = new_topic;
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StorageStats {
+    // Largest allocation first
+    maps: Vec<MapStats>,
+    allocated_pages: u64,
+    // Pages of the canister's stable memory, including the memory manager's header
+    stable_memory_pages: u64,
+}
 
-    DISCUSSIONS_STORAGE.with(|storage| storage.borrow_mut().insert(discussion_id, discussion));
+// Counters of the in-heap discussion cache since the last upgrade
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CacheStats {
+    entries: u64,
+    hits: u64,
+    misses: u64,
+}
 
-    Ok("Discussion topic updated".to_string())
+// Least-recently-used cache of decoded discussions, kept in the heap in front of stable memory
+#[derive(Default)]
+struct DiscussionCache {
+    // Discussion and the tick it was last used at
+    entries: BTreeMap<u64, (Discussion, u64)>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
 }
 
-// Function to vote on a discussion
-#[ic_cdk::update]
-fn vote_discussion(vote_type: VoteType, discussion_id: u64, username: String) -> Result<String, String> {
-    if !is_user_registered(&username) {
-        return Err("User is not registered".to_string());
+impl DiscussionCache {
+    fn get(&mut self, id: u64) -> Option<Discussion> {
+        self.tick += 1;
+        match self.entries.get_mut(&id) {
+            Some((discussion, used_at)) => {
+                *used_at = self.tick;
+                self.hits += 1;
+                Some(discussion.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
     }
 
-    let user_has_voted = VOTES_STORAGE.with(|storage| {
-        storage.borrow().iter().any(|(_, vote)| vote.by == username && vote.discussion_id == discussion_id)
-    });
+    fn put(&mut self, discussion: Discussion) {
+        self.tick += 1;
+        if !self.entries.contains_key(&discussion.id) && self.entries.len() >= DISCUSSION_CACHE_CAPACITY {
+            let oldest = self.entries.iter().min_by_key(|(_, (_, used_at))| *used_at).map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(discussion.id, (discussion, self.tick));
+    }
 
-    if user_has_voted {
-        return Err("User has already voted on this discussion".to_string());
+    fn remove(&mut self, id: u64) {
+        self.entries.remove(&id);
     }
 
-    let id = ID_COUNTER.with(|counter| {
-        let current_value = *counter.borrow().get();
-        counter.borrow_mut().set(current_value + 1)
-    }).expect("Cannot increment ID counter");
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
 
-    let vote = Vote {
-        id,
-        by: username.clone(),
-        discussion_id,
-        vote_type: vote_type.clone(),
-        created_at: time(),
-    };
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len() as u64,
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+}
 
-    VOTES_STORAGE.with(|storage| storage.borrow_mut().insert(id, vote));
+// While enabled, every non-admin update call fails and queries keep working
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct MaintenanceMode {
+    enabled: bool,
+    message: Option<String>,
+    // Expected end of maintenance, in nanoseconds since the epoch
+    eta: Option<u64>,
+}
 
-    let updated_discussion = DISCUSSIONS_STORAGE.with(|storage| {
-        storage.borrow().get(&discussion_id).map(|d| d.clone())
-    });
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FeatureFlag {
+    id: u64,
+    name: String,
+    enabled: bool,
+    updated_by: Principal,
+    updated_at: u64,
+}
 
-    if let Some(mut discussion) = updated_discussion {
-        match vote_type {
-            VoteType::Upvote => discussion.upvotes += 1,
-            VoteType::Downvote => discussion.downvotes += 1,
-        }
+// Principal granted admin rights by a controller
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Admin {
+    id: u64,
+    principal: Principal,
+    added_by: Principal,
+    added_at: u64,
+}
 
-        DISCUSSIONS_STORAGE.with(|storage| {
-            storage.borrow_mut().insert(discussion_id, discussion);
-        });
+// SNS governance canister acting as super-admin; while set, admins can't change settings or admin rights
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DaoConfig {
+    governance: Option<Principal>,
+}
 
-        Ok("Vote recorded for discussion".to_string())
-    } else {
-        Err("Discussion not found".to_string())
-    }
+// Destructive action an adopted SNS proposal asks the canister to perform
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum DaoAction {
+    UpdateSettings(Box<Settings>),
+    AddAdmin(Principal),
+    RemoveAdmin(Principal),
+    // None hands administration back to admins and controllers
+    SetGovernance(Option<Principal>),
+    ApproveTreasuryPayout(u64),
+    RestoreBackup(Box<Backup>),
+    BulkDeleteDiscussions(Vec<u64>),
+    PatchRecord { map: StoredMap, key: u64, patches: Vec<FieldPatch> },
+    DeleteRecord { map: StoredMap, key: u64 },
+    SetFeatureFlag { name: String, enabled: bool },
 }
 
-// New function to remove a vote from a discussion
-#[ic_cdk::update]
-fn remove_vote(discussion_id: u64, username: String) -> Result<String, String> {
-    if !is_user_registered(&username) {
-        return Err("User is not registered".to_string());
-    }
+// A payout from the community treasury, e.g. a bounty or contributor reward
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TreasuryPayout {
+    id: u64,
+    to: Account,
+    amount: u64,
+    reason: String,
+    requested_by: Principal,
+    requested_at: u64,
+    approved_by: Option<Principal>,
+    status: PayoutStatus,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum PayoutStatus {
+    Pending,
+    // The ledger transfer is in flight
+    Sending,
+    Paid { block_index: candid::Nat },
+    // The ledger refused the transfer, nothing was paid
+    Failed(String),
+    Rejected,
+    // The transfer may or may not have gone through; retry_treasury_payout settles it
+    Unsettled { created_at_time: u64, error: String },
+}
 
-    let vote = VOTES_STORAGE.with(|storage| {
-        storage.borrow().iter().find(|(_, vote)| vote.by == username && vote.discussion_id == discussion_id).map(|(_, v)| v.clone())
-    }).ok_or("Vote not found")?;
+// Paid feature a user can buy
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum PremiumFeature {
+    // Lists one of the buyer's discussions above the others for PIN_DURATION
+    PinDiscussion { discussion_id: u64 },
+    // Raises the buyer's storage quota by STORAGE_BOOST_BYTES for STORAGE_BOOST_DURATION
+    StorageBoost,
+}
 
-    VOTES_STORAGE.with(|storage| storage.borrow_mut().remove(&vote.id));
+// A purchased premium feature and how long it lasts
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Entitlement {
+    id: u64,
+    username: String,
+    feature: PremiumFeature,
+    amount_paid: u64,
+    block_index: candid::Nat,
+    purchased_at: u64,
+    expires_at: u64,
+}
 
-    let mut discussion = DISCUSSIONS_STORAGE.with(|storage| {
-        storage.borrow().get(&discussion_id).map(|d| d.clone())
-    }).ok_or("Discussion not found")?;
+// A registration made with someone's referral code
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Referral {
+    id: u64,
+    referrer: String,
+    referee: String,
+    created_at: u64,
+    status: ReferralStatus,
+}
 
-    match vote.vote_type {
-        VoteType::Upvote => discussion.upvotes -= 1,
-        VoteType::Downvote => discussion.downvotes -= 1,
-    }
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum ReferralStatus {
+    // Waiting for the referee to reach the activity threshold
+    Pending,
+    Credited { at: u64 },
+    // Not credited, e.g. because the referrer reached the cap
+    Declined(String),
+}
 
-    DISCUSSIONS_STORAGE.with(|storage| storage.borrow_mut().insert(discussion_id, discussion));
+// Karma granted outside of votes, e.g. for referrals
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct KarmaAward {
+    username: String,
+    amount: i64,
+    reason: String,
+    awarded_at: u64,
+}
 
-    Ok("Vote removed".to_string())
+// Where a user's karma comes from; the parts add up to `total`
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct KarmaBreakdown {
+    // Counted votes on the user's discussions
+    upvotes_received: u64,
+    downvotes_received: u64,
+    referrals: i64,
+    // Negative, taken with formal warnings
+    moderation_penalties: i64,
+    // Negative, lost while inactive
+    inactivity_decay: i64,
+    other_awards: i64,
+    total: i64,
 }
 
-// Function to delete a user and associated data
-#[ic_cdk::update]
-fn delete_user(username: String) -> Result<String, String> {
-    if !is_user_registered(&username) {
-        return Err("User not found".to_string());
-    }
+// Running karma of a user split by where it came from, updated as votes and awards happen so karma never
+// scans discussions or awards. A vote counts while it stands: removing or expiring it takes it back out,
+// deleting the discussion does not.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct KarmaTally {
+    upvotes_received: u64,
+    downvotes_received: u64,
+    referrals: i64,
+    moderation_penalties: i64,
+    inactivity_decay: i64,
+    other_awards: i64,
+    // When inactivity last decayed it, 0 if never
+    last_decayed_at: u64,
+}
 
-    let user_id = USERS_STORAGE.with(|storage| {
-        storage.borrow().iter().find(|(_, user)| user.username == username).map(|(id, _)| id)
-    }).ok_or("User not found")?;
+// A user's referral code and how their referrals are doing
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReferralStats {
+    code: String,
+    pending: u64,
+    credited: u64,
+    declined: u64,
+    karma_earned: i64,
+}
 
-    // Remove the user
-    USERS_STORAGE.with(|storage| storage.borrow_mut().remove(&user_id));
+// What a user shows about themselves
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Profile {
+    display_name: String,
+    bio: String,
+}
 
-    // Remove all votes and update discussions
-    VOTES_STORAGE.with(|storage| {
-        let votes: Vec<u64> = storage.borrow().iter()
-            .filter(|(_, vote)| vote.by == username)
-            .map(|(id, _)| id)
-            .collect();
+// When a user finished each first-run step
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct OnboardingProgress {
+    profile_completed_at: Option<u64>,
+    first_vote_at: Option<u64>,
+    first_discussion_at: Option<u64>,
+    completed_at: Option<u64>,
+}
 
-        let mut storage_mut = storage.borrow_mut();  // Mutable borrow happens here once, outside the loop
-        for vote_id in votes {
-            storage_mut.remove(&vote_id);
-        }
-    });
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum OnboardingStep {
+    CompleteProfile,
+    CastFirstVote,
+    StartFirstDiscussion,
+}
 
-    // Remove discussions created by the user (or mark them as anonymous)
-    DISCUSSIONS_STORAGE.with(|storage| {
-        let keys_to_update: Vec<u64> = storage.borrow().iter()
-        .filter(|(_, discussion)| discussion.created_by == username)
-        .map(|(id, _)| id)
-        .collect();
+// A user's onboarding progress and the step a guided first run should show next
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OnboardingStatus {
+    progress: OnboardingProgress,
+    next_step: Option<OnboardingStep>,
+}
 
-        let mut storage_mut = storage.borrow_mut();
-        for id in keys_to_update {
-            if let Some(mut discussion) = storage_mut.remove(&id) {
-                discussion.created_by = "Anonymous".to_string();
-                storage_mut.insert(id, discussion); // Reinsert the modified discussion
-            }
-        }
-    });
-    
-    Ok("User and associated data deleted".to_string())
+// Running counts of a user's activity, updated as it happens so stats never scan discussions or votes.
+// Counts are lifetime totals and are not lowered when a discussion or vote is removed.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct UserActivity {
+    discussions_created: u64,
+    votes_cast: u64,
+    // Votes cast on the user's discussions
+    votes_received: u64,
+    // 0 until the user first posts or votes
+    first_activity_at: u64,
+    last_activity_at: u64,
+    // Consecutive UTC days with a post or vote, ending on last_active_day (days since the Unix epoch)
+    current_streak: u32,
+    longest_streak: u32,
+    last_active_day: u64,
 }
 
-// Function to get all discussions
-#[ic_cdk::query]
-fn get_discussions() -> Vec<Discussion> {
-    DISCUSSIONS_STORAGE.with(|storage| {
-        storage.borrow().iter().map(|(_, discussion)| discussion).collect()
-    })
+// A user's activity counts, and the share of users with a lower count for each of them
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct UserStats {
+    discussions_created: u64,
+    votes_cast: u64,
+    votes_received: u64,
+    first_activity_at: Option<u64>,
+    last_activity_at: Option<u64>,
+    // 0 once a UTC day has passed without a post or vote
+    current_streak: u32,
+    longest_streak: u32,
+    // Percent of users ranked below, 0 to 100
+    discussions_percentile: u32,
+    votes_cast_percentile: u32,
+    votes_received_percentile: u32,
 }
 
-// Function to get all users
-#[ic_cdk::query]
-fn get_users() -> Vec<User> {
-    USERS_STORAGE.with(|storage| {
-        storage.borrow().iter().map(|(_, user)| user).collect()
-    })
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum BadgeKind {
+    // Finished onboarding
+    Onboarded,
+    // Posted or voted on this many UTC days in a row
+    Streak { days: u32 },
 }
 
-// Function to get total vote count for a discussion
-#[ic_cdk::query]
-fn get_vote_count(discussion_id: u64) -> Result<(u64, u64), String> {
-    let discussion = DISCUSSIONS_STORAGE.with(|storage| {
-        storage.borrow().get(&discussion_id).map(|d| d.clone())
-    }).ok_or("Discussion not found")?;
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Badge {
+    username: String,
+    kind: BadgeKind,
+    awarded_at: u64,
+}
 
-    Ok((discussion.upvotes, discussion.downvotes))
+// Maps of stored records, for quarantining records that no longer decode and repairing them
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum StoredMap {
+    Users,
+    Discussions,
+    Votes,
+    Groups,
+    GroupMembers,
+    GroupInvites,
+    JoinRequests,
+    Reports,
+    GroupAuditLog,
+    Conversations,
+    Messages,
+    PrincipalLinks,
+    LinkChallenges,
+    SiweChallenges,
+    PrivacySettings,
+    RecoveryConfigs,
+    PendingRecoveries,
+    AccountAuditLog,
+    Tombstones,
+    Admins,
+    FeatureFlags,
+    Idempotency,
+    VoteCommitments,
+    Personhood,
+    RegistrationChallenges,
+    RegistrationEvents,
+    Views,
+    ReadMarkers,
+    KeywordAlerts,
+    Notifications,
+    HashtagIndex,
+    LinkPreviews,
+    LinkPreviewQueue,
+    TopicMarkdown,
+    Attachments,
+    AttachmentChunks,
+    CanisterWasm,
+    Shards,
+    Archives,
+    SearchDocuments,
+    GroupDirectory,
+    Proposals,
+    ProposalVotes,
+    VotingPowerSnapshots,
+    TreasuryPayouts,
+    TreasuryDeposits,
+    Entitlements,
+    Referrals,
+    KarmaAwards,
+    Profiles,
+    Onboarding,
+    Badges,
+    Terms,
+    TermsAcceptances,
+    ContentPreferences,
+    FloodIncidents,
+    Log,
+    EndpointMetrics,
+    Trash,
+    Warnings,
+    Restrictions,
+    Appeals,
+    DiscussionRevisions,
+    OwnershipTransfers,
+    Surveys,
+    SurveyResponses,
+    Outbox,
+    DeadLetters,
+    EventSubscriptions,
+    Reminders,
+    ReminderWheel,
+    Recurrences,
+    UnlockRequests,
+    UserActivity,
+    KarmaTallies,
+    Raffles,
+    RandomnessAudit,
+    ResultAttestations,
+}
+
+// A formal warning from a moderator; each one counts as a strike for strike_window_days
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Warning {
+    id: u64,
+    username: String,
+    reason: String,
+    issued_by: Principal,
+    issued_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum RestrictionKind {
+    // Cannot post text or start discussions
+    Mute,
+    // Cannot use the account at all, apart from reading its warnings
+    Suspension,
+}
+
+// A temporary restriction on an account
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Restriction {
+    id: u64,
+    username: String,
+    kind: RestrictionKind,
+    reason: String,
+    // The warning whose strike triggered the restriction
+    warning_id: Option<u64>,
+    // The reported discussion that triggered an automatic mute
+    discussion_id: Option<u64>,
+    starts_at: u64,
+    // u64::MAX while the restriction waits for a moderator
+    ends_at: u64,
+    lifted: bool,
+    // Applied automatically because of reports, lifted again if moderators dismiss them
+    pending_review: bool,
+    // Group canisters: copied from the hub, replaced by the next sync of the account
+    from_hub: bool,
+}
+
+// Moderation actions a user can appeal
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum AppealTarget {
+    // A discussion a moderator moved to the trash
+    Removal { discussion_id: u64 },
+    // A mute or suspension
+    Restriction { restriction_id: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum AppealStatus {
+    Pending,
+    // The action stands
+    Upheld,
+    // The action was undone
+    Reversed,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Appeal {
+    id: u64,
+    username: String,
+    target: AppealTarget,
+    statement: String,
+    status: AppealStatus,
+    created_at: u64,
+    // Username of the deciding moderator, or the principal of an admin acting without one
+    decided_by: Option<String>,
+    decision_reason: Option<String>,
+    decided_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct MyWarnings {
+    warnings: Vec<Warning>,
+    restrictions: Vec<Restriction>,
+    // Warnings still inside the strike window
+    active_strikes: u32,
+}
+
+// A deleted discussion waiting in the trash. Its votes and other records stay where they are until it is purged.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct TrashedDiscussion {
+    discussion: Discussion,
+    // Empty when the discussion was hidden automatically because of reports
+    deleted_by: String,
+    deleted_at: u64,
+    purge_at: u64,
+    // Hidden by reports and waiting for a moderator, never purged in that state
+    pending_review: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum RevisionKind {
+    // The topic before the first recorded change, attributed to the creator
+    Baseline,
+    Edit,
+    Revert { revision_id: u64 },
+    // Ownership handed over; the topic is unchanged
+    Transfer { from: String, to: String },
+}
+
+// One version of a discussion's topic and who wrote it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DiscussionRevision {
+    // 0 for a baseline that has not been stored yet
+    id: u64,
+    discussion_id: u64,
+    // The discussion's version once this revision was applied
+    version: u64,
+    topic: String,
+    edited_by: String,
+    edited_at: u64,
+    kind: RevisionKind,
+}
+
+// An ownership handoff waiting for the new owner to accept, keyed by discussion ID
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OwnershipTransfer {
+    discussion_id: u64,
+    from: String,
+    to: String,
+    created_at: u64,
+}
+
+// A record moved out of its map because it no longer decodes under the current schema
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct QuarantinedRecord {
+    id: u64,
+    map: StoredMap,
+    key: u64,
+    bytes: Vec<u8>,
+    error: String,
+    quarantined_at: u64,
+}
+
+// A stored record as bytes, for repairing records the current schema cannot read
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RawRecord {
+    map: StoredMap,
+    key: u64,
+    bytes: Vec<u8>,
+    decode_error: Option<String>,
+    // The bytes rendered as Candid text, if they are Candid at all
+    candid: Option<String>,
+    quarantined: bool,
+}
+
+// New value for one field of a stored record, `value` holds a single Candid-encoded value
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FieldPatch {
+    field: String,
+    value: Vec<u8>,
+}
+
+// Progress of a quarantine scan; pass `next_cursor` back to continue
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct QuarantineScan {
+    quarantined: u64,
+    next_cursor: Option<u64>,
+}
+
+// Update calls seen from one principal in the current flood window
+#[derive(Default)]
+struct CallerActivity {
+    window_start: u64,
+    updates: u32,
+    blocked_until: u64,
+}
+
+// A burst of updates that triggered a backoff, kept for moderators
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FloodIncident {
+    id: u64,
+    principal: Principal,
+    // Account linked to the principal, if any
+    username: Option<String>,
+    updates: u32,
+    window_seconds: u64,
+    // 0 for the first incident of the day, each level doubles the backoff
+    level: u32,
+    detected_at: u64,
+    blocked_until: u64,
+}
+
+// A published version of the terms of service; the document itself is hosted elsewhere
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TermsVersion {
+    version: u32,
+    // SHA-256 of the document, as published by the admin
+    document_hash: Vec<u8>,
+    url: String,
+    published_at: u64,
+    published_by: Principal,
+}
+
+// A user's acceptance of one terms version
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TermsAcceptance {
+    username: String,
+    version: u32,
+    accepted_at: u64,
+}
+
+// Tokens a user moved into the treasury through an ICRC-2 approval
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TreasuryDeposit {
+    id: u64,
+    from: Principal,
+    amount: u64,
+    block_index: candid::Nat,
+    deposited_at: u64,
+}
+
+// A formal decision with fixed options, separate from discussions
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Proposal {
+    id: u64,
+    title: String,
+    description: String,
+    created_by: String,
+    created_at: u64,
+    options: Vec<String>,
+    // Vote weight per option, in option order
+    tallies: Vec<u64>,
+    // 32 bytes from raw_rand that, with a voter's user ID, fix the order options are shown to that voter
+    shuffle_seed: Vec<u8>,
+    rules: ProposalRules,
+    // Options added by voters, the last `write_ins` entries of `options`
+    write_ins: u32,
+    // Sum of all weights in the proposal's voting-power snapshot
+    eligible_weight: u64,
+    voting_ends_at: u64,
+    // Runs when option 0 wins
+    action: Option<ProposalAction>,
+    status: ProposalStatus,
+    winning_option: Option<u32>,
+    decided_at: Option<u64>,
+}
+
+// How a proposal is voted on and when it passes
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ProposalRules {
+    // Voters may add up to this many options of their own, none when unset
+    max_write_ins: Option<u32>,
+    // Total vote weight needed for the result to count
+    quorum: u64,
+    // Share of the vote weight (percent) the leading option needs
+    threshold_percent: u8,
+    weighting: VoteWeighting,
+}
+
+// How much each vote on a proposal counts. Weights come from a snapshot taken when the proposal is
+// created, so only accounts registered by then can vote and later changes don't move the outcome.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum VoteWeighting {
+    OnePerUser,
+    // The user's karma, accounts at zero or below can't vote
+    Reputation,
+}
+
+// A user's vote weight on one proposal, fixed when the proposal was created
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VotingPowerEntry {
+    proposal_id: u64,
+    username: String,
+    weight: u64,
+}
+
+// Change applied automatically when a proposal with an action passes
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum ProposalAction {
+    SetFeatureFlag { name: String, enabled: bool },
+    UpdateSettings(Box<Settings>),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum ProposalStatus {
+    Open,
+    Passed,
+    Rejected,
+    Executed,
+    ExecutionFailed(String),
+}
+
+// A proposal as one voter sees it. Votes still name the canonical option index.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ProposalBallot {
+    proposal: Proposal,
+    // Indexes into `proposal.options` in the order to show them
+    option_order: Vec<u32>,
+}
+
+// One user's vote on a proposal
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ProposalVote {
+    id: u64,
+    proposal_id: u64,
+    username: String,
+    option: u32,
+    weight: u64,
+    cast_at: u64,
+}
+
+// A set of questions attached to a discussion, answered once per user
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Survey {
+    id: u64,
+    discussion_id: u64,
+    created_by: String,
+    created_at: u64,
+    questions: Vec<SurveyQuestion>,
+    // Answers are rejected from this time on
+    closes_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SurveyQuestion {
+    prompt: String,
+    kind: QuestionKind,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum QuestionKind {
+    SingleChoice { options: Vec<String> },
+    MultiChoice { options: Vec<String> },
+    // Whole numbers from min to max
+    Rating { min: u8, max: u8 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum SurveyAnswer {
+    Choice(u32),
+    Choices(Vec<u32>),
+    Rating(u8),
+}
+
+// A user's answers to a survey, one per question in order. Saved drafts can still change, a submitted
+// response is final and the only kind counted in results.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SurveyResponse {
+    id: u64,
+    survey_id: u64,
+    username: String,
+    answers: Vec<Option<SurveyAnswer>>,
+    submitted: bool,
+    updated_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SurveyResults {
+    survey: Survey,
+    // Submitted responses
+    responses: u64,
+    questions: Vec<QuestionResults>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct QuestionResults {
+    prompt: String,
+    // Responses per option, or per rating from min to max
+    counts: Vec<u64>,
+    // Mean of the ratings given, for rating questions that have any
+    average: Option<f64>,
+}
+
+// Snapshot of the core forum data for backup and restore
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Backup {
+    next_id: u64,
+    users: Vec<User>,
+    discussions: Vec<Discussion>,
+    votes: Vec<Vote>,
+    groups: Vec<Group>,
+    group_members: Vec<GroupMember>,
+    principal_links: Vec<PrincipalLink>,
+}
+
+// Everything an admin UI needs on its landing page, in one call
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AdminOverview {
+    total_users: u64,
+    total_discussions: u64,
+    total_votes: u64,
+    total_groups: u64,
+    open_reports: u64,
+    pending_join_requests: u64,
+    recent_registrations: Vec<User>,
+    stable_memory_bytes: u64,
+    cycles_balance: u128,
+}
+
+// Left behind by a merged account so its username can't be registered again
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AccountTombstone {
+    id: u64,
+    username: String,
+    merged_into: String,
+    merged_at: u64,
+}
+
+// How a user can regain access after losing every linked principal
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RecoveryConfig {
+    recovery_principal: Option<Principal>,
+    // SHA3-256 of a secret recovery code kept offline by the user
+    recovery_code_hash: Option<Vec<u8>>,
+    // Recovery attempts in the current RECOVERY_ATTEMPT_WINDOW, which started at attempt_window_start
+    attempt_window_start: u64,
+    attempts_in_window: u32,
+    // Failed attempts since the last successful one or lockout
+    failed_attempts: u32,
+    // Recovery is refused until then, 0 if not locked
+    locked_until: u64,
+}
+
+// Rebinding request that can only be completed once its time lock has passed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PendingRecovery {
+    username: String,
+    new_principal: Principal,
+    initiated_by: Principal,
+    initiated_at: u64,
+    executable_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AccountAuditEntry {
+    id: u64,
+    username: String,
+    actor: Principal,
+    action: String,
+    created_at: u64,
+}
+
+// Proof-of-work puzzle a new account must solve before registering
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RegistrationChallenge {
+    id: u64,
+    nonce: String,
+    // Leading zero bits required of SHA3-256("<nonce>:<username>:<counter>")
+    difficulty: u8,
+    issued_to: Principal,
+    expires_at: u64,
+}
+
+// One account creation, kept for the sliding registration window
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RegistrationEvent {
+    principal: Principal,
+    at: u64,
+}
+
+// Solution to a registration challenge
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PowSolution {
+    challenge_id: u64,
+    counter: u64,
+}
+
+// Pending Sign-In-With-Ethereum message waiting for the wallet's signature
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SiweChallenge {
+    id: u64,
+    address: String,
+    message: String,
+    expires_at: u64,
+    // Only this caller can finish the sign-in
+    issued_to: Principal,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Discussion {
+    id: u64,
+    topic: String,
+    created_by: String,
+    created_at: u64,
+    upvotes: u64,
+    downvotes: u64,
+    visibility: Visibility,
+    group_id: Option<u64>,
+    version: u64,
+    // Votes are rejected from this time on
+    voting_ends_at: Option<u64>,
+    // Set once the deadline has passed and the tally is frozen
+    results_final: bool,
+    // Tallies stay hidden from queries until the voting deadline passes
+    blind: bool,
+    // When set, votes are hash commitments until `voting_ends_at` and are revealed until this time
+    reveal_ends_at: Option<u64>,
+    // Only users with a current proof-of-personhood verification may vote
+    requires_personhood: bool,
+    views: u64,
+    // IDs of images attached by the creator
+    attachments: Vec<u64>,
+    // NSFW or otherwise sensitive, hidden from listings unless the viewer opted in
+    sensitive: bool,
+    // Short label clients show before the topic, e.g. "spoilers"
+    content_warning: Option<String>,
+    // ISO 639-1 code set by the creator or detected from the topic
+    language: Option<String>,
+    // Lower bound of the Wilson interval for the upvote share, updated with the tallies
+    wilson_score: f64,
+    // High when many votes are split close to evenly, 0 without votes on both sides
+    controversy_score: f64,
+    participation: ParticipationLimits,
+    // Any user at or above wiki_edit_trust_level who can see the discussion may edit its topic
+    wiki: bool,
+    // The discussion this one split off from
+    forked_from: Option<u64>,
+    // Excerpt of another discussion this one replies to
+    quote: Option<Quote>,
+    // Locked discussions take no more votes, edits or survey answers
+    locked: bool,
+    // Last vote, topic change or unlock; discussions idle past the auto-lock period are locked
+    last_activity_at: u64,
+}
+
+// A range of characters in another discussion's topic. The topic as it was at `version` can be looked up in
+// the quoted discussion's revisions, so the range stays meaningful after edits.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Quote {
+    discussion_id: u64,
+    version: u64,
+    start: u32,
+    end: u32,
+}
+
+// Who may vote on a discussion, set by its creator
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ParticipationLimits {
+    max_voters: Option<u64>,
+    // Only members of the discussion's group may vote
+    members_only: bool,
+    min_account_age_days: Option<u32>,
+}
+
+// An uploaded image; its bytes live in ATTACHMENT_CHUNKS_STORAGE
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Attachment {
+    id: u64,
+    owner: String,
+    content_type: String,
+    total_size: u64,
+    uploaded: u64,
+    // Keys of the chunks in upload order
+    chunk_ids: Vec<u64>,
+    complete: bool,
+    created_at: u64,
+}
+
+// What this canister does in a multi-canister deployment; spawned canisters run the same wasm
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+enum CanisterRole {
+    // Serves users directly and routes to any shards it created
+    #[default]
+    Hub,
+    // Holds a range of forum discussions and their votes on behalf of the router
+    Shard { router: Principal },
+    // Holds cold discussions and their votes moved out of the hub
+    Archive { hub: Principal },
+    // Holds the inverted search index for the hub's discussions
+    SearchIndex { hub: Principal },
+    // Runs one group promoted out of the hub
+    Group { hub: Principal, group_id: u64 },
+}
+
+// Where a group promoted to its own canister now lives
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct GroupDirectoryEntry {
+    group_id: u64,
+    name: String,
+    canister_id: Principal,
+    promoted_at: u64,
+    // False until all of the group's data was copied and removed from the hub
+    migrated: bool,
+}
+
+// One call's worth of a group's data copied into its own canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct GroupImportBatch {
+    groups: Vec<Group>,
+    members: Vec<GroupMember>,
+    invites: Vec<GroupInvite>,
+    join_requests: Vec<JoinRequest>,
+    users: Vec<User>,
+    principal_links: Vec<PrincipalLink>,
+    discussions: Vec<Discussion>,
+    votes: Vec<Vote>,
+}
+
+// An account as the hub holds it, sent to the promoted group canisters whenever it changes. No user means
+// the account was deleted or merged away.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AccountSync {
+    user_id: u64,
+    user: Option<User>,
+    principal_links: Vec<PrincipalLink>,
+    restrictions: Vec<Restriction>,
+}
+
+// The companion search canister of a hub, if one was created
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SearchIndexConfig {
+    canister_id: Option<Principal>,
+}
+
+// The tokens a search canister indexed for one discussion, kept so they can be unindexed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SearchDocument {
+    tokens: Vec<String>,
+}
+
+// An archive canister and how many discussions it holds
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ArchiveInfo {
+    canister_id: Principal,
+    created_at: u64,
+    discussions: u64,
+}
+
+// A shard canister and the first discussion ID it owns; it owns every ID up to the next shard's first
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ShardInfo {
+    canister_id: Principal,
+    first_discussion_id: u64,
+    created_at: u64,
+}
+
+// One piece of the wasm module installed into spawned canisters
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct WasmChunk {
+    data: Vec<u8>,
+}
+
+// Size and hash of the uploaded canister wasm
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CanisterWasmInfo {
+    size: u64,
+    chunks: u32,
+    sha3_256: Vec<u8>,
+}
+
+// One piece of an attachment's bytes
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AttachmentChunk {
+    data: Vec<u8>,
+}
+
+// A keyword or phrase a user wants to hear about, stored lowercase
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct KeywordAlert {
+    id: u64,
+    username: String,
+    keyword: String,
+    created_at: u64,
+}
+
+// A discussion started automatically on a schedule, e.g. a weekly open thread
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Recurrence {
+    id: u64,
+    // Topic of each instance; "{date}" becomes its UTC date as YYYY-MM-DD
+    topic_template: String,
+    // User the instances are posted as
+    author: String,
+    group_id: Option<u64>,
+    schedule: Schedule,
+    // Lock the previous instance when a new one starts
+    lock_previous: bool,
+    next_run_at: u64,
+    last_discussion_id: Option<u64>,
+    created_at: u64,
+}
+
+// When a recurrence runs, in UTC
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum Schedule {
+    Daily { hour: u8, minute: u8 },
+    // 0 is Monday
+    Weekly { weekday: u8, hour: u8, minute: u8 },
+}
+
+// A user asking moderators to reopen a locked discussion
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct UnlockRequest {
+    id: u64,
+    discussion_id: u64,
+    username: String,
+    reason: String,
+    created_at: u64,
+}
+
+// A drawing among a discussion's voters, replayable from its seed as described by RAFFLE_DERIVATION
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Raffle {
+    id: u64,
+    discussion_id: u64,
+    // 32 bytes from raw_rand
+    seed: Vec<u8>,
+    entrant_count: u64,
+    // SHA3-256 of the entrants' usernames joined by newlines
+    entrants_digest: Vec<u8>,
+    winners: Vec<String>,
+    drawn_by: Principal,
+    drawn_at: u64,
+}
+
+// A discussion's final tally signed by the canister. `message` is the signed bytes: RESULTS_ATTESTATION_TAG,
+// one byte with the length of the canister ID, the canister ID, then discussion_id, upvotes, downvotes and
+// closed_at as 8 big-endian bytes each.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ResultAttestation {
+    discussion_id: u64,
+    upvotes: u64,
+    downvotes: u64,
+    // When voting, or the reveal window, ended
+    closed_at: u64,
+    message: Vec<u8>,
+    signature: CanisterSignature,
+    attested_at: u64,
+}
+
+// Proof that a vote is part of the certified vote tree. Each discussion's votes, in vote ID order, are the
+// leaves of a Merkle tree (see the merkle module) with leaf data vote ID, discussion ID, created_at (8
+// big-endian bytes each), vote type (0 upvote, 1 downvote), expired (0 or 1) and the voter's username.
+// Each discussion's root then forms a leaf with data discussion ID (8 big-endian bytes) and root, in
+// discussion ID order, and the root over those is the canister's certified data.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VoteProof {
+    vote: Vote,
+    // Siblings from the vote's leaf up to `discussion_root`
+    vote_path: Vec<MerkleStep>,
+    discussion_root: Vec<u8>,
+    // Siblings from the discussion's leaf up to `certified_root`
+    discussion_path: Vec<MerkleStep>,
+    certified_root: Vec<u8>,
+    // IC certificate over the certified data, check that it signs `certified_root`
+    certificate: Option<Vec<u8>>,
+}
+
+// What a public random selection was made for. Randomness kept secret, such as challenges and invite
+// codes, is not recorded.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum RandomnessPurpose {
+    Raffle { discussion_id: u64 },
+    ProposalOptionOrder { proposal_id: u64 },
+}
+
+// Everything needed to check a random selection afterwards, keyed by the ID of the raffle or proposal it was
+// made for
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RandomnessAudit {
+    event_id: u64,
+    purpose: RandomnessPurpose,
+    // 32 bytes from raw_rand. The subnet's random beacon has no public round number, recorded_at stands in for it.
+    seed: Vec<u8>,
+    // How the outputs follow from the seed and inputs
+    derivation: String,
+    // SHA3-256 of the inputs the derivation ran on, empty when the seed is the only input
+    inputs_digest: Vec<u8>,
+    outputs: Vec<String>,
+    recorded_at: u64,
+}
+
+// A user's request to be notified about a discussion at a later time
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Reminder {
+    id: u64,
+    username: String,
+    discussion_id: u64,
+    remind_at: u64,
+    note: Option<String>,
+    created_at: u64,
+}
+
+// IDs of the reminders due within one minute of the reminder wheel, keyed by minute since the epoch
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ReminderSlot {
+    reminder_ids: Vec<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum NotificationKind {
+    KeywordMatch { discussion_id: u64, keyword: String },
+    Warning { warning_id: u64 },
+    AppealDecided { appeal_id: u64, status: AppealStatus },
+    TransferOffered { discussion_id: u64, from: String },
+    Reminder { reminder_id: u64, discussion_id: u64, note: Option<String> },
+    UnlockDecided { discussion_id: u64, unlocked: bool },
+    RaffleWon { raffle_id: u64, discussion_id: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Notification {
+    id: u64,
+    username: String,
+    kind: NotificationKind,
+    created_at: u64,
+    read: bool,
+}
+
+// Metadata of the first link in a discussion, fetched in the background; keyed by discussion ID
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LinkPreview {
+    url: String,
+    title: Option<String>,
+    description: Option<String>,
+    image: Option<String>,
+    fetched_at: u64,
+}
+
+// Normalized Markdown of a discussion topic, keyed by discussion ID
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NormalizedText {
+    markdown: String,
+}
+
+// A queued link preview fetch
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LinkPreviewRequest {
+    url: String,
+}
+
+// One hashtag used by one discussion
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct HashtagEntry {
+    tag: String,
+    discussion_id: u64,
+    created_at: u64,
+}
+
+// Interface version and what this deployment has switched on, so frontends can adapt up front
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApiVersion {
+    // Semantic version of the Candid interface
+    version: String,
+    major: u32,
+    minor: u32,
+    patch: u32,
+    role: CanisterRole,
+    // Feature flags followed by optional subsystems, such as "treasury" or "search_index"
+    capabilities: Vec<(String, bool)>,
+    // Locales get_message_catalog translates into
+    locales: Vec<String>,
+}
+
+// One translated error message; errors carrying this code start with "<code>: "
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CatalogEntry {
+    code: String,
+    message: String,
+}
+
+// Usage of a hashtag across listed discussions
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct HashtagStat {
+    tag: String,
+    // Discussions using it within the trending window
+    recent: u64,
+    total: u64,
+}
+
+// When a user last read a discussion
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReadMarker {
+    username: String,
+    discussion_id: u64,
+    last_read_at: u64,
+}
+
+// Activity on a discussion since the user last read it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct UnreadCount {
+    discussion_id: u64,
+    // Votes cast after the last read
+    unread: u64,
+}
+
+// Last counted view of a discussion by one viewer, used to dedup repeated views
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ViewRecord {
+    discussion_id: u64,
+    // Username, or the caller's principal text for unauthenticated viewers
+    viewer: String,
+    viewed_at: u64,
+}
+
+// Cached result of a proof-of-personhood check, keyed by user ID
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PersonhoodVerification {
+    principal: Principal,
+    verified_at: u64,
+    expires_at: u64,
+}
+
+// A sealed vote in a commit-reveal discussion
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VoteCommitment {
+    id: u64,
+    discussion_id: u64,
+    by: String,
+    // SHA3-256 of "<discussion_id>:<username>:<upvote|downvote>:<salt>", so a copied commitment can't be
+    // revealed by anyone else
+    commitment: Vec<u8>,
+    committed_at: u64,
+    revealed: bool,
+    // False for commitments made before the username was part of the hash, checked against the old form
+    voter_bound: bool,
+}
+
+// Vote counts of a discussion; the split is withheld while a blind vote is running
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VoteSummary {
+    total_votes: u64,
+    upvotes: Option<u64>,
+    downvotes: Option<u64>,
+}
+
+// Final outcome of a discussion whose voting deadline has passed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ClosedResult {
+    discussion_id: u64,
+    topic: String,
+    content_warning: Option<String>,
+    upvotes: u64,
+    downvotes: u64,
+    voting_ended_at: u64,
+    outcome: VoteOutcome,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum VoteOutcome {
+    Passed,
+    Rejected,
+    Tied,
+}
+
+// Error returned by edits guarded by an expected version
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum EditError {
+    // The discussion changed since the caller read it; carries the current record
+    Conflict(Box<Discussion>),
+    Rejected(String),
+}
+
+impl From<String> for EditError {
+    fn from(message: String) -> Self {
+        EditError::Rejected(message)
+    }
+}
+
+impl From<&str> for EditError {
+    fn from(message: &str) -> Self {
+        EditError::Rejected(message.to_string())
+    }
+}
+
+impl From<ErrorCode> for EditError {
+    fn from(code: ErrorCode) -> Self {
+        EditError::Rejected(code.into())
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+enum Visibility {
+    #[default]
+    Public,
+    Unlisted,
+    Private { allowed_users: Vec<String> },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Group {
+    id: u64,
+    name: String,
+    description: String,
+    created_by: String,
+    created_at: u64,
+    settings: GroupSettings,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct GroupSettings {
+    // Anyone may join without an invitation
+    open: bool,
+    // Only members can see the group's discussions
+    members_only: bool,
+    permissions: GroupPermissions,
+    // Case-insensitive words rejected in the group's discussion topics
+    blocked_words: Vec<String>,
+    // Days without activity before the group's discussions are locked, 0 never; unset follows auto_lock_after_days
+    auto_lock_after_days: Option<u32>,
+}
+
+// Minimum role required for each action inside a group
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct GroupPermissions {
+    post: GroupRole,
+    vote: GroupRole,
+    moderate: GroupRole,
+}
+
+impl Default for GroupPermissions {
+    fn default() -> Self {
+        GroupPermissions {
+            post: GroupRole::Member,
+            vote: GroupRole::Member,
+            moderate: GroupRole::Moderator,
+        }
+    }
+}
+
+// Ordered from least to most privileged
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord)]
+enum GroupRole {
+    #[default]
+    Member,
+    Moderator,
+    Owner,
+}
+
+enum GroupAction {
+    Post,
+    Vote,
+    Moderate,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct GroupMember {
+    id: u64,
+    group_id: u64,
+    username: String,
+    role: GroupRole,
+    joined_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct GroupInvite {
+    id: u64,
+    group_id: u64,
+    code: String,
+    created_by: String,
+    created_at: u64,
+    expires_at: u64,
+    max_uses: u32,
+    uses: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct JoinRequest {
+    id: u64,
+    group_id: u64,
+    username: String,
+    created_at: u64,
+    status: JoinRequestStatus,
+    reviewed_by: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+enum JoinRequestStatus {
+    #[default]
+    Pending,
+    Approved,
+    Denied,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Report {
+    id: u64,
+    discussion_id: u64,
+    group_id: Option<u64>,
+    reported_by: String,
+    reason: String,
+    created_at: u64,
+    status: ReportStatus,
+    resolved_by: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+enum ReportStatus {
+    #[default]
+    Open,
+    Dismissed,
+    ContentRemoved,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct GroupAuditEntry {
+    id: u64,
+    group_id: u64,
+    actor: String,
+    action: String,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Conversation {
+    id: u64,
+    participants: Vec<String>,
+    created_at: u64,
+    last_message_at: u64,
+}
+
+// Messages are encrypted client-side, the canister never sees plaintext
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DirectMessage {
+    id: u64,
+    conversation_id: u64,
+    sender: String,
+    ciphertext: Vec<u8>,
+    created_at: u64,
+}
+
+// Argument and reply types of the management canister's vetKD API
+#[derive(candid::CandidType, Deserialize)]
+enum VetKDCurve {
+    #[serde(rename = "bls12_381_g2")]
+    Bls12381G2,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKDKeyId {
+    curve: VetKDCurve,
+    name: String,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKDPublicKeyArgs {
+    canister_id: Option<Principal>,
+    context: Vec<u8>,
+    key_id: VetKDKeyId,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKDPublicKeyReply {
+    public_key: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKDDeriveKeyArgs {
+    input: Vec<u8>,
+    context: Vec<u8>,
+    transport_public_key: Vec<u8>,
+    key_id: VetKDKeyId,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKDDeriveKeyReply {
+    encrypted_key: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Vote {
+    id: u64,
+    by: String,
+    discussion_id: u64,
+    vote_type: VoteType,
+    created_at: u64,
+    // Principal that cast the vote, None for anonymous callers and votes that predate tracking
+    voter: Option<Principal>,
+    // Why a downvote was cast, if the voter said
+    reason: Option<DownvoteReason>,
+    // Older than vote_expiry_days, kept but left out of the discussion's tally
+    expired: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum DownvoteReason {
+    OffTopic,
+    Inaccurate,
+    // Also files a report for moderators
+    Spam,
+}
+
+// Downvotes on a discussion grouped by reason
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DownvoteReasonCounts {
+    off_topic: u64,
+    inaccurate: u64,
+    spam: u64,
+    unspecified: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+enum VoteType {
+    #[default]
+    Upvote,
+    Downvote,
+}
+
+// Orders for get_ranked_discussions
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum DiscussionSort {
+    // Most upvotes
+    Top,
+    // Highest Wilson score, so a few unanimous votes do not outrank many mostly positive ones
+    Best,
+    Controversial,
+}
+
+// Runs a generic expression against the storage map a StoredMap names
+macro_rules! with_stored_map {
+    ($map:expr, |$storage:ident| $body:expr) => {
+        match $map {
+            StoredMap::Users => { let $storage = &USERS_STORAGE; $body }
+            StoredMap::Discussions => { let $storage = &DISCUSSIONS_STORAGE; $body }
+            StoredMap::Votes => { let $storage = &VOTES_STORAGE; $body }
+            StoredMap::Groups => { let $storage = &GROUPS_STORAGE; $body }
+            StoredMap::GroupMembers => { let $storage = &GROUP_MEMBERS_STORAGE; $body }
+            StoredMap::GroupInvites => { let $storage = &GROUP_INVITES_STORAGE; $body }
+            StoredMap::JoinRequests => { let $storage = &JOIN_REQUESTS_STORAGE; $body }
+            StoredMap::Reports => { let $storage = &REPORTS_STORAGE; $body }
+            StoredMap::GroupAuditLog => { let $storage = &GROUP_AUDIT_LOG; $body }
+            StoredMap::Conversations => { let $storage = &CONVERSATIONS_STORAGE; $body }
+            StoredMap::Messages => { let $storage = &MESSAGES_STORAGE; $body }
+            StoredMap::PrincipalLinks => { let $storage = &PRINCIPAL_LINKS_STORAGE; $body }
+            StoredMap::LinkChallenges => { let $storage = &LINK_CHALLENGES_STORAGE; $body }
+            StoredMap::SiweChallenges => { let $storage = &SIWE_CHALLENGES_STORAGE; $body }
+            StoredMap::PrivacySettings => { let $storage = &PRIVACY_SETTINGS_STORAGE; $body }
+            StoredMap::RecoveryConfigs => { let $storage = &RECOVERY_CONFIGS_STORAGE; $body }
+            StoredMap::PendingRecoveries => { let $storage = &PENDING_RECOVERIES_STORAGE; $body }
+            StoredMap::AccountAuditLog => { let $storage = &ACCOUNT_AUDIT_LOG; $body }
+            StoredMap::Tombstones => { let $storage = &TOMBSTONES_STORAGE; $body }
+            StoredMap::Admins => { let $storage = &ADMINS_STORAGE; $body }
+            StoredMap::FeatureFlags => { let $storage = &FEATURE_FLAGS_STORAGE; $body }
+            StoredMap::Idempotency => { let $storage = &IDEMPOTENCY_STORAGE; $body }
+            StoredMap::VoteCommitments => { let $storage = &VOTE_COMMITMENTS_STORAGE; $body }
+            StoredMap::Personhood => { let $storage = &PERSONHOOD_STORAGE; $body }
+            StoredMap::RegistrationChallenges => { let $storage = &REGISTRATION_CHALLENGES_STORAGE; $body }
+            StoredMap::RegistrationEvents => { let $storage = &REGISTRATION_EVENTS_STORAGE; $body }
+            StoredMap::Views => { let $storage = &VIEWS_STORAGE; $body }
+            StoredMap::ReadMarkers => { let $storage = &READ_MARKERS_STORAGE; $body }
+            StoredMap::KeywordAlerts => { let $storage = &KEYWORD_ALERTS_STORAGE; $body }
+            StoredMap::Notifications => { let $storage = &NOTIFICATIONS_STORAGE; $body }
+            StoredMap::HashtagIndex => { let $storage = &HASHTAG_INDEX; $body }
+            StoredMap::LinkPreviews => { let $storage = &LINK_PREVIEWS_STORAGE; $body }
+            StoredMap::LinkPreviewQueue => { let $storage = &LINK_PREVIEW_QUEUE; $body }
+            StoredMap::TopicMarkdown => { let $storage = &TOPIC_MARKDOWN_STORAGE; $body }
+            StoredMap::Attachments => { let $storage = &ATTACHMENTS_STORAGE; $body }
+            StoredMap::AttachmentChunks => { let $storage = &ATTACHMENT_CHUNKS_STORAGE; $body }
+            StoredMap::CanisterWasm => { let $storage = &CANISTER_WASM_STORAGE; $body }
+            StoredMap::Shards => { let $storage = &SHARDS_STORAGE; $body }
+            StoredMap::Archives => { let $storage = &ARCHIVES_STORAGE; $body }
+            StoredMap::SearchDocuments => { let $storage = &SEARCH_DOCUMENTS; $body }
+            StoredMap::GroupDirectory => { let $storage = &GROUP_DIRECTORY; $body }
+            StoredMap::Proposals => { let $storage = &PROPOSALS_STORAGE; $body }
+            StoredMap::ProposalVotes => { let $storage = &PROPOSAL_VOTES_STORAGE; $body }
+            StoredMap::VotingPowerSnapshots => { let $storage = &VOTING_POWER_SNAPSHOTS; $body }
+            StoredMap::TreasuryPayouts => { let $storage = &TREASURY_PAYOUTS; $body }
+            StoredMap::TreasuryDeposits => { let $storage = &TREASURY_DEPOSITS; $body }
+            StoredMap::Entitlements => { let $storage = &ENTITLEMENTS_STORAGE; $body }
+            StoredMap::Referrals => { let $storage = &REFERRALS_STORAGE; $body }
+            StoredMap::KarmaAwards => { let $storage = &KARMA_AWARDS_STORAGE; $body }
+            StoredMap::Profiles => { let $storage = &PROFILES_STORAGE; $body }
+            StoredMap::Onboarding => { let $storage = &ONBOARDING_STORAGE; $body }
+            StoredMap::Badges => { let $storage = &BADGES_STORAGE; $body }
+            StoredMap::Terms => { let $storage = &TERMS_STORAGE; $body }
+            StoredMap::TermsAcceptances => { let $storage = &TERMS_ACCEPTANCES_STORAGE; $body }
+            StoredMap::ContentPreferences => { let $storage = &CONTENT_PREFERENCES_STORAGE; $body }
+            StoredMap::FloodIncidents => { let $storage = &FLOOD_INCIDENTS_STORAGE; $body }
+            StoredMap::Log => { let $storage = &LOG_STORAGE; $body }
+            StoredMap::EndpointMetrics => { let $storage = &ENDPOINT_METRICS_STORAGE; $body }
+            StoredMap::Trash => { let $storage = &TRASH_STORAGE; $body }
+            StoredMap::Warnings => { let $storage = &WARNINGS_STORAGE; $body }
+            StoredMap::Restrictions => { let $storage = &RESTRICTIONS_STORAGE; $body }
+            StoredMap::Appeals => { let $storage = &APPEALS_STORAGE; $body }
+            StoredMap::DiscussionRevisions => { let $storage = &REVISIONS_STORAGE; $body }
+            StoredMap::OwnershipTransfers => { let $storage = &TRANSFERS_STORAGE; $body }
+            StoredMap::Surveys => { let $storage = &SURVEYS_STORAGE; $body }
+            StoredMap::SurveyResponses => { let $storage = &SURVEY_RESPONSES_STORAGE; $body }
+            StoredMap::Outbox => { let $storage = &OUTBOX_STORAGE; $body }
+            StoredMap::DeadLetters => { let $storage = &DEAD_LETTERS_STORAGE; $body }
+            StoredMap::EventSubscriptions => { let $storage = &EVENT_SUBSCRIPTIONS_STORAGE; $body }
+            StoredMap::Reminders => { let $storage = &REMINDERS_STORAGE; $body }
+            StoredMap::ReminderWheel => { let $storage = &REMINDER_WHEEL; $body }
+            StoredMap::Recurrences => { let $storage = &RECURRENCES_STORAGE; $body }
+            StoredMap::UnlockRequests => { let $storage = &UNLOCK_REQUESTS_STORAGE; $body }
+            StoredMap::UserActivity => { let $storage = &USER_ACTIVITY_STORAGE; $body }
+            StoredMap::KarmaTallies => { let $storage = &KARMA_TALLIES_STORAGE; $body }
+            StoredMap::Raffles => { let $storage = &RAFFLES_STORAGE; $body }
+            StoredMap::RandomnessAudit => { let $storage = &RANDOMNESS_AUDIT_STORAGE; $body }
+            StoredMap::ResultAttestations => { let $storage = &ATTESTATIONS_STORAGE; $body }
+        }
+    };
+}
+
+// Candid-encoded storage with a fixed upper bound on the encoded size. Records written before fields were
+// added to their type decode with those fields filled in, see schema::decode.
+macro_rules! impl_storable {
+    // Cells are read once when the canister starts; one that no longer decodes starts over from its default
+    ($type:ty, $max_size:expr, cell) => {
+        impl Storable for $type {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(Encode!(self).unwrap())
+            }
+
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                schema::decode(bytes.as_ref(), schema_defaults).unwrap_or_default()
+            }
+        }
+
+        impl BoundedStorable for $type {
+            const MAX_SIZE: u32 = $max_size;
+            const IS_FIXED_SIZE: bool = false;
+        }
+    };
+    // Cells whose default would quietly change what the canister is allowed to do; failing to decode traps,
+    // which rolls the upgrade back
+    ($type:ty, $max_size:expr, strict) => {
+        impl Storable for $type {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(Encode!(self).unwrap())
+            }
+
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                schema::decode(bytes.as_ref(), schema_defaults).unwrap()
+            }
+        }
+
+        impl BoundedStorable for $type {
+            const MAX_SIZE: u32 = $max_size;
+            const IS_FIXED_SIZE: bool = false;
+        }
+    };
+    // Records of the map a StoredMap names, so try_get can quarantine them
+    ($type:ty, $max_size:expr, $map:expr) => {
+        impl_storable!($type, $max_size);
+
+        impl StoredRecord for $type {
+            const MAP: StoredMap = $map;
+        }
+    };
+    // Records that fail to decode read as a placeholder instead of trapping, see decode_stored
+    ($type:ty, $max_size:expr) => {
+        impl Storable for $type {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(Encode!(self).unwrap())
+            }
+
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                decode_stored(bytes.as_ref())
+            }
+        }
+
+        impl BoundedStorable for $type {
+            const MAX_SIZE: u32 = $max_size;
+            const IS_FIXED_SIZE: bool = false;
+        }
+    };
+}
+
+// A record type together with the map that holds it
+trait StoredRecord: BoundedStorable {
+    const MAP: StoredMap;
+}
+
+// Helper function to decode a stored record. One that no longer decodes comes back as a placeholder with
+// every field empty, and its bytes are kept in LAST_DECODE_FAILURE until the read is checked, see try_get
+// and decoded. Every successful decode clears the failure, so it always belongs to the latest read.
+fn decode_stored<T: candid::CandidType + serde::de::DeserializeOwned>(bytes: &[u8]) -> T {
+    match schema::decode(bytes, schema_defaults) {
+        Ok(value) => {
+            LAST_DECODE_FAILURE.with(|failure| *failure.borrow_mut() = None);
+            value
+        }
+        Err(error) => {
+            let placeholder = schema::placeholder().unwrap_or_else(|| ic_cdk::trap(&error));
+            LAST_DECODE_FAILURE.with(|failure| *failure.borrow_mut() = Some((bytes.to_vec(), error)));
+            placeholder
+        }
+    }
+}
+
+impl_storable!(User, 512, StoredMap::Users);
+impl_storable!(PrincipalLink, 512, StoredMap::PrincipalLinks);
+impl_storable!(LinkChallenge, 512, StoredMap::LinkChallenges);
+impl_storable!(SiweChallenge, 1024, StoredMap::SiweChallenges);
+impl_storable!(RegistrationChallenge, 256, StoredMap::RegistrationChallenges);
+impl_storable!(RegistrationEvent, 128, StoredMap::RegistrationEvents);
+impl_storable!(PrivacySettings, 128, StoredMap::PrivacySettings);
+impl_storable!(ContentPreferences, 128, StoredMap::ContentPreferences);
+impl_storable!(RecoveryConfig, 256, StoredMap::RecoveryConfigs);
+impl_storable!(PendingRecovery, 512, StoredMap::PendingRecoveries);
+impl_storable!(AccountAuditEntry, 512, StoredMap::AccountAuditLog);
+impl_storable!(AccountTombstone, 512, StoredMap::Tombstones);
+impl_storable!(Admin, 256, StoredMap::Admins);
+impl_storable!(Settings, 512, cell);
+impl_storable!(FeatureFlag, 256, StoredMap::FeatureFlags);
+impl_storable!(MaintenanceMode, 512, cell);
+impl_storable!(GcState, 256, cell);
+impl_storable!(IdempotencyRecord, 8192, StoredMap::Idempotency);
+impl_storable!(Discussion, 2048, StoredMap::Discussions);
+impl_storable!(Vote, 512, StoredMap::Votes);
+impl_storable!(VoteCommitment, 512, StoredMap::VoteCommitments);
+impl_storable!(ViewRecord, 256, StoredMap::Views);
+impl_storable!(ReadMarker, 256, StoredMap::ReadMarkers);
+impl_storable!(KeywordAlert, 256, StoredMap::KeywordAlerts);
+impl_storable!(HashtagEntry, 256, StoredMap::HashtagIndex);
+impl_storable!(LinkPreview, 4096, StoredMap::LinkPreviews);
+impl_storable!(LinkPreviewRequest, 1024, StoredMap::LinkPreviewQueue);
+impl_storable!(NormalizedText, 4096, StoredMap::TopicMarkdown);
+impl_storable!(Attachment, 1024, StoredMap::Attachments);
+impl_storable!(AttachmentChunk, ATTACHMENT_CHUNK_SIZE as u32 + 64, StoredMap::AttachmentChunks);
+impl_storable!(CanisterRole, 128, strict);
+impl_storable!(ShardInfo, 256, StoredMap::Shards);
+impl_storable!(ArchiveInfo, 256, StoredMap::Archives);
+impl_storable!(SearchIndexConfig, 128, cell);
+impl_storable!(SearchDocument, 10240, StoredMap::SearchDocuments);
+impl_storable!(GroupDirectoryEntry, 512, StoredMap::GroupDirectory);
+impl_storable!(DaoConfig, 128, strict);
+impl_storable!(Proposal, 24576, StoredMap::Proposals);
+impl_storable!(ProposalVote, 256, StoredMap::ProposalVotes);
+impl_storable!(Survey, 32768, StoredMap::Surveys);
+impl_storable!(Reminder, 512, StoredMap::Reminders);
+impl_storable!(Recurrence, 2048, StoredMap::Recurrences);
+impl_storable!(UnlockRequest, 1024, StoredMap::UnlockRequests);
+impl_storable!(ReminderSlot, 2048, StoredMap::ReminderWheel);
+impl_storable!(SurveyResponse, 512, StoredMap::SurveyResponses);
+impl_storable!(UserActivity, 128, StoredMap::UserActivity);
+impl_storable!(KarmaTally, 128, StoredMap::KarmaTallies);
+impl_storable!(Raffle, 4096, StoredMap::Raffles);
+impl_storable!(RandomnessAudit, 8192, StoredMap::RandomnessAudit);
+impl_storable!(ResultAttestation, 2048, StoredMap::ResultAttestations);
+impl_storable!(VotingPowerEntry, 256, StoredMap::VotingPowerSnapshots);
+impl_storable!(TreasuryPayout, 1024, StoredMap::TreasuryPayouts);
+impl_storable!(TreasuryDeposit, 256, StoredMap::TreasuryDeposits);
+impl_storable!(Entitlement, 512, StoredMap::Entitlements);
+impl_storable!(Referral, 512, StoredMap::Referrals);
+impl_storable!(KarmaAward, 256, StoredMap::KarmaAwards);
+impl_storable!(Profile, 2048, StoredMap::Profiles);
+impl_storable!(OnboardingProgress, 128, StoredMap::Onboarding);
+impl_storable!(Badge, 256, StoredMap::Badges);
+impl_storable!(TermsVersion, 1024, StoredMap::Terms);
+impl_storable!(TermsAcceptance, 256, StoredMap::TermsAcceptances);
+impl_storable!(FloodIncident, 256, StoredMap::FloodIncidents);
+impl_storable!(QuarantinedRecord, 8192);
+impl_storable!(TrashedDiscussion, 2304, StoredMap::Trash);
+impl_storable!(DiscussionRevision, 2048, StoredMap::DiscussionRevisions);
+impl_storable!(OwnershipTransfer, 256, StoredMap::OwnershipTransfers);
+impl_storable!(Warning, 1024, StoredMap::Warnings);
+impl_storable!(Restriction, 1024, StoredMap::Restrictions);
+impl_storable!(Appeal, 2048, StoredMap::Appeals);
+impl_storable!(WasmChunk, WASM_CHUNK_SIZE as u32 + 64, StoredMap::CanisterWasm);
+impl_storable!(Notification, 512, StoredMap::Notifications);
+impl_storable!(PersonhoodVerification, 256, StoredMap::Personhood);
+impl_storable!(Group, 4096, StoredMap::Groups);
+impl_storable!(GroupMember, 512, StoredMap::GroupMembers);
+impl_storable!(GroupInvite, 512, StoredMap::GroupInvites);
+impl_storable!(JoinRequest, 512, StoredMap::JoinRequests);
+impl_storable!(Report, 1024, StoredMap::Reports);
+impl_storable!(GroupAuditEntry, 512, StoredMap::GroupAuditLog);
+impl_storable!(Conversation, 512, StoredMap::Conversations);
+impl_storable!(DirectMessage, 2048, StoredMap::Messages);
+
+// Helper function to list the values that added fields of an enum or record type take in older records, for
+// types whose Default isn't their zero value. See schema::decode.
+fn schema_defaults() -> schema::Defaults {
+    vec![
+        schema::default_of(Visibility::default()),
+        schema::default_of(GroupPermissions::default()),
+        schema::default_of(GroupRole::default()),
+        schema::default_of(TrustLevel::default()),
+        schema::default_of(VoteWeighting::OnePerUser),
+    ]
+}
+
+// Upper bound on members of a private discussion, keeps the record within MAX_SIZE
+const MAX_PRIVATE_MEMBERS: usize = 32;
+
+// Upper bound on a group's blocked words, keeps the record within MAX_SIZE
+const MAX_BLOCKED_WORDS: usize = 50;
+
+// Hard limits for the length settings, keep the records within MAX_SIZE
+const TOPIC_LENGTH_LIMIT: u32 = 500;
+const REPORT_REASON_LENGTH_LIMIT: u32 = 500;
+
+// Largest accepted encrypted message, keeps the record within MAX_SIZE
+const MAX_CIPHERTEXT_LENGTH: usize = 1536;
+
+// vetKD master key of canisters whose settings predate Settings::vetkd_key_name
+const LEGACY_VETKD_KEY_NAME: &str = "key_1";
+
+// Domain separator so DM keys can't collide with keys derived for other purposes
+const VETKD_DM_CONTEXT: &[u8] = b"votehub_direct_messages";
+
+// Cycles attached to vetkd_derive_key on a 13-node subnet
+const VETKD_DERIVE_KEY_FEE: u128 = 26_153_846_153;
+
+// How long a link challenge stays valid (10 minutes)
+const LINK_CHALLENGE_TTL: u64 = 10 * 60 * 1_000_000_000;
+
+// How long a claim code stays valid (7 days), admins hand it to the owner out of band
+const CLAIM_CODE_TTL: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+// Domain and statement presented to the wallet in Sign-In-With-Ethereum messages
+const SIWE_DOMAIN: &str = "votehub.icp";
+const SIWE_STATEMENT: &str = "Sign in to VoteHub with your Ethereum account.";
+
+// How long a Sign-In-With-Ethereum message can be signed (5 minutes)
+const SIWE_CHALLENGE_TTL: u64 = 5 * 60 * 1_000_000_000;
+
+// How long a registration puzzle can be solved (10 minutes), and the hardest puzzle an admin may set
+const REGISTRATION_CHALLENGE_TTL: u64 = 10 * 60 * 1_000_000_000;
+const MAX_POW_DIFFICULTY: u8 = 32;
+
+// Version of the Candid interface: bump the major on breaking changes, the minor on additions
+const API_VERSION: (u32, u32, u32) = (2, 25, 0);
+
+// Feature flags checked by the canister and their values until an admin overrides them.
+// Flags not listed here (e.g. for subsystems still being rolled out) default to off.
+const FEATURE_FLAGS: &[(&str, bool)] = &[
+    ("groups", true),
+    ("direct_messages", true),
+    ("siwe_login", true),
+    ("reports", true),
+    ("link_previews", true),
+    ("proposals", true),
+];
+
+// Minimum time between two garbage collection ticks (10 minutes)
+const GC_INTERVAL: u64 = 10 * 60 * 1_000_000_000;
+
+// Time between two runs of the background jobs
+const BACKGROUND_TICK: Duration = Duration::from_secs(5);
+
+// Entries inspected per garbage collection tick, keeps each tick cheap
+const GC_BATCH_SIZE: usize = 500;
+
+// Votes visited per background tick by the backfill of voter principals
+const VOTER_BACKFILL_BATCH: usize = 200;
+
+// Records checked per background tick by the rewrite of records stored in an older shape
+const SCHEMA_REWRITE_BATCH: usize = 200;
+
+// Page sizes for listing queries
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 200;
+
+// How long a response is replayed for an idempotency key, and the longest accepted key
+const IDEMPOTENCY_TTL: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 64;
+
+// Repeat views by the same viewer within this window count once (1 hour)
+const VIEW_DEDUP_WINDOW: u64 = 60 * 60 * 1_000_000_000;
+
+// Views worth as much as one net upvote in the trending score
+const VIEWS_PER_VOTE: u64 = 10;
+
+// Limits on keyword alerts per user, their length, and the notifications kept per user
+const MAX_KEYWORD_ALERTS: usize = 20;
+const MAX_KEYWORD_LENGTH: usize = 50;
+const MAX_NOTIFICATIONS: usize = 100;
+
+// Hashtags indexed per discussion, their longest length, and the trending window (7 days)
+const MAX_HASHTAGS: usize = 10;
+const MAX_HASHTAG_LENGTH: usize = 32;
+const HASHTAG_TRENDING_WINDOW: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+// Trending counters are kept per hour, so the window is counted to the hour
+const HASHTAG_COUNT_BUCKET: u64 = 60 * 60 * 1_000_000_000;
+
+// Link preview outcalls: longest URL, response size cap, cycles attached, and stored field lengths
+const MAX_PREVIEW_URL_LENGTH: usize = 512;
+const PREVIEW_MAX_RESPONSE_BYTES: u64 = 64 * 1024;
+const PREVIEW_OUTCALL_CYCLES: u128 = 2_000_000_000;
+const MAX_PREVIEW_FIELD_LENGTH: usize = 200;
+
+// Attachments: accepted image types, upload chunk size, size caps, and how long an unfinished upload is kept
+const ATTACHMENT_CONTENT_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+const ATTACHMENT_CHUNK_SIZE: usize = 512 * 1024;
+const MAX_ATTACHMENT_SIZE: u64 = 4 * 1024 * 1024;
+const MAX_ATTACHMENTS_PER_DISCUSSION: usize = 4;
+const ATTACHMENT_UPLOAD_TTL: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Spawned canisters: upload chunk size of their wasm and the cycles each one starts with
+const WASM_CHUNK_SIZE: usize = 1024 * 1024;
+const SPAWN_CANISTER_CYCLES: u128 = 2_000_000_000_000;
+
+// Archiving: discussions per archive canister, and the most discussions and votes sent in one call
+const ARCHIVE_CAPACITY: u64 = 100_000;
+const ARCHIVE_BATCH_DISCUSSIONS: usize = 50;
+const ARCHIVE_BATCH_VOTES: usize = 5_000;
+
+// Discussions whose tokens are pushed to the search canister in one call
+const SEARCH_PUSH_BATCH: usize = 100;
+// Accounts sent to the group canisters per call
+const ACCOUNT_SYNC_BATCH: usize = 50;
+
+// Records of one kind copied to a group canister in one call
+const GROUP_IMPORT_BATCH: usize = 500;
+
+// Subaccount of this canister holding the community treasury
+const TREASURY_SUBACCOUNT: [u8; 32] = {
+    let mut subaccount = [0u8; 32];
+    subaccount[31] = 1;
+    subaccount
+};
+const MAX_PAYOUT_REASON_LENGTH: usize = 300;
+
+// Profile field limits
+const MAX_DISPLAY_NAME_LENGTH: usize = 50;
+const MAX_BIO_LENGTH: usize = 400;
+
+const MAX_CONTENT_WARNING_LENGTH: usize = 80;
+const MAX_PREFERRED_LANGUAGES: usize = 5;
+const MAX_MIN_ACCOUNT_AGE_DAYS: u32 = 365;
+
+// Longest retention admins can configure for the trash
+const MAX_TRASH_RETENTION_DAYS: u32 = 365;
+
+// Suspensions grow with every strike past the threshold but never beyond a year
+const MAX_SUSPENSION_DAYS: u64 = 365;
+
+// Flood protection: callers tracked in the heap before idle ones are pruned, and the longest backoff
+const MAX_TRACKED_CALLERS: usize = 10_000;
+const MAX_FLOOD_BACKOFF_SECONDS: u64 = 24 * 60 * 60;
+
+const MAX_LOG_PAGE_SIZE: u32 = 500;
+
+// Referrals: karma for each side, what the referee must do first, and how many referrals one user can be credited for
+const REFERRAL_KARMA: i64 = 10;
+const REFERRAL_MIN_VOTES: u64 = 5;
+const REFERRAL_MIN_DISCUSSIONS: u64 = 1;
+const MAX_CREDITED_REFERRALS: u64 = 50;
+// Accounts younger than this (1 day) can't refer anyone
+const REFERRER_MIN_ACCOUNT_AGE: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Premium features: how long a pin lasts (24 hours), and the size and length of a storage boost (30 days)
+const PIN_DURATION: u64 = 24 * 60 * 60 * 1_000_000_000;
+const STORAGE_BOOST_BYTES: u64 = 100 * 1024 * 1024;
+const STORAGE_BOOST_DURATION: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+// Proposals: length caps, option count, and the longest voting window (30 days)
+const MAX_PROPOSAL_TITLE_LENGTH: usize = 200;
+const MAX_PROPOSAL_DESCRIPTION_LENGTH: usize = 4000;
+const MAX_PROPOSAL_OPTIONS: usize = 10;
+const MAX_PROPOSAL_OPTION_LENGTH: usize = 100;
+const MAX_PROPOSAL_WRITE_INS: u32 = 10;
+const MAX_PROPOSAL_WINDOW: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+// Reminders: pending per user, furthest ahead (1 year), note length, and due per wheel minute to keep
+// a slot within MAX_SIZE
+const MAX_REMINDERS_PER_USER: usize = 50;
+const MAX_REMINDER_DELAY: u64 = 365 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_REMINDER_NOTE_LENGTH: usize = 200;
+const MAX_REMINDERS_PER_SLOT: usize = 200;
+const REMINDER_SLOT_WIDTH: u64 = 60 * 1_000_000_000;
+
+// Recovery attempts: how many per account and per caller in each window (1 hour), and how long an account's
+// recovery locks (24 hours) after too many failures in a row
+const MAX_RECOVERY_ATTEMPTS: u32 = 5;
+const RECOVERY_ATTEMPT_WINDOW: u64 = 60 * 60 * 1_000_000_000;
+const RECOVERY_LOCKOUT_FAILURES: u32 = 10;
+const RECOVERY_LOCKOUT: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Discussions the auto-lock job looks at per tick
+const AUTO_LOCK_BATCH: usize = 500;
+
+// Due entries the job finalizing closed votes looks at per tick
+const FINALIZE_BATCH: usize = 500;
+
+// Votes the vote expiry job looks at per tick
+const VOTE_EXPIRY_BATCH: usize = 1000;
+
+// Discussions purged from the trash per tick, and trash index entries the purge looks at per tick
+const TRASH_PURGE_BATCH: usize = 50;
+const TRASH_SCAN_BATCH: usize = 500;
+
+// Maps whose records belong to a discussion and go with it, see DISCUSSION_RECORDS. Only append: an
+// entry's position is stored in the index.
+const DISCUSSION_RECORD_MAPS: [StoredMap; 10] = [
+    StoredMap::VoteCommitments,
+    StoredMap::Views,
+    StoredMap::ReadMarkers,
+    StoredMap::DiscussionRevisions,
+    StoredMap::Surveys,
+    StoredMap::SurveyResponses,
+    StoredMap::Reminders,
+    StoredMap::UnlockRequests,
+    StoredMap::Raffles,
+    StoredMap::RandomnessAudit,
+];
+
+// Streak lengths in days that earn a badge the first time they are reached
+const STREAK_BADGE_DAYS: &[u32] = &[7, 30, 100, 365];
+
+// Accounts the karma decay job looks at per tick, and the reason its awards are recorded under
+const KARMA_DECAY_BATCH: usize = 200;
+const KARMA_DECAY_REASON: &str = "Inactivity decay";
+
+// Reasons karma awards are recorded under, used to break karma down by source
+const REFERRAL_KARMA_REASONS: &[&str] = &["referral", "referred"];
+const WARNING_KARMA_REASON: &str = "warning";
+
+// Winners one raffle may draw, keeps the record within MAX_SIZE
+const MAX_RAFFLE_WINNERS: u32 = 20;
+
+// Prefix of signed result attestations, also the derivation path of the key that signs them
+const RESULTS_ATTESTATION_TAG: &[u8] = b"votehub-results-v1";
+
+// Derivation path of the key that signs export manifests
+const EXPORT_SIGNING_PURPOSE: &[u8] = b"votehub-export-v1";
+
+// Derivations recorded with each random selection
+const RAFFLE_DERIVATION: &str = "Entrants are the distinct users with a counted vote, sorted by username; the inputs digest is \
+    SHA3-256 of their usernames joined by newlines. For i from 0, n is the first 8 bytes, big endian, of SHA3-256(seed || i as \
+    8 big-endian bytes), entrant i is swapped with entrant i + n mod (entrants - i), and the first `winners` entrants win.";
+const OPTION_ORDER_DERIVATION: &str = "A voter sees the options sorted by SHA3-256(seed || user ID as 8 big-endian bytes || \
+    option index as 4 big-endian bytes).";
+
+// Surveys: question and option caps, keep the record within MAX_SIZE
+const MAX_SURVEY_QUESTIONS: usize = 10;
+const MAX_SURVEY_PROMPT_LENGTH: usize = 200;
+const MAX_SURVEY_OPTIONS: usize = 8;
+const MAX_SURVEY_OPTION_LENGTH: usize = 60;
+
+// Number of related discussions bundled with a discussion page
+const RELATED_DISCUSSIONS: usize = 5;
+
+// Stream chunks stay well under the 2MB response cap; snapshots expire and are capped in number
+const STREAM_CHUNK_SIZE: usize = 1024 * 1024;
+const STREAM_TTL: u64 = 10 * 60 * 1_000_000_000;
+const MAX_OPEN_STREAMS: usize = 16;
+
+// Number of discussions kept decoded in the heap cache
+const DISCUSSION_CACHE_CAPACITY: usize = 256;
+
+// Number of newest users listed in the admin overview
+const ADMIN_RECENT_REGISTRATIONS: usize = 10;
+
+// Thread-local storage for the memory manager and data storage
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0).expect("Cannot create a counter")
+    );
+    static USERS_STORAGE: RefCell<StableBTreeMap<u64, User, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))))
+    );
+    // Moved off MemoryId 2, whose map was created with a smaller bound, see move_retired_maps
+    static DISCUSSIONS_STORAGE: RefCell<StableBTreeMap<u64, Discussion, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(91))))
+    );
+    static VOTES_STORAGE: RefCell<StableBTreeMap<u64, Vote, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))))
+    );
+    // Votes keyed by (discussion ID, vote ID), so one discussion's votes are read without scanning them all
+    static VOTES_BY_DISCUSSION: RefCell<StableBTreeMap<(u64, u64), u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(96))))
+    );
+    // Moved off MemoryId 5 like DISCUSSIONS_STORAGE
+    static GROUPS_STORAGE: RefCell<StableBTreeMap<u64, Group, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(92))))
+    );
+    static GROUP_MEMBERS_STORAGE: RefCell<StableBTreeMap<u64, GroupMember, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))))
+    );
+    static GROUP_INVITES_STORAGE: RefCell<StableBTreeMap<u64, GroupInvite, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))))
+    );
+    static JOIN_REQUESTS_STORAGE: RefCell<StableBTreeMap<u64, JoinRequest, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))))
+    );
+    static REPORTS_STORAGE: RefCell<StableBTreeMap<u64, Report, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))))
+    );
+    static GROUP_AUDIT_LOG: RefCell<StableBTreeMap<u64, GroupAuditEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))))
+    );
+    static CONVERSATIONS_STORAGE: RefCell<StableBTreeMap<u64, Conversation, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))))
+    );
+    static MESSAGES_STORAGE: RefCell<StableBTreeMap<u64, DirectMessage, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))))
+    );
+    static PRINCIPAL_LINKS_STORAGE: RefCell<StableBTreeMap<u64, PrincipalLink, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))))
+    );
+    static LINK_CHALLENGES_STORAGE: RefCell<StableBTreeMap<u64, LinkChallenge, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))))
+    );
+    static SIWE_CHALLENGES_STORAGE: RefCell<StableBTreeMap<u64, SiweChallenge, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))))
+    );
+    // Keyed by user ID, users without an entry use the default settings
+    static PRIVACY_SETTINGS_STORAGE: RefCell<StableBTreeMap<u64, PrivacySettings, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))))
+    );
+    // Keyed by user ID
+    static RECOVERY_CONFIGS_STORAGE: RefCell<StableBTreeMap<u64, RecoveryConfig, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))))
+    );
+    // Keyed by user ID, at most one pending recovery per account
+    static PENDING_RECOVERIES_STORAGE: RefCell<StableBTreeMap<u64, PendingRecovery, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))))
+    );
+    static ACCOUNT_AUDIT_LOG: RefCell<StableBTreeMap<u64, AccountAuditEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))))
+    );
+    static TOMBSTONES_STORAGE: RefCell<StableBTreeMap<u64, AccountTombstone, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))))
+    );
+    static ADMINS_STORAGE: RefCell<StableBTreeMap<u64, Admin, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))))
+    );
+    static FEATURE_FLAGS_STORAGE: RefCell<StableBTreeMap<u64, FeatureFlag, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))))
+    );
+    static MAINTENANCE: RefCell<MaintenanceCell> = RefCell::new(
+        MaintenanceCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))), MaintenanceMode::default()).expect("Cannot create maintenance mode")
+    );
+    static GC_STATE: RefCell<GcCell> = RefCell::new(
+        GcCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))), GcState::default()).expect("Cannot create GC state")
+    );
+    static IDEMPOTENCY_STORAGE: RefCell<StableBTreeMap<u64, IdempotencyRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))))
+    );
+    static VOTE_COMMITMENTS_STORAGE: RefCell<StableBTreeMap<u64, VoteCommitment, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))))
+    );
+    // Keyed by user ID
+    static PERSONHOOD_STORAGE: RefCell<StableBTreeMap<u64, PersonhoodVerification, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))))
+    );
+    static REGISTRATION_CHALLENGES_STORAGE: RefCell<StableBTreeMap<u64, RegistrationChallenge, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))))
+    );
+    static REGISTRATION_EVENTS_STORAGE: RefCell<StableBTreeMap<u64, RegistrationEvent, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))))
+    );
+    static VIEWS_STORAGE: RefCell<StableBTreeMap<u64, ViewRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))))
+    );
+    static READ_MARKERS_STORAGE: RefCell<StableBTreeMap<u64, ReadMarker, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))))
+    );
+    // Discussion ID -> created_at of every discussion without votes, kept in step by save_discussion
+    static NEEDS_ATTENTION_INDEX: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))))
+    );
+    static KEYWORD_ALERTS_STORAGE: RefCell<StableBTreeMap<u64, KeywordAlert, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))))
+    );
+    static NOTIFICATIONS_STORAGE: RefCell<StableBTreeMap<u64, Notification, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35))))
+    );
+    // Keyword alerts keyed by (token key of the keyword's first word, alert ID), so a new discussion only
+    // reads the alerts sharing a word with its topic
+    static KEYWORD_ALERTS_BY_TOKEN: RefCell<StableBTreeMap<(u64, u64), u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(98))))
+    );
+    // Notifications keyed by (username key, notification ID), see username_key
+    static NOTIFICATIONS_BY_USER: RefCell<StableBTreeMap<(u64, u64), u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(99))))
+    );
+    // Records belonging to a discussion keyed by (discussion ID, record key), each holding the position of
+    // its map in DISCUSSION_RECORD_MAPS, so deleting a discussion removes them without scanning the maps
+    static DISCUSSION_RECORDS: RefCell<StableBTreeMap<(u64, u64), u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(100))))
+    );
+    // Trashed discussions keyed by (purge_at, discussion ID), so the purge reads only the due ones
+    static TRASH_BY_PURGE_AT: RefCell<StableBTreeMap<(u64, u64), u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(101))))
+    );
+    // Voting power snapshot entries keyed by (proposal ID, user ID), holding the entry's key, so a vote
+    // reads its weight without scanning every proposal's snapshot
+    static VOTING_POWER_INDEX: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(102))))
+    );
+    // Restrictions keyed by (restriction key of the username and kind, restriction ID), see restriction_key
+    static RESTRICTIONS_BY_USER: RefCell<StableBTreeMap<(u64, u64), u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(103))))
+    );
+    // Discussions keyed by (username key of the author, created_at), holding the discussion ID, so rate
+    // limits read only the author's recent discussions
+    static DISCUSSIONS_BY_AUTHOR: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(104))))
+    );
+    // Discussions whose results are not final yet keyed by (time they become final, discussion ID), so the
+    // finalize job reads only the due ones
+    static DISCUSSIONS_BY_FINAL_AT: RefCell<StableBTreeMap<(u64, u64), u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(105))))
+    );
+    // Principal links keyed by (principal key, link ID) and by (username key, link ID), see principal_key
+    // and username_key, so authentication reads only the links it needs
+    static LINKS_BY_PRINCIPAL: RefCell<StableBTreeMap<(u64, u64), u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(106))))
+    );
+    static LINKS_BY_USER: RefCell<StableBTreeMap<(u64, u64), u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(107))))
+    );
+    // Hashtag entry IDs keyed by (tag key, discussion ID), see search::token_key, so a discussion's entries
+    // are found through the tags of its topic
+    static HASHTAGS_BY_TAG: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(108))))
+    );
+    // Forum-listed discussions per tag key, in total and by (creation hour, tag key) for the trending window
+    static HASHTAG_TOTALS: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(109))))
+    );
+    static HASHTAG_HOURLY_COUNTS: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(110))))
+    );
+    static HASHTAG_INDEX: RefCell<StableBTreeMap<u64, HashtagEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))))
+    );
+    // Keyed by discussion ID
+    static LINK_PREVIEWS_STORAGE: RefCell<StableBTreeMap<u64, LinkPreview, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))))
+    );
+    // Discussion ID -> URL waiting for its preview to be fetched
+    static LINK_PREVIEW_QUEUE: RefCell<StableBTreeMap<u64, LinkPreviewRequest, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))))
+    );
+    static TOPIC_MARKDOWN_STORAGE: RefCell<StableBTreeMap<u64, NormalizedText, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))))
+    );
+    static ATTACHMENTS_STORAGE: RefCell<StableBTreeMap<u64, Attachment, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40))))
+    );
+    // Blob region holding attachment bytes
+    static ATTACHMENT_CHUNKS_STORAGE: RefCell<StableBTreeMap<u64, AttachmentChunk, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))))
+    );
+    static CANISTER_ROLE: RefCell<RoleCell> = RefCell::new(
+        RoleCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42))), CanisterRole::default()).expect("Cannot create canister role")
+    );
+    // Wasm module for spawned canisters, keyed by chunk index
+    static CANISTER_WASM_STORAGE: RefCell<StableBTreeMap<u64, WasmChunk, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43))))
+    );
+    // Shards keyed by the first discussion ID they own
+    static SHARDS_STORAGE: RefCell<StableBTreeMap<u64, ShardInfo, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44))))
+    );
+    // Archives in creation order, the last one receives new discussions
+    static ARCHIVES_STORAGE: RefCell<StableBTreeMap<u64, ArchiveInfo, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45))))
+    );
+    // Redirect index: archived discussion ID to the key of its archive
+    static ARCHIVED_INDEX: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46))))
+    );
+    // Search canister: posting lists keyed by (token key, discussion ID)
+    static SEARCH_POSTINGS: RefCell<StableBTreeMap<(u64, u64), u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47))))
+    );
+    static SEARCH_DOCUMENTS: RefCell<StableBTreeMap<u64, SearchDocument, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48))))
+    );
+    // Hub: discussions whose tokens still have to be pushed, with the time they were queued
+    static SEARCH_QUEUE: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49))))
+    );
+    static SEARCH_INDEX: RefCell<SearchIndexCell> = RefCell::new(
+        SearchIndexCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50))), SearchIndexConfig::default()).expect("Cannot create search index config")
+    );
+    // Hub: user IDs whose account still has to be sent to the group canisters, with the time they were queued
+    static ACCOUNT_SYNC_QUEUE: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(93))))
+    );
+    // Groups promoted to their own canister, keyed by group ID
+    static GROUP_DIRECTORY: RefCell<StableBTreeMap<u64, GroupDirectoryEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51))))
+    );
+    static DAO_CONFIG: RefCell<DaoCell> = RefCell::new(
+        DaoCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52))), DaoConfig::default()).expect("Cannot create DAO config")
+    );
+    static PROPOSALS_STORAGE: RefCell<StableBTreeMap<u64, Proposal, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53))))
+    );
+    static PROPOSAL_VOTES_STORAGE: RefCell<StableBTreeMap<u64, ProposalVote, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54))))
+    );
+    static VOTING_POWER_SNAPSHOTS: RefCell<StableBTreeMap<u64, VotingPowerEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55))))
+    );
+    static TREASURY_PAYOUTS: RefCell<StableBTreeMap<u64, TreasuryPayout, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56))))
+    );
+    static TREASURY_DEPOSITS: RefCell<StableBTreeMap<u64, TreasuryDeposit, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57))))
+    );
+    static ENTITLEMENTS_STORAGE: RefCell<StableBTreeMap<u64, Entitlement, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58))))
+    );
+    static REFERRALS_STORAGE: RefCell<StableBTreeMap<u64, Referral, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59))))
+    );
+    static KARMA_AWARDS_STORAGE: RefCell<StableBTreeMap<u64, KarmaAward, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60))))
+    );
+    // Keyed by user ID
+    static PROFILES_STORAGE: RefCell<StableBTreeMap<u64, Profile, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61))))
+    );
+    // Keyed by user ID
+    static ONBOARDING_STORAGE: RefCell<StableBTreeMap<u64, OnboardingProgress, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62))))
+    );
+    static BADGES_STORAGE: RefCell<StableBTreeMap<u64, Badge, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63))))
+    );
+    // Keyed by version
+    static TERMS_STORAGE: RefCell<StableBTreeMap<u64, TermsVersion, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64))))
+    );
+    static TERMS_ACCEPTANCES_STORAGE: RefCell<StableBTreeMap<u64, TermsAcceptance, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65))))
+    );
+    // Keyed by user ID
+    static CONTENT_PREFERENCES_STORAGE: RefCell<StableBTreeMap<u64, ContentPreferences, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66))))
+    );
+    static FLOOD_INCIDENTS_STORAGE: RefCell<StableBTreeMap<u64, FloodIncident, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67))))
+    );
+    // Next vote key the backfill of voter principals resumes from, u64::MAX once it is done
+    static VOTER_BACKFILL_CURSOR: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(97))), 0).expect("Cannot create the voter backfill cursor")
+    );
+    static LOG_SEQUENCE: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68))), 0).expect("Cannot create the log sequence")
+    );
+    // Keyed by a hash of the endpoint name
+    static ENDPOINT_METRICS_STORAGE: RefCell<StableBTreeMap<u64, metrics::EndpointMetrics, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70))))
+    );
+    // Ring buffer keyed by sequence modulo logging::LOG_CAPACITY
+    static LOG_STORAGE: RefCell<StableBTreeMap<u64, logging::LogEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69))))
+    );
+    static QUARANTINE_STORAGE: RefCell<StableBTreeMap<u64, QuarantinedRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71))))
+    );
+    // Deleted discussions keyed by discussion ID
+    static TRASH_STORAGE: RefCell<StableBTreeMap<u64, TrashedDiscussion, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72))))
+    );
+    static WARNINGS_STORAGE: RefCell<StableBTreeMap<u64, Warning, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73))))
+    );
+    static RESTRICTIONS_STORAGE: RefCell<StableBTreeMap<u64, Restriction, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74))))
+    );
+    static APPEALS_STORAGE: RefCell<StableBTreeMap<u64, Appeal, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75))))
+    );
+    // Topic history of edited discussions, keyed by revision ID
+    static REVISIONS_STORAGE: RefCell<StableBTreeMap<u64, DiscussionRevision, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76))))
+    );
+    static TRANSFERS_STORAGE: RefCell<StableBTreeMap<u64, OwnershipTransfer, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77))))
+    );
+    static SURVEYS_STORAGE: RefCell<StableBTreeMap<u64, Survey, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78))))
+    );
+    static SURVEY_RESPONSES_STORAGE: RefCell<StableBTreeMap<u64, SurveyResponse, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79))))
+    );
+    // Events waiting for delivery and those that ran out of attempts, keyed by entry ID
+    static OUTBOX_STORAGE: RefCell<StableBTreeMap<u64, OutboxEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80))))
+    );
+    // Outbox entries keyed by (next attempt time, entry ID), so the dispatcher reads only the ones due
+    static OUTBOX_DUE_INDEX: RefCell<StableBTreeMap<(u64, u64), u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(94))))
+    );
+    static DEAD_LETTERS_STORAGE: RefCell<StableBTreeMap<u64, OutboxEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81))))
+    );
+    static EVENT_SUBSCRIPTIONS_STORAGE: RefCell<StableBTreeMap<u64, Subscription, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82))))
+    );
+    static REMINDERS_STORAGE: RefCell<StableBTreeMap<u64, Reminder, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83))))
+    );
+    static REMINDER_WHEEL: RefCell<StableBTreeMap<u64, ReminderSlot, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(84))))
+    );
+    static RECURRENCES_STORAGE: RefCell<StableBTreeMap<u64, Recurrence, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(85))))
+    );
+    static UNLOCK_REQUESTS_STORAGE: RefCell<StableBTreeMap<u64, UnlockRequest, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(86))))
+    );
+    // Keyed by user ID
+    static USER_ACTIVITY_STORAGE: RefCell<StableBTreeMap<u64, UserActivity, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(87))))
+    );
+    // Keyed by user ID
+    static KARMA_TALLIES_STORAGE: RefCell<StableBTreeMap<u64, KarmaTally, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(95))))
+    );
+    static RAFFLES_STORAGE: RefCell<StableBTreeMap<u64, Raffle, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(88))))
+    );
+    static RANDOMNESS_AUDIT_STORAGE: RefCell<StableBTreeMap<u64, RandomnessAudit, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(89))))
+    );
+    // Keyed by discussion ID
+    static ATTESTATIONS_STORAGE: RefCell<StableBTreeMap<u64, ResultAttestation, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(90))))
+    );
+    // Heap only, raw bytes and error of the last record that failed to decode
+    static LAST_DECODE_FAILURE: RefCell<Option<(Vec<u8>, String)>> = const { RefCell::new(None) };
+    // Heap only, windows restart after an upgrade
+    static CALLER_ACTIVITY: RefCell<BTreeMap<Principal, CallerActivity>> = RefCell::default();
+    // Heap only, recovery attempts by caller as (window start, attempts in the window); windows restart after an upgrade
+    static RECOVERY_ATTEMPTS: RefCell<BTreeMap<Principal, (u64, u32)>> = RefCell::default();
+    // Heap only, set while a batch of token updates is being pushed
+    static SEARCH_PUSH_IN_FLIGHT: RefCell<bool> = const { RefCell::new(false) };
+    // Heap only, set while a batch of accounts is being sent to the group canisters
+    static ACCOUNT_SYNC_IN_FLIGHT: RefCell<bool> = const { RefCell::new(false) };
+    // Heap only, set while a batch is being sent to an archive
+    static ARCHIVING_IN_FLIGHT: RefCell<bool> = const { RefCell::new(false) };
+    // Heap only, set while a preview outcall is running
+    static LINK_PREVIEW_IN_FLIGHT: RefCell<bool> = const { RefCell::new(false) };
+    // Heap only, discussions whose results are being signed, so each signature is paid for once
+    static ATTESTATIONS_IN_FLIGHT: RefCell<BTreeSet<u64>> = RefCell::default();
+    // Heap only, discussion ID the auto-lock scan resumes from; the scan restarts after an upgrade
+    static AUTO_LOCK_CURSOR: RefCell<u64> = const { RefCell::new(0) };
+    // Heap only, vote ID the vote expiry scan resumes from; the scan restarts after an upgrade
+    static VOTE_EXPIRY_CURSOR: RefCell<u64> = const { RefCell::new(0) };
+    // Heap only, user ID the karma decay scan resumes from; the scan restarts after an upgrade
+    static KARMA_DECAY_CURSOR: RefCell<u64> = const { RefCell::new(0) };
+    // Heap only, trash index entry the purge resumes from, so discussions held for review or appeal don't
+    // hold up the ones behind them; the scan restarts after an upgrade
+    static TRASH_PURGE_CURSOR: RefCell<(u64, u64)> = const { RefCell::new((0, 0)) };
+    // Heap only, set while the outbox dispatcher is delivering a batch
+    static OUTBOX_IN_FLIGHT: RefCell<bool> = const { RefCell::new(false) };
+    // Heap only, vote tree root of every discussion with votes, rebuilt after an upgrade
+    static VOTE_ROOTS: RefCell<BTreeMap<u64, merkle::Hash>> = RefCell::default();
+    // Heap only, discussions whose vote root is out of date until the next background tick refreshes it
+    static VOTE_ROOTS_STALE: RefCell<BTreeSet<u64>> = RefCell::default();
+    // Heap only, set while every vote root needs rebuilding, which includes after install and upgrade
+    static VOTE_ROOTS_REBUILD: RefCell<bool> = const { RefCell::new(true) };
+    // Heap only, map and key the rewrite of records in an older shape resumes from, None once done; the
+    // rewrite restarts after an upgrade
+    static SCHEMA_REWRITE_CURSOR: RefCell<Option<(usize, u64)>> = const { RefCell::new(Some((0, 0))) };
+    // Heap only, open streams do not survive upgrades
+    static STREAMS: RefCell<BTreeMap<u64, StreamSnapshot>> = RefCell::default();
+    // Heap only, results CSV downloads in progress keyed by download handle
+    static RESULTS_CSV_SNAPSHOTS: RefCell<BTreeMap<u64, ResultsCsvSnapshot>> = RefCell::default();
+    // Heap only, starts empty after an upgrade and refills on reads
+    static DISCUSSION_CACHE: RefCell<DiscussionCache> = RefCell::default();
+    static SETTINGS: RefCell<SettingsCell> = RefCell::new(
+        SettingsCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))), Settings::default()).expect("Cannot create settings")
+    );
+    // Heap only, set by an upgrade until the background tick has moved every entry out of the retired maps
+    static RETIRED_MAPS_PENDING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+// Helper function to remove every entry of a storage map matching a predicate, returns how many were removed
+fn remove_where<V: BoundedStorable>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+    predicate: impl Fn(&V) -> bool,
+) -> u64 {
+    storage.with(|storage| {
+        let keys: Vec<u64> = storage.borrow().iter()
+            .filter(|(_, value)| predicate(value))
+            .map(|(id, _)| id)
+            .collect();
+
+        let mut storage_mut = storage.borrow_mut();
+        for key in &keys {
+            storage_mut.remove(key);
+        }
+
+        keys.len() as u64
+    })
+}
+
+impl ChangeReport {
+    fn new(dry_run: bool) -> Self {
+        ChangeReport { dry_run, changes: Vec::new() }
+    }
+
+    fn change(&mut self, map: &str) -> &mut MapChange {
+        match self.changes.iter().position(|change| change.map == map) {
+            Some(index) => &mut self.changes[index],
+            None => {
+                self.changes.push(MapChange { map: map.to_string(), ..Default::default() });
+                self.changes.last_mut().unwrap()
+            }
+        }
+    }
+
+    fn removed(&mut self, map: &str, ids: impl IntoIterator<Item = u64>) {
+        let mut ids = ids.into_iter().peekable();
+        if ids.peek().is_some() {
+            let change = self.change(map);
+            for id in ids {
+                if !change.removed.contains(&id) {
+                    change.removed.push(id);
+                }
+            }
+        }
+    }
+
+    fn updated(&mut self, map: &str, ids: impl IntoIterator<Item = u64>) {
+        let mut ids = ids.into_iter().peekable();
+        if ids.peek().is_some() {
+            let change = self.change(map);
+            for id in ids {
+                if !change.updated.contains(&id) {
+                    change.updated.push(id);
+                }
+            }
+        }
+    }
+
+    fn inserted(&mut self, map: &str, count: u64) {
+        if count > 0 {
+            self.change(map).inserted += count;
+        }
+    }
+
+    // Removes the keys that exist, unless this is a dry run
+    fn remove_keys<V: BoundedStorable>(
+        &mut self,
+        map: &str,
+        storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+        keys: impl IntoIterator<Item = u64>,
+    ) {
+        let keys: Vec<u64> = storage.with(|storage| {
+            let storage = storage.borrow();
+            keys.into_iter().filter(|key| storage.contains_key(key)).collect()
+        });
+
+        if !self.dry_run {
+            storage.with(|storage| {
+                let mut storage_mut = storage.borrow_mut();
+                for key in &keys {
+                    storage_mut.remove(key);
+                }
+            });
+        }
+        self.removed(map, keys);
+    }
+
+    // Removes every entry matching a predicate, unless this is a dry run
+    fn remove_where<V: BoundedStorable>(
+        &mut self,
+        map: &str,
+        storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+        predicate: impl Fn(&V) -> bool,
+    ) {
+        let keys: Vec<u64> = storage.with(|storage| {
+            storage.borrow().iter().filter(|(_, value)| predicate(value)).map(|(id, _)| id).collect()
+        });
+        self.remove_keys(map, storage, keys);
+    }
+
+    // Rewrites every entry matching a predicate, unless this is a dry run
+    fn update_where<V: BoundedStorable>(
+        &mut self,
+        map: &str,
+        storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+        predicate: impl Fn(&V) -> bool,
+        update: impl Fn(&mut V),
+    ) {
+        // Placeholders of undecodable records must not be written back over their bytes
+        let affected: Vec<(u64, V)> = storage.with(|storage| {
+            storage.borrow().iter().filter(decoded).filter(|(_, value)| predicate(value)).collect()
+        });
+
+        self.updated(map, affected.iter().map(|(id, _)| *id));
+        if !self.dry_run {
+            storage.with(|storage| {
+                let mut storage_mut = storage.borrow_mut();
+                for (id, mut value) in affected {
+                    update(&mut value);
+                    storage_mut.insert(id, value);
+                }
+            });
+        }
+    }
+
+    // Replaces a map's contents, reporting the keys that go, change or arrive, unless this is a dry run
+    fn replace_all<V: BoundedStorable>(
+        &mut self,
+        map: &str,
+        storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+        entries: Vec<(u64, V)>,
+    ) {
+        let incoming: BTreeMap<u64, V> = entries.into_iter().collect();
+
+        let (removed, updated, kept) = storage.with(|storage| {
+            let (mut removed, mut updated, mut kept) = (Vec::new(), Vec::new(), 0);
+            for (id, value) in storage.borrow().iter() {
+                match incoming.get(&id) {
+                    None => removed.push(id),
+                    Some(new) if new.to_bytes() != value.to_bytes() => updated.push(id),
+                    Some(_) => kept += 1,
+                }
+            }
+            (removed, updated, kept)
+        });
+
+        self.removed(map, removed);
+        let inserted = incoming.len() as u64 - kept - updated.len() as u64;
+        self.updated(map, updated);
+        self.inserted(map, inserted);
+
+        if !self.dry_run {
+            remove_where(storage, |_| true);
+            storage.with(|storage| {
+                let mut storage_mut = storage.borrow_mut();
+                for (id, value) in incoming {
+                    storage_mut.insert(id, value);
+                }
+            });
+        }
+    }
+}
+
+// Helper function to read the current settings
+fn settings() -> Settings {
+    SETTINGS.with(|settings| settings.borrow().get().clone())
+}
+
+// Helper function for update entrypoints, fails while the canister is in maintenance mode or the
+// caller is backing off after a burst of updates
+fn ensure_writable() -> Result<(), String> {
+    check_maintenance()?;
+    check_flood()
+}
+
+// Helper function to fail while the canister is in maintenance mode
+fn check_maintenance() -> Result<(), String> {
+    let maintenance = MAINTENANCE.with(|maintenance| maintenance.borrow().get().clone());
+
+    let moving = RETIRED_MAPS_PENDING.with(|pending| *pending.borrow());
+    if !maintenance.enabled && !moving {
+        return Ok(());
+    }
+
+    let mut error = String::from(ErrorCode::MaintenanceMode);
+    if moving {
+        error.push_str(", records are being moved to new storage after an upgrade");
+    }
+    if let Some(message) = maintenance.message {
+        error.push_str(&format!(", {}", message));
+    }
+    if let Some(eta) = maintenance.eta {
+        error.push_str(&format!(" (expected back at {})", eta));
+    }
+
+    Err(error)
+}
+
+// Helper function to count an update from the caller and reject it while the caller is backing off.
+// Admins are never throttled; anonymous callers share one window.
+fn check_flood() -> Result<(), String> {
+    let settings = settings();
+    let principal = caller();
+
+    if settings.flood_max_updates == 0 || is_admin(&principal) {
+        return Ok(());
+    }
+
+    let now = time();
+    let window = settings.flood_window_seconds.max(1) * 1_000_000_000;
+
+    let burst = CALLER_ACTIVITY.with(|activity| {
+        let mut activity = activity.borrow_mut();
+
+        if activity.len() >= MAX_TRACKED_CALLERS {
+            activity.retain(|_, entry| entry.blocked_until > now || now - entry.window_start < window);
+        }
+
+        let entry = activity.entry(principal).or_default();
+
+        if entry.blocked_until > now {
+            return Err(format!("Too many updates, retry in {} seconds", (entry.blocked_until - now).div_ceil(1_000_000_000)));
+        }
+
+        if now.saturating_sub(entry.window_start) >= window {
+            entry.window_start = now;
+            entry.updates = 0;
+        }
+        entry.updates += 1;
+
+        Ok((entry.updates > settings.flood_max_updates).then_some(entry.updates))
+    })?;
+
+    let Some(updates) = burst else {
+        return Ok(());
+    };
+
+    let since = now.saturating_sub(86_400_000_000_000);
+    let level = FLOOD_INCIDENTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, incident)| incident.principal == principal && incident.detected_at >= since)
+            .count() as u32
+    });
+    let backoff = settings.flood_backoff_seconds.saturating_mul(1 << level.min(10)).min(MAX_FLOOD_BACKOFF_SECONDS);
+    let blocked_until = now + backoff * 1_000_000_000;
+
+    CALLER_ACTIVITY.with(|activity| {
+        if let Some(entry) = activity.borrow_mut().get_mut(&principal) {
+            entry.blocked_until = blocked_until;
+            entry.updates = 0;
+        }
+    });
+
+    let id = next_id();
+    let incident = FloodIncident {
+        id,
+        principal,
+        username: linked_username(&principal),
+        updates,
+        window_seconds: settings.flood_window_seconds,
+        level,
+        detected_at: now,
+        blocked_until,
+    };
+    FLOOD_INCIDENTS_STORAGE.with(|storage| storage.borrow_mut().insert(id, incident));
+    logging::log(LogLevel::Warn, "flood", format!("{} sent {} updates in {}s, blocked for {}s", principal, updates, settings.flood_window_seconds, backoff));
+
+    Err(format!("Too many updates, retry in {} seconds", backoff))
+}
+
+// Function to page through recorded update bursts (admins only)
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_flood_incidents(cursor: Option<String>, limit: Option<u32>) -> Result<Page<FloodIncident>, String> {
+    paginate(&FLOOD_INCIDENTS_STORAGE, cursor, limit, |_, _| true)
+}
+
+// Function to lift a principal's current backoff early (admins only). Only heap state changes, so this
+// stays available in maintenance mode.
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn clear_flood_backoff(principal: Principal) -> String {
+    let _meter = metrics::measure("clear_flood_backoff");
+
+    CALLER_ACTIVITY.with(|activity| activity.borrow_mut().remove(&principal));
+
+    "Backoff cleared".to_string()
+}
+
+// Helper function to check that the caller may warn a user: admins warn anyone, and group moderators
+// warn members of their group with a lower role
+fn check_warning_issuer(username: &str, moderator: Option<&String>, group_id: Option<u64>) -> Result<(), String> {
+    if is_admin(&caller()) {
+        return Ok(());
+    }
+
+    let (Some(moderator), Some(group_id)) = (moderator, group_id) else {
+        return Err("Only admins and group moderators can issue warnings".to_string());
+    };
+
+    authenticate(moderator)?;
+
+    get_moderated_group(group_id, moderator)?;
+
+    let actor = find_group_member(group_id, moderator).ok_or(ErrorCode::NotGroupMember)?;
+    let target = find_group_member(group_id, username).ok_or("User is not a member of this group")?;
+
+    if target.role >= actor.role {
+        return Err("Cannot warn a member with an equal or higher role".to_string());
+    }
+
+    Ok(())
+}
+
+// Function to formally warn a user; enough warnings within the strike window mute or suspend them. Group
+// moderators pass their username and the group, see check_warning_issuer.
+#[ic_cdk::update]
+fn issue_warning(username: String, reason: String, moderator: Option<String>, group_id: Option<u64>) -> Result<Warning, String> {
+    let _meter = metrics::measure("issue_warning");
+
+    ensure_writable()?;
+
+    check_warning_issuer(&username, moderator.as_ref(), group_id)?;
+
+    find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+    let reason = validate_reason(&reason)?;
+
+    let id = next_id();
+    let now = time();
+
+    let warning = Warning {
+        id,
+        username: username.clone(),
+        reason: reason.clone(),
+        issued_by: caller(),
+        issued_at: now,
+    };
+
+    WARNINGS_STORAGE.with(|storage| storage.borrow_mut().insert(id, warning.clone()));
+    log_account_action(&username, format!("Warned: {}", reason));
+    match (&moderator, group_id) {
+        (Some(moderator), Some(group_id)) if !is_admin(&caller()) => log_group_action(group_id, moderator, format!("Warned {}", username)),
+        _ => {}
+    }
+    notify(&username, NotificationKind::Warning { warning_id: id });
+
+    apply_strike_escalation(&username, id, now);
+
+    let penalty = settings().warning_karma_penalty;
+    if penalty > 0 {
+        award_karma(&username, -(penalty as i64), WARNING_KARMA_REASON);
+    }
+
+    Ok(warning)
+}
+
+// Helper function to count a user's warnings inside the strike window
+fn active_strikes(username: &str, now: u64) -> u32 {
+    let window_start = now.saturating_sub(settings().strike_window_days as u64 * 86_400_000_000_000);
+
+    WARNINGS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, warning)| warning.username == username && warning.issued_at >= window_start)
+            .count() as u32
+    })
+}
+
+// Helper function to mute or suspend a user whose strikes reached a threshold
+fn apply_strike_escalation(username: &str, warning_id: u64, now: u64) {
+    let settings = settings();
+    let strikes = active_strikes(username, now);
+
+    let (kind, duration) = if settings.strikes_to_suspend > 0 && strikes >= settings.strikes_to_suspend {
+        let doublings = (strikes - settings.strikes_to_suspend).min(10);
+        let days = (settings.suspension_days as u64).saturating_mul(1 << doublings).min(MAX_SUSPENSION_DAYS);
+        (RestrictionKind::Suspension, days * 86_400_000_000_000)
+    } else if settings.strikes_to_mute > 0 && strikes >= settings.strikes_to_mute {
+        (RestrictionKind::Mute, settings.mute_hours as u64 * 3_600_000_000_000)
+    } else {
+        return;
+    };
+
+    let id = next_id();
+
+    let restriction = Restriction {
+        id,
+        username: username.to_string(),
+        kind,
+        reason: format!("{} strikes within {} days", strikes, settings.strike_window_days),
+        warning_id: Some(warning_id),
+        discussion_id: None,
+        starts_at: now,
+        ends_at: now.saturating_add(duration),
+        lifted: false,
+        pending_review: false,
+        from_hub: false,
+    };
+
+    insert_restriction(restriction);
+    queue_account_sync_for(username);
+    log_account_action(username, format!("{} after {} strikes", if kind == RestrictionKind::Mute { "Muted" } else { "Suspended" }, strikes));
+}
+
+// Helper function to hash a username and restriction kind into the first half of RESTRICTIONS_BY_USER keys
+fn restriction_key(username: &str, kind: RestrictionKind) -> u64 {
+    username_key(username).wrapping_add(kind as u64)
+}
+
+// Helper function to store a new restriction and index it under its user and kind
+fn insert_restriction(restriction: Restriction) {
+    RESTRICTIONS_BY_USER.with(|index| index.borrow_mut().insert((restriction_key(&restriction.username, restriction.kind), restriction.id), 0));
+    RESTRICTIONS_STORAGE.with(|storage| storage.borrow_mut().insert(restriction.id, restriction));
+}
+
+// Helper function to read a user's restrictions of a kind through the index. Entries whose restriction is
+// gone, no longer decodes or belongs to another user or kind are skipped; rebuild_restriction_index drops them.
+fn user_restrictions(username: &str, kind: RestrictionKind) -> Vec<Restriction> {
+    let key = restriction_key(username, kind);
+    let ids: Vec<u64> = RESTRICTIONS_BY_USER.with(|index| {
+        index.borrow().range((key, 0)..=(key, u64::MAX)).map(|((_, id), _)| id).collect()
+    });
+
+    ids.into_iter()
+        .filter_map(|id| try_get(&RESTRICTIONS_STORAGE, id))
+        .filter(|restriction| restriction.username == username && restriction.kind == kind)
+        .collect()
+}
+
+// Helper function to rebuild RESTRICTIONS_BY_USER from the restrictions
+fn rebuild_restriction_index() {
+    RESTRICTIONS_BY_USER.with(|index| {
+        let mut index = index.borrow_mut();
+        let keys: Vec<(u64, u64)> = index.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index.remove(&key);
+        }
+        RESTRICTIONS_STORAGE.with(|storage| {
+            for (id, restriction) in storage.borrow().iter().filter(decoded) {
+                index.insert((restriction_key(&restriction.username, restriction.kind), id), 0);
+            }
+        });
+    });
+}
+
+// Function to lift a mute or suspension before it ends (admins only)
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn lift_restriction(restriction_id: u64) -> Result<Restriction, String> {
+    let _meter = metrics::measure("lift_restriction");
+
+    ensure_writable()?;
+
+    let mut restriction = try_get(&RESTRICTIONS_STORAGE, restriction_id).ok_or("Restriction not found")?;
+
+    restriction.lifted = true;
+    RESTRICTIONS_STORAGE.with(|storage| storage.borrow_mut().insert(restriction_id, restriction.clone()));
+    queue_account_sync_for(&restriction.username);
+    log_account_action(&restriction.username, format!("Lifted restriction {}", restriction_id));
+
+    Ok(restriction)
+}
+
+// Function to appeal a removal or restriction; suspended users can still appeal
+#[ic_cdk::update]
+fn appeal_moderation(target: AppealTarget, statement: String, username: String) -> Result<Appeal, String> {
+    let _meter = metrics::measure("appeal_moderation");
+
+    ensure_writable()?;
+
+    authenticate_identity(&username)?;
+
+    match &target {
+        AppealTarget::Removal { discussion_id } => {
+            let trashed = try_get(&TRASH_STORAGE, *discussion_id).ok_or("Discussion is not in the trash")?;
+            if trashed.discussion.created_by != username || trashed.deleted_by == username {
+                return Err("Only removals of your own discussions by a moderator can be appealed".to_string());
+            }
+        }
+        AppealTarget::Restriction { restriction_id } => {
+            let restriction = try_get(&RESTRICTIONS_STORAGE, *restriction_id).ok_or("Restriction not found")?;
+            if restriction.username != username {
+                return Err("Only your own restrictions can be appealed".to_string());
+            }
+            if restriction.lifted || restriction.ends_at <= time() {
+                return Err("Restriction is no longer in force".to_string());
+            }
+        }
+    }
+
+    if has_pending_appeal(&target) {
+        return Err("An appeal of this action is already pending".to_string());
+    }
+
+    let statement = validate_reason(&statement)?;
+    let id = next_id();
+
+    let appeal = Appeal {
+        id,
+        username,
+        target,
+        statement,
+        status: AppealStatus::Pending,
+        created_at: time(),
+        decided_by: None,
+        decision_reason: None,
+        decided_at: None,
+    };
+
+    APPEALS_STORAGE.with(|storage| storage.borrow_mut().insert(id, appeal.clone()));
+
+    Ok(appeal)
+}
+
+// Helper function to check if an action already has an undecided appeal
+fn has_pending_appeal(target: &AppealTarget) -> bool {
+    APPEALS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).any(|(_, appeal)| appeal.status == AppealStatus::Pending && appeal.target == *target)
+    })
+}
+
+// Helper function to check if the caller, acting as `username` when given, may decide an appeal.
+// Moderators decide removals in their groups and admins everything, but never the moderator who acted.
+fn check_appeal_decider(appeal: &Appeal, username: Option<&str>) -> Result<(), String> {
+    if username == Some(appeal.username.as_str()) {
+        return Err("Users cannot decide their own appeals".to_string());
+    }
+
+    match &appeal.target {
+        AppealTarget::Removal { discussion_id } => {
+            let trashed = try_get(&TRASH_STORAGE, *discussion_id).ok_or("Discussion is no longer in the trash")?;
+            if username == Some(trashed.deleted_by.as_str()) {
+                return Err("The moderator who removed the discussion cannot decide the appeal".to_string());
+            }
+            if !is_discussion_moderator(&trashed.discussion, username.unwrap_or_default()) {
+                return Err("Only moderators can decide this appeal".to_string());
+            }
+        }
+        AppealTarget::Restriction { restriction_id } => {
+            if !is_admin(&caller()) {
+                return Err("Only admins can decide appeals of restrictions".to_string());
+            }
+            let restriction = try_get(&RESTRICTIONS_STORAGE, *restriction_id).ok_or("Restriction not found")?;
+            let issued_by = restriction.warning_id
+                .and_then(|warning_id| try_get(&WARNINGS_STORAGE, warning_id))
+                .map(|warning| warning.issued_by);
+            if issued_by == Some(caller()) {
+                return Err("The moderator who issued the warning cannot decide the appeal".to_string());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Function to page through pending appeals the caller may decide. Group moderators pass their username,
+// admins may leave it out.
+#[ic_cdk::query]
+fn get_appeal_queue(username: Option<String>, cursor: Option<String>, limit: Option<u32>) -> Result<Page<Appeal>, String> {
+    if let Some(username) = &username {
+        authenticate(username)?;
+    }
+
+    paginate(&APPEALS_STORAGE, cursor, limit, |_, appeal| {
+        appeal.status == AppealStatus::Pending && check_appeal_decider(appeal, username.as_deref()).is_ok()
+    })
+}
+
+// Function to decide an appeal with a reason. Reversing restores the removed discussion or lifts the
+// restriction; upholding leaves the action in place.
+#[ic_cdk::update]
+fn decide_appeal(appeal_id: u64, reverse: bool, reason: String, username: Option<String>) -> Result<Appeal, String> {
+    let _meter = metrics::measure("decide_appeal");
+
+    ensure_writable()?;
+
+    if let Some(username) = &username {
+        authenticate(username)?;
+    }
+
+    let mut appeal = try_get(&APPEALS_STORAGE, appeal_id).ok_or("Appeal not found")?;
+
+    if appeal.status != AppealStatus::Pending {
+        return Err("Appeal has already been decided".to_string());
+    }
+
+    check_appeal_decider(&appeal, username.as_deref())?;
+    let reason = validate_reason(&reason)?;
+    let decided_by = username.unwrap_or_else(|| caller().to_text());
+
+    if reverse {
+        match &appeal.target {
+            AppealTarget::Removal { discussion_id } => {
+                if let Some(trashed) = try_get(&TRASH_STORAGE, *discussion_id) {
+                    untrash_discussion(&trashed.discussion, &decided_by);
+                }
+            }
+            AppealTarget::Restriction { restriction_id } => {
+                RESTRICTIONS_STORAGE.with(|storage| {
+                    let mut storage = storage.borrow_mut();
+                    if let Some(mut restriction) = storage.get(restriction_id).filter(decoded) {
+                        restriction.lifted = true;
+                        storage.insert(*restriction_id, restriction);
+                    }
+                });
+                queue_account_sync_for(&appeal.username);
+            }
+        }
+    }
+
+    appeal.status = if reverse { AppealStatus::Reversed } else { AppealStatus::Upheld };
+    appeal.decided_by = Some(decided_by);
+    appeal.decision_reason = Some(reason);
+    appeal.decided_at = Some(time());
+
+    APPEALS_STORAGE.with(|storage| storage.borrow_mut().insert(appeal_id, appeal.clone()));
+    log_account_action(&appeal.username, format!("Appeal {} {}", appeal_id, if reverse { "reversed the action" } else { "upheld the action" }));
+    notify(&appeal.username, NotificationKind::AppealDecided { appeal_id, status: appeal.status });
+
+    Ok(appeal)
+}
+
+// Function to get the appeals a user has filed, with their outcomes
+#[ic_cdk::query]
+fn get_my_appeals(username: String) -> Result<Vec<Appeal>, String> {
+    authenticate_identity(&username)?;
+
+    Ok(APPEALS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, appeal)| appeal).filter(|appeal| appeal.username == username).collect()
+    }))
+}
+
+// Function to get a user's warnings and restrictions; suspended users can still read theirs
+#[ic_cdk::query]
+fn get_my_warnings(username: String) -> Result<MyWarnings, String> {
+    authenticate_identity(&username)?;
+
+    let warnings = WARNINGS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, warning)| warning).filter(|warning| warning.username == username).collect()
+    });
+    let restrictions = RESTRICTIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, restriction)| restriction).filter(|restriction| restriction.username == username).collect()
+    });
+
+    Ok(MyWarnings {
+        warnings,
+        restrictions,
+        active_strikes: active_strikes(&username, time()),
+    })
+}
+
+// Helper function to check whether a feature flag is on
+fn is_feature_enabled(name: &str) -> bool {
+    let stored = FEATURE_FLAGS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).find(|(_, flag)| flag.name == name).map(|(_, flag)| flag.enabled)
+    });
+
+    stored.unwrap_or_else(|| FEATURE_FLAGS.iter().any(|(flag, enabled)| *flag == name && *enabled))
+}
+
+// Helper function for entrypoints behind a feature flag
+fn require_feature(name: &str) -> Result<(), String> {
+    if is_feature_enabled(name) {
+        Ok(())
+    } else {
+        Err(format!("Feature {} is disabled", name))
+    }
+}
+
+// Helper function to reject topics that are empty or too long
+fn validate_topic(topic: &str) -> Result<String, String> {
+    let topic = sanitize_user_text(topic)?;
+
+    if topic.trim().is_empty() {
+        return Err("Topic is required".to_string());
+    }
+
+    let max_length = settings().max_topic_length;
+    if topic.chars().count() > max_length as usize {
+        return Err(format!("Topic cannot exceed {} characters", max_length));
+    }
+
+    Ok(topic)
+}
+
+// Helper function to run user-supplied text through the sanitizer with the configured redirect endpoint
+fn sanitize_user_text(text: &str) -> Result<String, String> {
+    sanitize::sanitize_text(text, settings().link_redirect_prefix.as_deref())
+}
+
+// Helper function to turn the last key of a page into an opaque cursor
+fn encode_cursor(key: u64) -> String {
+    format!("c1{:016x}", key)
+}
+
+// Helper function to decode a cursor into the bound the next page starts after
+fn decode_cursor(cursor: Option<String>) -> Result<Bound<u64>, String> {
+    match cursor {
+        None => Ok(Bound::Unbounded),
+        Some(cursor) => cursor.strip_prefix("c1")
+            .filter(|hex| hex.len() == 16)
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+            .map(Bound::Excluded)
+            .ok_or_else(|| "Invalid cursor".to_string()),
+    }
+}
+
+// Helper function to read one page of a storage map in key order. Keyset cursors stay stable
+// under concurrent inserts and deletes because they resume after a key, not an offset.
+fn paginate<V: BoundedStorable>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+    cursor: Option<String>,
+    limit: Option<u32>,
+    filter: impl Fn(u64, &V) -> bool,
+) -> Result<Page<V>, String> {
+    let start = decode_cursor(cursor)?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+
+    let items: Vec<(u64, V)> = storage.with(|storage| {
+        storage.borrow().range((start, Bound::Unbounded))
+            .filter(decoded)
+            .filter(|(id, value)| filter(*id, value))
+            .take(limit)
+            .collect()
+    });
+
+    let next_cursor = if items.len() == limit { items.last().map(|(id, _)| encode_cursor(*id)) } else { None };
+
+    Ok(Page {
+        items: items.into_iter().map(|(_, value)| value).collect(),
+        next_cursor,
+    })
+}
+
+// Helper function to run a mutating call at most once per (caller, method, key). A retry within
+// the TTL gets the first successful response back; failed calls are not cached and may be retried.
+fn with_idempotency<T: candid::CandidType + for<'de> serde::Deserialize<'de>>(
+    method: &str,
+    idempotency_key: Option<String>,
+    call: impl FnOnce() -> Result<T, String>,
+) -> Result<T, String> {
+    let Some(key) = idempotency_key else {
+        return call();
+    };
+
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+        return Err(format!("Idempotency key must be 1 to {} bytes", MAX_IDEMPOTENCY_KEY_LENGTH));
+    }
+
+    // Every anonymous caller would share one set of keys and could replay each other's responses
+    let caller = caller();
+    if caller == Principal::anonymous() {
+        return Err("Idempotency keys need an authenticated caller".to_string());
+    }
+
+    let now = time();
+    let slot = idempotency_slot(&caller, method, &key);
+
+    let cached = try_get(&IDEMPOTENCY_STORAGE, slot)
+        .filter(|record| record.caller == caller && record.method == method && record.key == key && record.expires_at > now)
+        .map(|record| record.response);
+
+    if let Some(response) = cached {
+        return Decode!(&response, T).map_err(|e| format!("Cannot replay cached response: {}", e));
+    }
+
+    let value = call()?;
+
+    // Responses too large for a record are simply not cached
+    if let Ok(response) = Encode!(&value) {
+        let record = IdempotencyRecord {
+            id: slot,
+            caller,
+            method: method.to_string(),
+            key,
+            response,
+            expires_at: now + IDEMPOTENCY_TTL,
+        };
+        if Encode!(&record).is_ok_and(|bytes| bytes.len() <= IdempotencyRecord::MAX_SIZE as usize) {
+            IDEMPOTENCY_STORAGE.with(|storage| storage.borrow_mut().insert(record.id, record));
+        }
+    }
+
+    Ok(value)
+}
+
+// Helper function to derive where the idempotency record of (caller, method, key) is stored, so a retry
+// is a single lookup. Each part is length-prefixed, so different triples never hash the same input.
+fn idempotency_slot(caller: &Principal, method: &str, key: &str) -> u64 {
+    use sha3::{Digest, Sha3_256};
+
+    let mut hasher = Sha3_256::new();
+    for part in [caller.as_slice(), method.as_bytes(), key.as_bytes()] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    let digest = hasher.finalize();
+
+    u64::from_be_bytes(digest[..8].try_into().expect("Digest is 32 bytes"))
+}
+
+// Helper function to allocate the next unique ID
+fn next_id() -> u64 {
+    ID_COUNTER.with(|counter| {
+        let current_value = *counter.borrow().get();
+        counter.borrow_mut().set(current_value + 1)
+    }).expect("Cannot increment ID counter")
+}
+
+// Function to register a user
+#[ic_cdk::update]
+fn register_user(username: String, idempotency_key: Option<String>, solution: Option<PowSolution>, referral_code: Option<String>) -> Result<User, String> {
+    let _meter = metrics::measure("register_user");
+
+    ensure_writable()?;
+
+    with_idempotency("register_user", idempotency_key, || {
+        let difficulty = settings().registration_pow_difficulty;
+        let challenge_id = if difficulty > 0 {
+            Some(verify_pow_solution(&username, solution)?)
+        } else {
+            None
+        };
+
+        let referrer = referral_code.map(|code| check_referral_code(&code)).transpose()?;
+
+        let user = create_user(username, None)?;
+
+        if let Some(challenge_id) = challenge_id {
+            REGISTRATION_CHALLENGES_STORAGE.with(|storage| storage.borrow_mut().remove(&challenge_id));
+        }
+
+        if let Some(referrer) = referrer {
+            let referral = Referral {
+                id: next_id(),
+                referrer,
+                referee: user.username.clone(),
+                created_at: time(),
+                status: ReferralStatus::Pending,
+            };
+            REFERRALS_STORAGE.with(|storage| storage.borrow_mut().insert(referral.id, referral));
+        }
+
+        Ok(user)
+    })
+}
+
+// Function to get a proof-of-work puzzle to solve before calling register_user
+#[ic_cdk::update]
+async fn request_registration_challenge() -> Result<RegistrationChallenge, String> {
+    let _meter = metrics::measure("request_registration_challenge");
+
+    ensure_writable()?;
+
+    let difficulty = settings().registration_pow_difficulty;
+    if difficulty == 0 {
+        return Err("Registration does not require a challenge".to_string());
+    }
+
+    let (random_bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, message)| format!("Cannot generate challenge: {}", message))?;
+    let nonce: String = random_bytes.iter().take(16).map(|byte| format!("{:02x}", byte)).collect();
+
+    let now = time();
+    let issued_to = caller();
+
+    // Only the latest puzzle per caller can be used. Anonymous callers all share one principal, so their
+    // puzzles are told apart by ID alone and each stays usable until it expires.
+    let anonymous = issued_to == Principal::anonymous();
+    remove_where(&REGISTRATION_CHALLENGES_STORAGE, |challenge| {
+        (!anonymous && challenge.issued_to == issued_to) || challenge.expires_at <= now
+    });
+
+    let challenge = RegistrationChallenge {
+        id: next_id(),
+        nonce,
+        difficulty,
+        issued_to,
+        expires_at: now.saturating_add(REGISTRATION_CHALLENGE_TTL),
+    };
+
+    REGISTRATION_CHALLENGES_STORAGE.with(|storage| storage.borrow_mut().insert(challenge.id, challenge.clone()));
+
+    Ok(challenge)
+}
+
+// Helper function to check a proof-of-work solution for a username, returns the solved challenge's ID
+fn verify_pow_solution(username: &str, solution: Option<PowSolution>) -> Result<u64, String> {
+    use sha3::{Digest, Sha3_256};
+
+    let solution = solution.ok_or("Registration requires a solved challenge, call request_registration_challenge first")?;
+
+    let challenge = try_get(&REGISTRATION_CHALLENGES_STORAGE, solution.challenge_id)
+        .filter(|challenge| challenge.issued_to == caller() && challenge.expires_at > time())
+        .ok_or("Challenge not found or expired")?;
+
+    let digest = Sha3_256::digest(format!("{}:{}:{}", challenge.nonce, username, solution.counter).as_bytes());
+
+    let mut zero_bits = 0;
+    for byte in digest.iter() {
+        zero_bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+
+    if zero_bits < challenge.difficulty as u32 {
+        return Err("Challenge solution is not valid".to_string());
+    }
+
+    Ok(challenge.id)
+}
+
+// Helper function shared by username and Sign-In-With-Ethereum registration
+fn create_user(username: String, eth_address: Option<String>) -> Result<User, String> {
+    if username.is_empty() {
+        return Err("Username is required".to_string());
+    }
+
+    if is_user_registered(&username) || is_tombstoned(&username) {
+        return Err("Username already exists".to_string());
+    }
+
+    let principal = caller();
+    if linked_username(&principal).is_some() {
+        return Err("Caller is already linked to another account".to_string());
+    }
+
+    check_registration_quota(&principal)?;
+
+    let id = next_id();
+
+    let new_user = User {
+        username: username.clone(),
+        id,
+        created_at: time(),
+        eth_address,
+        trust_level: TrustLevel::New,
+    };
+
+    USERS_STORAGE.with(|storage| storage.borrow_mut().insert(id, new_user.clone()));
+
+    // Anonymous callers get an unprotected account, as before principal linking existed
+    if principal != Principal::anonymous() {
+        insert_principal_link(principal, username);
+    }
+
+    let event_id = next_id();
+    REGISTRATION_EVENTS_STORAGE.with(|storage| storage.borrow_mut().insert(event_id, RegistrationEvent { principal, at: time() }));
+
+    Ok(new_user)
+}
+
+// Helper function to enforce the per-principal and global registration quotas over a sliding window
+fn check_registration_quota(principal: &Principal) -> Result<(), String> {
+    let settings = settings();
+    let now = time();
+    let window = settings.registration_window_seconds.saturating_mul(1_000_000_000);
+    let window_start = now.saturating_sub(window);
+
+    // Events older than the window no longer count
+    remove_where(&REGISTRATION_EVENTS_STORAGE, |event| event.at <= window_start);
+
+    let events: Vec<RegistrationEvent> = REGISTRATION_EVENTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, event)| event).collect()
+    });
+
+    let throttled = |events: Vec<&RegistrationEvent>, limit: u32, scope: &str| -> Result<(), String> {
+        if limit == 0 || events.len() < limit as usize {
+            return Ok(());
+        }
+        // The window frees a slot once its oldest event expires
+        let oldest = events.iter().map(|event| event.at).min().unwrap_or(now);
+        let retry_after = (oldest + window).saturating_sub(now) / 1_000_000_000 + 1;
+        Err(format!("Throttled: {} registration limit of {} per {} seconds reached, retry after {} seconds",
+            scope, limit, settings.registration_window_seconds, retry_after))
+    };
+
+    // Anonymous callers all share one principal, a quota on it would let one client lock everyone else out.
+    // They are left to the global quota and the proof of work.
+    if *principal != Principal::anonymous() {
+        throttled(events.iter().filter(|event| event.principal == *principal).collect(), settings.max_registrations_per_principal, "per-principal")?;
+    }
+    throttled(events.iter().collect(), settings.max_registrations_global, "global")
+}
+
+// Helper function to check if a user is registered
+fn is_user_registered(username: &String) -> bool {
+    USERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).any(|(_, user)| user.username == *username)
+    })
+}
+
+// Helper function to collect every registered username, for checks over many records at once
+fn registered_usernames() -> BTreeSet<String> {
+    USERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, user)| user.username).collect()
+    })
+}
+
+// Helper function to check if a username belonged to a merged account
+fn is_tombstoned(username: &str) -> bool {
+    TOMBSTONES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).any(|(_, tombstone)| tombstone.username == username)
+    })
+}
+
+// Helper function to look up a user by username
+fn find_user(username: &str) -> Option<User> {
+    USERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).find(|(_, user)| user.username == username).map(|(_, user)| user)
+    })
+}
+
+// Helper function to get a user's privacy settings
+fn privacy_settings(user_id: u64) -> PrivacySettings {
+    try_get(&PRIVACY_SETTINGS_STORAGE, user_id).unwrap_or_default()
+}
+
+// Function to change the caller's privacy settings
+#[ic_cdk::update]
+fn update_privacy_settings(settings: PrivacySettings, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("update_privacy_settings");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    PRIVACY_SETTINGS_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, settings));
+
+    Ok("Privacy settings updated".to_string())
+}
+
+// Helper function to get a user's content preferences, defaults apply until they change them
+fn content_preferences(user_id: u64) -> ContentPreferences {
+    try_get(&CONTENT_PREFERENCES_STORAGE, user_id).unwrap_or_default()
+}
+
+// Function to change which content the caller's listings include
+#[ic_cdk::update]
+fn update_content_preferences(preferences: ContentPreferences, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("update_content_preferences");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    if preferences.languages.len() > MAX_PREFERRED_LANGUAGES {
+        return Err(format!("At most {} preferred languages are allowed", MAX_PREFERRED_LANGUAGES));
+    }
+    if let Some(code) = preferences.languages.iter().find(|code| !language::is_language_code(code)) {
+        return Err(format!("{} is not an ISO 639-1 language code", code));
+    }
+
+    CONTENT_PREFERENCES_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, preferences));
+
+    Ok("Content preferences updated".to_string())
+}
+
+// Function to get the caller's content preferences
+#[ic_cdk::query]
+fn get_content_preferences(username: String) -> Result<ContentPreferences, String> {
+    authenticate(&username)?;
+
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    Ok(content_preferences(user.id))
+}
+
+// Helper function to get the preferences listings apply for a viewer; anonymous viewers get the defaults
+fn viewer_preferences(username: &str) -> ContentPreferences {
+    find_user(username).map(|user| content_preferences(user.id)).unwrap_or_default()
+}
+
+// Helper function for listings that take no username: the viewer is the account linked to the caller
+fn caller_preferences() -> ContentPreferences {
+    linked_username(&caller()).map(|username| viewer_preferences(&username)).unwrap_or_default()
+}
+
+// Helper function to check if a listing should include a discussion for these preferences.
+// Discussions without a known language are kept, the viewer's filter cannot rule them out.
+fn matches_preferences(discussion: &Discussion, preferences: &ContentPreferences) -> bool {
+    (preferences.show_sensitive || !discussion.sensitive)
+        && (preferences.languages.is_empty()
+            || discussion.language.as_ref().is_none_or(|language| preferences.languages.contains(language)))
+}
+
+// Helper function to check that the caller may act as the given user.
+// Accounts without any linked principal predate linking and stay open to every caller.
+fn authenticate(username: &String) -> Result<(), String> {
+    authenticate_identity(username)?;
+    permissions::check_not_suspended(username)
+}
+
+// Helper function for endpoints that hand out control of an account or its secrets. Unlike authenticate,
+// accounts without a linked principal are refused; their owners claim them first, see claim_account.
+fn authenticate_owner(username: &String) -> Result<(), String> {
+    authenticate(username)?;
+
+    if linked_principals(username).is_empty() {
+        return Err("Account has no linked principal, ask an admin for a claim code".to_string());
+    }
+
+    Ok(())
+}
+
+// Helper function to check that the caller may act as the user, whether or not the account is suspended
+fn authenticate_identity(username: &String) -> Result<(), String> {
+    if !is_user_registered(username) {
+        return Err(ErrorCode::UserNotRegistered.into());
+    }
+
+    let principals = linked_principals(username);
+    if !principals.is_empty() && !principals.contains(&caller()) {
+        return Err(ErrorCode::CallerNotLinked.into());
+    }
+
+    Ok(())
+}
+
+// Helper function for read-only endpoints: unauthenticated viewers only see public content
+fn resolve_viewer(username: String) -> String {
+    if authenticate_identity(&username).is_ok() { username } else { String::new() }
+}
+
+// Helper function to hash a principal into the first half of LINKS_BY_PRINCIPAL keys
+fn principal_key(principal: &Principal) -> u64 {
+    search::token_key(&principal.to_text())
+}
+
+// Helper function to index a stored principal link under its principal and its user
+fn index_principal_link(link: &PrincipalLink) {
+    LINKS_BY_PRINCIPAL.with(|index| index.borrow_mut().insert((principal_key(&link.principal), link.id), 0));
+    LINKS_BY_USER.with(|index| index.borrow_mut().insert((username_key(&link.username), link.id), 0));
+}
+
+// Helper function to read the links an index lists under one key. Entries whose link is gone or no longer
+// decodes are skipped; the callers check the rest, and rebuild_principal_link_indexes drops them.
+fn indexed_links(index: &StableBTreeMap<(u64, u64), u8, Memory>, key: u64) -> Vec<PrincipalLink> {
+    let ids: Vec<u64> = index.range((key, 0)..=(key, u64::MAX)).map(|((_, id), _)| id).collect();
+
+    ids.into_iter().filter_map(|id| try_get(&PRINCIPAL_LINKS_STORAGE, id)).collect()
+}
+
+// Helper function to rebuild both principal link indexes from the links
+fn rebuild_principal_link_indexes() {
+    for index in [&LINKS_BY_PRINCIPAL, &LINKS_BY_USER] {
+        index.with(|index| {
+            let mut index = index.borrow_mut();
+            let keys: Vec<(u64, u64)> = index.iter().map(|(key, _)| key).collect();
+            for key in keys {
+                index.remove(&key);
+            }
+        });
+    }
+
+    let links: Vec<PrincipalLink> = PRINCIPAL_LINKS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, link)| link).collect()
+    });
+    for link in &links {
+        index_principal_link(link);
+    }
+}
+
+// Helper function to get every principal linked to a user
+fn linked_principals(username: &str) -> Vec<Principal> {
+    LINKS_BY_USER.with(|index| indexed_links(&index.borrow(), username_key(username))).into_iter()
+        .filter(|link| link.username == username)
+        .map(|link| link.principal)
+        .collect()
+}
+
+// Helper function to find the account a principal is linked to
+fn linked_username(principal: &Principal) -> Option<String> {
+    LINKS_BY_PRINCIPAL.with(|index| indexed_links(&index.borrow(), principal_key(principal))).into_iter()
+        .find(|link| link.principal == *principal)
+        .map(|link| link.username)
+}
+
+// Helper function to record a principal link
+fn insert_principal_link(principal: Principal, username: String) {
+    let id = next_id();
+
+    let link = PrincipalLink {
+        id,
+        principal,
+        username: username.clone(),
+        linked_at: time(),
+    };
+
+    index_principal_link(&link);
+    PRINCIPAL_LINKS_STORAGE.with(|storage| storage.borrow_mut().insert(id, link));
+    queue_account_sync_for(&username);
+}
+
+// Function to issue a one-time code that lets another principal join the account
+#[ic_cdk::update]
+async fn create_link_challenge(username: String) -> Result<String, String> {
+    let _meter = metrics::measure("create_link_challenge");
+
+    ensure_writable()?;
+
+    authenticate_owner(&username)?;
+
+    let (random_bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, message)| format!("Cannot generate link code: {}", message))?;
+    let code: String = random_bytes.iter().take(16).map(|byte| format!("{:02x}", byte)).collect();
+
+    // The account may have been deleted while waiting for randomness
+    authenticate_owner(&username)?;
+
+    let id = next_id();
+
+    let challenge = LinkChallenge {
+        id,
+        username,
+        code: code.clone(),
+        issued_by: caller(),
+        expires_at: time().saturating_add(LINK_CHALLENGE_TTL),
+        claim: false,
+    };
+
+    LINK_CHALLENGES_STORAGE.with(|storage| storage.borrow_mut().insert(id, challenge));
+
+    Ok(code)
+}
+
+// Function for a new principal to confirm a link challenge and join the account
+#[ic_cdk::update]
+fn link_principal(username: String, code: String) -> Result<String, String> {
+    let _meter = metrics::measure("link_principal");
+
+    ensure_writable()?;
+
+    let principal = caller();
+
+    if principal == Principal::anonymous() {
+        return Err("Anonymous principals cannot be linked".to_string());
+    }
+
+    if linked_username(&principal).is_some() {
+        return Err("Caller is already linked to an account".to_string());
+    }
+
+    let challenge = LINK_CHALLENGES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .find(|(_, challenge)| !challenge.claim && challenge.username == username && challenge.code == code)
+            .map(|(_, challenge)| challenge)
+    }).ok_or("Link challenge not found")?;
+
+    LINK_CHALLENGES_STORAGE.with(|storage| storage.borrow_mut().remove(&challenge.id));
+
+    if challenge.expires_at <= time() {
+        return Err("Link challenge has expired".to_string());
+    }
+
+    if !is_user_registered(&username) {
+        return Err(ErrorCode::UserNotRegistered.into());
+    }
+
+    // Challenges issued before linking required an owner could have been created by anyone
+    if linked_principals(&username).is_empty() {
+        return Err("Account has no linked principal, ask an admin for a claim code".to_string());
+    }
+
+    insert_principal_link(principal, username.clone());
+    log_account_action(&username, format!("Linked principal {}", principal));
+
+    Ok("Principal linked".to_string())
+}
+
+// Function to issue a one-time code that lets the owner of an account without any linked principal claim
+// it (admins only). The admin verifies ownership out of band and hands the code over.
+#[ic_cdk::update(guard = "caller_is_admin")]
+async fn issue_claim_code(username: String) -> Result<String, String> {
+    let _meter = metrics::measure("issue_claim_code");
+
+    ensure_writable()?;
+
+    find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    if !linked_principals(&username).is_empty() {
+        return Err("Account already has a linked principal".to_string());
+    }
+
+    let (random_bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, message)| format!("Cannot generate claim code: {}", message))?;
+    let code: String = random_bytes.iter().take(16).map(|byte| format!("{:02x}", byte)).collect();
+
+    // The account may have been deleted or claimed while waiting for randomness
+    find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+    if !linked_principals(&username).is_empty() {
+        return Err("Account already has a linked principal".to_string());
+    }
+
+    // Only the latest code per account can be used
+    remove_where(&LINK_CHALLENGES_STORAGE, |challenge| challenge.claim && challenge.username == username);
+
+    let id = next_id();
+
+    let challenge = LinkChallenge {
+        id,
+        username: username.clone(),
+        code: code.clone(),
+        issued_by: caller(),
+        expires_at: time().saturating_add(CLAIM_CODE_TTL),
+        claim: true,
+    };
+
+    LINK_CHALLENGES_STORAGE.with(|storage| storage.borrow_mut().insert(id, challenge));
+    log_account_action(&username, "Issued a claim code".to_string());
+
+    Ok(code)
+}
+
+// Function for the owner of an account without any linked principal to link the caller with a claim code
+#[ic_cdk::update]
+fn claim_account(username: String, code: String) -> Result<String, String> {
+    let _meter = metrics::measure("claim_account");
+
+    ensure_writable()?;
+
+    let principal = caller();
+
+    if principal == Principal::anonymous() {
+        return Err("Anonymous principals cannot be linked".to_string());
+    }
+
+    if linked_username(&principal).is_some() {
+        return Err("Caller is already linked to an account".to_string());
+    }
+
+    let challenge = LINK_CHALLENGES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .find(|(_, challenge)| challenge.claim && challenge.username == username && challenge.code == code)
+            .map(|(_, challenge)| challenge)
+    }).ok_or("Claim code not found")?;
+
+    LINK_CHALLENGES_STORAGE.with(|storage| storage.borrow_mut().remove(&challenge.id));
+
+    if challenge.expires_at <= time() {
+        return Err("Claim code has expired".to_string());
+    }
+
+    if !is_user_registered(&username) {
+        return Err(ErrorCode::UserNotRegistered.into());
+    }
+
+    if !linked_principals(&username).is_empty() {
+        return Err("Account already has a linked principal".to_string());
+    }
+
+    insert_principal_link(principal, username.clone());
+    log_account_action(&username, format!("Claimed by principal {}", principal));
+
+    Ok("Account claimed".to_string())
+}
+
+// Function to detach a principal from an account (the last principal cannot be removed)
+#[ic_cdk::update]
+fn unlink_principal(principal: Principal, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("unlink_principal");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let principals = linked_principals(&username);
+
+    if !principals.contains(&principal) {
+        return Err("Principal is not linked to this account".to_string());
+    }
+
+    if principals.len() == 1 {
+        return Err("Cannot unlink the last principal of an account".to_string());
+    }
+
+    remove_principal_links(|link| link.principal == principal);
+    log_account_action(&username, format!("Unlinked principal {}", principal));
+
+    Ok("Principal unlinked".to_string())
+}
+
+// Function to start a Sign-In-With-Ethereum login, returns the message the wallet must sign
+#[ic_cdk::update]
+async fn siwe_prepare_login(address: String) -> Result<String, String> {
+    let _meter = metrics::measure("siwe_prepare_login");
+
+    ensure_writable()?;
+
+    require_feature("siwe_login")?;
+
+    let address = normalize_eth_address(&address)?;
+
+    let (random_bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, message)| format!("Cannot generate nonce: {}", message))?;
+    let nonce: String = random_bytes.iter().take(16).map(|byte| format!("{:02x}", byte)).collect();
+
+    let now = time();
+    let message = format!(
+        "{} wants you to sign in with your Ethereum account:\n{}\n\n{}\n\nVersion: 1\nChain ID: 1\nNonce: {}\nIssued At: {}",
+        SIWE_DOMAIN, address, SIWE_STATEMENT, nonce, now
+    );
+
+    // Only the caller's latest message per address can be used, other callers' messages are left alone.
+    // Anonymous callers all share one principal, so each of their messages stays usable until it expires.
+    let issued_to = caller();
+    let anonymous = issued_to == Principal::anonymous();
+    remove_where(&SIWE_CHALLENGES_STORAGE, |challenge| {
+        (!anonymous && challenge.issued_to == issued_to && challenge.address == address) || challenge.expires_at <= now
+    });
+
+    let id = next_id();
+
+    let challenge = SiweChallenge {
+        id,
+        address,
+        message: message.clone(),
+        expires_at: now.saturating_add(SIWE_CHALLENGE_TTL),
+        issued_to,
+    };
+
+    SIWE_CHALLENGES_STORAGE.with(|storage| storage.borrow_mut().insert(id, challenge));
+
+    Ok(message)
+}
+
+// Function to finish a Sign-In-With-Ethereum login. The caller is linked to the account that owns the
+// address; if there is none, the address is attached to `username` (creating that user if needed).
+#[ic_cdk::update]
+fn siwe_login(address: String, signature: String, username: String) -> Result<User, String> {
+    let _meter = metrics::measure("siwe_login");
+
+    ensure_writable()?;
+
+    require_feature("siwe_login")?;
+
+    let address = normalize_eth_address(&address)?;
+
+    let now = time();
+    let challenges: Vec<SiweChallenge> = SIWE_CHALLENGES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .map(|(_, challenge)| challenge)
+            .filter(|challenge| challenge.address == address && challenge.issued_to == caller() && challenge.expires_at > now)
+            .collect()
+    });
+
+    if challenges.is_empty() {
+        return Err("No pending sign-in for this address, or it has expired".to_string());
+    }
+
+    // An anonymous caller may hold several messages, the signature picks the one the wallet signed
+    let mut signed = None;
+    for challenge in challenges {
+        if recover_eth_address(&challenge.message, &signature)? == address {
+            signed = Some(challenge);
+            break;
+        }
+    }
+    let challenge = signed.ok_or("Signature does not match the address")?;
+
+    SIWE_CHALLENGES_STORAGE.with(|storage| storage.borrow_mut().remove(&challenge.id));
+
+    let principal = caller();
+
+    let existing = USERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).find(|(_, user)| user.eth_address.as_ref() == Some(&address)).map(|(_, user)| user)
+    });
+
+    if let Some(user) = existing {
+        match linked_username(&principal) {
+            Some(linked) if linked == user.username => {}
+            Some(_) => return Err("Caller is already linked to another account".to_string()),
+            None if principal != Principal::anonymous() => insert_principal_link(principal, user.username.clone()),
+            None => {}
+        }
+        return Ok(user);
+    }
+
+    if !is_user_registered(&username) {
+        return create_user(username, Some(address));
+    }
+
+    // Signing in with the address would link the caller, so it is owner-only like create_link_challenge
+    authenticate_owner(&username)?;
+
+    let mut user = USERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).find(|(_, user)| user.username == username).map(|(_, user)| user)
+    }).ok_or(ErrorCode::UserNotFound)?;
+
+    if user.eth_address.is_some() {
+        return Err("Account is already tied to another Ethereum address".to_string());
+    }
+
+    user.eth_address = Some(address);
+    USERS_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, user.clone()));
+    queue_account_sync(user.id);
+
+    Ok(user)
+}
+
+// Helper function to validate and lowercase an Ethereum address
+fn normalize_eth_address(address: &str) -> Result<String, String> {
+    let hex = address.strip_prefix("0x").ok_or("Ethereum address must start with 0x")?;
+
+    if hex.len() != 40 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("Invalid Ethereum address".to_string());
+    }
+
+    Ok(format!("0x{}", hex.to_lowercase()))
+}
+
+// Helper function to recover the signer of an EIP-191 personal_sign signature
+fn recover_eth_address(message: &str, signature: &str) -> Result<String, String> {
+    use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+    use sha3::{Digest, Keccak256};
+
+    let bytes = decode_hex(signature.strip_prefix("0x").unwrap_or(signature))?;
+    if bytes.len() != 65 {
+        return Err("Signature must be 65 bytes".to_string());
+    }
+
+    let signature = Signature::from_slice(&bytes[..64]).map_err(|_| "Invalid signature".to_string())?;
+    let v = if bytes[64] >= 27 { bytes[64] - 27 } else { bytes[64] };
+    let recovery_id = RecoveryId::from_byte(v).ok_or("Invalid signature recovery id")?;
+
+    let prefixed = format!("\x19Ethereum Signed Message:\n{}{}", message.len(), message);
+    let digest = Keccak256::digest(prefixed.as_bytes());
+
+    let key = VerifyingKey::recover_from_prehash(&digest, &signature, recovery_id)
+        .map_err(|_| "Cannot recover signer".to_string())?;
+
+    let public_key = key.to_encoded_point(false);
+    let hash = Keccak256::digest(&public_key.as_bytes()[1..]);
+
+    Ok(format!("0x{}", hash[12..].iter().map(|byte| format!("{:02x}", byte)).collect::<String>()))
+}
+
+// Helper function to decode a hex string into bytes
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err("Invalid hex string".to_string());
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| "Invalid hex string".to_string()))
+        .collect()
+}
+
+// Helper function to drop every principal link matching a predicate
+fn remove_principal_links(predicate: impl Fn(&PrincipalLink) -> bool) {
+    let usernames: BTreeSet<String> = PRINCIPAL_LINKS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).filter(|(_, link)| predicate(link)).map(|(_, link)| link.username).collect()
+    });
+
+    remove_where(&PRINCIPAL_LINKS_STORAGE, predicate);
+
+    for username in usernames {
+        queue_account_sync_for(&username);
+    }
+}
+
+// Helper function to append an entry to an account's audit log
+fn log_account_action(username: &str, action: String) {
+    let id = next_id();
+
+    let entry = AccountAuditEntry {
+        id,
+        username: username.to_string(),
+        actor: caller(),
+        action,
+        created_at: time(),
+    };
+
+    ACCOUNT_AUDIT_LOG.with(|log| log.borrow_mut().insert(id, entry));
+}
+
+// Function to designate a recovery principal and/or recovery code hash for the account
+#[ic_cdk::update]
+fn set_recovery(recovery_principal: Option<Principal>, recovery_code_hash: Option<Vec<u8>>, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("set_recovery");
+
+    ensure_writable()?;
+
+    authenticate_owner(&username)?;
+
+    if recovery_code_hash.as_ref().is_some_and(|hash| hash.len() != 32) {
+        return Err("Recovery code hash must be a 32-byte SHA3-256 digest".to_string());
+    }
+
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    // Attempt counters and a lockout carry over
+    let config = RecoveryConfig {
+        recovery_principal,
+        recovery_code_hash,
+        ..try_get(&RECOVERY_CONFIGS_STORAGE, user.id).unwrap_or_default()
+    };
+
+    RECOVERY_CONFIGS_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, config));
+    log_account_action(&username, "Updated recovery settings".to_string());
+
+    Ok("Recovery settings updated".to_string())
+}
+
+// Helper function to count a recovery attempt by the caller, refusing it once the caller used up
+// MAX_RECOVERY_ATTEMPTS in the current window
+fn count_caller_recovery_attempt(now: u64) -> Result<(), String> {
+    RECOVERY_ATTEMPTS.with(|attempts| {
+        let mut attempts = attempts.borrow_mut();
+        attempts.retain(|_, (window_start, _)| now.saturating_sub(*window_start) < RECOVERY_ATTEMPT_WINDOW);
+
+        let (_, count) = attempts.entry(caller()).or_insert((now, 0));
+        if *count >= MAX_RECOVERY_ATTEMPTS {
+            return Err("Too many recovery attempts, try again later".to_string());
+        }
+        *count += 1;
+        Ok(())
+    })
+}
+
+// Function to start rebinding an account to a new principal, called by the recovery principal
+// or by anyone holding the recovery code. Attempts are limited per account and per caller, and
+// RECOVERY_LOCKOUT_FAILURES failures in a row lock the account's recovery for RECOVERY_LOCKOUT.
+#[ic_cdk::update]
+fn initiate_recovery(username: String, new_principal: Principal, recovery_code: Option<String>) -> Result<PendingRecovery, String> {
+    let _meter = metrics::measure("initiate_recovery");
+
+    ensure_writable()?;
+
+    use sha3::{Digest, Sha3_256};
+
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    let mut config = try_get(&RECOVERY_CONFIGS_STORAGE, user.id)
+        .ok_or("Account has no recovery configured")?;
+
+    if caller() == Principal::anonymous() {
+        return Err("Recovery needs a signed-in caller".to_string());
+    }
+
+    let now = time();
+
+    if config.locked_until > now {
+        return Err(format!("Recovery of this account is locked for another {} hours", (config.locked_until - now).div_ceil(3_600_000_000_000)));
+    }
+
+    if now.saturating_sub(config.attempt_window_start) >= RECOVERY_ATTEMPT_WINDOW {
+        config.attempt_window_start = now;
+        config.attempts_in_window = 0;
+    }
+    if config.attempts_in_window >= MAX_RECOVERY_ATTEMPTS {
+        return Err("Too many recovery attempts on this account, try again later".to_string());
+    }
+
+    count_caller_recovery_attempt(now)?;
+    config.attempts_in_window += 1;
+
+    let by_principal = config.recovery_principal == Some(caller());
+    let by_code = match (&recovery_code, &config.recovery_code_hash) {
+        (Some(code), Some(hash)) => Sha3_256::digest(code.as_bytes()).as_slice() == hash.as_slice(),
+        _ => false,
+    };
+
+    if !by_principal && !by_code {
+        config.failed_attempts += 1;
+        // Only the lockout is logged, failed guesses would flood the owner's audit log
+        if config.failed_attempts >= RECOVERY_LOCKOUT_FAILURES {
+            config.failed_attempts = 0;
+            config.locked_until = now.saturating_add(RECOVERY_LOCKOUT);
+            log_account_action(&username, format!("Locked recovery after {} failed attempts", RECOVERY_LOCKOUT_FAILURES));
+        }
+        RECOVERY_CONFIGS_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, config));
+        return Err("Not authorized to recover this account".to_string());
+    }
+
+    config.failed_attempts = 0;
+    RECOVERY_CONFIGS_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, config));
+
+    if new_principal == Principal::anonymous() {
+        return Err("Anonymous principals cannot be linked".to_string());
+    }
+
+    if linked_username(&new_principal).is_some_and(|linked| linked != username) {
+        return Err("New principal is already linked to another account".to_string());
+    }
+
+    let pending = PendingRecovery {
+        username: username.clone(),
+        new_principal,
+        initiated_by: caller(),
+        initiated_at: now,
+        executable_at: now.saturating_add(settings().recovery_delay_seconds.saturating_mul(1_000_000_000)),
+    };
+
+    PENDING_RECOVERIES_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, pending.clone()));
+    log_account_action(&username, format!("Initiated recovery to principal {}", new_principal));
+
+    Ok(pending)
+}
+
+// Function for the current owner to cancel a pending recovery during the time lock
+#[ic_cdk::update]
+fn cancel_recovery(username: String) -> Result<String, String> {
+    let _meter = metrics::measure("cancel_recovery");
+
+    ensure_writable()?;
+
+    authenticate_owner(&username)?;
+
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    PENDING_RECOVERIES_STORAGE.with(|storage| storage.borrow_mut().remove(&user.id))
+        .ok_or("No pending recovery")?;
+    log_account_action(&username, "Cancelled recovery".to_string());
+
+    Ok("Recovery cancelled".to_string())
+}
+
+// Function to complete a recovery once its time lock has passed, replacing every linked principal
+#[ic_cdk::update]
+fn complete_recovery(username: String) -> Result<String, String> {
+    let _meter = metrics::measure("complete_recovery");
+
+    ensure_writable()?;
+
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    let pending = try_get(&PENDING_RECOVERIES_STORAGE, user.id)
+        .ok_or("No pending recovery")?;
+
+    if caller() != pending.initiated_by && caller() != pending.new_principal {
+        return Err("Only the recovery initiator or the new principal can complete the recovery".to_string());
+    }
+
+    if time() < pending.executable_at {
+        return Err(format!("Recovery can be completed after {}", pending.executable_at));
+    }
+
+    if linked_username(&pending.new_principal).is_some_and(|linked| linked != username) {
+        return Err("New principal is already linked to another account".to_string());
+    }
+
+    PENDING_RECOVERIES_STORAGE.with(|storage| storage.borrow_mut().remove(&user.id));
+    remove_principal_links(|link| link.username == username);
+    insert_principal_link(pending.new_principal, username.clone());
+    log_account_action(&username, format!("Completed recovery to principal {}", pending.new_principal));
+
+    Ok("Account recovered".to_string())
+}
+
+// Function to create a new discussion with user validation
+#[ic_cdk::update]
+fn create_discussion(topic: String, username: String, idempotency_key: Option<String>) -> Result<Discussion, String> {
+    let _meter = metrics::measure("create_discussion");
+
+    ensure_writable()?;
+
+    if has_shards() {
+        return Err("Forum discussions are stored on shards, use create_sharded_discussion".to_string());
+    }
+
+    with_idempotency("create_discussion", idempotency_key, || {
+        insert_discussion(topic, username, None)
+    })
+}
+
+// Helper function shared by the forum-wide and per-group discussion entrypoints
+fn insert_discussion(topic: String, username: String, group_id: Option<u64>) -> Result<Discussion, String> {
+    let discussion = new_discussion(topic, username, group_id)?;
+
+    publish_discussion(discussion)
+}
+
+// Helper function to store a validated discussion and run what follows a new post
+fn publish_discussion(discussion: Discussion) -> Result<Discussion, String> {
+    store_discussion(&discussion);
+    track_activity(&discussion.created_by, discussion.created_at, |activity| activity.discussions_created += 1);
+    if is_announced(&discussion) {
+        outbox::publish(EventKind::DiscussionCreated { discussion_id: discussion.id });
+    }
+    notify_keyword_matches(&discussion);
+    credit_referral(&discussion.created_by);
+    advance_onboarding(&discussion.created_by, OnboardingStep::StartFirstDiscussion);
+
+    Ok(discussion)
+}
+
+// Helper function to validate a new discussion and assign its ID, without storing it
+fn new_discussion(topic: String, username: String, group_id: Option<u64>) -> Result<Discussion, String> {
+    let topic = validate_topic(&topic)?;
+
+    // Validate if user is registered
+    authenticate(&username)?;
+
+    check_terms_accepted(&username)?;
+
+    permissions::check_permission(&username, Action::CreateDiscussion)?;
+    permissions::check_cooldown(&username)?;
+    permissions::check_permission(&username, Action::PostText(&topic))?;
+
+    let settings = settings();
+    if settings.max_discussions_per_window > 0 {
+        let window_start = time().saturating_sub(settings.rate_limit_window_seconds.saturating_mul(1_000_000_000));
+        let recent = discussions_started_since(&username, window_start).len();
+
+        if recent >= settings.max_discussions_per_window as usize {
+            return Err("Too many discussions started recently, try again later".to_string());
+        }
+    }
+
+    check_storage_quota(&username, topic.len() as u64)?;
+
+    Ok(discussion_record(topic, username, group_id))
+}
+
+// Helper function to build a new forum discussion with a fresh ID from an already validated topic
+fn discussion_record(topic: String, username: String, group_id: Option<u64>) -> Discussion {
+    let language = language::detect_language(&topic).map(str::to_string);
+
+    Discussion {
+        id: next_id(),
+        topic,
+        created_by: username,
+        created_at: time(),
+        upvotes: 0,
+        downvotes: 0,
+        visibility: Visibility::Public,
+        group_id,
+        version: 0,
+        voting_ends_at: None,
+        results_final: false,
+        blind: false,
+        reveal_ends_at: None,
+        requires_personhood: false,
+        views: 0,
+        attachments: Vec::new(),
+        sensitive: false,
+        content_warning: None,
+        language,
+        wilson_score: 0.0,
+        controversy_score: 0.0,
+        participation: ParticipationLimits::default(),
+        wiki: false,
+        forked_from: None,
+        quote: None,
+        locked: false,
+        last_activity_at: time(),
+    }
+}
+
+// Helper function to store a new discussion and index its topic
+fn store_discussion(discussion: &Discussion) {
+    save_discussion(discussion.id, discussion.clone());
+    index_topic(discussion);
+}
+
+// Helper function to find the first https link in a text
+fn extract_first_url(text: &str) -> Option<String> {
+    let start = text.find("https://")?;
+    let url: String = text[start..].chars()
+        .take_while(|c| !c.is_whitespace() && !matches!(c, '<' | '>' | '"' | '\''))
+        .collect();
+    let url = url.trim_end_matches(['.', ',', ')', ';', '!', '?']).to_string();
+
+    (url.len() > "https://".len() && url.len() <= MAX_PREVIEW_URL_LENGTH).then_some(url)
+}
+
+// Helper function to get the link to preview for a text, looking through the redirect endpoint
+fn preview_url(text: &str) -> Option<String> {
+    let url = extract_first_url(text)?;
+
+    match settings().link_redirect_prefix {
+        Some(prefix) => match sanitize::unwrap_redirect(&url, &prefix) {
+            Some(target) => target.starts_with("https://").then_some(target),
+            None => Some(url),
+        },
+        None => Some(url),
+    }
+}
+
+// Helper function to (re)queue the preview of a discussion's first link, dropping a stale preview
+fn queue_link_preview(discussion: &Discussion) {
+    let url = preview_url(&discussion.topic);
+
+    let current = try_get(&LINK_PREVIEWS_STORAGE, discussion.id);
+    if current.is_some_and(|preview| Some(&preview.url) == url.as_ref()) {
+        return;
+    }
+
+    LINK_PREVIEWS_STORAGE.with(|storage| storage.borrow_mut().remove(&discussion.id));
+    LINK_PREVIEW_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        match url {
+            Some(url) => queue.insert(discussion.id, LinkPreviewRequest { url }),
+            None => queue.remove(&discussion.id),
+        }
+    });
+}
+
+// Helper function called from the background tick: starts one preview fetch at a time, off the update path
+fn process_link_preview_queue() {
+    if LINK_PREVIEW_IN_FLIGHT.with(|in_flight| *in_flight.borrow()) || !is_feature_enabled("link_previews") {
+        return;
+    }
+
+    let Some((discussion_id, request)) = LINK_PREVIEW_QUEUE.with(|queue| queue.borrow().iter().find(decoded)) else {
+        return;
+    };
+
+    LINK_PREVIEW_QUEUE.with(|queue| queue.borrow_mut().remove(&discussion_id));
+    LINK_PREVIEW_IN_FLIGHT.with(|in_flight| *in_flight.borrow_mut() = true);
+
+    ic_cdk::spawn(async move {
+        let preview = fetch_link_preview(&request.url).await;
+
+        // The discussion may have been edited or deleted while the outcall was running
+        let still_wanted = load_discussion(discussion_id)
+            .is_some_and(|discussion| preview_url(&discussion.topic).as_ref() == Some(&request.url));
+
+        match preview {
+            Ok(preview) if still_wanted => {
+                LINK_PREVIEWS_STORAGE.with(|storage| storage.borrow_mut().insert(discussion_id, preview));
+            }
+            Ok(_) => {}
+            Err(message) => logging::log(LogLevel::Warn, "link_preview", format!("Link preview for discussion {} failed: {}", discussion_id, message)),
+        }
+
+        LINK_PREVIEW_IN_FLIGHT.with(|in_flight| *in_flight.borrow_mut() = false);
+    });
+}
+
+// Helper function to fetch a page and pull its preview metadata through the transform function
+async fn fetch_link_preview(url: &str) -> Result<LinkPreview, String> {
+    use ic_cdk::api::management_canister::http_request::{http_request as outcall, CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformContext};
+
+    let request = CanisterHttpRequestArgument {
+        url: url.to_string(),
+        max_response_bytes: Some(PREVIEW_MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: vec![HttpHeader { name: "User-Agent".to_string(), value: "votehub-link-preview".to_string() }],
+        body: None,
+        transform: Some(TransformContext::from_name("transform_link_preview".to_string(), vec![])),
+    };
+
+    let (response,) = outcall(request, PREVIEW_OUTCALL_CYCLES)
+        .await
+        .map_err(|(_, message)| format!("Outcall failed: {}", message))?;
+
+    if response.status != 200u64 {
+        return Err(format!("Page returned status {}", response.status));
+    }
+
+    let mut preview = Decode!(&response.body, LinkPreview).map_err(|e| format!("Cannot decode preview: {}", e))?;
+    preview.url = url.to_string();
+    preview.fetched_at = time();
+
+    Ok(preview)
+}
+
+// Function used by link preview outcalls to reduce a page to its metadata, so replicas agree on the response
+#[ic_cdk::query]
+fn transform_link_preview(raw: ic_cdk::api::management_canister::http_request::TransformArgs) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    let html = String::from_utf8_lossy(&raw.response.body);
+
+    let preview = LinkPreview {
+        title: html_meta(&html, "og:title").or_else(|| html_title(&html)),
+        description: html_meta(&html, "og:description").or_else(|| html_meta(&html, "description")),
+        image: html_meta(&html, "og:image").filter(|image| image.starts_with("https://")),
+        ..Default::default()
+    };
+
+    ic_cdk::api::management_canister::http_request::HttpResponse {
+        status: raw.response.status,
+        headers: vec![],
+        body: Encode!(&preview).unwrap_or_default(),
+    }
+}
+
+// Helper function to read the <title> of an HTML page
+fn html_title(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let start = lower.find("<title")?;
+    let start = start + lower[start..].find('>')? + 1;
+    let end = start + lower[start..].find("</title>")?;
+
+    preview_field(&html[start..end])
+}
+
+// Helper function to read the content of a <meta property="..."> or <meta name="..."> tag
+fn html_meta(html: &str, key: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+
+    lower.match_indices("<meta").find_map(|(start, _)| {
+        let end = start + lower[start..].find('>')?;
+        let tag = &lower[start..end];
+        let keyed = tag.contains(&format!("property=\"{}\"", key)) || tag.contains(&format!("name=\"{}\"", key));
+        if !keyed {
+            return None;
+        }
+
+        let content_start = start + tag.find("content=\"")? + "content=\"".len();
+        let content_end = content_start + lower[content_start..end].find('"')?;
+        preview_field(&html[content_start..content_end])
+    })
+}
+
+// Helper function to tidy and cap a scraped preview field
+fn preview_field(text: &str) -> Option<String> {
+    let text: String = text.split_whitespace().collect::<Vec<_>>().join(" ").chars().take(MAX_PREVIEW_FIELD_LENGTH).collect();
+    (!text.is_empty()).then_some(text)
+}
+
+// Helper function to parse the distinct lowercase #hashtags out of a text
+fn extract_hashtags(text: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+
+    for (index, _) in text.match_indices('#') {
+        let tag: String = text[index + 1..].chars()
+            .take_while(|c| c.is_alphanumeric() || *c == '_')
+            .collect::<String>()
+            .to_lowercase();
+
+        if !tag.is_empty() && tag.chars().count() <= MAX_HASHTAG_LENGTH && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+        if tags.len() == MAX_HASHTAGS {
+            break;
+        }
+    }
+
+    tags
+}
+
+// Helper function to add a discussion's hashtags to the index, skipping tags it is already indexed under
+fn insert_hashtags(discussion: &Discussion) {
+    let listed = is_forum_listed(discussion);
+
+    for tag in extract_hashtags(&discussion.topic) {
+        let key = (search::token_key(&tag), discussion.id);
+        if HASHTAGS_BY_TAG.with(|index| index.borrow().contains_key(&key)) {
+            continue;
+        }
+
+        let id = next_id();
+        let entry = HashtagEntry {
+            tag,
+            discussion_id: discussion.id,
+            created_at: discussion.created_at,
+        };
+        HASHTAG_INDEX.with(|index| index.borrow_mut().insert(id, entry));
+        HASHTAGS_BY_TAG.with(|index| index.borrow_mut().insert(key, id));
+        if listed {
+            count_hashtag(key.0, discussion.created_at, true);
+        }
+    }
+}
+
+// Helper function to drop a discussion's entries from the hashtag index, found through the tags of its
+// topic as last indexed. Call it before the topic or visibility changes.
+fn remove_hashtags(discussion: &Discussion) {
+    let listed = is_forum_listed(discussion);
+
+    for tag in extract_hashtags(&discussion.topic) {
+        let key = (search::token_key(&tag), discussion.id);
+        if let Some(id) = HASHTAGS_BY_TAG.with(|index| index.borrow_mut().remove(&key)) {
+            HASHTAG_INDEX.with(|index| index.borrow_mut().remove(&id));
+            if listed {
+                count_hashtag(key.0, discussion.created_at, false);
+            }
+        }
+    }
+}
+
+// Helper function to get the IDs of a discussion's entries in the hashtag index
+fn hashtag_entry_ids(discussion: &Discussion) -> Vec<u64> {
+    HASHTAGS_BY_TAG.with(|index| {
+        let index = index.borrow();
+        extract_hashtags(&discussion.topic).iter()
+            .filter_map(|tag| index.get(&(search::token_key(tag), discussion.id)))
+            .collect()
+    })
+}
+
+// Helper function to count a forum-listed discussion's tag in or out of the trending counters
+fn count_hashtag(tag_key: u64, created_at: u64, added: bool) {
+    fn adjust<K: BoundedStorable + Ord + Clone>(counters: &mut StableBTreeMap<K, u64, Memory>, key: K, added: bool) {
+        let count = counters.get(&key).unwrap_or(0);
+        let count = if added { count + 1 } else { count.saturating_sub(1) };
+        if count == 0 {
+            counters.remove(&key);
+        } else {
+            counters.insert(key, count);
+        }
+    }
+
+    HASHTAG_TOTALS.with(|totals| adjust(&mut totals.borrow_mut(), tag_key, added));
+    HASHTAG_HOURLY_COUNTS.with(|counts| adjust(&mut counts.borrow_mut(), (created_at / HASHTAG_COUNT_BUCKET, tag_key), added));
+}
+
+// Helper function to get the tag a tag key stands for, from any of its entries
+fn hashtag_name(tag_key: u64) -> Option<String> {
+    let id = HASHTAGS_BY_TAG.with(|index| {
+        index.borrow().range((tag_key, 0)..=(tag_key, u64::MAX)).next().map(|(_, id)| id)
+    })?;
+    try_get(&HASHTAG_INDEX, id).map(|entry| entry.tag)
+}
+
+// Function to get the hashtags most used by public discussions in the trending window
+#[ic_cdk::query]
+fn get_trending_hashtags(limit: Option<u32>) -> Vec<HashtagStat> {
+    let window_start = time().saturating_sub(HASHTAG_TRENDING_WINDOW);
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+
+    // Only the hours inside the window are read, the totals come from the per-tag counters
+    let mut recent: BTreeMap<u64, u64> = BTreeMap::new();
+    HASHTAG_HOURLY_COUNTS.with(|counts| {
+        for ((_, tag_key), count) in counts.borrow().range((window_start / HASHTAG_COUNT_BUCKET, 0)..) {
+            *recent.entry(tag_key).or_default() += count;
+        }
+    });
+
+    let mut stats: Vec<HashtagStat> = recent.into_iter()
+        .filter_map(|(tag_key, recent)| {
+            let total = HASHTAG_TOTALS.with(|totals| totals.borrow().get(&tag_key)).unwrap_or(recent);
+            hashtag_name(tag_key).map(|tag| HashtagStat { tag, recent, total })
+        })
+        .collect();
+    stats.sort_by(|a, b| b.recent.cmp(&a.recent).then(b.total.cmp(&a.total)).then(a.tag.cmp(&b.tag)));
+    stats.truncate(limit);
+
+    stats
+}
+
+// Function to page through the discussions tagged with a hashtag that the user can see
+#[ic_cdk::query]
+fn get_discussions_by_hashtag(tag: String, username: String, cursor: Option<String>, limit: Option<u32>) -> Result<Page<Discussion>, String> {
+    let username = resolve_viewer(username);
+    let tag = tag.trim_start_matches('#').to_lowercase();
+    let preferences = viewer_preferences(&username);
+
+    let entries = paginate(&HASHTAG_INDEX, cursor, limit, |_, entry| {
+        entry.tag == tag
+            && load_discussion(entry.discussion_id).is_some_and(|discussion| {
+                !matches!(discussion.visibility, Visibility::Unlisted) && can_access_discussion(&discussion, &username)
+                    && matches_preferences(&discussion, &preferences)
+            })
+    })?;
+
+    Ok(Page {
+        items: entries.items.into_iter()
+            .filter_map(|entry| load_discussion(entry.discussion_id))
+            .map(redact_tallies)
+            .collect(),
+        next_cursor: entries.next_cursor,
+    })
+}
+
+// Helper function to notify subscribers every word of whose keyword appears in a new discussion they can see
+fn notify_keyword_matches(discussion: &Discussion) {
+    let topic = search::tokenize(&discussion.topic);
+
+    // Candidates share the first word of their keyword with the topic, in alert ID order
+    let alert_ids: BTreeSet<u64> = KEYWORD_ALERTS_BY_TOKEN.with(|index| {
+        let index = index.borrow();
+        topic.iter()
+            .flat_map(|token| {
+                let key = search::token_key(token);
+                index.range((key, 0)..=(key, u64::MAX)).map(|((_, alert_id), _)| alert_id).collect::<Vec<u64>>()
+            })
+            .collect()
+    });
+
+    // A keyword matches when every one of its words is in the topic
+    let matches: Vec<KeywordAlert> = alert_ids.into_iter()
+        .filter_map(|alert_id| try_get(&KEYWORD_ALERTS_STORAGE, alert_id))
+        .filter(|alert| alert.username != discussion.created_by)
+        .filter(|alert| search::tokenize(&alert.keyword).iter().all(|token| topic.contains(token)))
+        .collect();
+
+    // One notification per subscriber, for the first matching keyword
+    let mut notified: Vec<String> = Vec::new();
+    for alert in matches {
+        if notified.contains(&alert.username) || !can_access_discussion(discussion, &alert.username) {
+            continue;
+        }
+        notify(&alert.username, NotificationKind::KeywordMatch { discussion_id: discussion.id, keyword: alert.keyword });
+        notified.push(alert.username);
+    }
+}
+
+// Helper function to store a notification, dropping the user's oldest ones past MAX_NOTIFICATIONS
+fn notify(username: &str, kind: NotificationKind) {
+    let id = next_id();
+
+    let notification = Notification {
+        id,
+        username: username.to_string(),
+        kind,
+        created_at: time(),
+        read: false,
+    };
+
+    NOTIFICATIONS_STORAGE.with(|storage| storage.borrow_mut().insert(id, notification));
+    NOTIFICATIONS_BY_USER.with(|index| index.borrow_mut().insert((username_key(username), id), 0));
+
+    let ids: Vec<u64> = user_notifications(username).iter().map(|notification| notification.id).collect();
+    NOTIFICATIONS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for id in ids.iter().take(ids.len().saturating_sub(MAX_NOTIFICATIONS)) {
+            storage.remove(id);
+        }
+    });
+    NOTIFICATIONS_BY_USER.with(|index| {
+        let mut index = index.borrow_mut();
+        for id in ids.iter().take(ids.len().saturating_sub(MAX_NOTIFICATIONS)) {
+            index.remove(&(username_key(username), *id));
+        }
+    });
+}
+
+// Helper function to hash a username into the first half of the keys of per-user indexes
+fn username_key(username: &str) -> u64 {
+    search::token_key(username)
+}
+
+// Helper function to read a user's notifications in ID order through the index. Entries whose notification
+// is gone, no longer decodes or belongs to a user with a colliding key are skipped; rebuild_alert_indexes
+// drops them.
+fn user_notifications(username: &str) -> Vec<Notification> {
+    let key = username_key(username);
+    let ids: Vec<u64> = NOTIFICATIONS_BY_USER.with(|index| {
+        index.borrow().range((key, 0)..=(key, u64::MAX)).map(|((_, id), _)| id).collect()
+    });
+
+    ids.into_iter()
+        .filter_map(|id| try_get(&NOTIFICATIONS_STORAGE, id))
+        .filter(|notification| notification.username == username)
+        .collect()
+}
+
+// Helper function to rebuild the keyword alert and notification indexes from their maps
+fn rebuild_alert_indexes() {
+    KEYWORD_ALERTS_BY_TOKEN.with(|index| {
+        let mut index = index.borrow_mut();
+        let keys: Vec<(u64, u64)> = index.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index.remove(&key);
+        }
+        KEYWORD_ALERTS_STORAGE.with(|storage| {
+            for (id, alert) in storage.borrow().iter().filter(decoded) {
+                if let Some(token) = search::tokenize(&alert.keyword).first() {
+                    index.insert((search::token_key(token), id), 0);
+                }
+            }
+        });
+    });
+
+    NOTIFICATIONS_BY_USER.with(|index| {
+        let mut index = index.borrow_mut();
+        let keys: Vec<(u64, u64)> = index.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index.remove(&key);
+        }
+        NOTIFICATIONS_STORAGE.with(|storage| {
+            for (id, notification) in storage.borrow().iter().filter(decoded) {
+                index.insert((username_key(&notification.username), id), 0);
+            }
+        });
+    });
+}
+
+// Function to subscribe to new discussions whose topic contains every word of a keyword or phrase
+#[ic_cdk::update]
+fn add_keyword_alert(keyword: String, username: String) -> Result<KeywordAlert, String> {
+    let _meter = metrics::measure("add_keyword_alert");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let keyword = keyword.trim().to_lowercase();
+    if keyword.chars().count() < 2 || keyword.len() > MAX_KEYWORD_LENGTH {
+        return Err(format!("Keyword must be between 2 and {} characters", MAX_KEYWORD_LENGTH));
+    }
+    // Alerts are indexed by their first word, see KEYWORD_ALERTS_BY_TOKEN
+    let Some(first_token) = search::tokenize(&keyword).into_iter().next() else {
+        return Err("Keyword must contain a word of at least 2 letters or digits".to_string());
+    };
+
+    let existing: Vec<KeywordAlert> = KEYWORD_ALERTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, alert)| alert.username == username)
+            .map(|(_, alert)| alert)
+            .collect()
+    });
+
+    if existing.iter().any(|alert| alert.keyword == keyword) {
+        return Err("Keyword alert already exists".to_string());
+    }
+
+    if existing.len() >= MAX_KEYWORD_ALERTS {
+        return Err(format!("A user can have at most {} keyword alerts", MAX_KEYWORD_ALERTS));
+    }
+
+    let alert = KeywordAlert {
+        id: next_id(),
+        username,
+        keyword,
+        created_at: time(),
+    };
+
+    KEYWORD_ALERTS_STORAGE.with(|storage| storage.borrow_mut().insert(alert.id, alert.clone()));
+    KEYWORD_ALERTS_BY_TOKEN.with(|index| index.borrow_mut().insert((search::token_key(&first_token), alert.id), 0));
+
+    Ok(alert)
+}
+
+// Function to unsubscribe from a keyword
+#[ic_cdk::update]
+fn remove_keyword_alert(keyword: String, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("remove_keyword_alert");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let keyword = keyword.trim().to_lowercase();
+    let alert_ids: Vec<u64> = KEYWORD_ALERTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, alert)| alert.username == username && alert.keyword == keyword)
+            .map(|(id, _)| id)
+            .collect()
+    });
+
+    if alert_ids.is_empty() {
+        return Err("Keyword alert not found".to_string());
+    }
+
+    let token_key = search::tokenize(&keyword).first().map(|token| search::token_key(token));
+    for alert_id in alert_ids {
+        KEYWORD_ALERTS_STORAGE.with(|storage| storage.borrow_mut().remove(&alert_id));
+        if let Some(token_key) = token_key {
+            KEYWORD_ALERTS_BY_TOKEN.with(|index| index.borrow_mut().remove(&(token_key, alert_id)));
+        }
+    }
+
+    Ok("Keyword alert removed".to_string())
+}
+
+// Function to list a user's keyword alerts
+#[ic_cdk::query]
+fn get_keyword_alerts(username: String) -> Result<Vec<KeywordAlert>, String> {
+    authenticate(&username)?;
+
+    Ok(KEYWORD_ALERTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, alert)| alert.username == username)
+            .map(|(_, alert)| alert)
+            .collect()
+    }))
+}
+
+// Function to get a user's notifications, newest first
+#[ic_cdk::query]
+fn get_notifications(username: String) -> Result<Vec<Notification>, String> {
+    authenticate(&username)?;
+
+    let mut notifications = user_notifications(&username);
+    notifications.reverse();
+
+    Ok(notifications)
+}
+
+// Function to mark all of a user's notifications as read
+#[ic_cdk::update]
+fn mark_notifications_read(username: String) -> Result<u64, String> {
+    let _meter = metrics::measure("mark_notifications_read");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let unread: Vec<Notification> = user_notifications(&username).into_iter()
+        .filter(|notification| !notification.read)
+        .collect();
+
+    let count = unread.len() as u64;
+    NOTIFICATIONS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for mut notification in unread {
+            notification.read = true;
+            storage.insert(notification.id, notification);
+        }
+    });
+
+    Ok(count)
+}
+
+// Function to be notified about a discussion at `remind_at`, to the minute
+#[ic_cdk::update]
+fn set_reminder(discussion_id: u64, remind_at: u64, note: Option<String>, username: String) -> Result<Reminder, String> {
+    let _meter = metrics::measure("set_reminder");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+    if !can_access_discussion(&discussion, &username) {
+        return Err(ErrorCode::DiscussionNotFound.into());
+    }
+
+    let now = time();
+    if remind_at <= now || remind_at > now.saturating_add(MAX_REMINDER_DELAY) {
+        return Err("Reminders must be set for the future and within a year".to_string());
+    }
+
+    let note = note.map(|note| sanitize_user_text(&note)).transpose()?.filter(|note| !note.trim().is_empty());
+    if note.as_ref().is_some_and(|note| note.chars().count() > MAX_REMINDER_NOTE_LENGTH) {
+        return Err(format!("Notes cannot exceed {} characters", MAX_REMINDER_NOTE_LENGTH));
+    }
+
+    let pending = REMINDERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).filter(|(_, reminder)| reminder.username == username).count()
+    });
+    if pending >= MAX_REMINDERS_PER_USER {
+        return Err(format!("A user can have at most {} pending reminders", MAX_REMINDERS_PER_USER));
+    }
+
+    let slot_key = remind_at / REMINDER_SLOT_WIDTH;
+    let mut slot = try_get(&REMINDER_WHEEL, slot_key).unwrap_or_default();
+    if slot.reminder_ids.len() >= MAX_REMINDERS_PER_SLOT {
+        return Err("Too many reminders are due that minute, pick another time".to_string());
+    }
+
+    let reminder = Reminder {
+        id: next_id(),
+        username,
+        discussion_id,
+        remind_at,
+        note,
+        created_at: now,
+    };
+
+    slot.reminder_ids.push(reminder.id);
+    REMINDER_WHEEL.with(|wheel| wheel.borrow_mut().insert(slot_key, slot));
+    index_discussion_record(reminder.discussion_id, StoredMap::Reminders, reminder.id);
+    REMINDERS_STORAGE.with(|storage| storage.borrow_mut().insert(reminder.id, reminder.clone()));
+
+    Ok(reminder)
+}
+
+// Function to list a user's pending reminders, soonest first
+#[ic_cdk::query]
+fn get_my_reminders(username: String) -> Result<Vec<Reminder>, String> {
+    authenticate(&username)?;
+
+    let mut reminders: Vec<Reminder> = REMINDERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, reminder)| reminder.username == username)
+            .map(|(_, reminder)| reminder)
+            .collect()
+    });
+    reminders.sort_by_key(|reminder| reminder.remind_at);
+
+    Ok(reminders)
+}
+
+// Function to cancel a pending reminder
+#[ic_cdk::update]
+fn cancel_reminder(reminder_id: u64, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("cancel_reminder");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let reminder = try_get(&REMINDERS_STORAGE, reminder_id)
+        .filter(|reminder| reminder.username == username)
+        .ok_or("Reminder not found")?;
+
+    let slot_key = reminder.remind_at / REMINDER_SLOT_WIDTH;
+    REMINDER_WHEEL.with(|wheel| {
+        let mut wheel = wheel.borrow_mut();
+        if let Some(mut slot) = wheel.get(&slot_key).filter(decoded) {
+            slot.reminder_ids.retain(|id| *id != reminder_id);
+            if slot.reminder_ids.is_empty() {
+                wheel.remove(&slot_key);
+            } else {
+                wheel.insert(slot_key, slot);
+            }
+        }
+    });
+    REMINDERS_STORAGE.with(|storage| storage.borrow_mut().remove(&reminder_id));
+
+    Ok("Reminder cancelled".to_string())
+}
+
+// Helper function run by the background tick to notify the owners of reminders in every slot that has come due.
+// Reminders removed with their account or discussion are skipped, those on discussions the owner can no
+// longer see are dropped.
+fn fire_due_reminders(now: u64) {
+    let due: Vec<(u64, ReminderSlot)> = REMINDER_WHEEL.with(|wheel| wheel.borrow().range(..=now / REMINDER_SLOT_WIDTH).filter(decoded).collect());
+
+    for (slot_key, slot) in due {
+        for reminder_id in slot.reminder_ids {
+            let Some(reminder) = REMINDERS_STORAGE.with(|storage| storage.borrow_mut().remove(&reminder_id)) else {
+                continue;
+            };
+
+            if load_discussion(reminder.discussion_id).is_some_and(|discussion| can_access_discussion(&discussion, &reminder.username)) {
+                notify(&reminder.username, NotificationKind::Reminder {
+                    reminder_id,
+                    discussion_id: reminder.discussion_id,
+                    note: reminder.note,
+                });
+            }
+        }
+
+        REMINDER_WHEEL.with(|wheel| wheel.borrow_mut().remove(&slot_key));
+    }
+}
+
+// Function to create a new group, the creator becomes its first member
+#[ic_cdk::update]
+fn create_group(name: String, description: String, settings: GroupSettings, username: String, idempotency_key: Option<String>) -> Result<Group, String> {
+    let _meter = metrics::measure("create_group");
+
+    ensure_writable()?;
+
+    with_idempotency("create_group", idempotency_key, || {
+        require_feature("groups")?;
+
+        let name = sanitize_user_text(&name)?;
+        let description = sanitize_user_text(&description)?;
+
+        if name.trim().is_empty() {
+            return Err("Group name is required".to_string());
+        }
+
+        authenticate(&username)?;
+
+        permissions::check_permission(&username, Action::PostText(&description))?;
+
+        let name_taken = GROUPS_STORAGE.with(|storage| {
+            storage.borrow().iter().filter(decoded).any(|(_, group)| group.name == name)
+        });
+
+        if name_taken {
+            return Err("Group name already exists".to_string());
+        }
+
+        validate_group_settings(&settings)?;
+
+        let id = next_id();
+
+        let group = Group {
+            id,
+            name,
+            description,
+            created_by: username.clone(),
+            created_at: time(),
+            settings,
+        };
+
+        GROUPS_STORAGE.with(|storage| storage.borrow_mut().insert(id, group.clone()));
+        add_group_member(id, username, GroupRole::Owner);
+
+        Ok(group)
+    })
+}
+
+// Helper function to check if a user belongs to a group
+fn is_group_member(group_id: u64, username: &str) -> bool {
+    find_group_member(group_id, username).is_some()
+}
+
+// Helper function to look up a user's membership record in a group
+fn find_group_member(group_id: u64, username: &str) -> Option<GroupMember> {
+    GROUP_MEMBERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .find(|(_, member)| member.group_id == group_id && member.username == username)
+            .map(|(_, member)| member)
+    })
+}
+
+// Helper function to check if a user owns a group
+fn is_group_owner(group_id: u64, username: &str) -> bool {
+    find_group_member(group_id, username).is_some_and(|member| member.role == GroupRole::Owner)
+}
+
+// Helper function to check a user's role against the group's permission matrix
+fn has_group_permission(group: &Group, username: &str, action: GroupAction) -> bool {
+    let required = match action {
+        GroupAction::Post => group.settings.permissions.post,
+        GroupAction::Vote => group.settings.permissions.vote,
+        GroupAction::Moderate => group.settings.permissions.moderate,
+    };
+
+    find_group_member(group.id, username).is_some_and(|member| member.role >= required)
+}
+
+// Helper function to load a group the user is allowed to moderate
+fn get_moderated_group(group_id: u64, username: &str) -> Result<Group, String> {
+    let group = try_get(&GROUPS_STORAGE, group_id).ok_or(ErrorCode::GroupNotFound)?;
+
+    if !has_group_permission(&group, username, GroupAction::Moderate) {
+        return Err("User is not allowed to moderate this group".to_string());
+    }
+
+    Ok(group)
+}
+
+// Helper function to record a new group membership
+fn add_group_member(group_id: u64, username: String, role: GroupRole) {
+    let id = next_id();
+
+    let member = GroupMember {
+        id,
+        group_id,
+        username,
+        role,
+        joined_at: time(),
+    };
+
+    GROUP_MEMBERS_STORAGE.with(|storage| storage.borrow_mut().insert(id, member));
+}
+
+// Function to join an open group
+#[ic_cdk::update]
+fn join_group(group_id: u64, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("join_group");
+
+    ensure_writable()?;
+
+    require_feature("groups")?;
+
+    authenticate(&username)?;
+
+    let group = try_get(&GROUPS_STORAGE, group_id).ok_or(ErrorCode::GroupNotFound)?;
+
+    if is_group_member(group_id, &username) {
+        return Err(ErrorCode::AlreadyGroupMember.into());
+    }
+
+    if !group.settings.open {
+        return Err("Group is closed to new members".to_string());
+    }
+
+    add_group_member(group_id, username, GroupRole::Member);
+
+    Ok("Joined group".to_string())
+}
+
+// Function to leave a group (the owner cannot leave their own group)
+#[ic_cdk::update]
+fn leave_group(group_id: u64, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("leave_group");
+
+    ensure_writable()?;
+
+    require_feature("groups")?;
+
+    authenticate(&username)?;
+
+    if !GROUPS_STORAGE.with(|storage| storage.borrow().contains_key(&group_id)) {
+        return Err(ErrorCode::GroupNotFound.into());
+    }
+
+    let member = find_group_member(group_id, &username).ok_or(ErrorCode::NotGroupMember)?;
+
+    if member.role == GroupRole::Owner {
+        return Err("The owner cannot leave the group".to_string());
+    }
+
+    GROUP_MEMBERS_STORAGE.with(|storage| storage.borrow_mut().remove(&member.id));
+
+    Ok("Left group".to_string())
+}
+
+// Function to change a group's settings (only by owner)
+#[ic_cdk::update]
+fn update_group_settings(group_id: u64, settings: GroupSettings, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("update_group_settings");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut group = try_get(&GROUPS_STORAGE, group_id).ok_or(ErrorCode::GroupNotFound)?;
+
+    if !is_group_owner(group_id, &username) {
+        return Err("Only the owner can change group settings".to_string());
+    }
+
+    validate_group_settings(&settings)?;
+
+    group.settings = settings;
+
+    GROUPS_STORAGE.with(|storage| storage.borrow_mut().insert(group_id, group));
+    log_group_action(group_id, &username, "Updated group settings".to_string());
+
+    Ok("Group settings updated".to_string())
+}
+
+// Function to start a discussion inside a group (requires the post permission)
+#[ic_cdk::update]
+fn create_group_discussion(group_id: u64, topic: String, username: String, idempotency_key: Option<String>) -> Result<Discussion, String> {
+    let _meter = metrics::measure("create_group_discussion");
+
+    ensure_writable()?;
+
+    with_idempotency("create_group_discussion", idempotency_key, || {
+        require_feature("groups")?;
+
+        let group = try_get(&GROUPS_STORAGE, group_id).ok_or(ErrorCode::GroupNotFound)?;
+
+        if !has_group_permission(&group, &username, GroupAction::Post) {
+            return Err("User is not allowed to post in this group".to_string());
+        }
+
+        check_group_filter(&group, &topic)?;
+
+        insert_discussion(topic, username, Some(group_id))
+    })
+}
+
+// Function to start a new discussion that links back to the one it splits off from
+#[ic_cdk::update]
+fn fork_discussion(discussion_id: u64, new_topic: String, username: String, idempotency_key: Option<String>) -> Result<Discussion, String> {
+    let _meter = metrics::measure("fork_discussion");
+
+    ensure_writable()?;
+
+    with_idempotency("fork_discussion", idempotency_key, || {
+        let (_, mut fork) = new_linked_discussion(discussion_id, new_topic, username)?;
+
+        fork.forked_from = Some(discussion_id);
+
+        publish_discussion(fork)
+    })
+}
+
+// Function to start a new discussion replying to the characters `start..end` of another discussion's topic
+#[ic_cdk::update]
+fn quote_reply(
+    discussion_id: u64,
+    start: u32,
+    end: u32,
+    topic: String,
+    username: String,
+    idempotency_key: Option<String>,
+) -> Result<Discussion, String> {
+    let _meter = metrics::measure("quote_reply");
+
+    ensure_writable()?;
+
+    with_idempotency("quote_reply", idempotency_key, || {
+        let (original, mut reply) = new_linked_discussion(discussion_id, topic, username)?;
+
+        if start >= end || end as usize > original.topic.chars().count() {
+            return Err("Quoted range is outside the discussion's topic".to_string());
+        }
+
+        reply.quote = Some(Quote { discussion_id, version: original.version, start, end });
+
+        publish_discussion(reply)
+    })
+}
+
+// Helper function to validate a discussion that refers to another one, for fork_discussion and quote_reply.
+// It stays in the original's group and is visible to the same users.
+fn new_linked_discussion(discussion_id: u64, topic: String, username: String) -> Result<(Discussion, Discussion), String> {
+    if has_shards() {
+        return Err("Forum discussions are stored on shards, use create_sharded_discussion".to_string());
+    }
+
+    authenticate(&username)?;
+
+    let original = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if !can_access_discussion(&original, &username) {
+        return Err(ErrorCode::DiscussionNotFound.into());
+    }
+
+    if let Some(group) = original.group_id.and_then(|group_id| try_get(&GROUPS_STORAGE, group_id)) {
+        if !has_group_permission(&group, &username, GroupAction::Post) {
+            return Err("User is not allowed to post in this group".to_string());
+        }
+
+        check_group_filter(&group, &topic)?;
+    }
+
+    let mut discussion = new_discussion(topic, username, original.group_id)?;
+
+    discussion.visibility = original.visibility.clone();
+    if let Visibility::Private { allowed_users } = &mut discussion.visibility {
+        allowed_users.retain(|user| *user != discussion.created_by);
+        if original.created_by != discussion.created_by && !allowed_users.contains(&original.created_by) {
+            allowed_users.push(original.created_by.clone());
+        }
+    }
+
+    Ok((original, discussion))
+}
+
+// Helper function to validate group settings before they are stored
+fn validate_group_settings(settings: &GroupSettings) -> Result<(), String> {
+    if settings.blocked_words.len() > MAX_BLOCKED_WORDS {
+        return Err(format!("A group can block at most {} words", MAX_BLOCKED_WORDS));
+    }
+
+    if settings.blocked_words.iter().any(|word| word.trim().is_empty()) {
+        return Err("Blocked words cannot be empty".to_string());
+    }
+
+    Ok(())
+}
+
+// Helper function to reject text containing one of the group's blocked words
+fn check_group_filter(group: &Group, text: &str) -> Result<(), String> {
+    let text = text.to_lowercase();
+
+    match group.settings.blocked_words.iter().find(|word| text.contains(&word.to_lowercase())) {
+        Some(word) => Err(format!("Content contains a word blocked in this group: {}", word)),
+        None => Ok(()),
+    }
+}
+
+// Helper function to append an entry to a group's audit log
+fn log_group_action(group_id: u64, actor: &str, action: String) {
+    let id = next_id();
+
+    let entry = GroupAuditEntry {
+        id,
+        group_id,
+        actor: actor.to_string(),
+        action,
+        created_at: time(),
+    };
+
+    GROUP_AUDIT_LOG.with(|log| log.borrow_mut().insert(id, entry));
+}
+
+// Function to change a member's role (only by owner, ownership itself cannot be granted)
+#[ic_cdk::update]
+fn set_group_member_role(group_id: u64, member: String, role: GroupRole, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("set_group_member_role");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    if !GROUPS_STORAGE.with(|storage| storage.borrow().contains_key(&group_id)) {
+        return Err(ErrorCode::GroupNotFound.into());
+    }
+
+    if !is_group_owner(group_id, &username) {
+        return Err("Only the owner can change member roles".to_string());
+    }
+
+    if role == GroupRole::Owner {
+        return Err("Ownership cannot be granted".to_string());
+    }
+
+    let mut target = find_group_member(group_id, &member).ok_or(ErrorCode::NotGroupMember)?;
+
+    if target.role == GroupRole::Owner {
+        return Err("The owner's role cannot be changed".to_string());
+    }
+
+    target.role = role;
+
+    GROUP_MEMBERS_STORAGE.with(|storage| storage.borrow_mut().insert(target.id, target));
+    log_group_action(group_id, &username, format!("Changed role of {}", member));
+
+    Ok("Member role updated".to_string())
+}
+
+// Function to remove a member from a group (requires the moderate permission and a higher role)
+#[ic_cdk::update]
+fn remove_group_member(group_id: u64, member: String, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("remove_group_member");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    get_moderated_group(group_id, &username)?;
+
+    let actor = find_group_member(group_id, &username).ok_or(ErrorCode::NotGroupMember)?;
+    let target = find_group_member(group_id, &member).ok_or("Member not found")?;
+
+    if target.role >= actor.role {
+        return Err("Cannot remove a member with an equal or higher role".to_string());
+    }
+
+    GROUP_MEMBERS_STORAGE.with(|storage| storage.borrow_mut().remove(&target.id));
+    log_group_action(group_id, &username, format!("Removed member {}", member));
+
+    Ok("Member removed from group".to_string())
+}
+
+// Function to move a discussion and its votes from a group to the trash (requires the moderate permission)
+#[ic_cdk::update]
+fn remove_group_discussion(discussion_id: u64, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("remove_group_discussion");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    let group_id = discussion.group_id.ok_or("Discussion does not belong to a group")?;
+
+    get_moderated_group(group_id, &username)?;
+
+    trash_discussion(discussion, &username, false);
+    log_group_action(group_id, &username, format!("Removed discussion {}", discussion_id));
+
+    Ok("Discussion removed from group".to_string())
+}
+
+// Helper function to load a discussion through the heap cache. Query calls run on a throwaway copy
+// of the heap, so only lookups made during update calls warm the cache and move its counters.
+fn load_discussion(discussion_id: u64) -> Option<Discussion> {
+    if let Some(discussion) = DISCUSSION_CACHE.with(|cache| cache.borrow_mut().get(discussion_id)) {
+        return Some(discussion);
+    }
+
+    let discussion = try_get(&DISCUSSIONS_STORAGE, discussion_id)?;
+    DISCUSSION_CACHE.with(|cache| cache.borrow_mut().put(discussion.clone()));
+    Some(discussion)
+}
+
+// Helper function to write a discussion and drop its stale cache entry
+fn save_discussion(discussion_id: u64, discussion: Discussion) {
+    update_attention_index(&discussion);
+    index_discussion_author(&discussion);
+    index_final_at(&discussion);
+    DISCUSSIONS_STORAGE.with(|storage| storage.borrow_mut().insert(discussion_id, discussion));
+    DISCUSSION_CACHE.with(|cache| cache.borrow_mut().remove(discussion_id));
+}
+
+// Helper function to drop every cached discussion and rebuild derived indexes after a bulk rewrite of the storage map
+fn clear_discussion_cache() {
+    DISCUSSION_CACHE.with(|cache| cache.borrow_mut().clear());
+    rebuild_discussion_indexes();
+}
+
+// Helper function to recompute everything derived from discussions in one scan
+fn rebuild_discussion_indexes() {
+    remove_where(&NEEDS_ATTENTION_INDEX, |_| true);
+    remove_where(&HASHTAG_INDEX, |_| true);
+    remove_where(&TOPIC_MARKDOWN_STORAGE, |_| true);
+    HASHTAGS_BY_TAG.with(|index| {
+        let mut index = index.borrow_mut();
+        let keys: Vec<(u64, u64)> = index.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index.remove(&key);
+        }
+    });
+    HASHTAG_TOTALS.with(|totals| {
+        let mut totals = totals.borrow_mut();
+        let keys: Vec<u64> = totals.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            totals.remove(&key);
+        }
+    });
+    HASHTAG_HOURLY_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        let keys: Vec<(u64, u64)> = counts.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            counts.remove(&key);
+        }
+    });
+    DISCUSSIONS_BY_AUTHOR.with(|index| {
+        let mut index = index.borrow_mut();
+        let keys: Vec<(u64, u64)> = index.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index.remove(&key);
+        }
+    });
+    DISCUSSIONS_BY_FINAL_AT.with(|index| {
+        let mut index = index.borrow_mut();
+        let keys: Vec<(u64, u64)> = index.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index.remove(&key);
+        }
+    });
+
+    let discussions: Vec<Discussion> = DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, discussion)| discussion).collect()
+    });
+
+    for discussion in &discussions {
+        update_attention_index(discussion);
+        index_discussion_author(discussion);
+        index_final_at(discussion);
+        insert_hashtags(discussion);
+        store_topic_markdown(discussion);
+        queue_search_update(discussion.id);
+    }
+
+    rebuild_discussion_records();
+    rebuild_trash_index();
+}
+
+// Helper function to note a discussion under its author and creation time. Discussions the same author
+// started at the same instant take the next free nanosecond.
+fn index_discussion_author(discussion: &Discussion) {
+    DISCUSSIONS_BY_AUTHOR.with(|index| {
+        let mut index = index.borrow_mut();
+        let mut key = (username_key(&discussion.created_by), discussion.created_at);
+        loop {
+            match index.get(&key) {
+                Some(id) if id == discussion.id => return,
+                Some(_) => key.1 = key.1.saturating_add(1),
+                None => {
+                    index.insert(key, discussion.id);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+// Helper function to note when a discussion's results become final, for finalize_closed_votes. Entries of
+// an earlier deadline stay until they are due and are dropped then.
+fn index_final_at(discussion: &Discussion) {
+    if let Some(final_at) = results_final_at(discussion).filter(|_| !discussion.results_final) {
+        DISCUSSIONS_BY_FINAL_AT.with(|index| index.borrow_mut().insert((final_at, discussion.id), 0));
+    }
+}
+
+// Helper function to get the creation times of the discussions a user started at or after `since`, oldest
+// first, through the index. Entries of discussions since removed or now credited to someone else are
+// skipped; rebuild_discussion_indexes drops them.
+fn discussions_started_since(username: &str, since: u64) -> Vec<u64> {
+    let key = username_key(username);
+    let ids: Vec<u64> = DISCUSSIONS_BY_AUTHOR.with(|index| {
+        index.borrow().range((key, since)..=(key, u64::MAX)).map(|(_, id)| id).collect()
+    });
+
+    ids.into_iter()
+        .filter_map(|id| try_get(&DISCUSSIONS_STORAGE, id))
+        .filter(|discussion| discussion.created_by == username)
+        .map(|discussion| discussion.created_at)
+        .collect()
+}
+
+// Helper function to note that a record of one of DISCUSSION_RECORD_MAPS belongs to a discussion
+fn index_discussion_record(discussion_id: u64, map: StoredMap, key: u64) {
+    if let Some(position) = DISCUSSION_RECORD_MAPS.iter().position(|listed| *listed == map) {
+        DISCUSSION_RECORDS.with(|index| index.borrow_mut().insert((discussion_id, key), position as u8));
+    }
+}
+
+// Helper function to list the keys of a discussion's records in one map. Keys of records removed since
+// may be listed, so the caller reads each record and checks it.
+fn discussion_record_keys(discussion_id: u64, map: StoredMap) -> Vec<u64> {
+    discussion_records(discussion_id).into_iter().filter(|(listed, _)| *listed == map).map(|(_, key)| key).collect()
+}
+
+// Helper function to list every record of a discussion through the index, with its map
+fn discussion_records(discussion_id: u64) -> Vec<(StoredMap, u64)> {
+    DISCUSSION_RECORDS.with(|index| {
+        index.borrow().range((discussion_id, 0)..=(discussion_id, u64::MAX))
+            .filter_map(|((_, key), position)| DISCUSSION_RECORD_MAPS.get(position as usize).map(|map| (*map, key)))
+            .collect()
+    })
+}
+
+// Helper function to rebuild DISCUSSION_RECORDS from the maps it indexes
+fn rebuild_discussion_records() {
+    fn index_map<V: BoundedStorable>(
+        storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+        map: StoredMap,
+        discussion_of: impl Fn(&V) -> Option<u64>,
+    ) {
+        let entries: Vec<(u64, u64)> = storage.with(|storage| {
+            storage.borrow().iter().filter(decoded)
+                .filter_map(|(key, value)| discussion_of(&value).map(|discussion_id| (discussion_id, key)))
+                .collect()
+        });
+        for (discussion_id, key) in entries {
+            index_discussion_record(discussion_id, map, key);
+        }
+    }
+
+    DISCUSSION_RECORDS.with(|index| {
+        let mut index = index.borrow_mut();
+        let keys: Vec<(u64, u64)> = index.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index.remove(&key);
+        }
+    });
+
+    let survey_discussions: BTreeMap<u64, u64> = SURVEYS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(id, survey)| (id, survey.discussion_id)).collect()
+    });
+
+    index_map(&VOTE_COMMITMENTS_STORAGE, StoredMap::VoteCommitments, |commitment| Some(commitment.discussion_id));
+    index_map(&VIEWS_STORAGE, StoredMap::Views, |view| Some(view.discussion_id));
+    index_map(&READ_MARKERS_STORAGE, StoredMap::ReadMarkers, |marker| Some(marker.discussion_id));
+    index_map(&REVISIONS_STORAGE, StoredMap::DiscussionRevisions, |revision| Some(revision.discussion_id));
+    index_map(&SURVEYS_STORAGE, StoredMap::Surveys, |survey| Some(survey.discussion_id));
+    index_map(&SURVEY_RESPONSES_STORAGE, StoredMap::SurveyResponses, |response| survey_discussions.get(&response.survey_id).copied());
+    index_map(&REMINDERS_STORAGE, StoredMap::Reminders, |reminder| Some(reminder.discussion_id));
+    index_map(&UNLOCK_REQUESTS_STORAGE, StoredMap::UnlockRequests, |request| Some(request.discussion_id));
+    index_map(&RAFFLES_STORAGE, StoredMap::Raffles, |raffle| Some(raffle.discussion_id));
+    index_map(&RANDOMNESS_AUDIT_STORAGE, StoredMap::RandomnessAudit, |audit| match audit.purpose {
+        RandomnessPurpose::Raffle { discussion_id } => Some(discussion_id),
+        _ => None,
+    });
+}
+
+// Helper function to rebuild TRASH_BY_PURGE_AT from the trash
+fn rebuild_trash_index() {
+    TRASH_BY_PURGE_AT.with(|index| {
+        let mut index = index.borrow_mut();
+        let keys: Vec<(u64, u64)> = index.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index.remove(&key);
+        }
+        TRASH_STORAGE.with(|storage| {
+            for (id, trashed) in storage.borrow().iter().filter(decoded) {
+                index.insert((trashed.purge_at, id), 0);
+            }
+        });
+    });
+}
+
+// Helper function to refresh what is derived from a discussion's topic after it is written
+fn index_topic(discussion: &Discussion) {
+    insert_hashtags(discussion);
+    queue_link_preview(discussion);
+    store_topic_markdown(discussion);
+    queue_search_update(discussion.id);
+}
+
+// Helper function to store the normalized Markdown of a discussion's topic
+fn store_topic_markdown(discussion: &Discussion) {
+    let text = NormalizedText { markdown: markdown::normalize_markdown(&discussion.topic) };
+    TOPIC_MARKDOWN_STORAGE.with(|storage| storage.borrow_mut().insert(discussion.id, text));
+}
+
+// Helper function to keep a discussion's entry in the needs-attention index in step with its tally
+fn update_attention_index(discussion: &Discussion) {
+    NEEDS_ATTENTION_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if discussion.upvotes + discussion.downvotes == 0 {
+            index.insert(discussion.id, discussion.created_at);
+        } else {
+            index.remove(&discussion.id);
+        }
+    });
+}
+
+// Helper function to check if a user moderates a discussion: admins, and moderators of its group
+fn is_discussion_moderator(discussion: &Discussion, username: &str) -> bool {
+    is_admin(&caller()) || discussion.group_id.is_some_and(|group_id| get_moderated_group(group_id, username).is_ok())
+}
+
+// Helper function to take a discussion out of every listing and index and keep it in the trash until
+// the retention period ends
+fn trash_discussion(discussion: Discussion, deleted_by: &str, pending_review: bool) {
+    let discussion_id = discussion.id;
+    let now = time();
+
+    DISCUSSIONS_STORAGE.with(|storage| storage.borrow_mut().remove(&discussion_id));
+    DISCUSSION_CACHE.with(|cache| cache.borrow_mut().remove(discussion_id));
+    NEEDS_ATTENTION_INDEX.with(|index| index.borrow_mut().remove(&discussion_id));
+    remove_hashtags(&discussion);
+    queue_search_update(discussion_id);
+
+    let announced = is_announced(&discussion);
+    let trashed = TrashedDiscussion {
+        discussion,
+        deleted_by: deleted_by.to_string(),
+        deleted_at: now,
+        purge_at: now.saturating_add(settings().trash_retention_days as u64 * 86_400_000_000_000),
+        pending_review,
+    };
+
+    TRASH_BY_PURGE_AT.with(|index| index.borrow_mut().insert((trashed.purge_at, discussion_id), 0));
+    TRASH_STORAGE.with(|storage| storage.borrow_mut().insert(discussion_id, trashed));
+    if announced {
+        outbox::publish(EventKind::DiscussionDeleted { discussion_id });
+    }
+}
+
+// Helper function to check if a discussion is waiting in the trash
+fn is_trashed(discussion_id: u64) -> bool {
+    TRASH_STORAGE.with(|storage| storage.borrow().contains_key(&discussion_id))
+}
+
+// Function to delete a discussion; it stays in the trash for the retention period (only by creator)
+#[ic_cdk::update]
+fn delete_discussion(discussion_id: u64, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("delete_discussion");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if discussion.created_by != username {
+        return Err(ErrorCode::NotCreator.into());
+    }
+
+    trash_discussion(discussion, &username, false);
+
+    Ok("Discussion moved to the trash".to_string())
+}
+
+// Function to bring a discussion back from the trash. Creators can restore what they deleted themselves,
+// moderators anything they could have removed.
+#[ic_cdk::update]
+fn restore_discussion(discussion_id: u64, username: String) -> Result<Discussion, String> {
+    let _meter = metrics::measure("restore_discussion");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let trashed = try_get(&TRASH_STORAGE, discussion_id).ok_or("Discussion is not in the trash")?;
+    let discussion = trashed.discussion;
+
+    let own_deletion = discussion.created_by == username && trashed.deleted_by == username;
+    if !own_deletion && !is_discussion_moderator(&discussion, &username) {
+        return Err("Only the creator or a moderator can restore the discussion".to_string());
+    }
+
+    untrash_discussion(&discussion, &username);
+
+    Ok(discussion)
+}
+
+// Helper function to put a trashed discussion back into storage and its indexes
+fn untrash_discussion(discussion: &Discussion, restored_by: &str) {
+    if let Some(trashed) = TRASH_STORAGE.with(|storage| storage.borrow_mut().remove(&discussion.id)) {
+        TRASH_BY_PURGE_AT.with(|index| index.borrow_mut().remove(&(trashed.purge_at, discussion.id)));
+    }
+    insert_hashtags(discussion);
+    queue_search_update(discussion.id);
+    save_discussion(discussion.id, discussion.clone());
+
+    if let Some(group_id) = discussion.group_id {
+        log_group_action(group_id, restored_by, format!("Restored discussion {}", discussion.id));
+    }
+}
+
+// Function to page through the trash: a user's own discussions and those of groups they moderate,
+// everything for admins
+#[ic_cdk::query]
+fn list_trash(username: String, cursor: Option<String>, limit: Option<u32>) -> Result<Page<TrashedDiscussion>, String> {
+    authenticate(&username)?;
+
+    paginate(&TRASH_STORAGE, cursor, limit, |_, trashed| {
+        trashed.discussion.created_by == username || is_discussion_moderator(&trashed.discussion, &username)
+    })
+}
+
+// Helper function to permanently delete up to TRASH_PURGE_BATCH discussions whose retention has ended,
+// looking at up to TRASH_SCAN_BATCH due entries of the trash index from where the previous tick stopped
+fn purge_trash(now: u64) {
+    let cursor = TRASH_PURGE_CURSOR.with(|cursor| *cursor.borrow());
+    let due: Vec<(u64, u64)> = TRASH_BY_PURGE_AT.with(|index| {
+        index.borrow().range(cursor..=(now, u64::MAX)).map(|(key, _)| key).take(TRASH_SCAN_BATCH).collect()
+    });
+
+    let mut expired = Vec::new();
+    let mut next = (0, 0);
+    for &(purge_at, discussion_id) in &due {
+        if expired.len() == TRASH_PURGE_BATCH {
+            next = (purge_at, discussion_id);
+            break;
+        }
+
+        // Entries left behind by a restore are dropped
+        let Some(trashed) = try_get(&TRASH_STORAGE, discussion_id).filter(|trashed| trashed.purge_at == purge_at) else {
+            TRASH_BY_PURGE_AT.with(|index| index.borrow_mut().remove(&(purge_at, discussion_id)));
+            continue;
+        };
+        if !trashed.pending_review && !has_pending_appeal(&AppealTarget::Removal { discussion_id }) {
+            expired.push(discussion_id);
+        }
+    }
+    if expired.len() < TRASH_PURGE_BATCH && due.len() == TRASH_SCAN_BATCH {
+        if let Some(&(purge_at, discussion_id)) = due.last() {
+            next = (purge_at, discussion_id.saturating_add(1));
+        }
+    }
+    TRASH_PURGE_CURSOR.with(|cursor| *cursor.borrow_mut() = next);
+
+    for discussion_id in &expired {
+        if let Some(trashed) = TRASH_STORAGE.with(|storage| storage.borrow_mut().remove(discussion_id)) {
+            TRASH_BY_PURGE_AT.with(|index| index.borrow_mut().remove(&(trashed.purge_at, *discussion_id)));
+        }
+        delete_discussion_and_votes(*discussion_id);
+    }
+
+    if !expired.is_empty() {
+        logging::log(LogLevel::Info, "trash", format!("Purged {} discussions from the trash", expired.len()));
+    }
+}
+
+// Helper function to report what delete_discussion_and_votes would remove
+fn plan_discussion_deletion(report: &mut ChangeReport, discussion_id: u64) {
+    report.removed("discussions", [discussion_id]);
+    if NEEDS_ATTENTION_INDEX.with(|index| index.borrow().contains_key(&discussion_id)) {
+        report.removed("needs_attention_index", [discussion_id]);
+    }
+    if let Some(discussion) = try_get(&DISCUSSIONS_STORAGE, discussion_id) {
+        report.removed("hashtag_index", hashtag_entry_ids(&discussion));
+    }
+    if LINK_PREVIEWS_STORAGE.with(|storage| storage.borrow().contains_key(&discussion_id)) {
+        report.removed("link_previews", [discussion_id]);
+    }
+    if LINK_PREVIEW_QUEUE.with(|queue| queue.borrow().contains_key(&discussion_id)) {
+        report.removed("link_preview_queue", [discussion_id]);
+    }
+    if TOPIC_MARKDOWN_STORAGE.with(|storage| storage.borrow().contains_key(&discussion_id)) {
+        report.removed("topic_markdown", [discussion_id]);
+    }
+    report.removed("votes", VOTES_BY_DISCUSSION.with(|index| {
+        index.borrow().range((discussion_id, 0)..=(discussion_id, u64::MAX)).map(|((_, vote_id), _)| vote_id).collect::<Vec<u64>>()
+    }));
+    if TRANSFERS_STORAGE.with(|storage| storage.borrow().contains_key(&discussion_id)) {
+        report.removed("ownership_transfers", [discussion_id]);
+    }
+    if ATTESTATIONS_STORAGE.with(|storage| storage.borrow().contains_key(&discussion_id)) {
+        report.removed("result_attestations", [discussion_id]);
+    }
+    for (map, key) in discussion_records(discussion_id) {
+        if with_stored_map!(map, |storage| storage.with(|storage| storage.borrow().contains_key(&key))) {
+            report.removed(map_name(map), [key]);
+        }
+    }
+}
+
+// Helper function to delete a discussion together with its votes and the records that belong to it,
+// found through VOTES_BY_DISCUSSION and DISCUSSION_RECORDS
+fn delete_discussion_and_votes(discussion_id: u64) {
+    // A trashed discussion left the hashtag index when it was trashed
+    if let Some(discussion) = DISCUSSIONS_STORAGE.with(|storage| storage.borrow_mut().remove(&discussion_id)) {
+        remove_hashtags(&discussion);
+    }
+    DISCUSSION_CACHE.with(|cache| cache.borrow_mut().remove(discussion_id));
+    NEEDS_ATTENTION_INDEX.with(|index| index.borrow_mut().remove(&discussion_id));
+    LINK_PREVIEWS_STORAGE.with(|storage| storage.borrow_mut().remove(&discussion_id));
+    LINK_PREVIEW_QUEUE.with(|queue| queue.borrow_mut().remove(&discussion_id));
+    TOPIC_MARKDOWN_STORAGE.with(|storage| storage.borrow_mut().remove(&discussion_id));
+    queue_search_update(discussion_id);
+
+    let vote_keys: Vec<(u64, u64)> = VOTES_BY_DISCUSSION.with(|index| {
+        index.borrow().range((discussion_id, 0)..=(discussion_id, u64::MAX)).map(|(key, _)| key).collect()
+    });
+    for (_, vote_id) in &vote_keys {
+        VOTES_STORAGE.with(|storage| storage.borrow_mut().remove(vote_id));
+    }
+    VOTES_BY_DISCUSSION.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in &vote_keys {
+            index.remove(key);
+        }
+    });
+
+    mark_vote_root_stale(discussion_id);
+
+    TRANSFERS_STORAGE.with(|storage| storage.borrow_mut().remove(&discussion_id));
+    ATTESTATIONS_STORAGE.with(|storage| storage.borrow_mut().remove(&discussion_id));
+
+    for (map, key) in discussion_records(discussion_id) {
+        with_stored_map!(map, |storage| storage.with(|storage| {
+            storage.borrow_mut().remove(&key);
+        }));
+        DISCUSSION_RECORDS.with(|index| index.borrow_mut().remove(&(discussion_id, key)));
+    }
+    // Removing decodes the old values
+    take_decode_failure();
+}
+
+// Helper function to sanitize a moderation reason and check it is present and within max_report_reason_length
+fn validate_reason(reason: &str) -> Result<String, String> {
+    let reason = sanitize_user_text(reason)?;
+
+    if reason.trim().is_empty() {
+        return Err("Reason is required".to_string());
+    }
+
+    let max_length = settings().max_report_reason_length;
+    if reason.len() > max_length as usize {
+        return Err(format!("Reason cannot exceed {} characters", max_length));
+    }
+
+    Ok(reason)
+}
+
+// Function to report a discussion, reports on group discussions go to that group's moderators
+#[ic_cdk::update]
+fn report_discussion(discussion_id: u64, reason: String, username: String, idempotency_key: Option<String>) -> Result<Report, String> {
+    let _meter = metrics::measure("report_discussion");
+
+    ensure_writable()?;
+
+    with_idempotency("report_discussion", idempotency_key, || {
+        require_feature("reports")?;
+
+        authenticate(&username)?;
+
+        let reason = validate_reason(&reason)?;
+
+        let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+        if !can_access_discussion(&discussion, &username) {
+            return Err(ErrorCode::DiscussionNotFound.into());
+        }
+
+        if has_open_report(discussion_id, &username) {
+            return Err("User has already reported this discussion".to_string());
+        }
+
+        let report = insert_report(&discussion, username, reason);
+        apply_report_thresholds(discussion);
+
+        Ok(report)
+    })
+}
+
+// Helper function to count the distinct users with an open report matching a predicate
+fn open_reporters(predicate: impl Fn(&Report) -> bool) -> u32 {
+    use std::collections::BTreeSet;
+
+    REPORTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, report)| report.status == ReportStatus::Open && predicate(report))
+            .map(|(_, report)| report.reported_by)
+            .collect::<BTreeSet<String>>()
+            .len() as u32
+    })
+}
+
+// Helper function to count the distinct users with an open report against any of an author's discussions,
+// hidden ones included
+fn open_reporters_of_author(author: &str) -> u32 {
+    open_reporters(|report| {
+        let created_by = load_discussion(report.discussion_id)
+            .or_else(|| try_get(&TRASH_STORAGE, report.discussion_id).map(|trashed| trashed.discussion))
+            .map(|discussion| discussion.created_by);
+        created_by.as_deref() == Some(author)
+    })
+}
+
+// Helper function to hide a discussion and mute its author once reports cross the configured thresholds
+fn apply_report_thresholds(discussion: Discussion) {
+    let settings = settings();
+    let author = discussion.created_by.clone();
+    let discussion_id = discussion.id;
+
+    let reporters = open_reporters(|report| report.discussion_id == discussion_id);
+    if settings.auto_hide_report_threshold > 0 && reporters >= settings.auto_hide_report_threshold {
+        trash_discussion(discussion, "", true);
+        auto_mute(&author, Some(discussion_id), format!("Discussion {} reported by {} users, pending review", discussion_id, reporters));
+        logging::log(LogLevel::Info, "report_discussion", format!("Hid discussion {} after {} reports", discussion_id, reporters));
+        return;
+    }
+
+    let author_reporters = open_reporters_of_author(&author);
+    if settings.auto_mute_report_threshold > 0 && author_reporters >= settings.auto_mute_report_threshold {
+        auto_mute(&author, None, format!("Reported by {} users, pending review", author_reporters));
+    }
+}
+
+// Helper function to mute a user until moderators review the reports against them
+fn auto_mute(username: &str, discussion_id: Option<u64>, reason: String) {
+    let already_muted = user_restrictions(username, RestrictionKind::Mute).into_iter()
+        .any(|restriction| restriction.pending_review && !restriction.lifted && restriction.discussion_id == discussion_id);
+    if already_muted {
+        return;
+    }
+
+    let id = next_id();
+
+    let restriction = Restriction {
+        id,
+        username: username.to_string(),
+        kind: RestrictionKind::Mute,
+        reason,
+        warning_id: None,
+        discussion_id,
+        starts_at: time(),
+        ends_at: u64::MAX,
+        lifted: false,
+        pending_review: true,
+        from_hub: false,
+    };
+
+    insert_restriction(restriction);
+    queue_account_sync_for(username);
+    log_account_action(username, "Muted automatically pending review of reports".to_string());
+}
+
+// Helper function to settle the automatic hide and mutes after moderators resolved a report on a discussion.
+// Once open reports fall below the thresholds a hidden discussion comes back and the mutes are lifted;
+// a removal confirms the hide, and the mute then runs for the usual mute_hours.
+fn review_report_thresholds(discussion_id: u64, removed: bool, resolved_by: &str) {
+    let settings = settings();
+    let now = time();
+
+    let hidden = try_get(&TRASH_STORAGE, discussion_id).filter(|trashed| trashed.pending_review);
+    let author = hidden.as_ref().map(|trashed| trashed.discussion.created_by.clone())
+        .or_else(|| load_discussion(discussion_id).map(|discussion| discussion.created_by));
+
+    if let Some(mut trashed) = hidden {
+        if removed {
+            trashed.pending_review = false;
+            trashed.deleted_by = resolved_by.to_string();
+            TRASH_STORAGE.with(|storage| storage.borrow_mut().insert(discussion_id, trashed));
+        } else if open_reporters(|report| report.discussion_id == discussion_id) < settings.auto_hide_report_threshold {
+            untrash_discussion(&trashed.discussion, resolved_by);
+        }
+    }
+
+    let Some(author) = author else {
+        return;
+    };
+    let author_cleared = open_reporters_of_author(&author) < settings.auto_mute_report_threshold;
+
+    // Only automatic mutes wait for review
+    let pending: Vec<(u64, Restriction)> = user_restrictions(&author, RestrictionKind::Mute).into_iter()
+        .filter(|restriction| restriction.pending_review && !restriction.lifted)
+        .map(|restriction| (restriction.id, restriction))
+        .collect();
+
+    RESTRICTIONS_STORAGE.with(|storage| {
+
+        let mut storage_mut = storage.borrow_mut();
+        for (id, mut restriction) in pending {
+            match restriction.discussion_id {
+                Some(other) if other != discussion_id => continue,
+                Some(_) if removed => {
+                    restriction.pending_review = false;
+                    restriction.ends_at = now.saturating_add(settings.mute_hours as u64 * 3_600_000_000_000);
+                }
+                Some(_) => {
+                    if is_trashed(discussion_id) {
+                        continue;
+                    }
+                    restriction.lifted = true;
+                }
+                None if author_cleared => restriction.lifted = true,
+                None => continue,
+            }
+            storage_mut.insert(id, restriction);
+        }
+    });
+    queue_account_sync_for(&author);
+}
+
+// Helper function to check if a user already has an open report on a discussion
+fn has_open_report(discussion_id: u64, username: &str) -> bool {
+    REPORTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).any(|(_, report)| {
+            report.discussion_id == discussion_id && report.reported_by == username && report.status == ReportStatus::Open
+        })
+    })
+}
+
+// Helper function to open a report, it goes to the group's moderators or to the admins
+fn insert_report(discussion: &Discussion, username: String, reason: String) -> Report {
+    let id = next_id();
+
+    let report = Report {
+        id,
+        discussion_id: discussion.id,
+        group_id: discussion.group_id,
+        reported_by: username,
+        reason,
+        created_at: time(),
+        status: ReportStatus::Open,
+        resolved_by: None,
+    };
+
+    REPORTS_STORAGE.with(|storage| storage.borrow_mut().insert(id, report.clone()));
+
+    report
+}
+
+// Function to get the downvotes on a discussion grouped by reason (only by creator)
+#[ic_cdk::query]
+fn get_downvote_reasons(discussion_id: u64, username: String) -> Result<DownvoteReasonCounts, String> {
+    authenticate(&username)?;
+
+    let discussion = get_owned_discussion(discussion_id, &username)?;
+
+    if tallies_hidden(&discussion, time()) {
+        return Err("Results are hidden until voting closes".to_string());
+    }
+
+    let mut counts = DownvoteReasonCounts::default();
+
+    VOTES_STORAGE.with(|storage| {
+        for (_, vote) in storage.borrow().iter().filter(decoded) {
+            if vote.discussion_id != discussion_id || !matches!(vote.vote_type, VoteType::Downvote) {
+                continue;
+            }
+            match vote.reason {
+                Some(DownvoteReason::OffTopic) => counts.off_topic += 1,
+                Some(DownvoteReason::Inaccurate) => counts.inaccurate += 1,
+                Some(DownvoteReason::Spam) => counts.spam += 1,
+                None => counts.unspecified += 1,
+            }
+        }
+    });
+
+    Ok(counts)
+}
+
+// Function to resolve a group report, optionally removing the discussion (requires the moderate permission)
+#[ic_cdk::update]
+fn resolve_group_report(report_id: u64, remove_discussion: bool, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("resolve_group_report");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let report = try_get(&REPORTS_STORAGE, report_id).ok_or("Report not found")?;
+
+    let group_id = report.group_id.ok_or("Report does not belong to a group")?;
+
+    get_moderated_group(group_id, &username)?;
+
+    settle_report(report, remove_discussion, &username)?;
+
+    let action = if remove_discussion { "removed discussion" } else { "dismissed" };
+    log_group_action(group_id, &username, format!("Resolved report {}: {}", report_id, action));
+
+    Ok("Report resolved".to_string())
+}
+
+// Function to resolve any report, including those on discussions outside groups (admins only)
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn resolve_report(report_id: u64, remove_discussion: bool) -> Result<String, String> {
+    let _meter = metrics::measure("resolve_report");
+
+    ensure_writable()?;
+
+    let report = try_get(&REPORTS_STORAGE, report_id).ok_or("Report not found")?;
+
+    settle_report(report, remove_discussion, &caller().to_text())?;
+
+    Ok("Report resolved".to_string())
+}
+
+// Helper function to close a report, optionally removing the discussion, and review any automatic hide or mute
+fn settle_report(mut report: Report, remove_discussion: bool, resolved_by: &str) -> Result<(), String> {
+    if report.status != ReportStatus::Open {
+        return Err("Report has already been resolved".to_string());
+    }
+
+    if remove_discussion {
+        if let Some(discussion) = load_discussion(report.discussion_id) {
+            trash_discussion(discussion, resolved_by, false);
+        }
+    }
+
+    // Removing the discussion settles every other open report against it
+    let status = if remove_discussion { ReportStatus::ContentRemoved } else { ReportStatus::Dismissed };
+    let report_id = report.id;
+    let discussion_id = report.discussion_id;
+    report.status = status.clone();
+    report.resolved_by = Some(resolved_by.to_string());
+
+    REPORTS_STORAGE.with(|storage| {
+        let mut storage_mut = storage.borrow_mut();
+        storage_mut.insert(report_id, report);
+
+        if remove_discussion {
+            let related: Vec<(u64, Report)> = storage_mut.iter()
+                .filter(|(_, other)| other.discussion_id == discussion_id && other.status == ReportStatus::Open)
+                .collect();
+
+            for (id, mut other) in related {
+                other.status = status.clone();
+                other.resolved_by = Some(resolved_by.to_string());
+                storage_mut.insert(id, other);
+            }
+        }
+    });
+
+    review_report_thresholds(discussion_id, remove_discussion, resolved_by);
+
+    Ok(())
+}
+
+// Function to create an expiring invite code for a group (requires the moderate permission)
+#[ic_cdk::update]
+async fn create_invite(group_id: u64, max_uses: u32, ttl_seconds: u64, username: String) -> Result<GroupInvite, String> {
+    let _meter = metrics::measure("create_invite");
+
+    ensure_writable()?;
+
+    require_feature("groups")?;
+
+    authenticate(&username)?;
+
+    get_moderated_group(group_id, &username)?;
+
+    if max_uses == 0 || ttl_seconds == 0 {
+        return Err("An invite needs at least one use and a positive lifetime".to_string());
+    }
+
+    let (random_bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, message)| format!("Cannot generate invite code: {}", message))?;
+    let code: String = random_bytes.iter().take(8).map(|byte| format!("{:02x}", byte)).collect();
+
+    let id = next_id();
+    let now = time();
+
+    let invite = GroupInvite {
+        id,
+        group_id,
+        code,
+        created_by: username,
+        created_at: now,
+        expires_at: now.saturating_add(ttl_seconds.saturating_mul(1_000_000_000)),
+        max_uses,
+        uses: 0,
+    };
+
+    GROUP_INVITES_STORAGE.with(|storage| storage.borrow_mut().insert(id, invite.clone()));
+    log_group_action(group_id, &invite.created_by, format!("Created invite {}", id));
+
+    Ok(invite)
+}
+
+// Function to join a group with an invite code, bypassing the open setting
+#[ic_cdk::update]
+fn join_with_invite(code: String, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("join_with_invite");
+
+    ensure_writable()?;
+
+    require_feature("groups")?;
+
+    authenticate(&username)?;
+
+    let mut invite = GROUP_INVITES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).find(|(_, invite)| invite.code == code).map(|(_, invite)| invite)
+    }).ok_or("Invite not found")?;
+
+    if invite.expires_at <= time() {
+        return Err("Invite has expired".to_string());
+    }
+
+    if invite.uses >= invite.max_uses {
+        return Err("Invite has no uses left".to_string());
+    }
+
+    if !GROUPS_STORAGE.with(|storage| storage.borrow().contains_key(&invite.group_id)) {
+        return Err(ErrorCode::GroupNotFound.into());
+    }
+
+    if is_group_member(invite.group_id, &username) {
+        return Err(ErrorCode::AlreadyGroupMember.into());
+    }
+
+    add_group_member(invite.group_id, username, GroupRole::Member);
+
+    invite.uses += 1;
+    GROUP_INVITES_STORAGE.with(|storage| storage.borrow_mut().insert(invite.id, invite));
+
+    Ok("Joined group".to_string())
+}
+
+// Function to revoke an invite code (requires the moderate permission)
+#[ic_cdk::update]
+fn revoke_invite(invite_id: u64, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("revoke_invite");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let invite = try_get(&GROUP_INVITES_STORAGE, invite_id).ok_or("Invite not found")?;
+
+    get_moderated_group(invite.group_id, &username)?;
+
+    GROUP_INVITES_STORAGE.with(|storage| storage.borrow_mut().remove(&invite_id));
+    log_group_action(invite.group_id, &username, format!("Revoked invite {}", invite_id));
+
+    Ok("Invite revoked".to_string())
+}
+
+// Function to ask to join a closed group
+#[ic_cdk::update]
+fn request_to_join(group_id: u64, username: String) -> Result<JoinRequest, String> {
+    let _meter = metrics::measure("request_to_join");
+
+    ensure_writable()?;
+
+    require_feature("groups")?;
+
+    authenticate(&username)?;
+
+    if !GROUPS_STORAGE.with(|storage| storage.borrow().contains_key(&group_id)) {
+        return Err(ErrorCode::GroupNotFound.into());
+    }
+
+    if is_group_member(group_id, &username) {
+        return Err(ErrorCode::AlreadyGroupMember.into());
+    }
+
+    let already_pending = JOIN_REQUESTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).any(|(_, request)| {
+            request.group_id == group_id && request.username == username && request.status == JoinRequestStatus::Pending
+        })
+    });
+
+    if already_pending {
+        return Err("A join request is already pending".to_string());
+    }
+
+    let id = next_id();
+
+    let request = JoinRequest {
+        id,
+        group_id,
+        username,
+        created_at: time(),
+        status: JoinRequestStatus::Pending,
+        reviewed_by: None,
+    };
+
+    JOIN_REQUESTS_STORAGE.with(|storage| storage.borrow_mut().insert(id, request.clone()));
+
+    Ok(request)
+}
+
+// Function to approve or deny a pending join request (requires the moderate permission)
+#[ic_cdk::update]
+fn review_join_request(request_id: u64, approve: bool, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("review_join_request");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut request = try_get(&JOIN_REQUESTS_STORAGE, request_id).ok_or("Join request not found")?;
+
+    if request.status != JoinRequestStatus::Pending {
+        return Err("Join request has already been reviewed".to_string());
+    }
+
+    get_moderated_group(request.group_id, &username)?;
+
+    if approve && !is_group_member(request.group_id, &request.username) {
+        add_group_member(request.group_id, request.username.clone(), GroupRole::Member);
+    }
+
+    let action = format!("{} join request from {}", if approve { "Approved" } else { "Denied" }, request.username);
+    log_group_action(request.group_id, &username, action);
+
+    request.status = if approve { JoinRequestStatus::Approved } else { JoinRequestStatus::Denied };
+    request.reviewed_by = Some(username);
+
+    JOIN_REQUESTS_STORAGE.with(|storage| storage.borrow_mut().insert(request_id, request));
+
+    Ok(if approve { "Join request approved" } else { "Join request denied" }.to_string())
+}
+
+// Function to edit a discussion's topic (by its creator, or by trusted users on a wiki discussion)
+#[ic_cdk::update]
+fn edit_discussion(discussion_id: u64, expected_version: u64, new_topic: String, username: String) -> Result<Discussion, EditError> {
+    let _meter = metrics::measure("edit_discussion");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    change_topic(discussion, expected_version, &new_topic, &username, RevisionKind::Edit)
+}
+
+// Function to restore the topic of an earlier revision, recorded as a new revision
+#[ic_cdk::update]
+fn revert_discussion(discussion_id: u64, revision_id: u64, expected_version: u64, username: String) -> Result<Discussion, EditError> {
+    let _meter = metrics::measure("revert_discussion");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    let revision = try_get(&REVISIONS_STORAGE, revision_id)
+        .filter(|revision| revision.discussion_id == discussion_id)
+        .ok_or("Revision not found")?;
+
+    change_topic(discussion, expected_version, &revision.topic, &username, RevisionKind::Revert { revision_id })
+}
+
+// Helper function to check and apply a new topic for edit_discussion and revert_discussion
+fn change_topic(
+    mut discussion: Discussion,
+    expected_version: u64,
+    new_topic: &str,
+    username: &str,
+    kind: RevisionKind,
+) -> Result<Discussion, EditError> {
+    check_topic_editor(&discussion, username)?;
+
+    if discussion.locked {
+        return Err("The discussion is locked".to_string().into());
+    }
+
+    let new_topic = validate_topic(new_topic)?;
+
+    permissions::check_permission(username, Action::PostText(&new_topic))?;
+
+    if discussion.version != expected_version {
+        return Err(EditError::Conflict(Box::new(redact_tallies(discussion))));
+    }
+
+    if let Some(group) = discussion.group_id.and_then(|group_id| try_get(&GROUPS_STORAGE, group_id)) {
+        check_group_filter(&group, &new_topic)?;
+    }
+
+    // Only growth counts against the quota, so a user over it can still shorten a topic
+    if new_topic.len() > discussion.topic.len() {
+        check_storage_quota(username, (new_topic.len() - discussion.topic.len()) as u64)?;
+    }
+
+    record_baseline_revision(&discussion);
+    remove_hashtags(&discussion);
+
+    // A detected language follows the new topic, one the creator chose stays
+    let detected = language::detect_language(&discussion.topic).map(str::to_string);
+    if discussion.language == detected {
+        discussion.language = language::detect_language(&new_topic).map(str::to_string);
+    }
+
+    discussion.topic = new_topic;
+    discussion.version += 1;
+    discussion.last_activity_at = time();
+
+    let id = next_id();
+    let revision = DiscussionRevision {
+        id,
+        discussion_id: discussion.id,
+        version: discussion.version,
+        topic: discussion.topic.clone(),
+        edited_by: username.to_string(),
+        edited_at: time(),
+        kind,
+    };
+    index_discussion_record(revision.discussion_id, StoredMap::DiscussionRevisions, id);
+    REVISIONS_STORAGE.with(|storage| storage.borrow_mut().insert(id, revision));
+
+    save_discussion(discussion.id, discussion.clone());
+    index_topic(&discussion);
+    if is_announced(&discussion) {
+        outbox::publish(EventKind::DiscussionEdited { discussion_id: discussion.id, version: discussion.version });
+    }
+
+    Ok(redact_tallies(discussion))
+}
+
+// Function to offer a discussion to another user, who becomes its owner once they accept (only by creator).
+// A new offer replaces a pending one.
+#[ic_cdk::update]
+fn transfer_discussion(discussion_id: u64, new_owner: String, username: String) -> Result<OwnershipTransfer, String> {
+    let _meter = metrics::measure("transfer_discussion");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let discussion = get_owned_discussion(discussion_id, &username)?;
+
+    if new_owner == username {
+        return Err("The discussion already belongs to this user".to_string());
+    }
+
+    find_user(&new_owner).ok_or(ErrorCode::UserNotFound)?;
+
+    if let Some(group) = discussion.group_id.and_then(|group_id| try_get(&GROUPS_STORAGE, group_id)) {
+        if !has_group_permission(&group, &new_owner, GroupAction::Post) {
+            return Err("The new owner is not allowed to post in this group".to_string());
+        }
+    }
+
+    let transfer = OwnershipTransfer {
+        discussion_id,
+        from: username.clone(),
+        to: new_owner.clone(),
+        created_at: time(),
+    };
+
+    TRANSFERS_STORAGE.with(|storage| storage.borrow_mut().insert(discussion_id, transfer.clone()));
+    log_account_action(&username, format!("Offered discussion {} to {}", discussion_id, new_owner));
+    notify(&new_owner, NotificationKind::TransferOffered { discussion_id, from: username });
+
+    Ok(transfer)
+}
+
+// Function to accept a pending ownership transfer, by the user it was offered to
+#[ic_cdk::update]
+fn accept_discussion_transfer(discussion_id: u64, username: String) -> Result<Discussion, String> {
+    let _meter = metrics::measure("accept_discussion_transfer");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let transfer = try_get(&TRANSFERS_STORAGE, discussion_id)
+        .filter(|transfer| transfer.to == username)
+        .ok_or("No pending transfer of this discussion to the user")?;
+
+    let mut discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    // The offer lapses if the discussion changed hands some other way since it was made
+    if discussion.created_by != transfer.from {
+        TRANSFERS_STORAGE.with(|storage| storage.borrow_mut().remove(&discussion_id));
+        return Err("The transfer is no longer valid".to_string());
+    }
+
+    record_baseline_revision(&discussion);
+
+    discussion.created_by = username.clone();
+    // A private discussion stays visible to its previous owner
+    if let Visibility::Private { allowed_users } = &mut discussion.visibility {
+        allowed_users.retain(|user| *user != username);
+        if !allowed_users.contains(&transfer.from) {
+            allowed_users.push(transfer.from.clone());
+        }
+    }
+    discussion.version += 1;
+
+    let id = next_id();
+    let revision = DiscussionRevision {
+        id,
+        discussion_id,
+        version: discussion.version,
+        topic: discussion.topic.clone(),
+        edited_by: username.clone(),
+        edited_at: time(),
+        kind: RevisionKind::Transfer { from: transfer.from.clone(), to: username.clone() },
+    };
+    index_discussion_record(revision.discussion_id, StoredMap::DiscussionRevisions, id);
+    REVISIONS_STORAGE.with(|storage| storage.borrow_mut().insert(id, revision));
+    TRANSFERS_STORAGE.with(|storage| storage.borrow_mut().remove(&discussion_id));
+
+    save_discussion(discussion_id, discussion.clone());
+    log_account_action(&transfer.from, format!("Transferred discussion {} to {}", discussion_id, username));
+    log_account_action(&username, format!("Took over discussion {} from {}", discussion_id, transfer.from));
+
+    Ok(redact_tallies(discussion))
+}
+
+// Function to decline a pending ownership transfer, or withdraw it as the current owner
+#[ic_cdk::update]
+fn decline_discussion_transfer(discussion_id: u64, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("decline_discussion_transfer");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    try_get(&TRANSFERS_STORAGE, discussion_id)
+        .filter(|transfer| transfer.to == username || transfer.from == username)
+        .ok_or("No pending transfer of this discussion involving the user")?;
+
+    TRANSFERS_STORAGE.with(|storage| storage.borrow_mut().remove(&discussion_id));
+
+    Ok("Transfer cancelled".to_string())
+}
+
+// Function to get the pending ownership transfers offered by or to a user
+#[ic_cdk::query]
+fn get_pending_transfers(username: String) -> Result<Vec<OwnershipTransfer>, String> {
+    authenticate(&username)?;
+
+    Ok(TRANSFERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .map(|(_, transfer)| transfer)
+            .filter(|transfer| transfer.from == username || transfer.to == username)
+            .collect()
+    }))
+}
+
+// Helper function to check a user may change a discussion's topic: its creator, or on a wiki discussion
+// anyone who can see it and has reached wiki_edit_trust_level
+fn check_topic_editor(discussion: &Discussion, username: &str) -> Result<(), String> {
+    if discussion.created_by == username {
+        return Ok(());
+    }
+
+    if !discussion.wiki || !can_access_discussion(discussion, username) {
+        return Err("Only the creator can edit the discussion".to_string());
+    }
+
+    let user = find_user(username).ok_or(ErrorCode::UserNotFound)?;
+    let required = settings().wiki_edit_trust_level;
+    if permissions::compute_trust_level(&user) < required {
+        return Err(format!("Trust level {:?} is needed to edit wiki discussions", required));
+    }
+
+    Ok(())
+}
+
+// Helper function to describe a discussion's topic before its first recorded change
+fn baseline_revision(discussion: &Discussion) -> DiscussionRevision {
+    DiscussionRevision {
+        id: 0,
+        discussion_id: discussion.id,
+        version: discussion.version,
+        topic: discussion.topic.clone(),
+        edited_by: discussion.created_by.clone(),
+        edited_at: discussion.created_at,
+        kind: RevisionKind::Baseline,
+    }
+}
+
+// Helper function to store the baseline of a discussion about to change for the first time, so it can be reverted to
+fn record_baseline_revision(discussion: &Discussion) {
+    let has_history = REVISIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).any(|(_, revision)| revision.discussion_id == discussion.id)
+    });
+    if has_history {
+        return;
+    }
+
+    let id = next_id();
+    let revision = DiscussionRevision { id, ..baseline_revision(discussion) };
+    index_discussion_record(revision.discussion_id, StoredMap::DiscussionRevisions, id);
+    REVISIONS_STORAGE.with(|storage| storage.borrow_mut().insert(id, revision));
+}
+
+// Helper function to check if a user may see and vote on a discussion
+fn can_access_discussion(discussion: &Discussion, username: &str) -> bool {
+    if let Some(group_id) = discussion.group_id {
+        let members_only = GROUPS_STORAGE.with(|storage| {
+            storage.borrow().get(&group_id).filter(decoded).map(|group| group.settings.members_only)
+        }).unwrap_or(false);
+
+        if members_only && !is_group_member(group_id, username) {
+            return false;
+        }
+    }
+
+    match &discussion.visibility {
+        Visibility::Public | Visibility::Unlisted => true,
+        Visibility::Private { allowed_users } => {
+            discussion.created_by == username || allowed_users.iter().any(|user| user == username)
+        }
+    }
+}
+
+// Helper function to load a discussion that only its creator may manage
+fn get_owned_discussion(discussion_id: u64, username: &str) -> Result<Discussion, String> {
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if discussion.created_by != username {
+        return Err(ErrorCode::NotCreator.into());
+    }
+
+    Ok(discussion)
+}
+
+// Helper function to check whether a discussion's voting deadline has passed
+fn is_voting_closed(discussion: &Discussion, now: u64) -> bool {
+    discussion.voting_ends_at.is_some_and(|ends_at| now >= ends_at)
+}
+
+// Helper function to get when a discussion's result becomes final, after the reveal window if any
+fn results_final_at(discussion: &Discussion) -> Option<u64> {
+    discussion.reveal_ends_at.or(discussion.voting_ends_at)
+}
+
+// Helper function to check whether a discussion's tally can no longer change
+fn is_result_final(discussion: &Discussion, now: u64) -> bool {
+    results_final_at(discussion).is_some_and(|final_at| now >= final_at)
+}
+
+// Function to set or clear the voting deadline of a discussion (only by creator, before it closes)
+#[ic_cdk::update]
+fn set_voting_deadline(discussion_id: u64, voting_ends_at: Option<u64>, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("set_voting_deadline");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = get_owned_discussion(discussion_id, &username)?;
+    let now = time();
+
+    if is_voting_closed(&discussion, now) {
+        return Err(ErrorCode::VotingClosed.into());
+    }
+
+    if voting_ends_at.is_some_and(|ends_at| ends_at <= now) {
+        return Err("Voting deadline must be in the future".to_string());
+    }
+
+    if voting_ends_at.is_none() && discussion.blind {
+        return Err("Blind votes need a deadline".to_string());
+    }
+
+    match (voting_ends_at, discussion.reveal_ends_at) {
+        (None, Some(_)) => return Err("Commit-reveal votes need a deadline".to_string()),
+        (Some(ends_at), Some(reveal_ends_at)) if ends_at >= reveal_ends_at => {
+            return Err("Voting deadline must be before the end of the reveal window".to_string());
+        }
+        _ => {}
+    }
+
+    discussion.voting_ends_at = voting_ends_at;
+    discussion.version += 1;
+
+    save_discussion(discussion_id, discussion);
+
+    Ok("Voting deadline updated".to_string())
+}
+
+// Function to hide or show running tallies until the voting deadline (only by creator, before it closes)
+#[ic_cdk::update]
+fn set_blind_voting(discussion_id: u64, blind: bool, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("set_blind_voting");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = get_owned_discussion(discussion_id, &username)?;
+
+    if is_voting_closed(&discussion, time()) {
+        return Err(ErrorCode::VotingClosed.into());
+    }
+
+    if blind && discussion.voting_ends_at.is_none() {
+        return Err("Blind votes need a deadline".to_string());
+    }
+
+    discussion.blind = blind;
+    discussion.version += 1;
+
+    save_discussion(discussion_id, discussion);
+
+    Ok(if blind { "Tallies hidden until voting closes" } else { "Tallies visible" }.to_string())
+}
+
+// Helper function to check whether a discussion's tallies are currently hidden
+fn tallies_hidden(discussion: &Discussion, now: u64) -> bool {
+    discussion.blind && !is_result_final(discussion, now)
+}
+
+// Helper function to check if subscribers may hear about a discussion. Only discussions anyone can find are
+// announced, so private, unlisted and members-only ones never reach the outbox.
+fn is_announced(discussion: &Discussion) -> bool {
+    if !matches!(discussion.visibility, Visibility::Public) {
+        return false;
+    }
+
+    let members_only = discussion.group_id.and_then(|group_id| {
+        GROUPS_STORAGE.with(|storage| storage.borrow().get(&group_id).filter(decoded).map(|group| group.settings.members_only))
+    });
+    members_only != Some(true)
+}
+
+// Helper function to blank the tallies of a running blind vote before it leaves the canister
+fn redact_tallies(mut discussion: Discussion) -> Discussion {
+    if tallies_hidden(&discussion, time()) {
+        discussion.upvotes = 0;
+        discussion.downvotes = 0;
+        discussion.wilson_score = 0.0;
+        discussion.controversy_score = 0.0;
+    }
+    discussion
+}
+
+// Helper function to redact every discussion of a page
+fn redact_page(mut page: Page<Discussion>) -> Page<Discussion> {
+    page.items = page.items.into_iter().map(redact_tallies).collect();
+    page
+}
+
+// Helper function to freeze and mark the tallies of discussions whose deadline has passed, looking at up to
+// FINALIZE_BATCH due entries of the index per tick
+fn finalize_closed_votes(now: u64) {
+    let due: Vec<(u64, u64)> = DISCUSSIONS_BY_FINAL_AT.with(|index| {
+        index.borrow().range(..=(now, u64::MAX)).map(|(key, _)| key).take(FINALIZE_BATCH).collect()
+    });
+
+    for (final_at, discussion_id) in due {
+        DISCUSSIONS_BY_FINAL_AT.with(|index| index.borrow_mut().remove(&(final_at, discussion_id)));
+
+        // Entries of deleted discussions or moved deadlines are only dropped
+        let Some(mut discussion) = try_get(&DISCUSSIONS_STORAGE, discussion_id)
+            .filter(|discussion| !discussion.results_final && results_final_at(discussion) == Some(final_at))
+        else {
+            continue;
+        };
+
+        discussion.results_final = true;
+        save_discussion(discussion_id, discussion);
+    }
+}
+
+// Function to change who can see a discussion (only by creator)
+#[ic_cdk::update]
+fn set_discussion_visibility(discussion_id: u64, visibility: Visibility, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("set_discussion_visibility");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = get_owned_discussion(discussion_id, &username)?;
+
+    if let Visibility::Private { allowed_users } = &visibility {
+        if allowed_users.len() > MAX_PRIVATE_MEMBERS {
+            return Err(format!("A private discussion can have at most {} members", MAX_PRIVATE_MEMBERS));
+        }
+        if let Some(user) = allowed_users.iter().find(|user| !is_user_registered(user)) {
+            return Err(format!("User {} is not registered", user));
+        }
+    }
+
+    // The trending counters follow whether the discussion is forum-listed
+    remove_hashtags(&discussion);
+    discussion.visibility = visibility;
+    discussion.version += 1;
+
+    insert_hashtags(&discussion);
+    save_discussion(discussion_id, discussion);
+
+    Ok("Discussion visibility updated".to_string())
+}
+
+// Function to lock or unlock a discussion (by a moderator of its group or an admin)
+#[ic_cdk::update]
+fn set_discussion_locked(discussion_id: u64, locked: bool, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("set_discussion_locked");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if !is_discussion_moderator(&discussion, &username) {
+        return Err("Only a moderator can lock the discussion".to_string());
+    }
+
+    discussion.locked = locked;
+    discussion.version += 1;
+    // An unlocked discussion gets a full idle period before it can be locked again
+    if !locked {
+        discussion.last_activity_at = time();
+    }
+
+    save_discussion(discussion_id, discussion);
+
+    Ok(if locked { "Discussion locked" } else { "Discussion unlocked" }.to_string())
+}
+
+// Function to ask moderators to unlock a locked discussion, one pending request per user and discussion
+#[ic_cdk::update]
+fn request_unlock(discussion_id: u64, reason: String, username: String) -> Result<UnlockRequest, String> {
+    let _meter = metrics::measure("request_unlock");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if !can_access_discussion(&discussion, &username) {
+        return Err(ErrorCode::DiscussionNotFound.into());
+    }
+
+    if !discussion.locked {
+        return Err("The discussion is not locked".to_string());
+    }
+
+    let reason = validate_reason(&reason)?;
+
+    let pending = UNLOCK_REQUESTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).any(|(_, request)| request.discussion_id == discussion_id && request.username == username)
+    });
+    if pending {
+        return Err("User has already asked to unlock this discussion".to_string());
+    }
+
+    let request = UnlockRequest {
+        id: next_id(),
+        discussion_id,
+        username,
+        reason,
+        created_at: time(),
+    };
+
+    index_discussion_record(request.discussion_id, StoredMap::UnlockRequests, request.id);
+    UNLOCK_REQUESTS_STORAGE.with(|storage| storage.borrow_mut().insert(request.id, request.clone()));
+
+    Ok(request)
+}
+
+// Function to page through the unlock requests the caller may decide. Group moderators pass their username,
+// admins may leave it out.
+#[ic_cdk::query]
+fn get_unlock_requests(username: Option<String>, cursor: Option<String>, limit: Option<u32>) -> Result<Page<UnlockRequest>, String> {
+    if let Some(username) = &username {
+        authenticate(username)?;
+    }
+
+    paginate(&UNLOCK_REQUESTS_STORAGE, cursor, limit, |_, request| {
+        load_discussion(request.discussion_id)
+            .is_some_and(|discussion| is_discussion_moderator(&discussion, username.as_deref().unwrap_or_default()))
+    })
+}
+
+// Function to grant or turn down an unlock request. Granting unlocks the discussion and settles every
+// request for it.
+#[ic_cdk::update]
+fn decide_unlock_request(request_id: u64, unlock: bool, username: Option<String>) -> Result<String, String> {
+    let _meter = metrics::measure("decide_unlock_request");
+
+    ensure_writable()?;
+
+    if let Some(username) = &username {
+        authenticate(username)?;
+    }
+
+    let request = try_get(&UNLOCK_REQUESTS_STORAGE, request_id).ok_or("Unlock request not found")?;
+    let mut discussion = load_discussion(request.discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if !is_discussion_moderator(&discussion, username.as_deref().unwrap_or_default()) {
+        return Err("Only moderators can decide unlock requests".to_string());
+    }
+
+    let settled: Vec<UnlockRequest> = UNLOCK_REQUESTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .map(|(_, other)| other)
+            .filter(|other| other.id == request_id || (unlock && other.discussion_id == request.discussion_id))
+            .collect()
+    });
+
+    for other in settled {
+        UNLOCK_REQUESTS_STORAGE.with(|storage| storage.borrow_mut().remove(&other.id));
+        notify(&other.username, NotificationKind::UnlockDecided { discussion_id: other.discussion_id, unlocked: unlock });
+    }
+
+    if !unlock {
+        return Ok("Unlock request declined".to_string());
+    }
+
+    discussion.locked = false;
+    discussion.version += 1;
+    discussion.last_activity_at = time();
+    save_discussion(discussion.id, discussion);
+
+    Ok("Discussion unlocked".to_string())
+}
+
+// Helper function run on each background tick to bring up to VOTE_EXPIRY_BATCH votes in line with
+// vote_expiry_days, taking votes that aged out off their discussion's tally and putting back votes that
+// count again after the window was widened or turned off
+fn expire_old_votes(now: u64) {
+    let expiry_days = settings().vote_expiry_days;
+    let cursor = VOTE_EXPIRY_CURSOR.with(|cursor| *cursor.borrow());
+
+    let batch: Vec<Vote> = VOTES_STORAGE.with(|storage| {
+        storage.borrow().range(cursor..).filter(decoded).take(VOTE_EXPIRY_BATCH + 1).map(|(_, vote)| vote).collect()
+    });
+    let next = if batch.len() > VOTE_EXPIRY_BATCH { batch[VOTE_EXPIRY_BATCH].id } else { 0 };
+    VOTE_EXPIRY_CURSOR.with(|cursor| *cursor.borrow_mut() = next);
+
+    let mut changed: BTreeMap<u64, Discussion> = BTreeMap::new();
+
+    for mut vote in batch.into_iter().take(VOTE_EXPIRY_BATCH) {
+        let expired = expiry_days > 0 && now.saturating_sub(vote.created_at) >= expiry_days as u64 * 86_400_000_000_000;
+        if vote.expired == expired {
+            continue;
+        }
+
+        let discussion = match changed.remove(&vote.discussion_id) {
+            Some(discussion) => Some(discussion),
+            None => load_discussion(vote.discussion_id),
+        };
+        let Some(mut discussion) = discussion.filter(|discussion| !discussion.results_final) else {
+            continue;
+        };
+
+        let tally = match vote.vote_type {
+            VoteType::Upvote => &mut discussion.upvotes,
+            VoteType::Downvote => &mut discussion.downvotes,
+        };
+        *tally = if expired { tally.saturating_sub(1) } else { *tally + 1 };
+        tally_received_vote(&discussion.created_by, &vote.vote_type, !expired);
+
+        vote.expired = expired;
+        mark_vote_root_stale(vote.discussion_id);
+        VOTES_STORAGE.with(|storage| storage.borrow_mut().insert(vote.id, vote));
+        changed.insert(discussion.id, discussion);
+    }
+
+    for (discussion_id, mut discussion) in changed {
+        update_vote_scores(&mut discussion);
+        save_discussion(discussion_id, discussion);
+    }
+}
+
+// Helper function run on each background tick to decay the karma of up to KARMA_DECAY_BATCH accounts that
+// have been inactive for karma_decay_after_days. Each decay is a negative KarmaAward, so karma, weights
+// and leaderboards pick it up without further bookkeeping.
+fn decay_inactive_karma(now: u64) {
+    let policy = settings();
+    if policy.karma_decay_after_days == 0 {
+        return;
+    }
+
+    let cursor = KARMA_DECAY_CURSOR.with(|cursor| *cursor.borrow());
+    let batch: Vec<User> = USERS_STORAGE.with(|storage| {
+        storage.borrow().range(cursor..).filter(decoded).take(KARMA_DECAY_BATCH + 1).map(|(_, user)| user).collect()
+    });
+    let next = if batch.len() > KARMA_DECAY_BATCH { batch[KARMA_DECAY_BATCH].id } else { 0 };
+    KARMA_DECAY_CURSOR.with(|cursor| *cursor.borrow_mut() = next);
+
+    let inactive_after = policy.karma_decay_after_days as u64 * 86_400_000_000_000;
+    let interval = policy.karma_decay_interval_days as u64 * 86_400_000_000_000;
+
+    // Last activity comes from the user's activity counters and karma from their tally, not from scanning
+    for user in batch.into_iter().take(KARMA_DECAY_BATCH) {
+        let activity = try_get(&USER_ACTIVITY_STORAGE, user.id).unwrap_or_default();
+        let active_at = user.created_at.max(activity.last_activity_at);
+        let tally = try_get(&KARMA_TALLIES_STORAGE, user.id).unwrap_or_default();
+        let (decayed_at, karma) = (tally.last_decayed_at, tally_total(&tally));
+
+        if karma <= 0 || now.saturating_sub(active_at) < inactive_after || now.saturating_sub(decayed_at) < interval {
+            continue;
+        }
+
+        let amount = (karma * policy.karma_decay_percent as i64 / 100).max(1);
+        award_karma(&user.username, -amount, KARMA_DECAY_REASON);
+    }
+}
+
+// Helper function run on each background tick to lock up to AUTO_LOCK_BATCH idle discussions, resuming
+// where the previous tick stopped
+fn auto_lock_stale_discussions(now: u64) {
+    let forum_days = settings().auto_lock_after_days;
+    let cursor = AUTO_LOCK_CURSOR.with(|cursor| *cursor.borrow());
+
+    let batch: Vec<Discussion> = DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().range(cursor..).filter(decoded).take(AUTO_LOCK_BATCH + 1).map(|(_, discussion)| discussion).collect()
+    });
+    let next = if batch.len() > AUTO_LOCK_BATCH { batch[AUTO_LOCK_BATCH].id } else { 0 };
+    AUTO_LOCK_CURSOR.with(|cursor| *cursor.borrow_mut() = next);
+
+    for mut discussion in batch.into_iter().take(AUTO_LOCK_BATCH).filter(|discussion| !discussion.locked) {
+        let days = discussion.group_id
+            .and_then(|group_id| try_get(&GROUPS_STORAGE, group_id))
+            .and_then(|group| group.settings.auto_lock_after_days)
+            .unwrap_or(forum_days);
+        let last_activity_at = discussion.last_activity_at.max(discussion.created_at);
+
+        if days > 0 && now.saturating_sub(last_activity_at) >= days as u64 * 86_400_000_000_000 {
+            discussion.locked = true;
+            discussion.version += 1;
+            save_discussion(discussion.id, discussion);
+        }
+    }
+}
+
+// Function to flag a discussion as sensitive or clear the flag (by its creator, a moderator of its group or an admin)
+#[ic_cdk::update]
+fn set_discussion_sensitive(discussion_id: u64, sensitive: bool, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("set_discussion_sensitive");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if discussion.created_by != username && !is_discussion_moderator(&discussion, &username) {
+        return Err("Only the creator or a moderator can flag the discussion".to_string());
+    }
+
+    discussion.sensitive = sensitive;
+    discussion.version += 1;
+
+    save_discussion(discussion_id, discussion);
+
+    Ok("Discussion sensitivity updated".to_string())
+}
+
+// Function to turn wiki mode on or off, letting trusted users edit the topic (only by creator)
+#[ic_cdk::update]
+fn set_wiki_mode(discussion_id: u64, wiki: bool, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("set_wiki_mode");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = get_owned_discussion(discussion_id, &username)?;
+
+    discussion.wiki = wiki;
+    discussion.version += 1;
+
+    save_discussion(discussion_id, discussion);
+
+    Ok(if wiki { "Wiki mode enabled" } else { "Wiki mode disabled" }.to_string())
+}
+
+// Function to get the topic revisions of a discussion, oldest first. A discussion that was never edited has
+// only its baseline.
+#[ic_cdk::query]
+fn get_discussion_revisions(discussion_id: u64, username: String) -> Result<Vec<DiscussionRevision>, String> {
+    let username = resolve_viewer(username);
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if !can_access_discussion(&discussion, &username) {
+        return Err(ErrorCode::DiscussionNotFound.into());
+    }
+
+    let mut revisions: Vec<DiscussionRevision> = REVISIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, revision)| revision).filter(|revision| revision.discussion_id == discussion_id).collect()
+    });
+
+    if revisions.is_empty() {
+        revisions.push(baseline_revision(&discussion));
+    }
+    revisions.sort_by_key(|revision| revision.id);
+
+    Ok(revisions)
+}
+
+// Function to set or clear the content warning shown before a discussion (only by creator)
+#[ic_cdk::update]
+fn set_content_warning(discussion_id: u64, content_warning: Option<String>, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("set_content_warning");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = get_owned_discussion(discussion_id, &username)?;
+
+    let content_warning = match content_warning {
+        Some(label) => Some(sanitize_user_text(label.trim())?).filter(|label| !label.is_empty()),
+        None => None,
+    };
+
+    if content_warning.as_ref().is_some_and(|label| label.chars().count() > MAX_CONTENT_WARNING_LENGTH) {
+        return Err(format!("Content warning cannot exceed {} characters", MAX_CONTENT_WARNING_LENGTH));
+    }
+
+    discussion.content_warning = content_warning;
+    discussion.version += 1;
+
+    save_discussion(discussion_id, discussion);
+
+    Ok("Content warning updated".to_string())
+}
+
+// Function to cap who may vote on a discussion (only by creator)
+#[ic_cdk::update]
+fn set_participation_limits(discussion_id: u64, limits: ParticipationLimits, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("set_participation_limits");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = get_owned_discussion(discussion_id, &username)?;
+
+    if limits.members_only && discussion.group_id.is_none() {
+        return Err("Only group discussions can be limited to members".to_string());
+    }
+
+    if limits.max_voters == Some(0) {
+        return Err("max_voters must be at least 1".to_string());
+    }
+
+    if limits.min_account_age_days.is_some_and(|days| days > MAX_MIN_ACCOUNT_AGE_DAYS) {
+        return Err(format!("min_account_age_days cannot exceed {}", MAX_MIN_ACCOUNT_AGE_DAYS));
+    }
+
+    discussion.participation = limits;
+    discussion.version += 1;
+
+    save_discussion(discussion_id, discussion);
+
+    Ok("Participation limits updated".to_string())
+}
+
+// Function to set the language of a discussion (only by creator); None goes back to the detected one
+#[ic_cdk::update]
+fn set_discussion_language(discussion_id: u64, language: Option<String>, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("set_discussion_language");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = get_owned_discussion(discussion_id, &username)?;
+
+    if let Some(code) = &language {
+        if !language::is_language_code(code) {
+            return Err(format!("{} is not an ISO 639-1 language code", code));
+        }
+    }
+
+    discussion.language = language.or_else(|| language::detect_language(&discussion.topic).map(str::to_string));
+    discussion.version += 1;
+
+    save_discussion(discussion_id, discussion);
+
+    Ok("Discussion language updated".to_string())
+}
+
+// Function to let another user into a private discussion (only by creator)
+#[ic_cdk::update]
+fn add_discussion_member(discussion_id: u64, member: String, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("add_discussion_member");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = get_owned_discussion(discussion_id, &username)?;
+
+    if !is_user_registered(&member) {
+        return Err("Member is not registered".to_string());
+    }
+
+    match &mut discussion.visibility {
+        Visibility::Private { allowed_users } => {
+            if allowed_users.contains(&member) {
+                return Err("User is already a member".to_string());
+            }
+            if allowed_users.len() >= MAX_PRIVATE_MEMBERS {
+                return Err(format!("A private discussion can have at most {} members", MAX_PRIVATE_MEMBERS));
+            }
+            allowed_users.push(member);
+        }
+        _ => return Err("Discussion is not private".to_string()),
+    }
+
+    discussion.version += 1;
+
+    save_discussion(discussion_id, discussion);
+
+    Ok("Member added to discussion".to_string())
+}
+
+// Function to revoke a user's access to a private discussion (only by creator)
+#[ic_cdk::update]
+fn remove_discussion_member(discussion_id: u64, member: String, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("remove_discussion_member");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = get_owned_discussion(discussion_id, &username)?;
+
+    match &mut discussion.visibility {
+        Visibility::Private { allowed_users } => {
+            let count = allowed_users.len();
+            allowed_users.retain(|user| *user != member);
+            if allowed_users.len() == count {
+                return Err("User is not a member".to_string());
+            }
+        }
+        _ => return Err("Discussion is not private".to_string()),
+    }
+
+    discussion.version += 1;
+
+    save_discussion(discussion_id, discussion);
+
+    Ok("Member removed from discussion".to_string())
+}
+
+// Function to vote on a discussion
+#[ic_cdk::update]
+fn vote_discussion(vote_type: VoteType, discussion_id: u64, username: String, idempotency_key: Option<String>, reason: Option<DownvoteReason>) -> Result<String, String> {
+    let _meter = metrics::measure("vote_discussion");
+
+    ensure_writable()?;
+
+    with_idempotency("vote_discussion", idempotency_key, || {
+        authenticate(&username)?;
+
+        let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+        if is_voting_closed(&discussion, time()) {
+            return Err(ErrorCode::VotingClosed.into());
+        }
+
+        if discussion.reveal_ends_at.is_some() {
+            return Err("This discussion uses commit-reveal voting".to_string());
+        }
+
+        check_vote_allowed(&discussion, &username, &vote_type)?;
+        check_downvote_reason(&vote_type, &reason)?;
+
+        record_vote(discussion, &username, vote_type, Some(voter_principal()?), reason);
+
+        Ok("Vote recorded for discussion".to_string())
+    })
+}
+
+// Helper function to check that a reason comes only with a downvote, and with every downvote if required
+fn check_downvote_reason(vote_type: &VoteType, reason: &Option<DownvoteReason>) -> Result<(), String> {
+    match (vote_type, reason) {
+        (VoteType::Upvote, Some(_)) => Err("Only downvotes take a reason".to_string()),
+        (VoteType::Downvote, None) if settings().require_downvote_reason => Err("A reason is required to downvote".to_string()),
+        _ => Ok(()),
+    }
+}
+
+// Helper function to enforce a discussion's participation limits for a new voter, given the voters so far
+fn check_participation(discussion: &Discussion, username: &str, voters: u64) -> Result<(), String> {
+    let limits = &discussion.participation;
+
+    if limits.members_only && !discussion.group_id.is_some_and(|group_id| is_group_member(group_id, username)) {
+        return Err("Only group members can vote on this discussion".to_string());
+    }
+
+    if let Some(days) = limits.min_account_age_days {
+        let created_at = find_user(username).map(|user| user.created_at).unwrap_or_default();
+        if time().saturating_sub(created_at) < days as u64 * 86_400_000_000_000 {
+            return Err(format!("Accounts must be at least {} days old to vote on this discussion", days));
+        }
+    }
+
+    if limits.max_voters.is_some_and(|max_voters| voters >= max_voters) {
+        return Err("This discussion has reached its voter limit".to_string());
+    }
+
+    Ok(())
+}
+
+// Helper function to check that a user may cast a vote of this type on a discussion
+fn check_vote_allowed(discussion: &Discussion, username: &str, vote_type: &VoteType) -> Result<(), String> {
+    let settings = settings();
+    let enabled = match vote_type {
+        VoteType::Upvote => settings.upvotes_enabled,
+        VoteType::Downvote => settings.downvotes_enabled,
+    };
+
+    if !enabled {
+        return Err("This vote type is disabled".to_string());
+    }
+
+    if discussion.locked {
+        return Err("The discussion is locked".to_string());
+    }
+
+    if matches!(vote_type, VoteType::Downvote) {
+        permissions::check_permission(username, Action::Downvote)?;
+    }
+
+    check_participation(discussion, username, discussion.upvotes + discussion.downvotes)?;
+
+    if !can_access_discussion(discussion, username) {
+        return Err("User is not a member of this private discussion".to_string());
+    }
+
+    check_personhood(discussion, username)?;
+
+    if let Some(group_id) = discussion.group_id {
+        let group = try_get(&GROUPS_STORAGE, group_id).ok_or(ErrorCode::GroupNotFound)?;
+
+        if !has_group_permission(&group, username, GroupAction::Vote) {
+            return Err("User is not allowed to vote in this group".to_string());
+        }
+    }
+
+    check_not_voted(discussion.id, username, voter_principal()?)
+}
+
+// Helper function to reject a second vote on a discussion by the same user or principal
+fn check_not_voted(discussion_id: u64, username: &str, voter: Principal) -> Result<(), String> {
+    let votes = discussion_votes(discussion_id);
+
+    if votes.iter().any(|vote| vote.by == username) {
+        return Err("User has already voted on this discussion".to_string());
+    }
+
+    // One vote per principal, so a caller can't vote again through another (unlinked) account
+    if votes.iter().any(|vote| vote.voter == Some(voter)) {
+        return Err("This identity has already voted on this discussion".to_string());
+    }
+
+    Ok(())
+}
+
+// Helper function to check that the voter has a current personhood verification for the calling identity
+// when the discussion asks for one
+fn check_personhood(discussion: &Discussion, username: &str) -> Result<(), String> {
+    if !discussion.requires_personhood {
+        return Ok(());
+    }
+
+    // A verification vouches for the identity the provider checked, not for everyone acting as the account
+    let user = find_user(username).ok_or(ErrorCode::UserNotFound)?;
+    let verified = try_get(&PERSONHOOD_STORAGE, user.id)
+        .is_some_and(|verification| verification.principal == caller() && verification.expires_at > time());
+
+    if !verified {
+        return Err("This discussion requires a verified-human voter, call verify_personhood first".to_string());
+    }
+
+    Ok(())
+}
+
+// Function to check the caller with the configured proof-of-personhood provider and cache the result
+#[ic_cdk::update]
+async fn verify_personhood(username: String) -> Result<PersonhoodVerification, String> {
+    let _meter = metrics::measure("verify_personhood");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let settings = settings();
+    let provider = settings.personhood_provider.ok_or("Personhood verification is not configured")?;
+    let principal = caller();
+
+    if principal == Principal::anonymous() {
+        return Err("Personhood verification needs an authenticated caller".to_string());
+    }
+
+    let (is_human,): (bool,) = ic_cdk::call(provider, "is_human", (principal,))
+        .await
+        .map_err(|(_, message)| format!("Personhood provider call failed: {}", message))?;
+
+    if !is_human {
+        return Err("Personhood provider did not verify this identity".to_string());
+    }
+
+    // The account may have been deleted while waiting for the provider
+    authenticate(&username)?;
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    let now = time();
+    let verification = PersonhoodVerification {
+        principal,
+        verified_at: now,
+        expires_at: now.saturating_add(settings.personhood_ttl_seconds.saturating_mul(1_000_000_000)),
+    };
+
+    PERSONHOOD_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, verification.clone()));
+
+    Ok(verification)
+}
+
+// Function to require verified-human voters on a discussion, or lift the requirement (only by creator)
+#[ic_cdk::update]
+fn set_requires_personhood(discussion_id: u64, required: bool, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("set_requires_personhood");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = get_owned_discussion(discussion_id, &username)?;
+
+    if required && settings().personhood_provider.is_none() {
+        return Err("Personhood verification is not configured".to_string());
+    }
+
+    discussion.requires_personhood = required;
+    discussion.version += 1;
+
+    save_discussion(discussion_id, discussion);
+
+    Ok(if required { "Voters must be verified humans" } else { "Personhood requirement lifted" }.to_string())
+}
+
+// Helper function to get the principal a vote is attributed to. Anonymous callers can't vote, they could
+// vote again through every account without a linked principal.
+fn voter_principal() -> Result<Principal, String> {
+    Some(caller())
+        .filter(|principal| *principal != Principal::anonymous())
+        .ok_or_else(|| "Voting needs an authenticated caller".to_string())
+}
+
+// Helper function run by the background tick to attribute one batch of votes cast before principals were
+// tracked to their account's first linked principal. Resumes where the last batch stopped, also across
+// upgrades, and never runs again once every vote has been visited.
+fn backfill_vote_voters() {
+    let cursor = VOTER_BACKFILL_CURSOR.with(|cursor| *cursor.borrow().get());
+    if cursor == u64::MAX {
+        return;
+    }
+
+    let batch: Vec<(u64, Vote)> = VOTES_STORAGE.with(|storage| {
+        storage.borrow().range(cursor..).filter(decoded).take(VOTER_BACKFILL_BATCH + 1).collect()
+    });
+    let next = if batch.len() > VOTER_BACKFILL_BATCH { batch[VOTER_BACKFILL_BATCH].0 } else { u64::MAX };
+
+    let pending: Vec<(u64, Vote)> = batch.into_iter()
+        .take(VOTER_BACKFILL_BATCH)
+        .filter(|(_, vote)| vote.voter.is_none())
+        .collect();
+
+    let mut updated = 0;
+    if !pending.is_empty() {
+        let usernames: BTreeSet<&str> = pending.iter().map(|(_, vote)| vote.by.as_str()).collect();
+        let mut first_links: BTreeMap<String, Principal> = BTreeMap::new();
+        PRINCIPAL_LINKS_STORAGE.with(|storage| {
+            for (_, link) in storage.borrow().iter().filter(decoded) {
+                if usernames.contains(link.username.as_str()) {
+                    first_links.entry(link.username).or_insert(link.principal);
+                }
+            }
+        });
+
+        for (key, mut vote) in pending {
+            if let Some(principal) = first_links.get(&vote.by) {
+                vote.voter = Some(*principal);
+                VOTES_STORAGE.with(|storage| storage.borrow_mut().insert(key, vote));
+                updated += 1;
+            }
+        }
+    }
+
+    VOTER_BACKFILL_CURSOR.with(|cursor| cursor.borrow_mut().set(next)).expect("Cannot store the voter backfill cursor");
+
+    if updated > 0 {
+        logging::log(LogLevel::Info, "backfill", format!("Backfilled the voter principal of {} votes", updated));
+    }
+}
+
+// Helper function to count the activity that happened before activity was tracked. Runs once, while no
+// user has counters yet. Returns how many users were counted.
+fn backfill_user_activity() -> u64 {
+    if !USER_ACTIVITY_STORAGE.with(|storage| storage.borrow().is_empty()) {
+        return 0;
+    }
+
+    let authors: BTreeMap<u64, (String, u64)> = DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(id, discussion)| (id, (discussion.created_by, discussion.created_at))).collect()
+    });
+
+    // Replayed oldest first, so streaks build up day by day. `None` marks a new discussion, otherwise the
+    // voted discussion's author.
+    let mut events: Vec<(u64, String, Option<Option<String>>)> = authors.values()
+        .map(|(created_by, created_at)| (*created_at, created_by.clone(), None))
+        .collect();
+    VOTES_STORAGE.with(|storage| {
+        for (_, vote) in storage.borrow().iter().filter(decoded) {
+            let author = authors.get(&vote.discussion_id).map(|(author, _)| author.clone());
+            events.push((vote.created_at, vote.by, Some(author)));
+        }
+    });
+    events.sort_by_key(|(at, _, _)| *at);
+
+    for (at, username, vote) in events {
+        match vote {
+            None => track_activity(&username, at, |activity| activity.discussions_created += 1),
+            Some(author) => {
+                track_activity(&username, at, |activity| activity.votes_cast += 1);
+                if let Some(author) = author {
+                    track_received_vote(&author);
+                }
+            }
+        }
+    }
+
+    USER_ACTIVITY_STORAGE.with(|storage| storage.borrow().len())
+}
+
+#[ic_cdk::init]
+fn init() {
+    start_background_jobs();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    start_background_jobs();
+
+    // The rest reads the moved records, so it waits for the background tick to move them
+    if retired_maps_hold_entries() {
+        RETIRED_MAPS_PENDING.with(|pending| *pending.borrow_mut() = true);
+        return;
+    }
+
+    finish_upgrade();
+}
+
+// Helper function to rebuild and backfill what an upgrade needs, once the retired maps are moved
+fn finish_upgrade() {
+    rebuild_discussion_indexes();
+    rebuild_alert_indexes();
+    rebuild_voting_power_index();
+    rebuild_restriction_index();
+    rebuild_principal_link_indexes();
+    outbox::rebuild_due_index();
+
+    let counted = backfill_user_activity();
+    if counted > 0 {
+        logging::log(LogLevel::Info, "post_upgrade", format!("Counted the past activity of {} users", counted));
+    }
+
+    if KARMA_TALLIES_STORAGE.with(|storage| storage.borrow().is_empty()) {
+        let tallied = rebuild_karma_tallies();
+        if tallied > 0 {
+            logging::log(LogLevel::Info, "post_upgrade", format!("Tallied the karma of {} users", tallied));
+        }
+    }
+}
+
+// Helper function to store a vote and count it in the discussion's tally
+fn record_vote(mut discussion: Discussion, username: &str, vote_type: VoteType, voter: Option<Principal>, reason: Option<DownvoteReason>) {
+    let id = next_id();
+
+    let vote = Vote {
+        id,
+        by: username.to_string(),
+        discussion_id: discussion.id,
+        vote_type: vote_type.clone(),
+        created_at: time(),
+        voter,
+        reason: reason.clone(),
+        expired: false,
+    };
+
+    insert_vote(vote);
+    track_activity(username, time(), |activity| activity.votes_cast += 1);
+    track_received_vote(&discussion.created_by);
+
+    if reason == Some(DownvoteReason::Spam) && !has_open_report(discussion.id, username) {
+        insert_report(&discussion, username.to_string(), "Downvoted as spam".to_string());
+    }
+    credit_referral(username);
+    advance_onboarding(username, OnboardingStep::CastFirstVote);
+
+    // A vote's direction would give away the tally of a running blind vote, so those votes are never announced
+    if is_announced(&discussion) && !tallies_hidden(&discussion, time()) {
+        outbox::publish(EventKind::VoteCast { discussion_id: discussion.id, upvote: matches!(vote_type, VoteType::Upvote) });
+    }
+
+    discussion.last_activity_at = time();
+
+    match vote_type {
+        VoteType::Upvote => discussion.upvotes += 1,
+        VoteType::Downvote => discussion.downvotes += 1,
+    }
+    update_vote_scores(&mut discussion);
+    tally_received_vote(&discussion.created_by, &vote_type, true);
+
+    save_discussion(discussion.id, discussion);
+}
+
+// Helper function to compute the commitment for a sealed vote, without the username for commitments made
+// before it was bound in
+fn vote_commitment(discussion_id: u64, username: Option<&str>, vote_type: &VoteType, salt: &str) -> Vec<u8> {
+    use sha3::{Digest, Sha3_256};
+
+    let choice = match vote_type {
+        VoteType::Upvote => "upvote",
+        VoteType::Downvote => "downvote",
+    };
+
+    let preimage = match username {
+        Some(username) => format!("{}:{}:{}:{}", discussion_id, username, choice, salt),
+        None => format!("{}:{}:{}", discussion_id, choice, salt),
+    };
+
+    Sha3_256::digest(preimage.as_bytes()).to_vec()
+}
+
+// Function to switch a discussion to commit-reveal voting, or back (only by creator, before any votes)
+#[ic_cdk::update]
+fn set_commit_reveal(discussion_id: u64, reveal_ends_at: Option<u64>, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("set_commit_reveal");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = get_owned_discussion(discussion_id, &username)?;
+
+    if is_voting_closed(&discussion, time()) {
+        return Err(ErrorCode::VotingClosed.into());
+    }
+
+    if let Some(reveal_ends_at) = reveal_ends_at {
+        let voting_ends_at = discussion.voting_ends_at.ok_or("Commit-reveal votes need a deadline")?;
+        if reveal_ends_at <= voting_ends_at {
+            return Err("Reveal window must end after the voting deadline".to_string());
+        }
+    }
+
+    let has_votes = discussion.upvotes + discussion.downvotes > 0
+        || VOTE_COMMITMENTS_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).any(|(_, commitment)| commitment.discussion_id == discussion_id));
+
+    if has_votes {
+        return Err("Voting mode cannot change once votes are cast".to_string());
+    }
+
+    discussion.reveal_ends_at = reveal_ends_at;
+    discussion.version += 1;
+
+    save_discussion(discussion_id, discussion);
+
+    Ok("Voting mode updated".to_string())
+}
+
+// Function to submit or replace a sealed vote during the voting window of a commit-reveal discussion
+#[ic_cdk::update]
+fn commit_vote(discussion_id: u64, commitment: Vec<u8>, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("commit_vote");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if discussion.reveal_ends_at.is_none() {
+        return Err("This discussion does not use commit-reveal voting".to_string());
+    }
+
+    if is_voting_closed(&discussion, time()) {
+        return Err(ErrorCode::VotingClosed.into());
+    }
+
+    if commitment.len() != 32 {
+        return Err("Commitment must be a 32-byte SHA3-256 hash".to_string());
+    }
+
+    if !can_access_discussion(&discussion, &username) {
+        return Err("User is not a member of this private discussion".to_string());
+    }
+
+    check_personhood(&discussion, &username)?;
+
+    let existing = VOTE_COMMITMENTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .find(|(_, commitment)| commitment.discussion_id == discussion_id && commitment.by == username)
+            .map(|(id, _)| id)
+    });
+
+    // Sealed votes are counted when committed, replacing a commitment takes no new place
+    if existing.is_none() {
+        let committed = VOTE_COMMITMENTS_STORAGE.with(|storage| {
+            storage.borrow().iter().filter(decoded).filter(|(_, commitment)| commitment.discussion_id == discussion_id).count() as u64
+        });
+        check_participation(&discussion, &username, committed)?;
+    }
+
+    let id = existing.unwrap_or_else(next_id);
+
+    let record = VoteCommitment {
+        id,
+        discussion_id,
+        by: username,
+        commitment,
+        committed_at: time(),
+        revealed: false,
+        voter_bound: true,
+    };
+
+    index_discussion_record(record.discussion_id, StoredMap::VoteCommitments, id);
+    VOTE_COMMITMENTS_STORAGE.with(|storage| storage.borrow_mut().insert(id, record));
+
+    Ok("Vote committed".to_string())
+}
+
+// Function to open a sealed vote during the reveal window; only revealed votes are tallied
+#[ic_cdk::update]
+fn reveal_vote(discussion_id: u64, vote_type: VoteType, salt: String, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("reveal_vote");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+    let now = time();
+
+    let reveal_ends_at = discussion.reveal_ends_at.ok_or("This discussion does not use commit-reveal voting")?;
+
+    if !is_voting_closed(&discussion, now) {
+        return Err("The reveal window has not started".to_string());
+    }
+
+    if now >= reveal_ends_at {
+        return Err("The reveal window has closed".to_string());
+    }
+
+    let mut commitment = VOTE_COMMITMENTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .find(|(_, commitment)| commitment.discussion_id == discussion_id && commitment.by == username)
+            .map(|(_, commitment)| commitment)
+    }).ok_or("No committed vote found")?;
+
+    if commitment.revealed {
+        return Err("Vote already revealed".to_string());
+    }
+
+    let voter = commitment.voter_bound.then_some(username.as_str());
+    if vote_commitment(discussion_id, voter, &vote_type, &salt) != commitment.commitment {
+        return Err("Reveal does not match the commitment".to_string());
+    }
+
+    check_vote_allowed(&discussion, &username, &vote_type)?;
+
+    // A reason would have to be sealed too, so commit-reveal votes go without one
+    record_vote(discussion, &username, vote_type, Some(voter_principal()?), None);
+
+    commitment.revealed = true;
+    VOTE_COMMITMENTS_STORAGE.with(|storage| storage.borrow_mut().insert(commitment.id, commitment));
+
+    Ok("Vote revealed".to_string())
+}
+
+// New function to remove a vote from a discussion
+#[ic_cdk::update]
+fn remove_vote(discussion_id: u64, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("remove_vote");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if is_voting_closed(&discussion, time()) {
+        return Err(ErrorCode::VotingClosed.into());
+    }
+
+    let vote = discussion_votes(discussion_id).into_iter().find(|vote| vote.by == username).ok_or("Vote not found")?;
+
+    VOTES_STORAGE.with(|storage| storage.borrow_mut().remove(&vote.id));
+    VOTES_BY_DISCUSSION.with(|index| index.borrow_mut().remove(&(discussion_id, vote.id)));
+    mark_vote_root_stale(discussion_id);
+
+    if !vote.expired {
+        match vote.vote_type {
+            VoteType::Upvote => discussion.upvotes -= 1,
+            VoteType::Downvote => discussion.downvotes -= 1,
+        }
+        update_vote_scores(&mut discussion);
+        tally_received_vote(&discussion.created_by, &vote.vote_type, false);
+    }
+
+    save_discussion(discussion_id, discussion);
+
+    Ok("Vote removed".to_string())
+}
+
+// Function to delete a user and associated data. With `dry_run` nothing changes and the report lists
+// what would be removed or anonymized. Accounts without a linked principal are claimed first, see
+// authenticate_owner.
+#[ic_cdk::update]
+fn delete_user(username: String, dry_run: bool) -> Result<ChangeReport, String> {
+    let _meter = metrics::measure("delete_user");
+
+    ensure_writable()?;
+
+    authenticate_owner(&username)?;
+
+    let user_id = USERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).find(|(_, user)| user.username == username).map(|(id, _)| id)
+    }).ok_or(ErrorCode::UserNotFound)?;
+
+    let mut report = ChangeReport::new(dry_run);
+
+    // Remove the user
+    report.remove_keys("users", &USERS_STORAGE, [user_id]);
+    report.remove_keys("privacy_settings", &PRIVACY_SETTINGS_STORAGE, [user_id]);
+    report.remove_keys("content_preferences", &CONTENT_PREFERENCES_STORAGE, [user_id]);
+    report.remove_keys("recovery_configs", &RECOVERY_CONFIGS_STORAGE, [user_id]);
+    report.remove_keys("pending_recoveries", &PENDING_RECOVERIES_STORAGE, [user_id]);
+    report.remove_keys("personhood", &PERSONHOOD_STORAGE, [user_id]);
+    report.remove_keys("profiles", &PROFILES_STORAGE, [user_id]);
+    report.remove_keys("onboarding", &ONBOARDING_STORAGE, [user_id]);
+    report.remove_keys("user_activity", &USER_ACTIVITY_STORAGE, [user_id]);
+    report.remove_keys("karma_tallies", &KARMA_TALLIES_STORAGE, [user_id]);
+
+    // Release the user's principals and pending link challenges
+    report.remove_where("principal_links", &PRINCIPAL_LINKS_STORAGE, |link| link.username == username);
+    report.remove_where("link_challenges", &LINK_CHALLENGES_STORAGE, |challenge| challenge.username == username);
+    if !dry_run {
+        queue_account_sync(user_id);
+    }
+
+    // Remove all votes and everything else the user owns
+    report.remove_where("votes", &VOTES_STORAGE, |vote| vote.by == username);
+    if !dry_run {
+        mark_all_vote_roots_stale();
+    }
+    report.remove_where("vote_commitments", &VOTE_COMMITMENTS_STORAGE, |commitment| commitment.by == username);
+    report.remove_where("read_markers", &READ_MARKERS_STORAGE, |marker| marker.username == username);
+    report.remove_where("keyword_alerts", &KEYWORD_ALERTS_STORAGE, |alert| alert.username == username);
+    report.remove_where("notifications", &NOTIFICATIONS_STORAGE, |notification| notification.username == username);
+    if !dry_run {
+        rebuild_alert_indexes();
+    }
+    plan_attachment_removal(&mut report, |attachment| attachment.owner == username);
+    if !dry_run {
+        remove_attachments(|attachment| attachment.owner == username);
+    }
+    report.remove_where("referrals", &REFERRALS_STORAGE, |referral| referral.referrer == username || referral.referee == username);
+    report.remove_where("karma_awards", &KARMA_AWARDS_STORAGE, |award| award.username == username);
+    report.remove_where("badges", &BADGES_STORAGE, |badge| badge.username == username);
+    report.remove_where("terms_acceptances", &TERMS_ACCEPTANCES_STORAGE, |acceptance| acceptance.username == username);
+    report.remove_where("warnings", &WARNINGS_STORAGE, |warning| warning.username == username);
+    report.remove_where("restrictions", &RESTRICTIONS_STORAGE, |restriction| restriction.username == username);
+    if !dry_run {
+        rebuild_restriction_index();
+    }
+    report.remove_where("appeals", &APPEALS_STORAGE, |appeal| appeal.username == username);
+
+    // Mark discussions created by the user as anonymous
+    report.update_where(
+        "discussions",
+        &DISCUSSIONS_STORAGE,
+        |discussion| discussion.created_by == username,
+        |discussion| discussion.created_by = "Anonymous".to_string(),
+    );
+    if !dry_run {
+        clear_discussion_cache();
+    }
+    report.update_where(
+        "discussion_revisions",
+        &REVISIONS_STORAGE,
+        |revision| revision.edited_by == username,
+        |revision| revision.edited_by = "Anonymous".to_string(),
+    );
+    report.remove_where("ownership_transfers", &TRANSFERS_STORAGE, |transfer| transfer.from == username || transfer.to == username);
+    report.remove_where("survey_responses", &SURVEY_RESPONSES_STORAGE, |response| response.username == username);
+    report.remove_where("reminders", &REMINDERS_STORAGE, |reminder| reminder.username == username);
+    report.remove_where("recurrences", &RECURRENCES_STORAGE, |recurrence| recurrence.author == username);
+    report.remove_where("unlock_requests", &UNLOCK_REQUESTS_STORAGE, |request| request.username == username);
+    report.update_where(
+        "surveys",
+        &SURVEYS_STORAGE,
+        |survey| survey.created_by == username,
+        |survey| survey.created_by = "Anonymous".to_string(),
+    );
+
+    // Remove group memberships and anonymize groups created by the user
+    report.remove_where("group_members", &GROUP_MEMBERS_STORAGE, |member| member.username == username);
+    report.remove_where("join_requests", &JOIN_REQUESTS_STORAGE, |request| request.username == username);
+    report.update_where(
+        "groups",
+        &GROUPS_STORAGE,
+        |group| group.created_by == username,
+        |group| group.created_by = "Anonymous".to_string(),
+    );
+
+    Ok(report)
+}
+
+// Function to merge a duplicate account into another one (admins only). Discussions, votes,
+// group memberships and principals move to `dst`; `src` is removed and its username tombstoned.
+// With `dry_run` nothing changes and the report lists what would.
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn merge_accounts(src: String, dst: String, dry_run: bool) -> Result<ChangeReport, String> {
+    let _meter = metrics::measure("merge_accounts");
+
+    ensure_writable()?;
+
+    if src == dst {
+        return Err("Cannot merge an account into itself".to_string());
+    }
+
+    let src_user = find_user(&src).ok_or("Source user not found")?;
+    let dst_user = find_user(&dst).ok_or("Destination user not found")?;
+
+    let mut report = ChangeReport::new(dry_run);
+
+    // Votes: keep dst's vote where both voted on the same discussion
+    let src_votes: Vec<Vote> = VOTES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).filter(|(_, vote)| vote.by == src).map(|(_, vote)| vote).collect()
+    });
+
+    for mut vote in src_votes {
+        let duplicate = VOTES_STORAGE.with(|storage| {
+            storage.borrow().iter().filter(decoded).any(|(_, other)| other.by == dst && other.discussion_id == vote.discussion_id)
+        });
+
+        if duplicate {
+            report.remove_keys("votes", &VOTES_STORAGE, [vote.id]);
+
+            if let Some(mut discussion) = try_get(&DISCUSSIONS_STORAGE, vote.discussion_id) {
+                if vote.expired {
+                    continue;
+                }
+                report.updated("discussions", [vote.discussion_id]);
+                if dry_run {
+                    continue;
+                }
+
+                match vote.vote_type {
+                    VoteType::Upvote => discussion.upvotes = discussion.upvotes.saturating_sub(1),
+                    VoteType::Downvote => discussion.downvotes = discussion.downvotes.saturating_sub(1),
+                }
+                update_vote_scores(&mut discussion);
+                tally_received_vote(&discussion.created_by, &vote.vote_type, false);
+                save_discussion(vote.discussion_id, discussion);
+            }
+        } else {
+            report.updated("votes", [vote.id]);
+            if !dry_run {
+                vote.by = dst.clone();
+                VOTES_STORAGE.with(|storage| storage.borrow_mut().insert(vote.id, vote));
+            }
+        }
+    }
+
+    if !dry_run {
+        mark_all_vote_roots_stale();
+    }
+
+    // Discussions: authorship and private member lists
+    report.update_where(
+        "discussions",
+        &DISCUSSIONS_STORAGE,
+        |discussion| {
+            discussion.created_by == src
+                || matches!(&discussion.visibility, Visibility::Private { allowed_users } if allowed_users.contains(&src))
+        },
+        |discussion| {
+            if discussion.created_by == src {
+                discussion.created_by = dst.clone();
+            }
+            if let Visibility::Private { allowed_users } = &mut discussion.visibility {
+                allowed_users.retain(|user| *user != src);
+                if discussion.created_by != dst && !allowed_users.contains(&dst) {
+                    allowed_users.push(dst.clone());
+                }
+            }
+        },
+    );
+    if !dry_run {
+        clear_discussion_cache();
+    }
+    report.update_where(
+        "discussion_revisions",
+        &REVISIONS_STORAGE,
+        |revision| revision.edited_by == src,
+        |revision| revision.edited_by = dst.clone(),
+    );
+    // Pending offers between the two accounts would hand a discussion to its own owner
+    report.remove_where(
+        "ownership_transfers",
+        &TRANSFERS_STORAGE,
+        |transfer| (transfer.from == src || transfer.to == src) && (transfer.from == dst || transfer.to == dst),
+    );
+    report.update_where(
+        "ownership_transfers",
+        &TRANSFERS_STORAGE,
+        |transfer| transfer.from == src || transfer.to == src,
+        |transfer| {
+            if transfer.from == src {
+                transfer.from = dst.clone();
+            }
+            if transfer.to == src {
+                transfer.to = dst.clone();
+            }
+        },
+    );
+
+    // Survey responses: keep dst's response where both answered the same survey
+    let dst_surveys: Vec<u64> = SURVEY_RESPONSES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).filter(|(_, response)| response.username == dst).map(|(_, response)| response.survey_id).collect()
+    });
+    report.remove_where(
+        "survey_responses",
+        &SURVEY_RESPONSES_STORAGE,
+        |response| response.username == src && dst_surveys.contains(&response.survey_id),
+    );
+    report.update_where(
+        "survey_responses",
+        &SURVEY_RESPONSES_STORAGE,
+        |response| response.username == src && !dst_surveys.contains(&response.survey_id),
+        |response| response.username = dst.clone(),
+    );
+    report.update_where("surveys", &SURVEYS_STORAGE, |survey| survey.created_by == src, |survey| survey.created_by = dst.clone());
+    report.update_where("reminders", &REMINDERS_STORAGE, |reminder| reminder.username == src, |reminder| reminder.username = dst.clone());
+    report.update_where("recurrences", &RECURRENCES_STORAGE, |recurrence| recurrence.author == src, |recurrence| recurrence.author = dst.clone());
+    report.update_where("unlock_requests", &UNLOCK_REQUESTS_STORAGE, |request| request.username == src, |request| request.username = dst.clone());
+
+    // Group memberships: keep the higher role where both are members
+    let src_memberships: Vec<GroupMember> = GROUP_MEMBERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).filter(|(_, member)| member.username == src).map(|(_, member)| member).collect()
+    });
+
+    for mut membership in src_memberships {
+        match find_group_member(membership.group_id, &dst) {
+            Some(mut existing) => {
+                if membership.role > existing.role {
+                    report.updated("group_members", [existing.id]);
+                    if !dry_run {
+                        existing.role = membership.role;
+                        GROUP_MEMBERS_STORAGE.with(|storage| storage.borrow_mut().insert(existing.id, existing));
+                    }
+                }
+                report.remove_keys("group_members", &GROUP_MEMBERS_STORAGE, [membership.id]);
+            }
+            None => {
+                report.updated("group_members", [membership.id]);
+                if !dry_run {
+                    membership.username = dst.clone();
+                    GROUP_MEMBERS_STORAGE.with(|storage| storage.borrow_mut().insert(membership.id, membership));
+                }
+            }
+        }
+    }
+
+    report.update_where("groups", &GROUPS_STORAGE, |group| group.created_by == src, |group| group.created_by = dst.clone());
+
+    // Strikes and restrictions follow the person, not the account
+    report.update_where("warnings", &WARNINGS_STORAGE, |warning| warning.username == src, |warning| warning.username = dst.clone());
+    report.update_where(
+        "restrictions",
+        &RESTRICTIONS_STORAGE,
+        |restriction| restriction.username == src,
+        |restriction| restriction.username = dst.clone(),
+    );
+    if !dry_run {
+        rebuild_restriction_index();
+    }
+    report.update_where("appeals", &APPEALS_STORAGE, |appeal| appeal.username == src, |appeal| appeal.username = dst.clone());
+
+    // Principals: every identity of the old account now signs in to the surviving one
+    report.update_where("principal_links", &PRINCIPAL_LINKS_STORAGE, |link| link.username == src, |link| link.username = dst.clone());
+    if !dry_run {
+        rebuild_principal_link_indexes();
+    }
+
+    report.remove_keys("users", &USERS_STORAGE, [src_user.id]);
+    report.remove_keys("privacy_settings", &PRIVACY_SETTINGS_STORAGE, [src_user.id]);
+    report.remove_keys("content_preferences", &CONTENT_PREFERENCES_STORAGE, [src_user.id]);
+    report.remove_keys("recovery_configs", &RECOVERY_CONFIGS_STORAGE, [src_user.id]);
+    report.remove_keys("pending_recoveries", &PENDING_RECOVERIES_STORAGE, [src_user.id]);
+
+    // Activity counts add up, the surviving account keeps the earliest and latest activity of both
+    if let Some(src_activity) = try_get(&USER_ACTIVITY_STORAGE, src_user.id) {
+        report.updated("user_activity", [dst_user.id]);
+        if !dry_run {
+            let mut activity = try_get(&USER_ACTIVITY_STORAGE, dst_user.id).unwrap_or_default();
+            activity.discussions_created += src_activity.discussions_created;
+            activity.votes_cast += src_activity.votes_cast;
+            activity.votes_received += src_activity.votes_received;
+            activity.first_activity_at = match (activity.first_activity_at, src_activity.first_activity_at) {
+                (0, first) | (first, 0) => first,
+                (a, b) => a.min(b),
+            };
+            activity.last_activity_at = activity.last_activity_at.max(src_activity.last_activity_at);
+            activity.longest_streak = activity.longest_streak.max(src_activity.longest_streak);
+            if src_activity.last_active_day > activity.last_active_day {
+                activity.current_streak = src_activity.current_streak;
+                activity.last_active_day = src_activity.last_active_day;
+            }
+            USER_ACTIVITY_STORAGE.with(|storage| storage.borrow_mut().insert(dst_user.id, activity));
+        }
+        report.remove_keys("user_activity", &USER_ACTIVITY_STORAGE, [src_user.id]);
+    }
+
+    // So does karma, the surviving account now owns the discussions and awards of both
+    if let Some(src_tally) = try_get(&KARMA_TALLIES_STORAGE, src_user.id) {
+        report.updated("karma_tallies", [dst_user.id]);
+        if !dry_run {
+            let mut tally = try_get(&KARMA_TALLIES_STORAGE, dst_user.id).unwrap_or_default();
+            tally.upvotes_received += src_tally.upvotes_received;
+            tally.downvotes_received += src_tally.downvotes_received;
+            tally.referrals += src_tally.referrals;
+            tally.moderation_penalties += src_tally.moderation_penalties;
+            tally.inactivity_decay += src_tally.inactivity_decay;
+            tally.other_awards += src_tally.other_awards;
+            tally.last_decayed_at = tally.last_decayed_at.max(src_tally.last_decayed_at);
+            KARMA_TALLIES_STORAGE.with(|storage| storage.borrow_mut().insert(dst_user.id, tally));
+        }
+        report.remove_keys("karma_tallies", &KARMA_TALLIES_STORAGE, [src_user.id]);
+    }
+
+    report.inserted("tombstones", 1);
+    report.inserted("account_audit_log", 1);
+    if dry_run {
+        return Ok(report);
+    }
+
+    let id = next_id();
+
+    let tombstone = AccountTombstone {
+        id,
+        username: src.clone(),
+        merged_into: dst.clone(),
+        merged_at: time(),
+    };
+
+    TOMBSTONES_STORAGE.with(|storage| storage.borrow_mut().insert(id, tombstone));
+    log_account_action(&dst, format!("Merged account {} into this account", src));
+
+    queue_account_sync(src_user.id);
+    queue_account_sync(dst_user.id);
+
+    Ok(report)
+}
+
+// Function to grant admin rights to a principal (controllers only, unless the DAO governs)
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn add_admin(principal: Principal) -> Result<String, String> {
+    let _meter = metrics::measure("add_admin");
+
+    ensure_writable()?;
+
+    check_not_dao_governed()?;
+
+    grant_admin(principal)
+}
+
+// Helper function to add a principal to the admin set
+fn grant_admin(principal: Principal) -> Result<String, String> {
+    if guards::is_admin(&principal) {
+        return Err("Principal is already an admin".to_string());
+    }
+
+    let id = next_id();
+
+    let admin = Admin {
+        id,
+        principal,
+        added_by: caller(),
+        added_at: time(),
+    };
+
+    ADMINS_STORAGE.with(|storage| storage.borrow_mut().insert(id, admin));
+
+    Ok("Admin added".to_string())
+}
+
+// Function to revoke a principal's admin rights (controllers only, unless the DAO governs)
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn remove_admin(principal: Principal) -> Result<String, String> {
+    let _meter = metrics::measure("remove_admin");
+
+    ensure_writable()?;
+
+    check_not_dao_governed()?;
+
+    revoke_admin(principal)
+}
+
+// Helper function to remove a principal from the admin set
+fn revoke_admin(principal: Principal) -> Result<String, String> {
+    if remove_where(&ADMINS_STORAGE, |admin| admin.principal == principal) == 0 {
+        return Err("Principal is not an admin".to_string());
+    }
+
+    Ok("Admin removed".to_string())
+}
+
+// Function to replace the runtime settings (admins only, unless the DAO governs)
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn update_settings(new_settings: Settings) -> Result<String, String> {
+    let _meter = metrics::measure("update_settings");
+
+    ensure_writable()?;
+
+    check_not_dao_governed()?;
+
+    validate_settings(&new_settings)?;
+
+    SETTINGS.with(|settings| settings.borrow_mut().set(new_settings))
+        .map_err(|_| "Cannot store settings".to_string())?;
+
+    logging::log(LogLevel::Info, "update_settings", format!("Settings updated by {}", caller()));
+
+    Ok("Settings updated".to_string())
+}
+
+// Helper function to check new settings before they are stored
+fn validate_settings(new_settings: &Settings) -> Result<(), String> {
+    if new_settings.max_topic_length == 0 || new_settings.max_topic_length > TOPIC_LENGTH_LIMIT {
+        return Err(format!("max_topic_length must be between 1 and {}", TOPIC_LENGTH_LIMIT));
+    }
+
+    if new_settings.max_report_reason_length == 0 || new_settings.max_report_reason_length > REPORT_REASON_LENGTH_LIMIT {
+        return Err(format!("max_report_reason_length must be between 1 and {}", REPORT_REASON_LENGTH_LIMIT));
+    }
+
+    if new_settings.link_redirect_prefix.as_ref().is_some_and(|prefix| !prefix.starts_with("https://")) {
+        return Err("link_redirect_prefix must be an https URL".to_string());
+    }
+
+    if new_settings.registration_pow_difficulty > MAX_POW_DIFFICULTY {
+        return Err(format!("registration_pow_difficulty cannot exceed {}", MAX_POW_DIFFICULTY));
+    }
+
+    if new_settings.trash_retention_days > MAX_TRASH_RETENTION_DAYS {
+        return Err(format!("trash_retention_days cannot exceed {}", MAX_TRASH_RETENTION_DAYS));
+    }
+
+    if new_settings.strikes_to_mute > 0 && new_settings.strikes_to_suspend > 0 && new_settings.strikes_to_suspend <= new_settings.strikes_to_mute {
+        return Err("strikes_to_suspend must be above strikes_to_mute".to_string());
+    }
+
+    if new_settings.suspension_days as u64 > MAX_SUSPENSION_DAYS {
+        return Err(format!("suspension_days cannot exceed {}", MAX_SUSPENSION_DAYS));
+    }
+
+    if new_settings.signing_key_name.is_empty() || new_settings.signing_key_name.len() > 64 {
+        return Err("signing_key_name must be between 1 and 64 characters".to_string());
+    }
+
+    if new_settings.vetkd_key_name.is_empty() || new_settings.vetkd_key_name.len() > 64 {
+        return Err("vetkd_key_name must be between 1 and 64 characters".to_string());
+    }
+
+    if new_settings.karma_decay_after_days > 0 {
+        if new_settings.karma_decay_percent == 0 || new_settings.karma_decay_percent > 100 {
+            return Err("karma_decay_percent must be between 1 and 100".to_string());
+        }
+        if new_settings.karma_decay_interval_days == 0 {
+            return Err("karma_decay_interval_days must be at least 1".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+// Helper function to read the SNS governance canister, if one governs this canister
+fn dao_governance() -> Option<Principal> {
+    DAO_CONFIG.with(|config| config.borrow().get().governance)
+}
+
+// Helper function for destructive admin entrypoints, which only adopted proposals may perform once a DAO governs
+fn check_not_dao_governed() -> Result<(), String> {
+    match dao_governance() {
+        Some(governance) => Err(format!("This action is governed by the DAO at {}, submit a proposal", governance)),
+        None => Ok(()),
+    }
+}
+
+// Function to hand administration to an SNS governance canister (controllers only, before a DAO governs).
+// Afterwards only the DAO can change governance, through a SetGovernance action.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_dao_governance(governance: Principal) -> Result<String, String> {
+    let _meter = metrics::measure("set_dao_governance");
+
+    ensure_writable()?;
+
+    check_not_dao_governed()?;
+
+    DAO_CONFIG.with(|config| config.borrow_mut().set(DaoConfig { governance: Some(governance) }))
+        .map_err(|_| "Cannot store DAO config".to_string())?;
+
+    Ok("DAO governance configured".to_string())
+}
+
+// Function to get the SNS governance canister, if one governs this canister
+#[ic_cdk::query]
+fn get_dao_governance() -> Option<Principal> {
+    dao_governance()
+}
+
+// Function registered as the validator of the SNS generic proposal type; returns the text shown on the proposal
+#[ic_cdk::query]
+fn validate_dao_action(action: DaoAction) -> Result<String, String> {
+    match action {
+        DaoAction::UpdateSettings(new_settings) => {
+            validate_settings(&new_settings)?;
+            Ok("Replace the runtime settings".to_string())
+        }
+        DaoAction::AddAdmin(principal) => Ok(format!("Grant admin rights to {}", principal)),
+        DaoAction::RemoveAdmin(principal) => Ok(format!("Revoke admin rights of {}", principal)),
+        DaoAction::SetGovernance(Some(governance)) => Ok(format!("Hand governance to {}", governance)),
+        DaoAction::SetGovernance(None) => Ok("Return administration to admins and controllers".to_string()),
+        DaoAction::ApproveTreasuryPayout(payout_id) => {
+            let payout = pending_payout(payout_id)?;
+            Ok(format!("Pay {} to {} from the treasury: {}", payout.amount, payout.to.owner, payout.reason))
+        }
+        DaoAction::RestoreBackup(backup) => Ok(format!(
+            "Replace the forum data with a backup of {} users, {} discussions and {} votes",
+            backup.users.len(), backup.discussions.len(), backup.votes.len(),
+        )),
+        DaoAction::BulkDeleteDiscussions(discussion_ids) => Ok(format!("Delete {} discussions and their votes", discussion_ids.len())),
+        DaoAction::PatchRecord { map, key, patches } => {
+            let fields: Vec<&str> = patches.iter().map(|patch| patch.field.as_str()).collect();
+            Ok(format!("Overwrite {} of record {} of {}", fields.join(", "), key, map_name(map)))
+        }
+        DaoAction::DeleteRecord { map, key } => Ok(format!("Delete record {} of {}", key, map_name(map))),
+        DaoAction::SetFeatureFlag { name, enabled } => {
+            check_feature_flag_name(&name)?;
+            Ok(format!("Turn feature flag {} {}", name, if enabled { "on" } else { "off" }))
+        }
+    }
+}
+
+// Function registered as the target of the SNS generic proposal type. SNS governance makes this call only
+// when a proposal using it is adopted, so the caller being the governance canister is the verification.
+#[ic_cdk::update]
+async fn execute_dao_action(action: DaoAction) -> Result<String, String> {
+    let _meter = metrics::measure("execute_dao_action");
+
+    check_maintenance()?;
+
+    if dao_governance() != Some(caller()) {
+        return Err("Only the governing DAO can execute proposals".to_string());
+    }
+
+    validate_dao_action(action.clone())?;
+
+    match action {
+        DaoAction::UpdateSettings(new_settings) => {
+            SETTINGS.with(|settings| settings.borrow_mut().set(*new_settings))
+                .map_err(|_| "Cannot store settings".to_string())?;
+            Ok("Settings updated".to_string())
+        }
+        DaoAction::AddAdmin(principal) => grant_admin(principal),
+        DaoAction::RemoveAdmin(principal) => revoke_admin(principal),
+        DaoAction::SetGovernance(governance) => {
+            DAO_CONFIG.with(|config| config.borrow_mut().set(DaoConfig { governance }))
+                .map_err(|_| "Cannot store DAO config".to_string())?;
+            Ok("DAO governance updated".to_string())
+        }
+        DaoAction::ApproveTreasuryPayout(payout_id) => send_payout(payout_id).await,
+        DaoAction::RestoreBackup(backup) => apply_backup(*backup, false).map(|_| "Backup restored".to_string()),
+        DaoAction::BulkDeleteDiscussions(discussion_ids) => {
+            delete_discussions(discussion_ids, false).map(|_| "Discussions deleted".to_string())
+        }
+        DaoAction::PatchRecord { map, key, patches } => apply_record_patch(map, key, patches).map(|_| "Record patched".to_string()),
+        DaoAction::DeleteRecord { map, key } => remove_record(map, key).map(|_| "Record deleted".to_string()),
+        DaoAction::SetFeatureFlag { name, enabled } => store_feature_flag(name, enabled),
+    }
+}
+
+// Helper function to get the treasury's ledger
+fn treasury_ledger() -> Result<Principal, String> {
+    settings().treasury_ledger.ok_or_else(|| "The treasury is not configured".to_string())
+}
+
+// Function to get the account that receives treasury deposits
+#[ic_cdk::query]
+fn get_treasury_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(TREASURY_SUBACCOUNT.to_vec()),
+    }
+}
+
+// Function to get the treasury balance from its ledger. It only reads, so it works in maintenance mode too.
+#[ic_cdk::update]
+async fn get_treasury_balance() -> Result<candid::Nat, String> {
+    let _meter = metrics::measure("get_treasury_balance");
+
+    ledger::balance_of(treasury_ledger()?, get_treasury_account()).await
+}
+
+// Function to move tokens the caller approved for this canister (ICRC-2) into the treasury. Tokens sent
+// straight to the treasury account also count, they just aren't recorded here.
+#[ic_cdk::update]
+async fn deposit_to_treasury(amount: u64) -> Result<TreasuryDeposit, String> {
+    let _meter = metrics::measure("deposit_to_treasury");
+
+    ensure_writable()?;
+
+    let ledger = treasury_ledger()?;
+    let from = caller();
+
+    if amount == 0 {
+        return Err("Amount must be positive".to_string());
+    }
+
+    let block_index = ledger::transfer_from(ledger, Account { owner: from, subaccount: None }, get_treasury_account(), amount, None).await?;
+
+    let deposit = TreasuryDeposit {
+        id: next_id(),
+        from,
+        amount,
+        block_index,
+        deposited_at: time(),
+    };
+
+    TREASURY_DEPOSITS.with(|storage| storage.borrow_mut().insert(deposit.id, deposit.clone()));
+
+    Ok(deposit)
+}
+
+// Function to buy a premium feature. The price is pulled from the caller's account with ICRC-2 transfer_from,
+// so the caller must first approve this canister for at least the price plus the ledger fee.
+#[ic_cdk::update]
+async fn purchase_premium(feature: PremiumFeature, username: String) -> Result<Entitlement, String> {
+    let _meter = metrics::measure("purchase_premium");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let ledger = treasury_ledger()?;
+    let settings = settings();
+
+    let (price, duration) = match &feature {
+        PremiumFeature::PinDiscussion { discussion_id } => {
+            get_owned_discussion(*discussion_id, &username)?;
+            (settings.pin_price, PIN_DURATION)
+        }
+        PremiumFeature::StorageBoost => (settings.storage_boost_price, STORAGE_BOOST_DURATION),
+    };
+
+    if price == 0 {
+        return Err("This feature is not for sale".to_string());
+    }
+
+    // The entitlement ID fits the 32-byte memo limit whatever the username, and ties the transfer to the purchase
+    let id = next_id();
+    let memo = Some(id.to_be_bytes().to_vec());
+    let block_index = ledger::transfer_from(ledger, Account { owner: caller(), subaccount: None }, get_treasury_account(), price, memo).await?;
+
+    // A new purchase extends an active one of the same feature
+    let now = time();
+    let starts_at = active_entitlements(&username).iter()
+        .filter(|entitlement| entitlement.feature == feature)
+        .map(|entitlement| entitlement.expires_at)
+        .max()
+        .unwrap_or(now);
+
+    let entitlement = Entitlement {
+        id,
+        username,
+        feature,
+        amount_paid: price,
+        block_index,
+        purchased_at: now,
+        expires_at: starts_at.saturating_add(duration),
+    };
+
+    ENTITLEMENTS_STORAGE.with(|storage| storage.borrow_mut().insert(entitlement.id, entitlement.clone()));
+
+    Ok(entitlement)
+}
+
+// Function to list a user's premium purchases, including expired ones
+#[ic_cdk::query]
+fn get_my_entitlements(username: String) -> Result<Vec<Entitlement>, String> {
+    authenticate(&username)?;
+
+    Ok(ENTITLEMENTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, entitlement)| entitlement.username == username)
+            .map(|(_, entitlement)| entitlement)
+            .collect()
+    }))
+}
+
+// Helper function to get a user's unexpired entitlements
+fn active_entitlements(username: &str) -> Vec<Entitlement> {
+    let now = time();
+    ENTITLEMENTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, entitlement)| entitlement.username == username && entitlement.expires_at > now)
+            .map(|(_, entitlement)| entitlement)
+            .collect()
+    })
+}
+
+// Helper function to get the IDs of discussions with an active pin
+fn pinned_discussion_ids() -> Vec<u64> {
+    let now = time();
+    ENTITLEMENTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, entitlement)| entitlement.expires_at > now)
+            .filter_map(|(_, entitlement)| match entitlement.feature {
+                PremiumFeature::PinDiscussion { discussion_id } => Some(discussion_id),
+                PremiumFeature::StorageBoost => None,
+            })
+            .collect()
+    })
+}
+
+// Function to ask for a treasury payout (admins only). Another admin, or the DAO when one governs, approves it.
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn request_treasury_payout(to: Account, amount: u64, reason: String) -> Result<TreasuryPayout, String> {
+    let _meter = metrics::measure("request_treasury_payout");
+
+    ensure_writable()?;
+
+    treasury_ledger()?;
+
+    let reason = sanitize_user_text(&reason)?;
+
+    if amount == 0 {
+        return Err("Amount must be positive".to_string());
+    }
+
+    if reason.trim().is_empty() || reason.chars().count() > MAX_PAYOUT_REASON_LENGTH {
+        return Err(format!("Reason must be between 1 and {} characters", MAX_PAYOUT_REASON_LENGTH));
+    }
+
+    if to.subaccount.as_ref().is_some_and(|subaccount| subaccount.len() != 32) {
+        return Err("Subaccounts must be 32 bytes".to_string());
+    }
+
+    let payout = TreasuryPayout {
+        id: next_id(),
+        to,
+        amount,
+        reason,
+        requested_by: caller(),
+        requested_at: time(),
+        approved_by: None,
+        status: PayoutStatus::Pending,
+    };
+
+    TREASURY_PAYOUTS.with(|storage| storage.borrow_mut().insert(payout.id, payout.clone()));
+
+    Ok(payout)
+}
+
+// Function to approve and send a pending payout (admins only, not the requester, and not while the DAO governs)
+#[ic_cdk::update(guard = "caller_is_admin")]
+async fn approve_treasury_payout(payout_id: u64) -> Result<String, String> {
+    let _meter = metrics::measure("approve_treasury_payout");
+
+    ensure_writable()?;
+
+    check_not_dao_governed()?;
+
+    let payout = pending_payout(payout_id)?;
+
+    if payout.requested_by == caller() {
+        return Err("A payout must be approved by someone other than its requester".to_string());
+    }
+
+    send_payout(payout_id).await
+}
+
+// Function to reject a pending payout (admins only)
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn reject_treasury_payout(payout_id: u64) -> Result<String, String> {
+    let _meter = metrics::measure("reject_treasury_payout");
+
+    ensure_writable()?;
+
+    let mut payout = pending_payout(payout_id)?;
+
+    payout.status = PayoutStatus::Rejected;
+    payout.approved_by = Some(caller());
+    TREASURY_PAYOUTS.with(|storage| storage.borrow_mut().insert(payout_id, payout));
+
+    Ok("Payout rejected".to_string())
+}
+
+// Function to list treasury payouts (admins only)
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_treasury_payouts(cursor: Option<String>, limit: Option<u32>) -> Result<Page<TreasuryPayout>, String> {
+    paginate(&TREASURY_PAYOUTS, cursor, limit, |_, _| true)
+}
+
+// Helper function to find a payout still waiting for approval
+fn pending_payout(payout_id: u64) -> Result<TreasuryPayout, String> {
+    try_get(&TREASURY_PAYOUTS, payout_id)
+        .filter(|payout| payout.status == PayoutStatus::Pending)
+        .ok_or_else(|| "Pending payout not found".to_string())
+}
+
+// Helper function to transfer an approved payout. It is marked as sending first so it can't be paid twice.
+async fn send_payout(payout_id: u64) -> Result<String, String> {
+    let mut payout = pending_payout(payout_id)?;
+    payout.approved_by = Some(caller());
+
+    transfer_payout(payout, time()).await
+}
+
+// Helper function to run the ledger transfer of a payout. A retry passes the creation time of the first
+// attempt, so the ledger returns the first transfer rather than paying again if it went through.
+async fn transfer_payout(mut payout: TreasuryPayout, created_at_time: u64) -> Result<String, String> {
+    let ledger = treasury_ledger()?;
+    let payout_id = payout.id;
+
+    payout.status = PayoutStatus::Sending;
+    TREASURY_PAYOUTS.with(|storage| storage.borrow_mut().insert(payout_id, payout.clone()));
+
+    let memo = Some(payout_id.to_be_bytes().to_vec());
+    let result = ledger::transfer(ledger, Some(TREASURY_SUBACCOUNT.to_vec()), payout.to.clone(), payout.amount, memo, created_at_time).await;
+
+    payout.status = match &result {
+        Ok(block_index) => PayoutStatus::Paid { block_index: block_index.clone() },
+        Err(ledger::TransferFailure::Rejected(error)) => {
+            logging::log(LogLevel::Error, "send_payout", format!("Payout {} failed: {}", payout_id, error));
+            PayoutStatus::Failed(error.clone())
+        }
+        Err(ledger::TransferFailure::Unknown(error)) => {
+            logging::log(LogLevel::Error, "send_payout", format!("Payout {} has an unknown outcome: {}", payout_id, error));
+            PayoutStatus::Unsettled { created_at_time, error: error.clone() }
+        }
+    };
+    TREASURY_PAYOUTS.with(|storage| storage.borrow_mut().insert(payout_id, payout));
+
+    result.map(|block_index| format!("Payout sent in block {}", block_index)).map_err(|error| error.to_string())
+}
+
+// Function to retry a payout whose transfer ended without a clear outcome (admins only). The retry reuses the
+// memo and creation time of the first attempt, so the ledger pays it at most once.
+#[ic_cdk::update(guard = "caller_is_admin")]
+async fn retry_treasury_payout(payout_id: u64) -> Result<String, String> {
+    let _meter = metrics::measure("retry_treasury_payout");
+
+    ensure_writable()?;
+
+    let payout = try_get(&TREASURY_PAYOUTS, payout_id).ok_or("Payout not found")?;
+    let PayoutStatus::Unsettled { created_at_time, .. } = payout.status else {
+        return Err("Only payouts with an unknown outcome can be retried".to_string());
+    };
+
+    transfer_payout(payout, created_at_time).await
+}
+
+// Function to publish a new terms of service version (admins only). Users must accept it before posting again.
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn publish_terms(version: u32, document_hash: Vec<u8>, url: String) -> Result<TermsVersion, String> {
+    let _meter = metrics::measure("publish_terms");
+
+    ensure_writable()?;
+
+    if let Some(current) = current_terms() {
+        if version <= current.version {
+            return Err(format!("Version must be greater than {}", current.version));
+        }
+    }
+
+    if document_hash.len() != 32 {
+        return Err("Document hash must be 32 bytes".to_string());
+    }
+
+    if !url.starts_with("https://") || url.len() > 512 {
+        return Err("URL must be an https link of at most 512 characters".to_string());
+    }
+
+    let terms = TermsVersion {
+        version,
+        document_hash,
+        url,
+        published_at: time(),
+        published_by: caller(),
+    };
+
+    TERMS_STORAGE.with(|storage| storage.borrow_mut().insert(version as u64, terms.clone()));
+
+    Ok(terms)
+}
+
+// Helper function to get the latest terms version, if any was published
+fn current_terms() -> Option<TermsVersion> {
+    TERMS_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).last().map(|(_, terms)| terms))
+}
+
+// Function to get the terms of service users must currently accept
+#[ic_cdk::query]
+fn get_current_terms() -> Option<TermsVersion> {
+    current_terms()
+}
+
+// Function to accept the current terms of service; the timestamp is kept for compliance
+#[ic_cdk::update]
+fn accept_terms(version: u32, username: String) -> Result<TermsAcceptance, String> {
+    let _meter = metrics::measure("accept_terms");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let current = current_terms().ok_or("No terms have been published")?;
+    if version != current.version {
+        return Err(format!("The current terms are version {}", current.version));
+    }
+
+    if has_accepted_terms(&username, version) {
+        return Err("Terms already accepted".to_string());
+    }
+
+    let acceptance = TermsAcceptance {
+        username,
+        version,
+        accepted_at: time(),
+    };
+
+    TERMS_ACCEPTANCES_STORAGE.with(|storage| storage.borrow_mut().insert(next_id(), acceptance.clone()));
+
+    Ok(acceptance)
+}
+
+// Function to list the terms versions a user accepted and when
+#[ic_cdk::query]
+fn get_my_terms_acceptances(username: String) -> Result<Vec<TermsAcceptance>, String> {
+    authenticate(&username)?;
+
+    Ok(TERMS_ACCEPTANCES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, acceptance)| acceptance.username == username)
+            .map(|(_, acceptance)| acceptance)
+            .collect()
+    }))
+}
+
+fn has_accepted_terms(username: &str, version: u32) -> bool {
+    TERMS_ACCEPTANCES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).any(|(_, acceptance)| acceptance.username == username && acceptance.version == version)
+    })
+}
+
+// Helper function for posting entrypoints: the current terms, if any, must have been accepted
+fn check_terms_accepted(username: &str) -> Result<(), String> {
+    match current_terms() {
+        Some(terms) if !has_accepted_terms(username, terms.version) => {
+            Err(format!("Accept the terms of service (version {}) before posting", terms.version))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Function to get the error messages for a locale, given as a language code or an Accept-Language list
+#[ic_cdk::query]
+fn get_message_catalog(locale: String) -> Vec<CatalogEntry> {
+    let locale = errors::negotiate_locale(&locale);
+
+    errors::ALL_CODES.iter()
+        .map(|code| CatalogEntry {
+            code: code.name().to_string(),
+            message: code.message(locale).to_string(),
+        })
+        .collect()
+}
+
+// Function to translate an error returned by another call, for clients without a catalog of their own
+#[ic_cdk::query]
+fn localize_error(error: String, locale: String) -> String {
+    errors::localize(&error, errors::negotiate_locale(&locale))
+}
+
+// Function to switch read-only maintenance mode on or off (admins only)
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_maintenance_mode(enabled: bool, message: Option<String>, eta: Option<u64>) -> Result<String, String> {
+    let _meter = metrics::measure("set_maintenance_mode");
+
+    if message.as_ref().is_some_and(|message| message.len() > 256) {
+        return Err("Maintenance message cannot exceed 256 characters".to_string());
+    }
+
+    let maintenance = MaintenanceMode {
+        enabled,
+        message,
+        eta,
+    };
+
+    MAINTENANCE.with(|cell| cell.borrow_mut().set(maintenance))
+        .map_err(|_| "Cannot store maintenance mode".to_string())?;
+
+    let status = if enabled { "Maintenance mode enabled" } else { "Maintenance mode disabled" };
+    logging::log(LogLevel::Info, "set_maintenance_mode", format!("{} by {}", status, caller()));
+
+    Ok(status.to_string())
+}
+
+// Function to turn a feature flag on or off (admins only, unless the DAO governs)
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn set_feature_flag(name: String, enabled: bool) -> Result<String, String> {
+    let _meter = metrics::measure("set_feature_flag");
+
+    ensure_writable()?;
+
+    check_not_dao_governed()?;
+
+    store_feature_flag(name, enabled)
+}
+
+// Helper function to check the name of a feature flag
+fn check_feature_flag_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 64 {
+        return Err("Flag name must be between 1 and 64 characters".to_string());
+    }
+
+    Ok(())
+}
+
+// Helper function to create or update a feature flag
+fn store_feature_flag(name: String, enabled: bool) -> Result<String, String> {
+    check_feature_flag_name(&name)?;
+
+    let existing = FEATURE_FLAGS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).find(|(_, flag)| flag.name == name).map(|(id, _)| id)
+    });
+
+    let id = existing.unwrap_or_else(next_id);
+
+    let flag = FeatureFlag {
+        id,
+        name,
+        enabled,
+        updated_by: caller(),
+        updated_at: time(),
+    };
+
+    FEATURE_FLAGS_STORAGE.with(|storage| storage.borrow_mut().insert(id, flag));
+
+    Ok("Feature flag updated".to_string())
+}
+
+// Function to delete several discussions and their votes at once (admins only, unless the DAO governs).
+// With `dry_run` nothing changes and the report lists what would be removed, also while the DAO governs.
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn bulk_delete_discussions(discussion_ids: Vec<u64>, dry_run: bool) -> Result<ChangeReport, String> {
+    let _meter = metrics::measure("bulk_delete_discussions");
+
+    ensure_writable()?;
+
+    if !dry_run {
+        check_not_dao_governed()?;
+    }
+
+    delete_discussions(discussion_ids, dry_run)
+}
+
+// Helper function to delete discussions and their votes, reporting what was or would be removed
+fn delete_discussions(discussion_ids: Vec<u64>, dry_run: bool) -> Result<ChangeReport, String> {
+    let mut report = ChangeReport::new(dry_run);
+
+    for discussion_id in discussion_ids {
+        if DISCUSSIONS_STORAGE.with(|storage| storage.borrow().contains_key(&discussion_id)) {
+            plan_discussion_deletion(&mut report, discussion_id);
+            if !dry_run {
+                delete_discussion_and_votes(discussion_id);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+// Function to export the core forum data (admins only)
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn export_backup() -> Backup {
+    Backup {
+        next_id: ID_COUNTER.with(|counter| *counter.borrow().get()),
+        users: USERS_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).map(|(_, user)| user).collect()),
+        discussions: DISCUSSIONS_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).map(|(_, discussion)| discussion).collect()),
+        votes: VOTES_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).map(|(_, vote)| vote).collect()),
+        groups: GROUPS_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).map(|(_, group)| group).collect()),
+        group_members: GROUP_MEMBERS_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).map(|(_, member)| member).collect()),
+        principal_links: PRINCIPAL_LINKS_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).map(|(_, link)| link).collect()),
+    }
+}
+
+// Function to export the core forum data as a gzip-compressed CBOR blob (admins only)
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn export_backup_compressed() -> Result<CompressedBlob, String> {
+    compress_response(&export_backup())
+}
+
+// Helper function to pack a bulk query result as gzip-compressed CBOR
+fn compress_response<T: serde::Serialize>(value: &T) -> Result<CompressedBlob, String> {
+    let mut cbor = Vec::new();
+    ciborium::into_writer(value, &mut cbor).map_err(|e| format!("Cannot encode response: {}", e))?;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&cbor).map_err(|e| format!("Cannot compress response: {}", e))?;
+    let data = encoder.finish().map_err(|e| format!("Cannot compress response: {}", e))?;
+
+    Ok(CompressedBlob {
+        codec: Codec::GzipCbor,
+        uncompressed_size: cbor.len() as u64,
+        data,
+    })
+}
+
+// Function to snapshot a large result set so it can be fetched in chunks with stream_query
+#[ic_cdk::update]
+fn open_stream(source: StreamSource) -> Result<StreamInfo, String> {
+    let _meter = metrics::measure("open_stream");
+
+    ensure_writable()?;
+
+    let owner = caller();
+
+    if source == StreamSource::Backup && !is_admin(&owner) {
+        return Err("Caller is not an admin".to_string());
+    }
+
+    // get_discussions follows the caller's preferences
+    let includes_sensitive = source == StreamSource::Discussions && caller_preferences().show_sensitive;
+
+    let blob = match source {
+        StreamSource::Discussions => compress_response(&get_discussions())?,
+        StreamSource::Users => compress_response(&get_users())?,
+        StreamSource::Backup => compress_response(&export_backup())?,
+    };
+
+    let now = time();
+    let handle = next_id();
+    let info = StreamInfo {
+        handle,
+        codec: blob.codec,
+        total_size: blob.data.len() as u64,
+        chunk_count: chunk_count(&blob.data),
+        expires_at: now + STREAM_TTL,
+    };
+
+    STREAMS.with(|streams| {
+        let mut streams = streams.borrow_mut();
+        streams.retain(|_, snapshot| snapshot.expires_at > now);
+        // Evict the oldest snapshots, handles grow with time
+        while streams.len() >= MAX_OPEN_STREAMS {
+            streams.pop_first();
+        }
+        streams.insert(handle, StreamSnapshot {
+            owner,
+            source,
+            blob,
+            expires_at: info.expires_at,
+            includes_sensitive,
+            created_at: now,
+            manifest: None,
+        });
+    });
+
+    Ok(info)
+}
+
+// Function to fetch one chunk of a stream opened by the caller
+#[ic_cdk::query]
+fn stream_query(handle: u64, chunk_index: u32) -> Result<StreamChunk, String> {
+    STREAMS.with(|streams| {
+        let streams = streams.borrow();
+        let snapshot = streams.get(&handle)
+            .filter(|snapshot| snapshot.owner == caller() && snapshot.expires_at > time())
+            .ok_or("Stream not found")?;
+
+        let data = stream_chunk(&snapshot.blob.data, chunk_index).ok_or("Chunk index out of range")?;
+
+        Ok(StreamChunk {
+            handle,
+            chunk_index,
+            chunk_count: chunk_count(&snapshot.blob.data),
+            data: data.to_vec(),
+        })
+    })
+}
+
+// Function to sign a manifest of a stream opened by the caller with the canister's threshold ECDSA key, so
+// downstream consumers can prove an export came from this canister unmodified (admins only)
+#[ic_cdk::update(guard = "caller_is_admin")]
+async fn sign_stream_manifest(handle: u64) -> Result<SignedManifest, String> {
+    let _meter = metrics::measure("sign_stream_manifest");
+
+    ensure_writable()?;
+
+    let signed = STREAMS.with(|streams| {
+        streams.borrow().get(&handle)
+            .filter(|snapshot| snapshot.owner == caller() && snapshot.expires_at > time())
+            .map(|snapshot| snapshot.manifest.clone())
+    }).ok_or("Stream not found")?;
+    if let Some(signed) = signed {
+        return Ok(signed);
+    }
+
+    let manifest = STREAMS.with(|streams| streams.borrow().get(&handle).map(|snapshot| export_manifest(handle, snapshot)))
+        .ok_or("Stream not found")?;
+
+    let mut manifest_cbor = Vec::new();
+    ciborium::into_writer(&manifest, &mut manifest_cbor).map_err(|e| format!("Cannot encode manifest: {}", e))?;
+
+    let signature = signing::sign(&settings().signing_key_name, EXPORT_SIGNING_PURPOSE, &manifest_cbor).await?;
+    let signed = SignedManifest { manifest, manifest_cbor, signature };
+
+    STREAMS.with(|streams| {
+        if let Some(snapshot) = streams.borrow_mut().get_mut(&handle) {
+            snapshot.manifest = Some(signed.clone());
+        }
+    });
+
+    Ok(signed)
+}
+
+// Helper function to list the hash of every chunk of a stream snapshot
+fn export_manifest(handle: u64, snapshot: &StreamSnapshot) -> ExportManifest {
+    use k256::sha2::{Digest, Sha256};
+
+    let data = &snapshot.blob.data;
+
+    ExportManifest {
+        canister_id: ic_cdk::id(),
+        handle,
+        source: snapshot.source,
+        codec: snapshot.blob.codec,
+        total_size: data.len() as u64,
+        chunk_size: STREAM_CHUNK_SIZE as u64,
+        chunk_hashes: (0..chunk_count(data))
+            .filter_map(|index| stream_chunk(data, index))
+            .map(|chunk| Sha256::digest(chunk).to_vec())
+            .collect(),
+        created_at: snapshot.created_at,
+    }
+}
+
+// Helper function to count the chunks of a stream payload (an empty payload is still one chunk)
+fn chunk_count(data: &[u8]) -> u32 {
+    data.len().div_ceil(STREAM_CHUNK_SIZE).max(1) as u32
+}
+
+// Helper function to slice out one chunk of a stream payload
+fn stream_chunk(data: &[u8], chunk_index: u32) -> Option<&[u8]> {
+    if chunk_index >= chunk_count(data) {
+        return None;
+    }
+    let start = chunk_index as usize * STREAM_CHUNK_SIZE;
+    Some(&data[start..data.len().min(start + STREAM_CHUNK_SIZE)])
+}
+
+// Helper function to read a chunk of a public stream for the HTTP gateway, with the token for the next chunk
+fn public_stream_chunk(handle: u64, chunk_index: u32) -> Option<(Vec<u8>, Option<StreamingToken>)> {
+    STREAMS.with(|streams| {
+        let streams = streams.borrow();
+        let snapshot = streams.get(&handle)
+            .filter(|snapshot| snapshot.source != StreamSource::Backup && !snapshot.includes_sensitive && snapshot.expires_at > time())?;
+
+        let data = stream_chunk(&snapshot.blob.data, chunk_index)?.to_vec();
+        let next = (chunk_index + 1 < chunk_count(&snapshot.blob.data))
+            .then(|| StreamingToken { handle, chunk_index: chunk_index + 1 });
+
+        Some((data, next))
+    })
+}
+
+// Function to serve public streams at /streams/<handle> and images at /attachments/<id> over HTTP,
+// using the callback streaming strategy for anything larger than one chunk
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    let path = request.url.split('?').next().unwrap_or_default();
+    let id_after = |prefix: &str| path.strip_prefix(prefix).and_then(|id| id.parse::<u64>().ok());
+    let results_csv_id = path.strip_prefix("/discussions/")
+        .and_then(|rest| rest.strip_suffix("/results.csv"))
+        .and_then(|id| id.parse::<u64>().ok());
+
+    if request.method != "GET" {
+        return not_found();
+    }
+
+    if let Some((body, next)) = id_after("/streams/").and_then(|handle| public_stream_chunk(handle, 0)) {
+        return streamed_response(
+            vec![
+                ("Content-Type".to_string(), "application/cbor".to_string()),
+                ("Content-Encoding".to_string(), "gzip".to_string()),
+            ],
+            body,
+            next,
+        );
+    }
+
+    if let Some((attachment, body, next)) = id_after("/attachments/").and_then(|id| public_attachment_chunk(id, 0)) {
+        return streamed_response(
+            vec![
+                ("Content-Type".to_string(), attachment.content_type),
+                ("Content-Length".to_string(), attachment.total_size.to_string()),
+                ("Cache-Control".to_string(), "public, max-age=86400, immutable".to_string()),
+            ],
+            body,
+            next,
+        );
+    }
+
+    // Rendering the CSV snapshots it, which a query can't keep
+    if results_csv_id.and_then(public_results_discussion).is_some() {
+        return HttpResponse {
+            status_code: 200,
+            headers: Vec::new(),
+            body: Vec::new(),
+            streaming_strategy: None,
+            upgrade: Some(true),
+        };
+    }
+
+    not_found()
+}
+
+// Function called by the HTTP gateway for requests http_request upgraded: renders a discussion's results
+// CSV once and serves it in chunks from that snapshot. Only heap state changes, but it is unavailable in
+// maintenance mode like other updates.
+#[ic_cdk::update]
+fn http_request_update(request: HttpRequest) -> HttpResponse {
+    let _meter = metrics::measure("http_request_update");
+
+    let path = request.url.split('?').next().unwrap_or_default();
+    let discussion = path.strip_prefix("/discussions/")
+        .and_then(|rest| rest.strip_suffix("/results.csv"))
+        .and_then(|id| id.parse::<u64>().ok())
+        .and_then(public_results_discussion);
+
+    let Some(discussion) = discussion.filter(|_| request.method == "GET") else {
+        return not_found();
+    };
+
+    if let Err(error) = check_maintenance() {
+        return HttpResponse {
+            status_code: 503,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: error.into_bytes(),
+            streaming_strategy: None,
+            upgrade: None,
+        };
+    }
+
+    let now = time();
+    let handle = next_id();
+    let csv = results_csv(&discussion).into_bytes();
+    let body = stream_chunk(&csv, 0).unwrap_or_default().to_vec();
+    let next = (chunk_count(&csv) > 1).then_some(StreamingToken { handle, chunk_index: 1 });
+
+    RESULTS_CSV_SNAPSHOTS.with(|snapshots| {
+        let mut snapshots = snapshots.borrow_mut();
+        snapshots.retain(|_, snapshot| snapshot.expires_at > now);
+        // Evict the oldest downloads, handles grow with time
+        while snapshots.len() >= MAX_OPEN_STREAMS {
+            snapshots.pop_first();
+        }
+        if next.is_some() {
+            snapshots.insert(handle, ResultsCsvSnapshot { csv, expires_at: now + STREAM_TTL });
+        }
+    });
+
+    streamed_response(
+        vec![
+            ("Content-Type".to_string(), "text/csv; charset=utf-8".to_string()),
+            ("Content-Disposition".to_string(), "attachment; filename=\"results.csv\"".to_string()),
+        ],
+        body,
+        next,
+    )
+}
+
+// Helper function to get a discussion whose results are served as CSV over HTTP. Only public discussions whose
+// tallies are not hidden are served; voters who hide their vote history appear without a name.
+fn public_results_discussion(discussion_id: u64) -> Option<Discussion> {
+    load_discussion(discussion_id).filter(|discussion| can_access_discussion(discussion, "") && !tallies_hidden(discussion, time()))
+}
+
+// Helper function to read a later chunk of a results CSV download from its snapshot, so votes cast
+// mid-download can't shift the chunks
+fn public_results_csv_chunk(handle: u64, chunk_index: u32) -> Option<(Vec<u8>, Option<StreamingToken>)> {
+    RESULTS_CSV_SNAPSHOTS.with(|snapshots| {
+        let snapshots = snapshots.borrow();
+        let snapshot = snapshots.get(&handle).filter(|snapshot| snapshot.expires_at > time())?;
+
+        let data = stream_chunk(&snapshot.csv, chunk_index)?.to_vec();
+        let next = (chunk_index + 1 < chunk_count(&snapshot.csv))
+            .then(|| StreamingToken { handle, chunk_index: chunk_index + 1 });
+
+        Some((data, next))
+    })
+}
+
+// Helper function to render a discussion's votes, one row each, followed by the aggregated answers of its survey
+fn results_csv(discussion: &Discussion) -> String {
+    let hidden_voters: Vec<String> = USERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(id, _)| privacy_settings(*id).hide_vote_history)
+            .map(|(_, user)| user.username)
+            .collect()
+    });
+
+    let mut votes: Vec<Vote> = VOTES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, vote)| vote).filter(|vote| vote.discussion_id == discussion.id).collect()
+    });
+    votes.sort_by_key(|vote| vote.created_at);
+
+    let mut rows = vec![["item", "answer", "count", "voter", "reason", "cast_at_ns"].map(str::to_string)];
+
+    for vote in votes {
+        let answer = match vote.vote_type {
+            VoteType::Upvote => "upvote",
+            VoteType::Downvote => "downvote",
+        };
+        let reason = match vote.reason {
+            Some(DownvoteReason::OffTopic) => "off_topic",
+            Some(DownvoteReason::Inaccurate) => "inaccurate",
+            Some(DownvoteReason::Spam) => "spam",
+            None => "",
+        };
+        let voter = if hidden_voters.contains(&vote.by) { String::new() } else { vote.by };
+
+        rows.push(["vote".to_string(), answer.to_string(), "1".to_string(), voter, reason.to_string(), vote.created_at.to_string()]);
+    }
+
+    if let Some(results) = survey_of_discussion(discussion.id).and_then(|survey| get_survey_results(survey.id, String::new()).ok()) {
+        for (question, results) in results.survey.questions.iter().zip(results.questions) {
+            let answers: Vec<String> = match &question.kind {
+                QuestionKind::SingleChoice { options } | QuestionKind::MultiChoice { options } => options.clone(),
+                QuestionKind::Rating { min, max } => (*min..=*max).map(|rating| rating.to_string()).collect(),
+            };
+
+            for (answer, count) in answers.into_iter().zip(results.counts) {
+                rows.push([question.prompt.clone(), answer, count.to_string(), String::new(), String::new(), String::new()]);
+            }
+        }
+    }
+
+    rows.iter()
+        .map(|row| row.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(","))
+        .map(|line| line + "\r\n")
+        .collect()
+}
+
+// Helper function to quote a CSV field when needed. Text a spreadsheet would read as a formula is prefixed with
+// an apostrophe so user-written prompts and options cannot run as formulas.
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@']) { format!("'{}", field) } else { field.to_string() };
+
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+// Helper function to build a 200 response whose remaining chunks come through the streaming callback
+fn streamed_response(headers: Vec<(String, String)>, body: Vec<u8>, next: Option<StreamingToken>) -> HttpResponse {
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body,
+        streaming_strategy: next.map(|token| StreamingStrategy::Callback {
+            callback: StreamingCallback::new(ic_cdk::id(), "http_request_streaming_callback".to_string()),
+            token,
+        }),
+        upgrade: None,
+    }
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse {
+        status_code: 404,
+        headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+        body: b"Not found".to_vec(),
+        streaming_strategy: None,
+        upgrade: None,
+    }
+}
+
+// Function called by the HTTP gateway to fetch the following chunks of a stream, attachment or results CSV.
+// Stream handles, attachment IDs and download handles all come from next_id, so a token's handle names exactly one of them.
+#[ic_cdk::query]
+fn http_request_streaming_callback(token: StreamingToken) -> StreamingCallbackHttpResponse {
+    let chunk = public_stream_chunk(token.handle, token.chunk_index)
+        .or_else(|| public_attachment_chunk(token.handle, token.chunk_index).map(|(_, body, next)| (body, next)))
+        .or_else(|| public_results_csv_chunk(token.handle, token.chunk_index));
+
+    match chunk {
+        Some((body, token)) => StreamingCallbackHttpResponse { body, token },
+        None => StreamingCallbackHttpResponse { body: Vec::new(), token: None },
+    }
+}
+
+// Bytes a user has stored, by kind, against their quota
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StorageUsage {
+    discussion_bytes: u64,
+    attachment_bytes: u64,
+    total_bytes: u64,
+    // None when quotas are disabled
+    quota_bytes: Option<u64>,
+    remaining_bytes: Option<u64>,
+}
+
+// Helper function to add up the bytes a user stores; unfinished uploads count at their declared size
+fn storage_usage(username: &str) -> StorageUsage {
+    let discussion_bytes: u64 = DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, discussion)| discussion.created_by == username)
+            .map(|(_, discussion)| discussion.topic.len() as u64)
+            .sum()
+    });
+    let attachment_bytes: u64 = ATTACHMENTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, attachment)| attachment.owner == username)
+            .map(|(_, attachment)| attachment.total_size)
+            .sum()
+    });
+    let total_bytes = discussion_bytes + attachment_bytes;
+    // Repeat purchases extend the boost rather than stacking it
+    let boosted = active_entitlements(username).iter().any(|entitlement| entitlement.feature == PremiumFeature::StorageBoost);
+    let boost_bytes = if boosted { STORAGE_BOOST_BYTES } else { 0 };
+    let quota_bytes = Some(settings().storage_quota_bytes).filter(|quota| *quota > 0).map(|quota| quota + boost_bytes);
+
+    StorageUsage {
+        discussion_bytes,
+        attachment_bytes,
+        total_bytes,
+        quota_bytes,
+        remaining_bytes: quota_bytes.map(|quota| quota.saturating_sub(total_bytes)),
+    }
+}
+
+// Helper function to reject a write that would take a user past their storage quota
+fn check_storage_quota(username: &str, additional_bytes: u64) -> Result<(), String> {
+    let usage = storage_usage(username);
+
+    match usage.quota_bytes {
+        Some(quota) if usage.total_bytes + additional_bytes > quota => Err(format!(
+            "Storage quota exceeded: {} of {} bytes used, this needs {} more",
+            usage.total_bytes, quota, additional_bytes
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Function to show a user how much of their storage quota is used
+#[ic_cdk::query]
+fn get_my_storage_usage(username: String) -> Result<StorageUsage, String> {
+    authenticate(&username)?;
+
+    Ok(storage_usage(&username))
+}
+
+// Function to start an image upload; send the bytes with upload_attachment in ATTACHMENT_CHUNK_SIZE pieces
+#[ic_cdk::update]
+fn create_attachment(content_type: String, total_size: u64, username: String) -> Result<Attachment, String> {
+    let _meter = metrics::measure("create_attachment");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+    permissions::check_permission(&username, Action::AttachImage)?;
+
+    if !ATTACHMENT_CONTENT_TYPES.contains(&content_type.as_str()) {
+        return Err(format!("Content type must be one of {}", ATTACHMENT_CONTENT_TYPES.join(", ")));
+    }
+
+    if total_size == 0 || total_size > MAX_ATTACHMENT_SIZE {
+        return Err(format!("Attachments must be between 1 and {} bytes", MAX_ATTACHMENT_SIZE));
+    }
+
+    check_storage_quota(&username, total_size)?;
+
+    let attachment = Attachment {
+        id: next_id(),
+        owner: username,
+        content_type,
+        total_size,
+        uploaded: 0,
+        chunk_ids: Vec::new(),
+        complete: false,
+        created_at: time(),
+    };
+
+    ATTACHMENTS_STORAGE.with(|storage| storage.borrow_mut().insert(attachment.id, attachment.clone()));
+
+    Ok(attachment)
+}
+
+// Function to append the next chunk of an attachment; chunks must arrive in order
+#[ic_cdk::update]
+fn upload_attachment(attachment_id: u64, chunk_index: u32, data: Vec<u8>, username: String) -> Result<Attachment, String> {
+    let _meter = metrics::measure("upload_attachment");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut attachment = try_get(&ATTACHMENTS_STORAGE, attachment_id)
+        .filter(|attachment| attachment.owner == username)
+        .ok_or(ErrorCode::AttachmentNotFound)?;
+
+    if attachment.complete {
+        return Err("Attachment is already complete".to_string());
+    }
+
+    if chunk_index as usize != attachment.chunk_ids.len() {
+        return Err(format!("Expected chunk {}", attachment.chunk_ids.len()));
+    }
+
+    if data.is_empty() || data.len() > ATTACHMENT_CHUNK_SIZE {
+        return Err(format!("Chunks must be between 1 and {} bytes", ATTACHMENT_CHUNK_SIZE));
+    }
+
+    if attachment.uploaded + data.len() as u64 > attachment.total_size {
+        return Err("Chunk exceeds the declared attachment size".to_string());
+    }
+
+    // The first bytes must match the declared image type
+    if chunk_index == 0 && !has_image_signature(&attachment.content_type, &data) {
+        return Err(format!("Data is not a valid {} image", attachment.content_type));
+    }
+
+    let chunk_id = next_id();
+    attachment.uploaded += data.len() as u64;
+    attachment.chunk_ids.push(chunk_id);
+    attachment.complete = attachment.uploaded == attachment.total_size;
+
+    ATTACHMENT_CHUNKS_STORAGE.with(|storage| storage.borrow_mut().insert(chunk_id, AttachmentChunk { data }));
+    ATTACHMENTS_STORAGE.with(|storage| storage.borrow_mut().insert(attachment_id, attachment.clone()));
+
+    Ok(attachment)
+}
+
+// Helper function to check the magic bytes of an image against its declared content type
+fn has_image_signature(content_type: &str, data: &[u8]) -> bool {
+    match content_type {
+        "image/png" => data.starts_with(b"\x89PNG\r\n\x1a\n"),
+        "image/jpeg" => data.starts_with(&[0xFF, 0xD8, 0xFF]),
+        "image/gif" => data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a"),
+        "image/webp" => data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP",
+        _ => false,
+    }
+}
+
+// Function to attach a completed upload to a discussion (only by the creator of both)
+#[ic_cdk::update]
+fn attach_to_discussion(discussion_id: u64, attachment_id: u64, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("attach_to_discussion");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let mut discussion = get_owned_discussion(discussion_id, &username)?;
+
+    let attachment = try_get(&ATTACHMENTS_STORAGE, attachment_id)
+        .filter(|attachment| attachment.owner == username)
+        .ok_or(ErrorCode::AttachmentNotFound)?;
+
+    if !attachment.complete {
+        return Err("Attachment upload is not complete".to_string());
+    }
+
+    if discussion.attachments.contains(&attachment_id) {
+        return Err("Attachment is already on this discussion".to_string());
+    }
+
+    if discussion.attachments.len() >= MAX_ATTACHMENTS_PER_DISCUSSION {
+        return Err(format!("A discussion can have at most {} attachments", MAX_ATTACHMENTS_PER_DISCUSSION));
+    }
+
+    discussion.attachments.push(attachment_id);
+    discussion.version += 1;
+
+    save_discussion(discussion_id, discussion);
+
+    Ok("Attachment added to discussion".to_string())
+}
+
+// Function to delete an attachment, also removing it from any discussion
+#[ic_cdk::update]
+fn delete_attachment(attachment_id: u64, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("delete_attachment");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let removed = remove_attachments(|attachment| attachment.id == attachment_id && attachment.owner == username);
+
+    if removed == 0 {
+        return Err(ErrorCode::AttachmentNotFound.into());
+    }
+
+    Ok("Attachment deleted".to_string())
+}
+
+// Function to list a user's attachments
+#[ic_cdk::query]
+fn get_my_attachments(username: String) -> Result<Vec<Attachment>, String> {
+    authenticate(&username)?;
+
+    Ok(ATTACHMENTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, attachment)| attachment.owner == username)
+            .map(|(_, attachment)| attachment)
+            .collect()
+    }))
+}
+
+// Helper function to report what remove_attachments would change for the same predicate
+fn plan_attachment_removal(report: &mut ChangeReport, predicate: impl Fn(&Attachment) -> bool) {
+    let doomed: Vec<Attachment> = ATTACHMENTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, attachment)| predicate(attachment))
+            .map(|(_, attachment)| attachment)
+            .collect()
+    });
+    let ids: Vec<u64> = doomed.iter().map(|attachment| attachment.id).collect();
+
+    report.removed("attachments", ids.iter().copied());
+    report.removed("attachment_chunks", doomed.iter().flat_map(|attachment| attachment.chunk_ids.iter().copied()));
+
+    if !ids.is_empty() {
+        let referencing: Vec<u64> = DISCUSSIONS_STORAGE.with(|storage| {
+            storage.borrow().iter().filter(decoded)
+                .filter(|(_, discussion)| discussion.attachments.iter().any(|id| ids.contains(id)))
+                .map(|(id, _)| id)
+                .collect()
+        });
+        report.updated("discussions", referencing);
+    }
+}
+
+// Helper function to delete matching attachments with their chunks and references, returns how many were removed
+fn remove_attachments(predicate: impl Fn(&Attachment) -> bool) -> u64 {
+    let doomed: Vec<Attachment> = ATTACHMENTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, attachment)| predicate(attachment))
+            .map(|(_, attachment)| attachment)
+            .collect()
+    });
+
+    for attachment in &doomed {
+        ATTACHMENT_CHUNKS_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            for chunk_id in &attachment.chunk_ids {
+                storage.remove(chunk_id);
+            }
+        });
+        ATTACHMENTS_STORAGE.with(|storage| storage.borrow_mut().remove(&attachment.id));
+    }
+
+    if !doomed.is_empty() {
+        let ids: Vec<u64> = doomed.iter().map(|attachment| attachment.id).collect();
+        let referencing: Vec<Discussion> = DISCUSSIONS_STORAGE.with(|storage| {
+            storage.borrow().iter().filter(decoded)
+                .filter(|(_, discussion)| discussion.attachments.iter().any(|id| ids.contains(id)))
+                .map(|(_, discussion)| discussion)
+                .collect()
+        });
+        for mut discussion in referencing {
+            discussion.attachments.retain(|id| !ids.contains(id));
+            save_discussion(discussion.id, discussion);
+        }
+    }
+
+    doomed.len() as u64
+}
+
+// Helper function to read one chunk of a completed attachment for the HTTP gateway. Only attachments
+// that every referencing discussion shows to anonymous viewers are served.
+fn public_attachment_chunk(attachment_id: u64, chunk_index: u32) -> Option<(Attachment, Vec<u8>, Option<StreamingToken>)> {
+    let attachment = try_get(&ATTACHMENTS_STORAGE, attachment_id)
+        .filter(|attachment| attachment.complete)?;
+
+    let restricted = DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .any(|(_, discussion)| {
+                discussion.attachments.contains(&attachment_id) && (discussion.sensitive || !can_access_discussion(&discussion, ""))
+            })
+    });
+    if restricted {
+        return None;
+    }
+
+    let chunk_id = *attachment.chunk_ids.get(chunk_index as usize)?;
+    let data = try_get(&ATTACHMENT_CHUNKS_STORAGE, chunk_id)?.data;
+    let next = (chunk_index as usize + 1 < attachment.chunk_ids.len())
+        .then(|| StreamingToken { handle: attachment_id, chunk_index: chunk_index + 1 });
+
+    Some((attachment, data, next))
+}
+
+// Function to replace the core forum data with a backup (controllers only, unless the DAO governs). With
+// `dry_run` nothing changes and the report lists the records the backup would remove, overwrite or add,
+// also while the DAO governs. Maintenance mode doesn't block it, so writes can be paused during a restore.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn restore_backup(backup: Backup, dry_run: bool) -> Result<ChangeReport, String> {
+    let _meter = metrics::measure("restore_backup");
+
+    if !dry_run {
+        check_not_dao_governed()?;
+    }
+
+    apply_backup(backup, dry_run)
+}
+
+// Helper function to replace the core forum data with a backup, reporting what was or would be changed
+fn apply_backup(backup: Backup, dry_run: bool) -> Result<ChangeReport, String> {
+    let mut report = ChangeReport::new(dry_run);
+
+    // Accounts that disappear have to reach the group canisters as well as the restored ones
+    let mut user_ids: BTreeSet<u64> = USERS_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).map(|(id, _)| id).collect());
+    user_ids.extend(backup.users.iter().map(|user| user.id));
+
+    report.replace_all("users", &USERS_STORAGE, backup.users.into_iter().map(|user| (user.id, user)).collect());
+    report.replace_all("discussions", &DISCUSSIONS_STORAGE, backup.discussions.into_iter().map(|discussion| (discussion.id, discussion)).collect());
+    report.replace_all("votes", &VOTES_STORAGE, backup.votes.into_iter().map(|vote| (vote.id, vote)).collect());
+    report.replace_all("groups", &GROUPS_STORAGE, backup.groups.into_iter().map(|group| (group.id, group)).collect());
+    report.replace_all("group_members", &GROUP_MEMBERS_STORAGE, backup.group_members.into_iter().map(|member| (member.id, member)).collect());
+    report.replace_all("principal_links", &PRINCIPAL_LINKS_STORAGE, backup.principal_links.into_iter().map(|link| (link.id, link)).collect());
+
+    if dry_run {
+        return Ok(report);
+    }
+    clear_discussion_cache();
+    mark_all_vote_roots_stale();
+    rebuild_karma_tallies();
+    rebuild_voting_power_index();
+    rebuild_principal_link_indexes();
+    for user_id in user_ids {
+        queue_account_sync(user_id);
+    }
+
+    // Never hand out an ID that may already be in use
+    ID_COUNTER.with(|counter| {
+        let current_value = *counter.borrow().get();
+        counter.borrow_mut().set(current_value.max(backup.next_id))
+    }).expect("Cannot update ID counter");
+
+    Ok(report)
+}
+
+// Helper function to check if a discussion appears in the forum-wide listing
+fn is_forum_listed(discussion: &Discussion) -> bool {
+    discussion.group_id.is_none() && matches!(discussion.visibility, Visibility::Public)
+}
+
+// Function to get all public discussions
+#[ic_cdk::query]
+fn get_discussions() -> Vec<Discussion> {
+    let preferences = caller_preferences();
+
+    let mut discussions: Vec<Discussion> = DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, discussion)| is_forum_listed(discussion) && matches_preferences(discussion, &preferences))
+            .map(|(_, discussion)| redact_tallies(discussion))
+            .collect()
+    });
+
+    // Pinned discussions first, the sort is stable so both parts keep their order
+    let pinned = pinned_discussion_ids();
+    discussions.sort_by_key(|discussion| !pinned.contains(&discussion.id));
+
+    discussions
+}
+
+// Function to get the public discussions with a paid pin, for frontends that show them above paged listings
+#[ic_cdk::query]
+fn get_pinned_discussions() -> Vec<Discussion> {
+    let preferences = caller_preferences();
+
+    pinned_discussion_ids().into_iter()
+        .filter_map(load_discussion)
+        .filter(|discussion| is_forum_listed(discussion) && matches_preferences(discussion, &preferences))
+        .map(redact_tallies)
+        .collect()
+}
+
+// Function to get all public discussions as a gzip-compressed CBOR blob
+#[ic_cdk::query]
+fn get_discussions_compressed() -> Result<CompressedBlob, String> {
+    compress_response(&get_discussions())
+}
+
+// Function to page through public discussions
+#[ic_cdk::query]
+fn get_discussions_page(cursor: Option<String>, limit: Option<u32>) -> Result<Page<Discussion>, String> {
+    let preferences = caller_preferences();
+
+    paginate(&DISCUSSIONS_STORAGE, cursor, limit, |_, discussion| {
+        is_forum_listed(discussion) && matches_preferences(discussion, &preferences)
+    }).map(redact_page)
+}
+
+// Function to get a single discussion, including unlisted and private ones the user can access
+#[ic_cdk::query]
+fn get_discussion(discussion_id: u64, username: String) -> Result<Discussion, String> {
+    let username = resolve_viewer(username);
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if !can_access_discussion(&discussion, &username) {
+        return Err(ErrorCode::DiscussionNotFound.into());
+    }
+
+    Ok(redact_tallies(discussion))
+}
+
+// Function to get a discussion with its tallies, the viewer's vote and related discussions in one call
+#[ic_cdk::query]
+fn get_discussion_page(discussion_id: u64, username: String) -> Result<DiscussionPage, String> {
+    let discussion = get_discussion(discussion_id, username.clone())?;
+    let username = resolve_viewer(username);
+
+    let my_vote = VOTES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .find(|(_, vote)| vote.discussion_id == discussion_id && vote.by == username)
+            .map(|(_, vote)| vote)
+    });
+
+    let group = discussion.group_id.and_then(|group_id| try_get(&GROUPS_STORAGE, group_id));
+
+    let preferences = viewer_preferences(&username);
+
+    let mut related: Vec<Discussion> = DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(id, other)| *id != discussion_id && matches_preferences(other, &preferences))
+            .filter(|(_, other)| match discussion.group_id {
+                Some(group_id) => is_group_listed(other, group_id, &username),
+                None => is_forum_listed(other),
+            })
+            .map(|(_, other)| redact_tallies(other))
+            .collect()
+    });
+    related.sort_by_key(|other| std::cmp::Reverse(other.created_at));
+    related.truncate(RELATED_DISCUSSIONS);
+
+    Ok(DiscussionPage {
+        upvotes: discussion.upvotes,
+        downvotes: discussion.downvotes,
+        discussion,
+        my_vote,
+        group,
+        related,
+        link_preview: try_get(&LINK_PREVIEWS_STORAGE, discussion_id),
+        topic_markdown: try_get(&TOPIC_MARKDOWN_STORAGE, discussion_id).map(|text| text.markdown),
+        forks: visible_forks(discussion_id, &username),
+    })
+}
+
+// Function to get the discussions forked from a discussion, newest first
+#[ic_cdk::query]
+fn get_discussion_forks(discussion_id: u64, username: String) -> Result<Vec<Discussion>, String> {
+    get_discussion(discussion_id, username.clone())?;
+
+    Ok(visible_forks(discussion_id, &resolve_viewer(username)))
+}
+
+// Function to get the discussions quoting a discussion, newest first
+#[ic_cdk::query]
+fn get_quote_backlinks(discussion_id: u64, username: String) -> Result<Vec<Discussion>, String> {
+    get_discussion(discussion_id, username.clone())?;
+    let username = resolve_viewer(username);
+
+    let mut replies: Vec<Discussion> = DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .map(|(_, reply)| reply)
+            .filter(|reply| reply.quote.as_ref().is_some_and(|quote| quote.discussion_id == discussion_id))
+            .filter(|reply| can_access_discussion(reply, &username))
+            .map(redact_tallies)
+            .collect()
+    });
+
+    replies.sort_by_key(|reply| std::cmp::Reverse(reply.created_at));
+    Ok(replies)
+}
+
+// Helper function to list the forks of a discussion a viewer may see
+fn visible_forks(discussion_id: u64, username: &str) -> Vec<Discussion> {
+    let mut forks: Vec<Discussion> = DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .map(|(_, fork)| fork)
+            .filter(|fork| fork.forked_from == Some(discussion_id) && can_access_discussion(fork, username))
+            .map(redact_tallies)
+            .collect()
+    });
+
+    forks.sort_by_key(|fork| std::cmp::Reverse(fork.created_at));
+    forks
+}
+
+// Function to page through groups
+#[ic_cdk::query]
+fn get_groups_page(cursor: Option<String>, limit: Option<u32>) -> Result<Page<Group>, String> {
+    paginate(&GROUPS_STORAGE, cursor, limit, |_, _| true)
+}
+
+// Function to get all groups
+#[ic_cdk::query]
+fn get_groups() -> Vec<Group> {
+    GROUPS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, group)| group).collect()
+    })
+}
+
+// Function to get the members of a group
+#[ic_cdk::query]
+fn get_group_members(group_id: u64) -> Result<Vec<GroupMember>, String> {
+    if !GROUPS_STORAGE.with(|storage| storage.borrow().contains_key(&group_id)) {
+        return Err(ErrorCode::GroupNotFound.into());
+    }
+
+    Ok(GROUP_MEMBERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, member)| member.group_id == group_id)
+            .map(|(_, member)| member)
+            .collect()
+    }))
+}
+
+// Function to page through the members of a group
+#[ic_cdk::query]
+fn get_group_members_page(group_id: u64, cursor: Option<String>, limit: Option<u32>) -> Result<Page<GroupMember>, String> {
+    if !GROUPS_STORAGE.with(|storage| storage.borrow().contains_key(&group_id)) {
+        return Err(ErrorCode::GroupNotFound.into());
+    }
+
+    paginate(&GROUP_MEMBERS_STORAGE, cursor, limit, |_, member| member.group_id == group_id)
+}
+
+// Function to get a group's invites (requires the moderate permission)
+#[ic_cdk::query]
+fn get_group_invites(group_id: u64, username: String) -> Result<Vec<GroupInvite>, String> {
+    authenticate(&username)?;
+
+    get_moderated_group(group_id, &username)?;
+
+    Ok(GROUP_INVITES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, invite)| invite.group_id == group_id)
+            .map(|(_, invite)| invite)
+            .collect()
+    }))
+}
+
+// Function to get a group's pending join requests (requires the moderate permission)
+#[ic_cdk::query]
+fn get_join_requests(group_id: u64, username: String) -> Result<Vec<JoinRequest>, String> {
+    authenticate(&username)?;
+
+    get_moderated_group(group_id, &username)?;
+
+    Ok(JOIN_REQUESTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, request)| request.group_id == group_id && request.status == JoinRequestStatus::Pending)
+            .map(|(_, request)| request)
+            .collect()
+    }))
+}
+
+// Function to get a group's open reports (requires the moderate permission)
+#[ic_cdk::query]
+fn get_group_reports(group_id: u64, username: String) -> Result<Vec<Report>, String> {
+    authenticate(&username)?;
+
+    get_moderated_group(group_id, &username)?;
+
+    Ok(REPORTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, report)| report.group_id == Some(group_id) && report.status == ReportStatus::Open)
+            .map(|(_, report)| report)
+            .collect()
+    }))
+}
+
+// Function to get a group's audit log (requires the moderate permission)
+#[ic_cdk::query]
+fn get_group_audit_log(group_id: u64, username: String) -> Result<Vec<GroupAuditEntry>, String> {
+    authenticate(&username)?;
+
+    get_moderated_group(group_id, &username)?;
+
+    Ok(GROUP_AUDIT_LOG.with(|log| {
+        log.borrow().iter().filter(decoded)
+            .filter(|(_, entry)| entry.group_id == group_id)
+            .map(|(_, entry)| entry)
+            .collect()
+    }))
+}
+
+// Helper function to check that a viewer may list a group's discussions
+fn check_group_readable(group_id: u64, username: &str) -> Result<(), String> {
+    let group = try_get(&GROUPS_STORAGE, group_id).ok_or(ErrorCode::GroupNotFound)?;
+
+    if group.settings.members_only && !is_group_member(group_id, username) {
+        return Err(ErrorCode::NotGroupMember.into());
+    }
+
+    Ok(())
+}
+
+// Helper function to check if a discussion appears in a group's listing for the viewer
+fn is_group_listed(discussion: &Discussion, group_id: u64, username: &str) -> bool {
+    discussion.group_id == Some(group_id)
+        && !matches!(discussion.visibility, Visibility::Unlisted)
+        && can_access_discussion(discussion, username)
+}
+
+// Function to get the listed discussions of a group the user can see
+#[ic_cdk::query]
+fn get_group_discussions(group_id: u64, username: String) -> Result<Vec<Discussion>, String> {
+    let username = resolve_viewer(username);
+
+    check_group_readable(group_id, &username)?;
+
+    let preferences = viewer_preferences(&username);
+
+    Ok(DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, discussion)| is_group_listed(discussion, group_id, &username) && matches_preferences(discussion, &preferences))
+            .map(|(_, discussion)| redact_tallies(discussion))
+            .collect()
+    }))
+}
+
+// Function to page through the listed discussions of a group the user can see
+#[ic_cdk::query]
+fn get_group_discussions_page(group_id: u64, username: String, cursor: Option<String>, limit: Option<u32>) -> Result<Page<Discussion>, String> {
+    let username = resolve_viewer(username);
+
+    check_group_readable(group_id, &username)?;
+
+    let preferences = viewer_preferences(&username);
+
+    paginate(&DISCUSSIONS_STORAGE, cursor, limit, |_, discussion| {
+        is_group_listed(discussion, group_id, &username) && matches_preferences(discussion, &preferences)
+    }).map(redact_page)
+}
+
+// Function to get the principals linked to an account
+#[ic_cdk::query]
+fn get_linked_principals(username: String) -> Result<Vec<Principal>, String> {
+    authenticate(&username)?;
+
+    Ok(linked_principals(&username))
+}
+
+// Function to get the pending recovery of an account, if any
+#[ic_cdk::query]
+fn get_pending_recovery(username: String) -> Result<Option<PendingRecovery>, String> {
+    authenticate(&username)?;
+
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    Ok(try_get(&PENDING_RECOVERIES_STORAGE, user.id))
+}
+
+// Function to get an account's security audit log
+#[ic_cdk::query]
+fn get_account_audit_log(username: String) -> Result<Vec<AccountAuditEntry>, String> {
+    authenticate(&username)?;
+
+    Ok(ACCOUNT_AUDIT_LOG.with(|log| {
+        log.borrow().iter().filter(decoded)
+            .filter(|(_, entry)| entry.username == username)
+            .map(|(_, entry)| entry)
+            .collect()
+    }))
+}
+
+// Function to get an aggregated canister overview (admins only)
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_admin_overview() -> Result<AdminOverview, String> {
+    let mut users: Vec<User> = USERS_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).map(|(_, user)| user).collect());
+    let total_users = users.len() as u64;
+    users.sort_by_key(|user| std::cmp::Reverse(user.created_at));
+    users.truncate(ADMIN_RECENT_REGISTRATIONS);
+
+    Ok(AdminOverview {
+        total_users,
+        total_discussions: DISCUSSIONS_STORAGE.with(|storage| storage.borrow().len()),
+        total_votes: VOTES_STORAGE.with(|storage| storage.borrow().len()),
+        total_groups: GROUPS_STORAGE.with(|storage| storage.borrow().len()),
+        open_reports: REPORTS_STORAGE.with(|storage| {
+            storage.borrow().iter().filter(decoded).filter(|(_, report)| report.status == ReportStatus::Open).count() as u64
+        }),
+        pending_join_requests: JOIN_REQUESTS_STORAGE.with(|storage| {
+            storage.borrow().iter().filter(decoded).filter(|(_, request)| request.status == JoinRequestStatus::Pending).count() as u64
+        }),
+        recent_registrations: users,
+        stable_memory_bytes: ic_cdk::api::stable::stable64_size() * 65536,
+        cycles_balance: ic_cdk::api::canister_balance128(),
+    })
+}
+
+// Function to read the canister log, oldest first: entries at or after `since` and at least as severe as `level`
+// (controllers only)
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn get_logs(since: Option<u64>, level: Option<LogLevel>, limit: Option<u32>) -> Vec<logging::LogEntry> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_LOG_PAGE_SIZE);
+
+    logging::entries(since.unwrap_or_default(), level.unwrap_or(LogLevel::Debug), limit as usize)
+}
+
+// Function to get the interface version and which optional subsystems are enabled
+#[ic_cdk::query]
+fn get_api_version() -> ApiVersion {
+    let (major, minor, patch) = API_VERSION;
+    let settings = settings();
+
+    let mut capabilities = get_feature_flags();
+    capabilities.extend([
+        ("maintenance_mode".to_string(), check_maintenance().is_err()),
+        ("personhood".to_string(), settings.personhood_provider.is_some()),
+        ("treasury".to_string(), settings.treasury_ledger.is_some()),
+        ("premium".to_string(), settings.treasury_ledger.is_some() && (settings.pin_price > 0 || settings.storage_boost_price > 0)),
+        ("archive".to_string(), settings.archive_after_days > 0),
+        ("sharding".to_string(), has_shards()),
+        ("search_index".to_string(), search_index_canister().is_some()),
+        ("dao_governance".to_string(), dao_governance().is_some()),
+        ("terms_of_service".to_string(), current_terms().is_some()),
+    ]);
+
+    ApiVersion {
+        version: format!("{}.{}.{}", major, minor, patch),
+        major,
+        minor,
+        patch,
+        role: canister_role(),
+        capabilities,
+        locales: errors::LOCALES.iter().map(|locale| locale.to_string()).collect(),
+    }
+}
+
+// Function to get every known feature flag and its current value
+#[ic_cdk::query]
+fn get_feature_flags() -> Vec<(String, bool)> {
+    let mut flags: Vec<(String, bool)> = FEATURE_FLAGS.iter()
+        .map(|(name, _)| (name.to_string(), is_feature_enabled(name)))
+        .collect();
+
+    FEATURE_FLAGS_STORAGE.with(|storage| {
+        for (_, flag) in storage.borrow().iter().filter(decoded) {
+            if !FEATURE_FLAGS.iter().any(|(name, _)| *name == flag.name) {
+                flags.push((flag.name, flag.enabled));
+            }
+        }
+    });
+
+    flags
+}
+
+// Function to get entity counts and garbage collection totals
+#[ic_cdk::query]
+fn get_metrics() -> Metrics {
+    Metrics {
+        users: USERS_STORAGE.with(|storage| storage.borrow().len()),
+        discussions: DISCUSSIONS_STORAGE.with(|storage| storage.borrow().len()),
+        votes: VOTES_STORAGE.with(|storage| storage.borrow().len()),
+        groups: GROUPS_STORAGE.with(|storage| storage.borrow().len()),
+        gc: GC_STATE.with(|state| state.borrow().get().clone()),
+        discussion_cache: DISCUSSION_CACHE.with(|cache| cache.borrow().stats()),
+        endpoints: metrics::endpoint_metrics(),
+    }
+}
+
+// Applies `$map` to every stable map and `$cell` to every stable cell, passing (name, MemoryId, storage).
+// Add new maps and cells here so they show up in the storage stats and the state checksum.
+macro_rules! stable_regions {
+    ($map:ident, $cell:ident) => {
+        vec![
+            $cell("id_counter", 0, &ID_COUNTER),
+            $map("users", 1, &USERS_STORAGE),
+            $map("discussions", 91, &DISCUSSIONS_STORAGE),
+            $map("votes", 4, &VOTES_STORAGE),
+            $map("groups", 92, &GROUPS_STORAGE),
+            $map("group_members", 6, &GROUP_MEMBERS_STORAGE),
+            $map("group_invites", 7, &GROUP_INVITES_STORAGE),
+            $map("join_requests", 8, &JOIN_REQUESTS_STORAGE),
+            $map("reports", 9, &REPORTS_STORAGE),
+            $map("group_audit_log", 10, &GROUP_AUDIT_LOG),
+            $map("conversations", 11, &CONVERSATIONS_STORAGE),
+            $map("messages", 12, &MESSAGES_STORAGE),
+            $map("principal_links", 13, &PRINCIPAL_LINKS_STORAGE),
+            $map("link_challenges", 14, &LINK_CHALLENGES_STORAGE),
+            $map("siwe_challenges", 15, &SIWE_CHALLENGES_STORAGE),
+            $map("privacy_settings", 16, &PRIVACY_SETTINGS_STORAGE),
+            $map("recovery_configs", 17, &RECOVERY_CONFIGS_STORAGE),
+            $map("pending_recoveries", 18, &PENDING_RECOVERIES_STORAGE),
+            $map("account_audit_log", 19, &ACCOUNT_AUDIT_LOG),
+            $map("tombstones", 20, &TOMBSTONES_STORAGE),
+            $map("admins", 21, &ADMINS_STORAGE),
+            $map("feature_flags", 23, &FEATURE_FLAGS_STORAGE),
+            $map("idempotency", 26, &IDEMPOTENCY_STORAGE),
+            $map("vote_commitments", 27, &VOTE_COMMITMENTS_STORAGE),
+            $map("personhood", 28, &PERSONHOOD_STORAGE),
+            $map("registration_challenges", 29, &REGISTRATION_CHALLENGES_STORAGE),
+            $map("registration_events", 30, &REGISTRATION_EVENTS_STORAGE),
+            $map("views", 31, &VIEWS_STORAGE),
+            $map("read_markers", 32, &READ_MARKERS_STORAGE),
+            $map("needs_attention_index", 33, &NEEDS_ATTENTION_INDEX),
+            $map("keyword_alerts", 34, &KEYWORD_ALERTS_STORAGE),
+            $map("notifications", 35, &NOTIFICATIONS_STORAGE),
+            $map("hashtag_index", 36, &HASHTAG_INDEX),
+            $map("link_previews", 37, &LINK_PREVIEWS_STORAGE),
+            $map("link_preview_queue", 38, &LINK_PREVIEW_QUEUE),
+            $map("topic_markdown", 39, &TOPIC_MARKDOWN_STORAGE),
+            $map("attachments", 40, &ATTACHMENTS_STORAGE),
+            $map("attachment_chunks", 41, &ATTACHMENT_CHUNKS_STORAGE),
+            $map("canister_wasm", 43, &CANISTER_WASM_STORAGE),
+            $map("shards", 44, &SHARDS_STORAGE),
+            $map("archives", 45, &ARCHIVES_STORAGE),
+            $map("archived_index", 46, &ARCHIVED_INDEX),
+            $map("search_documents", 48, &SEARCH_DOCUMENTS),
+            $map("search_queue", 49, &SEARCH_QUEUE),
+            $map("group_directory", 51, &GROUP_DIRECTORY),
+            $map("account_sync_queue", 93, &ACCOUNT_SYNC_QUEUE),
+            $map("proposals", 53, &PROPOSALS_STORAGE),
+            $map("proposal_votes", 54, &PROPOSAL_VOTES_STORAGE),
+            $map("voting_power_snapshots", 55, &VOTING_POWER_SNAPSHOTS),
+            $map("treasury_payouts", 56, &TREASURY_PAYOUTS),
+            $map("treasury_deposits", 57, &TREASURY_DEPOSITS),
+            $map("entitlements", 58, &ENTITLEMENTS_STORAGE),
+            $map("referrals", 59, &REFERRALS_STORAGE),
+            $map("karma_awards", 60, &KARMA_AWARDS_STORAGE),
+            $map("profiles", 61, &PROFILES_STORAGE),
+            $map("onboarding", 62, &ONBOARDING_STORAGE),
+            $map("badges", 63, &BADGES_STORAGE),
+            $map("terms", 64, &TERMS_STORAGE),
+            $map("terms_acceptances", 65, &TERMS_ACCEPTANCES_STORAGE),
+            $map("content_preferences", 66, &CONTENT_PREFERENCES_STORAGE),
+            $map("flood_incidents", 67, &FLOOD_INCIDENTS_STORAGE),
+            $map("log", 69, &LOG_STORAGE),
+            $map("endpoint_metrics", 70, &ENDPOINT_METRICS_STORAGE),
+            $map("quarantine", 71, &QUARANTINE_STORAGE),
+            $map("trash", 72, &TRASH_STORAGE),
+            $map("warnings", 73, &WARNINGS_STORAGE),
+            $map("restrictions", 74, &RESTRICTIONS_STORAGE),
+            $map("appeals", 75, &APPEALS_STORAGE),
+            $map("discussion_revisions", 76, &REVISIONS_STORAGE),
+            $map("ownership_transfers", 77, &TRANSFERS_STORAGE),
+            $map("surveys", 78, &SURVEYS_STORAGE),
+            $map("survey_responses", 79, &SURVEY_RESPONSES_STORAGE),
+            $map("outbox", 80, &OUTBOX_STORAGE),
+            $map("dead_letters", 81, &DEAD_LETTERS_STORAGE),
+            $map("event_subscriptions", 82, &EVENT_SUBSCRIPTIONS_STORAGE),
+            $map("reminders", 83, &REMINDERS_STORAGE),
+            $map("reminder_wheel", 84, &REMINDER_WHEEL),
+            $map("recurrences", 85, &RECURRENCES_STORAGE),
+            $map("unlock_requests", 86, &UNLOCK_REQUESTS_STORAGE),
+            $map("user_activity", 87, &USER_ACTIVITY_STORAGE),
+            $map("karma_tallies", 95, &KARMA_TALLIES_STORAGE),
+            $map("raffles", 88, &RAFFLES_STORAGE),
+            $map("randomness_audit", 89, &RANDOMNESS_AUDIT_STORAGE),
+            $map("result_attestations", 90, &ATTESTATIONS_STORAGE),
+            $cell("settings", 22, &SETTINGS),
+            $cell("maintenance", 24, &MAINTENANCE),
+            $cell("gc_state", 25, &GC_STATE),
+            $cell("canister_role", 42, &CANISTER_ROLE),
+            $cell("search_index", 50, &SEARCH_INDEX),
+            $cell("dao_config", 52, &DAO_CONFIG),
+            $cell("log_sequence", 68, &LOG_SEQUENCE),
+            $cell("voter_backfill_cursor", 97, &VOTER_BACKFILL_CURSOR),
+        ]
+    };
+}
+
+// Entries sampled per map to estimate the average record size
+const STORAGE_STATS_SAMPLE: usize = 64;
+
+// Helper function to get the pages allocated to a MemoryId
+fn allocated_pages(memory_id: u8) -> u64 {
+    MEMORY_MANAGER.with(|m| ic_stable_structures::Memory::size(&m.borrow().get(MemoryId::new(memory_id))))
+}
+
+// Helper function to measure one map, estimating its size from the first entries
+fn map_stats<V: BoundedStorable>(
+    name: &str,
+    memory_id: u8,
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+) -> MapStats {
+    let (entries, sampled, sampled_bytes) = storage.with(|storage| {
+        let storage = storage.borrow();
+        let (sampled, sampled_bytes) = storage.iter()
+            .take(STORAGE_STATS_SAMPLE)
+            .fold((0u64, 0u64), |(count, bytes), (_, value)| (count + 1, bytes + 8 + value.to_bytes().len() as u64));
+        (storage.len(), sampled, sampled_bytes)
+    });
+    // Sampling may have decoded a corrupt record, it is reported by scan_for_corrupt_records instead
+    take_decode_failure();
+
+    MapStats {
+        name: name.to_string(),
+        memory_id,
+        entries,
+        approx_bytes: (entries * sampled_bytes).checked_div(sampled).unwrap_or(0),
+        pages: allocated_pages(memory_id),
+    }
+}
+
+// Helper function to measure a single-value cell
+fn cell_stats<T: Storable>(name: &str, memory_id: u8, cell: &'static LocalKey<RefCell<Cell<T, Memory>>>) -> MapStats {
+    MapStats {
+        name: name.to_string(),
+        memory_id,
+        entries: 1,
+        approx_bytes: cell.with(|cell| cell.borrow().get().to_bytes().len() as u64),
+        pages: allocated_pages(memory_id),
+    }
+}
+
+// Function to break down stable memory usage per map, to see which subsystem is growing (admins only)
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_storage_stats() -> StorageStats {
+    let mut maps = stable_regions!(map_stats, cell_stats);
+    maps.sort_by_key(|stats| std::cmp::Reverse(stats.pages));
+
+    StorageStats {
+        allocated_pages: maps.iter().map(|stats| stats.pages).sum(),
+        stable_memory_pages: ic_cdk::api::stable::stable64_size(),
+        maps,
+    }
+}
+
+// Regions left out of the overall checksum: the log sequence, the log and the endpoint metrics change with
+// every call, the upgrade included
+const CHECKSUM_EXCLUDED_MEMORY_IDS: &[u8] = &[68, 69, 70];
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RegionChecksum {
+    name: String,
+    memory_id: u8,
+    entries: u64,
+    checksum: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StateChecksum {
+    // SHA3-256 over the per-region checksums, excluding CHECKSUM_EXCLUDED_MEMORY_IDS
+    checksum: String,
+    regions: Vec<RegionChecksum>,
+}
+
+fn hex_digest(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Helper function to hash every entry of a map in key order, as key, value length and value bytes
+fn map_checksum<V: BoundedStorable>(
+    name: &str,
+    memory_id: u8,
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+) -> RegionChecksum {
+    use sha3::{Digest, Sha3_256};
+
+    let mut hasher = Sha3_256::new();
+    let entries = storage.with(|storage| {
+        let storage = storage.borrow();
+        for (key, value) in storage.iter() {
+            let bytes = value.to_bytes();
+            hasher.update(key.to_be_bytes());
+            hasher.update((bytes.len() as u64).to_be_bytes());
+            hasher.update(&bytes);
+        }
+        storage.len()
+    });
+    take_decode_failure();
+
+    RegionChecksum {
+        name: name.to_string(),
+        memory_id,
+        entries,
+        checksum: hex_digest(&hasher.finalize()),
+    }
+}
+
+// Helper function to hash the value of a cell
+fn cell_checksum<T: Storable>(name: &str, memory_id: u8, cell: &'static LocalKey<RefCell<Cell<T, Memory>>>) -> RegionChecksum {
+    use sha3::{Digest, Sha3_256};
+
+    RegionChecksum {
+        name: name.to_string(),
+        memory_id,
+        entries: 1,
+        checksum: hex_digest(&cell.with(|cell| Sha3_256::digest(cell.borrow().get().to_bytes()))),
+    }
+}
+
+// Function to hash a canonical serialization of all stable state. Compare the result before and after an
+// upgrade to check that migrations lost or changed nothing; per-region checksums show where a difference is
+// (controllers only)
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn state_checksum() -> StateChecksum {
+    use sha3::{Digest, Sha3_256};
+
+    let regions = stable_regions!(map_checksum, cell_checksum);
+
+    let mut hasher = Sha3_256::new();
+    for region in regions.iter().filter(|region| !CHECKSUM_EXCLUDED_MEMORY_IDS.contains(&region.memory_id)) {
+        hasher.update([region.memory_id]);
+        hasher.update(region.checksum.as_bytes());
+    }
+
+    StateChecksum {
+        checksum: hex_digest(&hasher.finalize()),
+        regions,
+    }
+}
+
+// Function to get the current maintenance mode state
+#[ic_cdk::query]
+fn get_maintenance_mode() -> MaintenanceMode {
+    MAINTENANCE.with(|maintenance| maintenance.borrow().get().clone())
+}
+
+// Function to get the current runtime settings
+#[ic_cdk::query]
+fn get_settings() -> Settings {
+    settings()
+}
+
+// Function to cross-check tallies, references and the ID counter (admins only)
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn verify_integrity() -> IntegrityReport {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    let mut report = IntegrityReport::default();
+
+    let usernames: BTreeSet<String> = USERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, user)| user.username).collect()
+    });
+    let discussions: BTreeMap<u64, Discussion> = DISCUSSIONS_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).collect());
+    let mut max_id = USERS_STORAGE.with(|storage| storage.borrow().last_key_value().map(|(id, _)| id)).unwrap_or(0);
+    max_id = max_id.max(discussions.keys().next_back().copied().unwrap_or(0));
+
+    let mut counted: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+    let mut seen: BTreeSet<(u64, String)> = BTreeSet::new();
+
+    VOTES_STORAGE.with(|storage| {
+        for (id, vote) in storage.borrow().iter().filter(decoded) {
+            report.checked_votes += 1;
+            max_id = max_id.max(id);
+
+            if !discussions.contains_key(&vote.discussion_id) || !usernames.contains(&vote.by) {
+                report.orphaned_votes.push(id);
+                continue;
+            }
+
+            if !seen.insert((vote.discussion_id, vote.by.clone())) {
+                report.duplicate_votes.push(id);
+            }
+
+            let tally = counted.entry(vote.discussion_id).or_default();
+            match vote.vote_type {
+                VoteType::Upvote => tally.0 += 1,
+                VoteType::Downvote => tally.1 += 1,
+            }
+        }
+    });
+
+    for (id, discussion) in &discussions {
+        report.checked_discussions += 1;
+
+        let (upvotes, downvotes) = counted.get(id).copied().unwrap_or((0, 0));
+        if discussion.upvotes != upvotes || discussion.downvotes != downvotes {
+            report.tally_mismatches.push(TallyMismatch {
+                discussion_id: *id,
+                stored_upvotes: discussion.upvotes,
+                stored_downvotes: discussion.downvotes,
+                counted_upvotes: upvotes,
+                counted_downvotes: downvotes,
+            });
+        }
+    }
+
+    GROUP_MEMBERS_STORAGE.with(|storage| {
+        for (id, member) in storage.borrow().iter().filter(decoded) {
+            max_id = max_id.max(id);
+            let group_exists = GROUPS_STORAGE.with(|groups| groups.borrow().contains_key(&member.group_id));
+            if !group_exists || !usernames.contains(&member.username) {
+                report.orphaned_group_members.push(id);
+            }
+        }
+    });
+
+    PRINCIPAL_LINKS_STORAGE.with(|storage| {
+        for (id, link) in storage.borrow().iter().filter(decoded) {
+            max_id = max_id.max(id);
+            if !usernames.contains(&link.username) {
+                report.orphaned_principal_links.push(id);
+            }
+        }
+    });
+
+    let next_id = ID_COUNTER.with(|counter| *counter.borrow().get());
+    report.id_counter_behind = max_id > 0 && max_id >= next_id;
+
+    report
+}
+
+// Function to get the principals granted admin rights (admins only)
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_admins() -> Vec<Admin> {
+    ADMINS_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).map(|(_, admin)| admin).collect())
+}
+
+// Function to get all users that haven't hidden their profile
+#[ic_cdk::query]
+fn get_users() -> Vec<User> {
+    USERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(id, _)| !privacy_settings(*id).hide_profile)
+            .map(|(_, user)| user)
+            .collect()
+    })
+}
+
+// Function to get all listed users as a gzip-compressed CBOR blob
+#[ic_cdk::query]
+fn get_users_compressed() -> Result<CompressedBlob, String> {
+    compress_response(&get_users())
+}
+
+// Function to page through users that haven't hidden their profile
+#[ic_cdk::query]
+fn get_users_page(cursor: Option<String>, limit: Option<u32>) -> Result<Page<User>, String> {
+    paginate(&USERS_STORAGE, cursor, limit, |id, _| !privacy_settings(id).hide_profile)
+}
+
+// Function to get the caller's privacy settings
+#[ic_cdk::query]
+fn get_privacy_settings(username: String) -> Result<PrivacySettings, String> {
+    authenticate(&username)?;
+
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    Ok(privacy_settings(user.id))
+}
+
+// Function to set the caller's display name and bio
+#[ic_cdk::update]
+fn set_profile(display_name: String, bio: String, username: String) -> Result<Profile, String> {
+    let _meter = metrics::measure("set_profile");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    let display_name = sanitize_user_text(display_name.trim())?;
+    let bio = sanitize_user_text(bio.trim())?;
+
+    permissions::check_permission(&username, Action::PostText(&bio))?;
+
+    if display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH {
+        return Err(format!("Display name cannot exceed {} characters", MAX_DISPLAY_NAME_LENGTH));
+    }
+
+    if bio.chars().count() > MAX_BIO_LENGTH {
+        return Err(format!("Bio cannot exceed {} characters", MAX_BIO_LENGTH));
+    }
+
+    let profile = Profile { display_name, bio };
+    PROFILES_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, profile.clone()));
+
+    if !profile.display_name.is_empty() && !profile.bio.is_empty() {
+        advance_onboarding(&username, OnboardingStep::CompleteProfile);
+    }
+
+    Ok(profile)
+}
+
+// Function to get a user's profile, unless they hid it
+#[ic_cdk::query]
+fn get_profile(target: String, username: String) -> Result<Profile, String> {
+    let viewer = resolve_viewer(username);
+    let user = find_user(&target).ok_or(ErrorCode::UserNotFound)?;
+
+    if privacy_settings(user.id).hide_profile && viewer != target {
+        return Err(ErrorCode::UserNotFound.into());
+    }
+
+    Ok(try_get(&PROFILES_STORAGE, user.id).unwrap_or_default())
+}
+
+// Helper function to record that a user finished an onboarding step, awarding the badge after the last one
+fn advance_onboarding(username: &str, step: OnboardingStep) {
+    let Some(user) = find_user(username) else {
+        return;
+    };
+
+    let mut progress = try_get(&ONBOARDING_STORAGE, user.id).unwrap_or_default();
+    if progress.completed_at.is_some() {
+        return;
+    }
+
+    let now = time();
+    let slot = match step {
+        OnboardingStep::CompleteProfile => &mut progress.profile_completed_at,
+        OnboardingStep::CastFirstVote => &mut progress.first_vote_at,
+        OnboardingStep::StartFirstDiscussion => &mut progress.first_discussion_at,
+    };
+    if slot.is_some() {
+        return;
+    }
+    *slot = Some(now);
+
+    if next_onboarding_step(&progress).is_none() {
+        progress.completed_at = Some(now);
+        let badge = Badge { username: username.to_string(), kind: BadgeKind::Onboarded, awarded_at: now };
+        BADGES_STORAGE.with(|storage| storage.borrow_mut().insert(next_id(), badge));
+    }
+
+    ONBOARDING_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, progress));
+}
+
+// Helper function to get the first onboarding step a user hasn't done yet
+fn next_onboarding_step(progress: &OnboardingProgress) -> Option<OnboardingStep> {
+    if progress.profile_completed_at.is_none() {
+        Some(OnboardingStep::CompleteProfile)
+    } else if progress.first_vote_at.is_none() {
+        Some(OnboardingStep::CastFirstVote)
+    } else if progress.first_discussion_at.is_none() {
+        Some(OnboardingStep::StartFirstDiscussion)
+    } else {
+        None
+    }
+}
+
+// Helper function to count something a user did at `at` in their activity counters and streak
+fn track_activity(username: &str, at: u64, count: impl FnOnce(&mut UserActivity)) {
+    let Some(user) = find_user(username) else {
+        return;
+    };
+
+    // Counters that no longer decode are quarantined by try_get and start over
+    let mut activity = try_get(&USER_ACTIVITY_STORAGE, user.id).unwrap_or_default();
+    count(&mut activity);
+    if activity.first_activity_at == 0 || at < activity.first_activity_at {
+        activity.first_activity_at = at;
+    }
+    activity.last_activity_at = activity.last_activity_at.max(at);
+    let reached = extend_streak(&mut activity, at);
+    USER_ACTIVITY_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, activity));
+
+    if let Some(days) = reached {
+        let badge = Badge { username: username.to_string(), kind: BadgeKind::Streak { days }, awarded_at: at };
+        BADGES_STORAGE.with(|storage| storage.borrow_mut().insert(next_id(), badge));
+    }
+}
+
+// Helper function to count activity at `at` toward the user's streak. Days are UTC, activity older than
+// the last active day is ignored. Returns the badge length reached for the first time, if any.
+fn extend_streak(activity: &mut UserActivity, at: u64) -> Option<u32> {
+    let day = at / 86_400_000_000_000;
+
+    if activity.current_streak > 0 && day <= activity.last_active_day {
+        return None;
+    }
+
+    activity.current_streak = if activity.current_streak > 0 && day == activity.last_active_day + 1 {
+        activity.current_streak + 1
+    } else {
+        1
+    };
+    activity.last_active_day = day;
+
+    let record = activity.current_streak > activity.longest_streak;
+    activity.longest_streak = activity.longest_streak.max(activity.current_streak);
+
+    Some(activity.current_streak).filter(|days| record && STREAK_BADGE_DAYS.contains(days))
+}
+
+// Helper function to count a vote on one of the author's discussions; being voted on is not activity
+fn track_received_vote(author: &str) {
+    let Some(user) = find_user(author) else {
+        return;
+    };
+
+    let mut activity = try_get(&USER_ACTIVITY_STORAGE, user.id).unwrap_or_default();
+    activity.votes_received += 1;
+    USER_ACTIVITY_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, activity));
+}
+
+// Helper function to get the percent of `total` users whose count is below `value`. Users without
+// counters count as 0.
+fn percentile_rank(value: u64, counts: &[u64], total: u64) -> u32 {
+    if total == 0 {
+        return 0;
+    }
+
+    let untracked = if value > 0 { total.saturating_sub(counts.len() as u64) } else { 0 };
+    let below = counts.iter().filter(|count| **count < value).count() as u64 + untracked;
+
+    (below * 100 / total).min(100) as u32
+}
+
+// Function to get a user's activity counts and where they rank, unless they hid their profile
+#[ic_cdk::query]
+fn get_user_stats(username: String) -> Result<UserStats, String> {
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    if privacy_settings(user.id).hide_profile && !linked_principals(&username).contains(&caller()) {
+        return Err(ErrorCode::UserNotFound.into());
+    }
+
+    let activity = try_get(&USER_ACTIVITY_STORAGE, user.id).unwrap_or_default();
+    let everyone: Vec<UserActivity> = USER_ACTIVITY_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, activity)| activity).collect()
+    });
+    let total = USERS_STORAGE.with(|storage| storage.borrow().len());
+
+    let today = time() / 86_400_000_000_000;
+    let current_streak = if activity.last_active_day + 1 >= today { activity.current_streak } else { 0 };
+
+    let rank = |count: fn(&UserActivity) -> u64| {
+        let counts: Vec<u64> = everyone.iter().map(count).collect();
+        percentile_rank(count(&activity), &counts, total)
+    };
+
+    Ok(UserStats {
+        discussions_percentile: rank(|activity| activity.discussions_created),
+        votes_cast_percentile: rank(|activity| activity.votes_cast),
+        votes_received_percentile: rank(|activity| activity.votes_received),
+        discussions_created: activity.discussions_created,
+        votes_cast: activity.votes_cast,
+        votes_received: activity.votes_received,
+        first_activity_at: Some(activity.first_activity_at).filter(|at| *at > 0),
+        last_activity_at: Some(activity.last_activity_at).filter(|at| *at > 0),
+        current_streak,
+        longest_streak: activity.longest_streak,
+    })
+}
+
+// Function to get the caller's onboarding progress and next step
+#[ic_cdk::query]
+fn get_onboarding_status(username: String) -> Result<OnboardingStatus, String> {
+    authenticate(&username)?;
+
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+    let progress = try_get(&ONBOARDING_STORAGE, user.id).unwrap_or_default();
+
+    Ok(OnboardingStatus {
+        next_step: next_onboarding_step(&progress),
+        progress,
+    })
+}
+
+// Function to get the badges a user has earned
+#[ic_cdk::query]
+fn get_badges(target: String) -> Vec<Badge> {
+    BADGES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, badge)| badge.username == target)
+            .map(|(_, badge)| badge)
+            .collect()
+    })
+}
+
+// Function to get a user's votes on discussions the viewer can see, unless they hid their vote history
+#[ic_cdk::query]
+fn get_user_votes(target: String, username: String) -> Result<Vec<Vote>, String> {
+    let viewer = resolve_viewer(username);
+
+    check_vote_history_access(&target, &viewer)?;
+
+    Ok(VOTES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, vote)| is_visible_vote(vote, &target, &viewer))
+            .map(|(_, vote)| vote)
+            .collect()
+    }))
+}
+
+// Function to page through a user's votes, with the same visibility rules as get_user_votes
+#[ic_cdk::query]
+fn get_user_votes_page(target: String, username: String, cursor: Option<String>, limit: Option<u32>) -> Result<Page<Vote>, String> {
+    let viewer = resolve_viewer(username);
+
+    check_vote_history_access(&target, &viewer)?;
+
+    paginate(&VOTES_STORAGE, cursor, limit, |_, vote| is_visible_vote(vote, &target, &viewer))
+}
+
+// Helper function to check that the viewer may see the target's vote history
+fn check_vote_history_access(target: &str, viewer: &str) -> Result<(), String> {
+    let user = find_user(target).ok_or(ErrorCode::UserNotFound)?;
+
+    if viewer != target && privacy_settings(user.id).hide_vote_history {
+        return Err("User has hidden their vote history".to_string());
+    }
+
+    Ok(())
+}
+
+// Helper function to check if a vote by the target is on a discussion the viewer can see
+fn is_visible_vote(vote: &Vote, target: &str, viewer: &str) -> bool {
+    vote.by == target
+        && try_get(&DISCUSSIONS_STORAGE, vote.discussion_id)
+            .is_some_and(|discussion| {
+                can_access_discussion(&discussion, viewer) && (viewer == target || !tallies_hidden(&discussion, time()))
+            })
+}
+
+// Function to count a view of a discussion, once per viewer per dedup window
+#[ic_cdk::update]
+fn record_view(discussion_id: u64, username: String) -> Result<u64, String> {
+    let _meter = metrics::measure("record_view");
+
+    ensure_writable()?;
+
+    let username = resolve_viewer(username);
+
+    let mut discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if !can_access_discussion(&discussion, &username) {
+        return Err(ErrorCode::DiscussionNotFound.into());
+    }
+
+    let now = time();
+
+    // Opening a discussion also marks it read for signed-in viewers
+    if !username.is_empty() {
+        set_read_marker(&username, discussion_id, now);
+    }
+
+    let viewer = if username.is_empty() { caller().to_text() } else { username };
+
+    let last_view = discussion_record_keys(discussion_id, StoredMap::Views).into_iter()
+        .filter_map(|id| try_get(&VIEWS_STORAGE, id).map(|view| (id, view)))
+        .find(|(_, view)| view.discussion_id == discussion_id && view.viewer == viewer);
+
+    if let Some((_, view)) = &last_view {
+        if view.viewed_at.saturating_add(VIEW_DEDUP_WINDOW) > now {
+            return Ok(discussion.views);
+        }
+    }
+
+    let id = last_view.map(|(id, _)| id).unwrap_or_else(next_id);
+    index_discussion_record(discussion_id, StoredMap::Views, id);
+    VIEWS_STORAGE.with(|storage| storage.borrow_mut().insert(id, ViewRecord { discussion_id, viewer, viewed_at: now }));
+
+    discussion.views += 1;
+    let views = discussion.views;
+    save_discussion(discussion_id, discussion);
+
+    Ok(views)
+}
+
+// Helper function to store the time a user last read a discussion
+fn set_read_marker(username: &str, discussion_id: u64, now: u64) {
+    let existing = discussion_record_keys(discussion_id, StoredMap::ReadMarkers).into_iter().find(|id| {
+        try_get(&READ_MARKERS_STORAGE, *id).is_some_and(|marker| marker.username == username && marker.discussion_id == discussion_id)
+    });
+
+    let id = existing.unwrap_or_else(next_id);
+    index_discussion_record(discussion_id, StoredMap::ReadMarkers, id);
+    let marker = ReadMarker { username: username.to_string(), discussion_id, last_read_at: now };
+
+    READ_MARKERS_STORAGE.with(|storage| storage.borrow_mut().insert(id, marker));
+}
+
+// Function to mark a discussion as read up to now
+#[ic_cdk::update]
+fn mark_read(discussion_id: u64, username: String) -> Result<String, String> {
+    let _meter = metrics::measure("mark_read");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if !can_access_discussion(&discussion, &username) {
+        return Err(ErrorCode::DiscussionNotFound.into());
+    }
+
+    set_read_marker(&username, discussion_id, time());
+
+    Ok("Discussion marked as read".to_string())
+}
+
+// Function to get the number of new votes on each discussion the user has read before
+#[ic_cdk::query]
+fn get_unread_counts(username: String) -> Result<Vec<UnreadCount>, String> {
+    authenticate(&username)?;
+
+    let markers: Vec<ReadMarker> = READ_MARKERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, marker)| marker.username == username)
+            .map(|(_, marker)| marker)
+            .collect()
+    });
+
+    let mut counts: BTreeMap<u64, (u64, u64)> = markers.iter()
+        .map(|marker| (marker.discussion_id, (marker.last_read_at, 0)))
+        .collect();
+
+    VOTES_STORAGE.with(|storage| {
+        for (_, vote) in storage.borrow().iter().filter(decoded) {
+            if let Some((last_read_at, unread)) = counts.get_mut(&vote.discussion_id) {
+                if vote.created_at > *last_read_at && vote.by != username {
+                    *unread += 1;
+                }
+            }
+        }
+    });
+
+    Ok(counts.into_iter()
+        .filter(|(_, (_, unread))| *unread > 0)
+        .map(|(discussion_id, (_, unread))| UnreadCount { discussion_id, unread })
+        .collect())
+}
+
+// Helper function to rank discussions by net votes plus views, decaying with age
+fn trending_score(discussion: &Discussion, now: u64) -> f64 {
+    let net_votes = discussion.upvotes as f64 - discussion.downvotes as f64;
+    let points = net_votes + (discussion.views / VIEWS_PER_VOTE) as f64;
+    let age_hours = now.saturating_sub(discussion.created_at) as f64 / 3_600_000_000_000.0;
+
+    points / (age_hours + 2.0).powf(1.5)
+}
+
+// Helper function to recompute the scores derived from a discussion's tallies after a vote changes
+fn update_vote_scores(discussion: &mut Discussion) {
+    let upvotes = discussion.upvotes as f64;
+    let downvotes = discussion.downvotes as f64;
+    let total = upvotes + downvotes;
+
+    discussion.wilson_score = if total == 0.0 {
+        0.0
+    } else {
+        // 95% confidence
+        let z = 1.96_f64;
+        let share = upvotes / total;
+        let spread = z * ((share * (1.0 - share) + z * z / (4.0 * total)) / total).sqrt();
+        (share + z * z / (2.0 * total) - spread) / (1.0 + z * z / total)
+    };
+
+    discussion.controversy_score = if upvotes == 0.0 || downvotes == 0.0 {
+        0.0
+    } else {
+        total.powf(upvotes.min(downvotes) / upvotes.max(downvotes))
+    };
+}
+
+// Function to get the top public discussions by trending score
+#[ic_cdk::query]
+fn get_trending_discussions(limit: Option<u32>) -> Vec<Discussion> {
+    ranked_discussions(limit, &caller_preferences(), trending_score)
+}
+
+// Function to get the top public discussions by votes, Wilson score or controversy
+#[ic_cdk::query]
+fn get_ranked_discussions(sort: DiscussionSort, limit: Option<u32>) -> Vec<Discussion> {
+    let score = match sort {
+        DiscussionSort::Top => |discussion: &Discussion, _| discussion.upvotes as f64,
+        DiscussionSort::Best => |discussion: &Discussion, _| discussion.wilson_score,
+        DiscussionSort::Controversial => |discussion: &Discussion, _| discussion.controversy_score,
+    };
+
+    ranked_discussions(limit, &caller_preferences(), score)
+}
+
+// Function to get the top public discussions in one language by trending score
+#[ic_cdk::query]
+fn get_trending_discussions_by_language(language: String, limit: Option<u32>) -> Vec<Discussion> {
+    let mut preferences = caller_preferences();
+    preferences.languages = vec![language.clone()];
+
+    // Untagged discussions pass the preference filter, a language feed only wants tagged ones
+    ranked_discussions(limit, &preferences, trending_score).into_iter()
+        .filter(|discussion| discussion.language.as_ref() == Some(&language))
+        .collect()
+}
+
+// Helper function to rank listed discussions matching the preferences, highest score first
+fn ranked_discussions(limit: Option<u32>, preferences: &ContentPreferences, score: fn(&Discussion, u64) -> f64) -> Vec<Discussion> {
+    let now = time();
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+
+    // Running blind votes would leak through their rank, so they are left out
+    let mut scored: Vec<(f64, Discussion)> = DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, discussion)| is_forum_listed(discussion) && !tallies_hidden(discussion, now))
+            .filter(|(_, discussion)| matches_preferences(discussion, preferences))
+            .map(|(_, discussion)| (score(&discussion, now), discussion))
+            .collect()
+    });
+
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    scored.into_iter().take(limit).map(|(_, discussion)| discussion).collect()
+}
+
+// Function to page through discussions the user can see that got no votes in their first `min_age_hours`,
+// oldest first
+#[ic_cdk::query]
+fn get_needs_attention(min_age_hours: u64, username: String, cursor: Option<String>, limit: Option<u32>) -> Result<Page<Discussion>, String> {
+    let username = resolve_viewer(username);
+    let start = decode_cursor(cursor)?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+    let cutoff = time().saturating_sub(min_age_hours.saturating_mul(3_600_000_000_000));
+    let preferences = viewer_preferences(&username);
+
+    // IDs grow with creation time, so the index is scanned oldest first and stops at the first entry too young
+    let ids: Vec<u64> = NEEDS_ATTENTION_INDEX.with(|index| {
+        index.borrow().range((start, Bound::Unbounded))
+            .take_while(|(_, created_at)| *created_at <= cutoff)
+            .map(|(id, _)| id)
+            .collect()
+    });
+
+    let mut items = Vec::new();
+    let mut next_cursor = None;
+    for id in ids {
+        let Some(discussion) = load_discussion(id) else { continue };
+        if matches!(discussion.visibility, Visibility::Unlisted) || !can_access_discussion(&discussion, &username) {
+            continue;
+        }
+        if !matches_preferences(&discussion, &preferences) {
+            continue;
+        }
+        items.push(redact_tallies(discussion));
+        if items.len() == limit {
+            next_cursor = Some(encode_cursor(id));
+            break;
+        }
+    }
+
+    Ok(Page { items, next_cursor })
+}
+
+// Function to get the final outcomes of closed votes the user can see
+#[ic_cdk::query]
+fn get_closed_results(username: String) -> Vec<ClosedResult> {
+    let username = resolve_viewer(username);
+    let now = time();
+
+    DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, discussion)| is_result_final(discussion, now) && can_access_discussion(discussion, &username))
+            .map(|(id, discussion)| ClosedResult {
+                discussion_id: id,
+                outcome: match discussion.upvotes.cmp(&discussion.downvotes) {
+                    std::cmp::Ordering::Greater => VoteOutcome::Passed,
+                    std::cmp::Ordering::Less => VoteOutcome::Rejected,
+                    std::cmp::Ordering::Equal => VoteOutcome::Tied,
+                },
+                voting_ended_at: results_final_at(&discussion).unwrap_or_default(),
+                topic: discussion.topic,
+                content_warning: discussion.content_warning,
+                upvotes: discussion.upvotes,
+                downvotes: discussion.downvotes,
+            })
+            .collect()
+    })
+}
+
+// Function to get total vote count for a discussion
+#[ic_cdk::query]
+fn get_vote_count(discussion_id: u64, username: String) -> Result<(u64, u64), String> {
+    let username = resolve_viewer(username);
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if !can_access_discussion(&discussion, &username) {
+        return Err(ErrorCode::DiscussionNotFound.into());
+    }
+
+    if tallies_hidden(&discussion, time()) {
+        return Err(format!("Results are hidden until voting closes ({} total votes)", discussion.upvotes + discussion.downvotes));
+    }
+
+    Ok((discussion.upvotes, discussion.downvotes))
+}
+
+// Function to get the vote counts of a discussion, with only the total during a blind vote
+#[ic_cdk::query]
+fn get_vote_summary(discussion_id: u64, username: String) -> Result<VoteSummary, String> {
+    let username = resolve_viewer(username);
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if !can_access_discussion(&discussion, &username) {
+        return Err(ErrorCode::DiscussionNotFound.into());
+    }
+
+    let hidden = tallies_hidden(&discussion, time());
+
+    Ok(VoteSummary {
+        total_votes: discussion.upvotes + discussion.downvotes,
+        upvotes: (!hidden).then_some(discussion.upvotes),
+        downvotes: (!hidden).then_some(discussion.downvotes),
+    })
+}
+
+// Function to open (or return the existing) direct conversation with another user
+#[ic_cdk::update]
+fn start_conversation(other: String, username: String, idempotency_key: Option<String>) -> Result<Conversation, String> {
+    let _meter = metrics::measure("start_conversation");
+
+    ensure_writable()?;
+
+    with_idempotency("start_conversation", idempotency_key, || {
+        require_feature("direct_messages")?;
+
+        authenticate(&username)?;
+        permissions::check_permission(&username, Action::SendDirectMessage)?;
+
+        if !is_user_registered(&other) {
+            return Err("Recipient is not registered".to_string());
+        }
+
+        if other == username {
+            return Err("Cannot start a conversation with yourself".to_string());
+        }
+
+        let mut participants = vec![username, other];
+        participants.sort();
+
+        let existing = CONVERSATIONS_STORAGE.with(|storage| {
+            storage.borrow().iter().filter(decoded).find(|(_, conversation)| conversation.participants == participants).map(|(_, conversation)| conversation)
+        });
+
+        if let Some(conversation) = existing {
+            return Ok(conversation);
+        }
+
+        let id = next_id();
+        let now = time();
+
+        let conversation = Conversation {
+            id,
+            participants,
+            created_at: now,
+            last_message_at: now,
+        };
+
+        CONVERSATIONS_STORAGE.with(|storage| storage.borrow_mut().insert(id, conversation.clone()));
+
+        Ok(conversation)
+    })
+}
+
+// Helper function to load a conversation the user takes part in
+fn get_participating_conversation(conversation_id: u64, username: &str) -> Result<Conversation, String> {
+    let conversation = try_get(&CONVERSATIONS_STORAGE, conversation_id).ok_or("Conversation not found")?;
+
+    if !conversation.participants.iter().any(|participant| participant == username) {
+        return Err("User is not a participant of this conversation".to_string());
+    }
+
+    Ok(conversation)
+}
+
+// Function to store an encrypted message in a conversation
+#[ic_cdk::update]
+fn send_message(conversation_id: u64, ciphertext: Vec<u8>, username: String, idempotency_key: Option<String>) -> Result<DirectMessage, String> {
+    let _meter = metrics::measure("send_message");
+
+    ensure_writable()?;
+
+    with_idempotency("send_message", idempotency_key, || {
+        require_feature("direct_messages")?;
+
+        authenticate(&username)?;
+        permissions::check_permission(&username, Action::SendDirectMessage)?;
+
+        let mut conversation = get_participating_conversation(conversation_id, &username)?;
+
+        if ciphertext.is_empty() {
+            return Err("Message is required".to_string());
+        }
+
+        if ciphertext.len() > MAX_CIPHERTEXT_LENGTH {
+            return Err(format!("Message cannot exceed {} bytes", MAX_CIPHERTEXT_LENGTH));
+        }
+
+        let id = next_id();
+        let now = time();
+
+        let message = DirectMessage {
+            id,
+            conversation_id,
+            sender: username,
+            ciphertext,
+            created_at: now,
+        };
+
+        MESSAGES_STORAGE.with(|storage| storage.borrow_mut().insert(id, message.clone()));
+
+        conversation.last_message_at = now;
+        CONVERSATIONS_STORAGE.with(|storage| storage.borrow_mut().insert(conversation_id, conversation));
+
+        Ok(message)
+    })
+}
+
+// Helper function to build the vetKD key ID used for direct messages. Canisters that haven't set a key
+// name yet keep the key their messages were encrypted under.
+fn dm_key_id() -> VetKDKeyId {
+    let name = settings().vetkd_key_name;
+
+    VetKDKeyId {
+        curve: VetKDCurve::Bls12381G2,
+        name: if name.is_empty() { LEGACY_VETKD_KEY_NAME.to_string() } else { name },
+    }
+}
+
+// Function to get the vetKD public key clients use to verify derived conversation keys
+#[ic_cdk::update]
+async fn get_dm_public_key() -> Result<Vec<u8>, String> {
+    let _meter = metrics::measure("get_dm_public_key");
+
+    ensure_writable()?;
+
+    require_feature("direct_messages")?;
+
+    let args = VetKDPublicKeyArgs {
+        canister_id: None,
+        context: VETKD_DM_CONTEXT.to_vec(),
+        key_id: dm_key_id(),
+    };
+
+    let (reply,): (VetKDPublicKeyReply,) = ic_cdk::call(Principal::management_canister(), "vetkd_public_key", (args,))
+        .await
+        .map_err(|(_, message)| format!("Cannot fetch vetKD public key: {}", message))?;
+
+    Ok(reply.public_key)
+}
+
+// Function to derive a conversation's key, encrypted under the client's transport key
+#[ic_cdk::update]
+async fn get_encrypted_conversation_key(conversation_id: u64, transport_public_key: Vec<u8>, username: String) -> Result<Vec<u8>, String> {
+    let _meter = metrics::measure("get_encrypted_conversation_key");
+
+    ensure_writable()?;
+
+    require_feature("direct_messages")?;
+
+    authenticate_owner(&username)?;
+
+    get_participating_conversation(conversation_id, &username)?;
+
+    let args = VetKDDeriveKeyArgs {
+        input: conversation_id.to_be_bytes().to_vec(),
+        context: VETKD_DM_CONTEXT.to_vec(),
+        transport_public_key,
+        key_id: dm_key_id(),
+    };
+
+    let (reply,): (VetKDDeriveKeyReply,) = ic_cdk::api::call::call_with_payment128(
+        Principal::management_canister(),
+        "vetkd_derive_key",
+        (args,),
+        VETKD_DERIVE_KEY_FEE,
+    )
+    .await
+    .map_err(|(_, message)| format!("Cannot derive conversation key: {}", message))?;
+
+    Ok(reply.encrypted_key)
+}
+
+// Function to get the conversations a user takes part in
+#[ic_cdk::query]
+fn get_conversations(username: String) -> Result<Vec<Conversation>, String> {
+    authenticate(&username)?;
+
+    Ok(CONVERSATIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, conversation)| conversation.participants.contains(&username))
+            .map(|(_, conversation)| conversation)
+            .collect()
+    }))
+}
+
+// Function to get the encrypted messages of a conversation
+#[ic_cdk::query]
+fn get_messages(conversation_id: u64, username: String) -> Result<Vec<DirectMessage>, String> {
+    authenticate(&username)?;
+
+    get_participating_conversation(conversation_id, &username)?;
+
+    Ok(MESSAGES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, message)| message.conversation_id == conversation_id)
+            .map(|(_, message)| message)
+            .collect()
+    }))
+}
+
+// Helper function to schedule the background jobs. Timers don't survive an upgrade, so both init and
+// post_upgrade call it.
+fn start_background_jobs() {
+    ic_cdk_timers::set_timer_interval(BACKGROUND_TICK, background_tick);
+}
+
+// Helper function run by the timer to drive background jobs; they are throttled so most ticks return quickly
+fn background_tick() {
+    // Nothing else runs until the retired maps are moved, it would see only some of the discussions
+    if move_retired_maps() > 0 {
+        return;
+    }
+
+    let now = time();
+
+    // Only heap state and certified data change, so this also runs in maintenance mode
+    refresh_vote_roots();
+
+    if check_maintenance().is_ok() {
+        rewrite_outdated_records();
+        backfill_vote_voters();
+        process_link_preview_queue();
+        fire_due_reminders(now);
+        run_recurrences(now);
+        outbox::dispatch(now);
+    }
+
+    let state = GC_STATE.with(|state| state.borrow().get().clone());
+
+    if now.saturating_sub(state.last_tick_at) < GC_INTERVAL || check_maintenance().is_err() {
+        return;
+    }
+
+    match canister_role() {
+        CanisterRole::Hub => {}
+        // A promoted group runs its own discussions, but its accounts are copies the hub keeps in sync, so
+        // karma decay and garbage collection stay on the hub
+        CanisterRole::Group { .. } => {
+            finalize_closed_votes(now);
+            purge_trash(now);
+            auto_lock_stale_discussions(now);
+            expire_old_votes(now);
+            record_gc_tick(state, now);
+            return;
+        }
+        // Shards and the other spawned canisters hold records for accounts that live on the hub, so anything
+        // judging records against the users held here would see them all as orphaned
+        _ => {
+            record_gc_tick(state, now);
+            return;
+        }
+    }
+
+    finalize_closed_votes(now);
+    finalize_proposals(now);
+    archive_cold_discussions(now);
+    purge_trash(now);
+    auto_lock_stale_discussions(now);
+    expire_old_votes(now);
+    decay_inactive_karma(now);
+    push_search_updates();
+    push_account_updates();
+    collect_garbage_chunk(state, now);
+}
+
+// Helper function to scan up to GC_BATCH_SIZE entries of a map from `cursor`, removing orphans.
+// Returns the number of removed entries and the key to resume from, or None once the map is done.
+fn gc_scan<V: BoundedStorable>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+    cursor: u64,
+    is_orphan: impl Fn(&V) -> bool,
+) -> (u64, Option<u64>) {
+    // Placeholders of undecodable records would look orphaned, they are skipped and left for scan_for_corrupt_records
+    take_decode_failure();
+    let batch: Vec<(u64, Option<V>)> = storage.with(|storage| {
+        storage.borrow().range(cursor..)
+            .take(GC_BATCH_SIZE + 1)
+            .map(|(key, value)| (key, take_decode_failure().is_none().then_some(value)))
+            .collect()
+    });
+
+    let next = if batch.len() > GC_BATCH_SIZE { Some(batch[GC_BATCH_SIZE].0) } else { None };
+
+    let orphans: Vec<u64> = batch.iter()
+        .take(GC_BATCH_SIZE)
+        .filter(|(_, value)| value.as_ref().is_some_and(&is_orphan))
+        .map(|(id, _)| *id)
+        .collect();
+
+    storage.with(|storage| {
+        let mut storage_mut = storage.borrow_mut();
+        for id in &orphans {
+            storage_mut.remove(id);
+        }
+    });
+
+    (orphans.len() as u64, next)
+}
+
+// Helper function to take the failure recorded by the last decode, if it failed
+fn take_decode_failure() -> Option<(Vec<u8>, String)> {
+    LAST_DECODE_FAILURE.with(|failure| failure.borrow_mut().take())
+}
+
+// Helper function for filtering reads: false when the record just read did not decode and is a placeholder.
+// Must run right after the read, before anything else is decoded.
+fn decoded<T>(_: &T) -> bool {
+    take_decode_failure().is_none()
+}
+
+// Helper function to read a record, treating one that no longer decodes as missing. Its bytes move to the
+// quarantine, so the placeholder is never cached or written back over them.
+fn try_get<V: StoredRecord>(storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>, key: u64) -> Option<V> {
+    let value = storage.with(|storage| storage.borrow().get(&key))?;
+    let Some(failure) = take_decode_failure() else {
+        return Some(value);
+    };
+
+    // A caller still holding the map leaves the record for the next read or scan
+    let moved = storage.with(|storage| storage.try_borrow_mut().map(|mut storage| storage.remove(&key)).is_ok());
+    take_decode_failure();
+    if moved {
+        store_quarantined(V::MAP, key, failure);
+    }
+
+    None
+}
+
+// Helper function to note the quarantine of a record; the caller removes it from its map
+fn store_quarantined(map: StoredMap, key: u64, (bytes, error): (Vec<u8>, String)) {
+    let id = next_id();
+
+    logging::log(LogLevel::Error, "quarantine", format!("Record {} of {:?} does not decode: {}", key, map_name(map), error));
+
+    let record = QuarantinedRecord {
+        id,
+        map,
+        key,
+        bytes,
+        error,
+        quarantined_at: time(),
+    };
+
+    QUARANTINE_STORAGE.with(|storage| storage.borrow_mut().insert(id, record));
+}
+
+// Helper function to check the caller may inspect or repair records of a map. Admins look after forum
+// content; the maps holding credentials, canister code or funds need a controller.
+fn check_repair_access(map: StoredMap) -> Result<(), String> {
+    let content = matches!(
+        map,
+        StoredMap::Users | StoredMap::Discussions | StoredMap::Votes | StoredMap::Groups | StoredMap::GroupMembers | StoredMap::Reports
+    );
+
+    if content || guards::caller_is_controller().is_ok() {
+        Ok(())
+    } else {
+        Err("Only controllers may inspect or repair records of this map".to_string())
+    }
+}
+
+fn map_name(map: StoredMap) -> &'static str {
+    match map {
+        StoredMap::Users => "users",
+        StoredMap::Discussions => "discussions",
+        StoredMap::Votes => "votes",
+        StoredMap::Groups => "groups",
+        StoredMap::GroupMembers => "group_members",
+        StoredMap::GroupInvites => "group_invites",
+        StoredMap::JoinRequests => "join_requests",
+        StoredMap::Reports => "reports",
+        StoredMap::GroupAuditLog => "group_audit_log",
+        StoredMap::Conversations => "conversations",
+        StoredMap::Messages => "messages",
+        StoredMap::PrincipalLinks => "principal_links",
+        StoredMap::LinkChallenges => "link_challenges",
+        StoredMap::SiweChallenges => "siwe_challenges",
+        StoredMap::PrivacySettings => "privacy_settings",
+        StoredMap::RecoveryConfigs => "recovery_configs",
+        StoredMap::PendingRecoveries => "pending_recoveries",
+        StoredMap::AccountAuditLog => "account_audit_log",
+        StoredMap::Tombstones => "tombstones",
+        StoredMap::Admins => "admins",
+        StoredMap::FeatureFlags => "feature_flags",
+        StoredMap::Idempotency => "idempotency",
+        StoredMap::VoteCommitments => "vote_commitments",
+        StoredMap::Personhood => "personhood",
+        StoredMap::RegistrationChallenges => "registration_challenges",
+        StoredMap::RegistrationEvents => "registration_events",
+        StoredMap::Views => "views",
+        StoredMap::ReadMarkers => "read_markers",
+        StoredMap::KeywordAlerts => "keyword_alerts",
+        StoredMap::Notifications => "notifications",
+        StoredMap::HashtagIndex => "hashtag_index",
+        StoredMap::LinkPreviews => "link_previews",
+        StoredMap::LinkPreviewQueue => "link_preview_queue",
+        StoredMap::TopicMarkdown => "topic_markdown",
+        StoredMap::Attachments => "attachments",
+        StoredMap::AttachmentChunks => "attachment_chunks",
+        StoredMap::CanisterWasm => "canister_wasm",
+        StoredMap::Shards => "shards",
+        StoredMap::Archives => "archives",
+        StoredMap::SearchDocuments => "search_documents",
+        StoredMap::GroupDirectory => "group_directory",
+        StoredMap::Proposals => "proposals",
+        StoredMap::ProposalVotes => "proposal_votes",
+        StoredMap::VotingPowerSnapshots => "voting_power_snapshots",
+        StoredMap::TreasuryPayouts => "treasury_payouts",
+        StoredMap::TreasuryDeposits => "treasury_deposits",
+        StoredMap::Entitlements => "entitlements",
+        StoredMap::Referrals => "referrals",
+        StoredMap::KarmaAwards => "karma_awards",
+        StoredMap::Profiles => "profiles",
+        StoredMap::Onboarding => "onboarding",
+        StoredMap::Badges => "badges",
+        StoredMap::Terms => "terms",
+        StoredMap::TermsAcceptances => "terms_acceptances",
+        StoredMap::ContentPreferences => "content_preferences",
+        StoredMap::FloodIncidents => "flood_incidents",
+        StoredMap::Log => "log",
+        StoredMap::EndpointMetrics => "endpoint_metrics",
+        StoredMap::Trash => "trash",
+        StoredMap::Warnings => "warnings",
+        StoredMap::Restrictions => "restrictions",
+        StoredMap::Appeals => "appeals",
+        StoredMap::DiscussionRevisions => "discussion_revisions",
+        StoredMap::OwnershipTransfers => "ownership_transfers",
+        StoredMap::Surveys => "surveys",
+        StoredMap::SurveyResponses => "survey_responses",
+        StoredMap::Outbox => "outbox",
+        StoredMap::DeadLetters => "dead_letters",
+        StoredMap::EventSubscriptions => "event_subscriptions",
+        StoredMap::Reminders => "reminders",
+        StoredMap::ReminderWheel => "reminder_wheel",
+        StoredMap::Recurrences => "recurrences",
+        StoredMap::UnlockRequests => "unlock_requests",
+        StoredMap::UserActivity => "user_activity",
+        StoredMap::KarmaTallies => "karma_tallies",
+        StoredMap::Raffles => "raffles",
+        StoredMap::RandomnessAudit => "randomness_audit",
+        StoredMap::ResultAttestations => "result_attestations",
+    }
+}
+
+// Helper function to move the undecodable records of one batch out of a map, returning how many were moved
+// and where the next batch starts
+fn quarantine_scan<V: BoundedStorable>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+    map: StoredMap,
+    cursor: u64,
+) -> QuarantineScan {
+    // Drop any failure left over from reads outside the scan
+    take_decode_failure();
+
+    let mut corrupt: Vec<(u64, (Vec<u8>, String))> = Vec::new();
+    let mut next_cursor = None;
+
+    storage.with(|storage| {
+        for (scanned, (key, _)) in storage.borrow().range(cursor..).enumerate() {
+            if scanned == GC_BATCH_SIZE {
+                next_cursor = Some(key);
+                break;
+            }
+            if let Some(failure) = take_decode_failure() {
+                corrupt.push((key, failure));
+            }
+        }
+
+        let mut storage = storage.borrow_mut();
+        for (key, _) in &corrupt {
+            storage.remove(key);
+        }
+    });
+    // Removing decodes the old value once more
+    take_decode_failure();
+
+    let quarantined = corrupt.len() as u64;
+    for (key, failure) in corrupt {
+        store_quarantined(map, key, failure);
+    }
+
+    QuarantineScan { quarantined, next_cursor }
+}
+
+// Function to move records that no longer decode out of a map, one batch per call (admins only).
+// Until then reads skip them, and try_get moves the ones it comes across. This and the other repair
+// endpoints stay available in maintenance mode, when repairs are usually made.
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn scan_for_corrupt_records(map: StoredMap, cursor: Option<u64>) -> QuarantineScan {
+    let _meter = metrics::measure("scan_for_corrupt_records");
+
+    let cursor = cursor.unwrap_or(0);
+    with_stored_map!(map, |storage| quarantine_scan(storage, map, cursor))
+}
+
+// Function to page through quarantined records (admins only, see check_repair_access)
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_quarantined_records(cursor: Option<String>, limit: Option<u32>) -> Result<Page<QuarantinedRecord>, String> {
+    paginate(&QUARANTINE_STORAGE, cursor, limit, |_, record| check_repair_access(record.map).is_ok())
+}
+
+// Helper function to read the bytes stored under a key, with the decode error if they no longer decode.
+// Decodable records come back re-encoded, which is byte-for-byte what is stored unless the schema moved on.
+fn read_raw_record<V: BoundedStorable>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+    key: u64,
+) -> Option<(Vec<u8>, Option<String>)> {
+    take_decode_failure();
+    let value = storage.with(|storage| storage.borrow().get(&key))?;
+
+    Some(match take_decode_failure() {
+        Some((bytes, error)) => (bytes, Some(error)),
+        None => (value.to_bytes().into_owned(), None),
+    })
+}
+
+// Helper function to find the quarantined copy of a record
+fn find_quarantined(map: StoredMap, key: u64) -> Option<QuarantinedRecord> {
+    QUARANTINE_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, record)| record).find(|record| record.map == map && record.key == key)
+    })
+}
+
+// Function to inspect a stored record as raw bytes and, if they are still valid Candid, as text.
+// Looks in the quarantine when the key is no longer in its map (admins only, see check_repair_access).
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn inspect_record(map: StoredMap, key: u64) -> Result<RawRecord, String> {
+    check_repair_access(map)?;
+
+    let (bytes, error, quarantined) = match with_stored_map!(map, |storage| read_raw_record(storage, key)) {
+        Some((bytes, error)) => (bytes, error, false),
+        None => {
+            let record = find_quarantined(map, key).ok_or("Record not found")?;
+            (record.bytes, Some(record.error), true)
+        }
+    };
+
+    // Field names show as hashes, the bytes carry no type table beyond their own
+    let candid = IDLArgs::from_bytes(&bytes).ok().map(|args| args.to_string());
+
+    Ok(RawRecord {
+        map,
+        key,
+        bytes,
+        decode_error: error,
+        candid,
+        quarantined,
+    })
+}
+
+// Function to rewrite a record under the current schema, e.g. to fill in fields added since it was
+// stored (admins only)
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn reencode_record(map: StoredMap, key: u64) -> Result<(), String> {
+    let _meter = metrics::measure("reencode_record");
+
+    with_stored_map!(map, |storage| {
+        take_decode_failure();
+        let value = storage.with(|storage| storage.borrow().get(&key)).ok_or("Record not found")?;
+        if let Some((_, error)) = take_decode_failure() {
+            return Err(format!("Record does not decode, patch it instead: {}", error));
+        }
+
+        storage.with(|storage| storage.borrow_mut().insert(key, value));
+        Ok(())
+    })
+}
+
+// Helper function to overwrite fields of a Candid record and decode the result under the current schema
+fn apply_field_patches<V: BoundedStorable + candid::CandidType + for<'de> serde::Deserialize<'de>>(
+    bytes: &[u8],
+    patches: &[FieldPatch],
+) -> Result<V, String> {
+    let mut args = IDLArgs::from_bytes(bytes).map_err(|e| format!("Record is not valid Candid: {}", e))?;
+    let Some(IDLValue::Record(fields)) = args.args.get_mut(0) else {
+        return Err("Record is not a Candid record".to_string());
+    };
+
+    for patch in patches {
+        let value = IDLArgs::from_bytes(&patch.value).ok()
+            .and_then(|args| args.args.into_iter().next())
+            .ok_or_else(|| format!("Value for {} is not a Candid-encoded value", patch.field))?;
+        let id = candid::types::Label::Named(patch.field.clone()).get_id();
+
+        match fields.iter_mut().find(|field| field.id.get_id() == id) {
+            Some(field) => field.val = value,
+            None => fields.push(IDLField { id: candid::types::Label::Id(id), val: value }),
+        }
+    }
+    fields.sort_by_key(|field| field.id.get_id());
+
+    let patched = args.to_bytes().map_err(|e| format!("Cannot encode patched record: {}", e))?;
+    if patched.len() > V::MAX_SIZE as usize {
+        return Err(format!("Patched record exceeds {} bytes", V::MAX_SIZE));
+    }
+
+    Decode!(&patched, V).map_err(|e| format!("Patched record still does not decode: {}", e))
+}
+
+// Function to overwrite individual fields of a stored record; each value is a single Candid-encoded
+// value. A quarantined record that decodes once patched is put back in its map (admins only, see
+// check_repair_access, unless the DAO governs).
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn patch_record(map: StoredMap, key: u64, patches: Vec<FieldPatch>) -> Result<(), String> {
+    let _meter = metrics::measure("patch_record");
+
+    check_not_dao_governed()?;
+    check_repair_access(map)?;
+
+    apply_record_patch(map, key, patches)
+}
+
+// Helper function to overwrite fields of a stored or quarantined record
+fn apply_record_patch(map: StoredMap, key: u64, patches: Vec<FieldPatch>) -> Result<(), String> {
+    let (bytes, quarantined) = match with_stored_map!(map, |storage| read_raw_record(storage, key)) {
+        Some((bytes, _)) => (bytes, None),
+        None => {
+            let record = find_quarantined(map, key).ok_or("Record not found")?;
+            (record.bytes, Some(record.id))
+        }
+    };
+
+    with_stored_map!(map, |storage| {
+        let value = apply_field_patches(&bytes, &patches)?;
+        storage.with(|storage| storage.borrow_mut().insert(key, value));
+        take_decode_failure();
+        Ok::<(), String>(())
+    })?;
+
+    if let Some(id) = quarantined {
+        QUARANTINE_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    }
+    if map == StoredMap::Users {
+        queue_account_sync(key);
+    }
+    if map == StoredMap::Outbox {
+        outbox::rebuild_due_index();
+    }
+    if map == StoredMap::Votes {
+        mark_all_vote_roots_stale();
+    }
+    if matches!(map, StoredMap::KeywordAlerts | StoredMap::Notifications) {
+        rebuild_alert_indexes();
+    }
+    if DISCUSSION_RECORD_MAPS.contains(&map) {
+        rebuild_discussion_records();
+    }
+    if map == StoredMap::Trash {
+        rebuild_trash_index();
+    }
+    if matches!(map, StoredMap::VotingPowerSnapshots | StoredMap::Users) {
+        rebuild_voting_power_index();
+    }
+    if map == StoredMap::Restrictions {
+        rebuild_restriction_index();
+    }
+    if map == StoredMap::PrincipalLinks {
+        rebuild_principal_link_indexes();
+    }
+    if map == StoredMap::Discussions {
+        clear_discussion_cache();
+    }
+
+    logging::log(LogLevel::Warn, "patch_record", format!("Patched record {} of {}", key, map_name(map)));
+    Ok(())
+}
+
+// Function to delete a stored record outright; records that referenced it are left for garbage collection
+// (admins only, see check_repair_access, unless the DAO governs)
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn delete_record(map: StoredMap, key: u64) -> Result<(), String> {
+    let _meter = metrics::measure("delete_record");
+
+    check_not_dao_governed()?;
+    check_repair_access(map)?;
+
+    remove_record(map, key)
+}
+
+// Helper function to delete a stored record
+fn remove_record(map: StoredMap, key: u64) -> Result<(), String> {
+    let removed = with_stored_map!(map, |storage| storage.with(|storage| storage.borrow_mut().remove(&key)).is_some());
+    // Removing decodes the old value
+    take_decode_failure();
+
+    if !removed {
+        return Err("Record not found".to_string());
+    }
+    if map == StoredMap::Users {
+        queue_account_sync(key);
+    }
+    if map == StoredMap::Votes {
+        mark_all_vote_roots_stale();
+    }
+    if matches!(map, StoredMap::KeywordAlerts | StoredMap::Notifications) {
+        rebuild_alert_indexes();
+    }
+    if DISCUSSION_RECORD_MAPS.contains(&map) {
+        rebuild_discussion_records();
+    }
+    if map == StoredMap::Trash {
+        rebuild_trash_index();
+    }
+    if matches!(map, StoredMap::VotingPowerSnapshots | StoredMap::Users) {
+        rebuild_voting_power_index();
+    }
+    if map == StoredMap::Restrictions {
+        rebuild_restriction_index();
+    }
+    if map == StoredMap::PrincipalLinks {
+        rebuild_principal_link_indexes();
+    }
+    if map == StoredMap::Discussions {
+        clear_discussion_cache();
+    }
+
+    logging::log(LogLevel::Warn, "delete_record", format!("Deleted record {} of {}", key, map_name(map)));
+    Ok(())
+}
+
+// Function to drop a quarantined record once it has been dealt with (admins only)
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn delete_quarantined_record(id: u64) -> Result<(), String> {
+    let _meter = metrics::measure("delete_quarantined_record");
+
+    QUARANTINE_STORAGE.with(|storage| storage.borrow_mut().remove(&id))
+        .map(|_| ())
+        .ok_or_else(|| "Quarantined record not found".to_string())
+}
+
+// Helper function to run one bounded garbage collection step and persist its progress
+fn collect_garbage_chunk(mut state: GcState, now: u64) {
+    // Orphans are judged against the users held here, which only the hub has. An archive would lose every
+    // archived vote in one pass, so the collector checks the role itself rather than trust its caller.
+    if canister_role() != CanisterRole::Hub {
+        logging::log(LogLevel::Error, "gc", "Garbage collection only runs on the hub".to_string());
+        record_gc_tick(state, now);
+        return;
+    }
+
+    // Records of trashed discussions are kept until the discussion is purged
+    let discussion_exists = |id: &u64| DISCUSSIONS_STORAGE.with(|storage| storage.borrow().contains_key(id)) || is_trashed(*id);
+    let group_exists = |id: &u64| GROUPS_STORAGE.with(|storage| storage.borrow().contains_key(id));
+
+    let (removed, next) = match state.phase {
+        GcPhase::Votes => {
+            let usernames = registered_usernames();
+            let (removed, next) = gc_scan(&VOTES_STORAGE, state.cursor, |vote| {
+                !discussion_exists(&vote.discussion_id) || !usernames.contains(&vote.by)
+            });
+            state.removed_votes += removed;
+            if removed > 0 {
+                mark_all_vote_roots_stale();
+            }
+            (removed, next)
+        }
+        GcPhase::GroupMembers => {
+            let usernames = registered_usernames();
+            let (removed, next) = gc_scan(&GROUP_MEMBERS_STORAGE, state.cursor, |member| {
+                !group_exists(&member.group_id) || !usernames.contains(&member.username)
+            });
+            state.removed_group_members += removed;
+            (removed, next)
+        }
+        GcPhase::JoinRequests => {
+            let usernames = registered_usernames();
+            let (removed, next) = gc_scan(&JOIN_REQUESTS_STORAGE, state.cursor, |request| {
+                !group_exists(&request.group_id) || !usernames.contains(&request.username)
+            });
+            state.removed_join_requests += removed;
+            (removed, next)
+        }
+        GcPhase::Invites => {
+            let (removed, next) = gc_scan(&GROUP_INVITES_STORAGE, state.cursor, |invite| {
+                !group_exists(&invite.group_id) || invite.expires_at <= now || invite.uses >= invite.max_uses
+            });
+            state.removed_invites += removed;
+            (removed, next)
+        }
+        GcPhase::Reports => {
+            let (removed, next) = gc_scan(&REPORTS_STORAGE, state.cursor, |report| {
+                report.status == ReportStatus::Open && !discussion_exists(&report.discussion_id)
+            });
+            state.removed_reports += removed;
+            (removed, next)
+        }
+        GcPhase::Messages => {
+            let (removed, next) = gc_scan(&MESSAGES_STORAGE, state.cursor, |message| {
+                !CONVERSATIONS_STORAGE.with(|storage| storage.borrow().contains_key(&message.conversation_id))
+            });
+            state.removed_messages += removed;
+            (removed, next)
+        }
+        GcPhase::LinkChallenges => {
+            let (removed, next) = gc_scan(&LINK_CHALLENGES_STORAGE, state.cursor, |challenge| challenge.expires_at <= now);
+            state.removed_challenges += removed;
+            (removed, next)
+        }
+        GcPhase::SiweChallenges => {
+            let (removed, next) = gc_scan(&SIWE_CHALLENGES_STORAGE, state.cursor, |challenge| challenge.expires_at <= now);
+            state.removed_challenges += removed;
+            (removed, next)
+        }
+        GcPhase::RegistrationChallenges => {
+            let (removed, next) = gc_scan(&REGISTRATION_CHALLENGES_STORAGE, state.cursor, |challenge| challenge.expires_at <= now);
+            state.removed_challenges += removed;
+            (removed, next)
+        }
+        GcPhase::IdempotencyRecords => {
+            let (removed, next) = gc_scan(&IDEMPOTENCY_STORAGE, state.cursor, |record| record.expires_at <= now);
+            state.removed_idempotency_records += removed;
+            (removed, next)
+        }
+        GcPhase::Views => {
+            let (removed, next) = gc_scan(&VIEWS_STORAGE, state.cursor, |view| view.viewed_at.saturating_add(VIEW_DEDUP_WINDOW) <= now);
+            state.removed_views += removed;
+            (removed, next)
+        }
+        GcPhase::Attachments => {
+            // Unfinished uploads can't be attached to a discussion, so only their chunks go with them
+            let chunk_ids = RefCell::new(Vec::new());
+            let (removed, next) = gc_scan(&ATTACHMENTS_STORAGE, state.cursor, |attachment| {
+                let stale = !attachment.complete && attachment.created_at.saturating_add(ATTACHMENT_UPLOAD_TTL) <= now;
+                if stale {
+                    chunk_ids.borrow_mut().extend_from_slice(&attachment.chunk_ids);
+                }
+                stale
+            });
+            ATTACHMENT_CHUNKS_STORAGE.with(|storage| {
+                let mut storage = storage.borrow_mut();
+                for chunk_id in chunk_ids.into_inner() {
+                    storage.remove(&chunk_id);
+                }
+            });
+            state.removed_attachments += removed;
+            (removed, next)
+        }
+    };
+
+    // Resume from `next` on the following tick, or move on to the next map
+    match next {
+        Some(cursor) => state.cursor = cursor,
+        None => {
+            state.cursor = 0;
+            state.phase = match state.phase {
+                GcPhase::Votes => GcPhase::GroupMembers,
+                GcPhase::GroupMembers => GcPhase::JoinRequests,
+                GcPhase::JoinRequests => GcPhase::Invites,
+                GcPhase::Invites => GcPhase::Reports,
+                GcPhase::Reports => GcPhase::Messages,
+                GcPhase::Messages => GcPhase::LinkChallenges,
+                GcPhase::LinkChallenges => GcPhase::SiweChallenges,
+                GcPhase::SiweChallenges => GcPhase::RegistrationChallenges,
+                GcPhase::RegistrationChallenges => GcPhase::IdempotencyRecords,
+                GcPhase::IdempotencyRecords => GcPhase::Views,
+                GcPhase::Views => GcPhase::Attachments,
+                GcPhase::Attachments => {
+                    state.completed_passes += 1;
+                    GcPhase::Votes
+                }
+            };
+        }
+    }
+
+    if removed > 0 {
+        logging::log(LogLevel::Info, "gc", format!("GC removed {} orphaned records", removed));
+    }
+
+    record_gc_tick(state, now);
+}
+
+// Helper function to store the GC state with the tick that just ran
+fn record_gc_tick(mut state: GcState, now: u64) {
+    state.last_tick_at = now;
+    GC_STATE.with(|cell| cell.borrow_mut().set(state)).expect("Cannot store GC state");
+}
+
+// Helper function to read this canister's role
+fn canister_role() -> CanisterRole {
+    CANISTER_ROLE.with(|role| role.borrow().get().clone())
+}
+
+// Function called by a parent canister right after installing this one to give it its role.
+// Only a fresh hub can take a role, so a live forum can't be turned into a shard by mistake.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn assume_role(role: CanisterRole) -> Result<String, String> {
+    let _meter = metrics::measure("assume_role");
+
+    ensure_writable()?;
+
+    if canister_role() != CanisterRole::Hub {
+        return Err("This canister already has a role".to_string());
+    }
+
+    if !USERS_STORAGE.with(|storage| storage.borrow().is_empty()) || !DISCUSSIONS_STORAGE.with(|storage| storage.borrow().is_empty()) {
+        return Err("Only an empty canister can take a role".to_string());
+    }
+
+    CANISTER_ROLE.with(|cell| cell.borrow_mut().set(role))
+        .map_err(|_| "Cannot store canister role".to_string())?;
+
+    Ok("Role assigned".to_string())
+}
+
+// Function to get this canister's role
+#[ic_cdk::query]
+fn get_canister_role() -> CanisterRole {
+    canister_role()
+}
+
+// Function to upload the wasm installed into spawned canisters, in WASM_CHUNK_SIZE pieces and in order.
+// Chunk 0 starts a new module. This is normally the hub's own wasm (controllers only).
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn upload_canister_wasm(chunk_index: u32, data: Vec<u8>) -> Result<CanisterWasmInfo, String> {
+    let _meter = metrics::measure("upload_canister_wasm");
+
+    ensure_writable()?;
+
+    if data.is_empty() || data.len() > WASM_CHUNK_SIZE {
+        return Err(format!("Chunks must be between 1 and {} bytes", WASM_CHUNK_SIZE));
+    }
+
+    CANISTER_WASM_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+
+        if chunk_index == 0 {
+            let keys: Vec<u64> = storage.iter().filter(decoded).map(|(index, _)| index).collect();
+            for key in keys {
+                storage.remove(&key);
+            }
+        } else if chunk_index as u64 != storage.len() {
+            return Err(format!("Expected chunk {}", storage.len()));
+        }
+
+        storage.insert(chunk_index as u64, WasmChunk { data });
+        Ok(())
+    })?;
+
+    get_canister_wasm_info().ok_or_else(|| "Cannot read canister wasm".to_string())
+}
+
+// Function to get the size and hash of the uploaded canister wasm
+#[ic_cdk::query]
+fn get_canister_wasm_info() -> Option<CanisterWasmInfo> {
+    use sha3::{Digest, Sha3_256};
+
+    let wasm = canister_wasm()?;
+    let chunks = CANISTER_WASM_STORAGE.with(|storage| storage.borrow().len()) as u32;
+
+    Some(CanisterWasmInfo {
+        size: wasm.len() as u64,
+        chunks,
+        sha3_256: Sha3_256::digest(&wasm).to_vec(),
+    })
+}
+
+// Helper function to reassemble the uploaded canister wasm
+fn canister_wasm() -> Option<Vec<u8>> {
+    let wasm: Vec<u8> = CANISTER_WASM_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).flat_map(|(_, chunk)| chunk.data).collect()
+    });
+
+    (!wasm.is_empty()).then_some(wasm)
+}
+
+// Helper function to create a canister controlled by this one, install the uploaded wasm and assign its role
+async fn spawn_canister(role: CanisterRole) -> Result<Principal, String> {
+    use ic_cdk::api::management_canister::main::{
+        create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument, InstallCodeArgument,
+    };
+
+    let wasm_module = canister_wasm().ok_or("Upload the canister wasm first")?;
+
+    let settings = CanisterSettings {
+        controllers: Some(vec![ic_cdk::id()]),
+        ..Default::default()
+    };
+    let (record,) = create_canister(CreateCanisterArgument { settings: Some(settings) }, SPAWN_CANISTER_CYCLES)
+        .await
+        .map_err(|(_, message)| format!("Cannot create canister: {}", message))?;
+
+    install_code(InstallCodeArgument {
+        mode: CanisterInstallMode::Install,
+        canister_id: record.canister_id,
+        wasm_module,
+        arg: Encode!().map_err(|_| "Cannot encode install argument".to_string())?,
+    })
+    .await
+    .map_err(|(_, message)| format!("Cannot install canister {}: {}", record.canister_id, message))?;
+
+    let (assigned,): (Result<String, String>,) = ic_cdk::call(record.canister_id, "assume_role", (role,))
+        .await
+        .map_err(|(_, message)| format!("Cannot assign role to {}: {}", record.canister_id, message))?;
+    assigned?;
+
+    Ok(record.canister_id)
+}
+
+// Helper function to check whether this canister routes forum discussions to shards
+fn has_shards() -> bool {
+    !SHARDS_STORAGE.with(|storage| storage.borrow().is_empty())
+}
+
+// Helper function to list the shards in discussion ID order
+fn shards() -> Vec<ShardInfo> {
+    SHARDS_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).map(|(_, shard)| shard).collect())
+}
+
+// Helper function to find the shard owning a discussion ID. Discussions still held here (older forum
+// discussions and every group discussion) are never routed.
+fn shard_for(discussion_id: u64) -> Option<Principal> {
+    if DISCUSSIONS_STORAGE.with(|storage| storage.borrow().contains_key(&discussion_id)) {
+        return None;
+    }
+
+    SHARDS_STORAGE.with(|storage| {
+        storage.borrow().range(..=discussion_id).filter(decoded).last().map(|(_, shard)| shard.canister_id)
+    })
+}
+
+// Function to add a shard; every forum discussion created from now on goes to it (controllers only).
+// Shards get their cycles from this canister and are controlled only by it.
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn create_shard() -> Result<ShardInfo, String> {
+    let _meter = metrics::measure("create_shard");
+
+    ensure_writable()?;
+
+    if canister_role() != CanisterRole::Hub {
+        return Err("Only the hub can create shards".to_string());
+    }
+
+    let canister_id = spawn_canister(CanisterRole::Shard { router: ic_cdk::id() }).await?;
+
+    // IDs are only handed out after the shard exists, so no discussion lands between two ranges
+    let shard = ShardInfo {
+        canister_id,
+        first_discussion_id: next_id(),
+        created_at: time(),
+    };
+
+    SHARDS_STORAGE.with(|storage| storage.borrow_mut().insert(shard.first_discussion_id, shard.clone()));
+
+    Ok(shard)
+}
+
+// Function to list the shards of this canister
+#[ic_cdk::query]
+fn get_shards() -> Vec<ShardInfo> {
+    shards()
+}
+
+// Function to start a forum discussion on the current shard. Rate limits and storage quotas only count
+// discussions held by this canister.
+#[ic_cdk::update]
+async fn create_sharded_discussion(topic: String, username: String) -> Result<Discussion, String> {
+    let _meter = metrics::measure("create_sharded_discussion");
+
+    ensure_writable()?;
+
+    let shard = shards().pop().ok_or("No shards exist, use create_discussion")?;
+    let discussion = new_discussion(topic, username, None)?;
+
+    let (stored,): (Result<(), String>,) = ic_cdk::call(shard.canister_id, "shard_store_discussion", (discussion.clone(),))
+        .await
+        .map_err(|(_, message)| format!("Shard call failed: {}", message))?;
+    stored?;
+
+    notify_keyword_matches(&discussion);
+
+    Ok(discussion)
+}
+
+// Function to vote on a discussion held by a shard
+#[ic_cdk::update]
+async fn vote_sharded_discussion(vote_type: VoteType, discussion_id: u64, username: String, reason: Option<DownvoteReason>) -> Result<String, String> {
+    let _meter = metrics::measure("vote_sharded_discussion");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let shard = shard_for(discussion_id).ok_or("Discussion is not on a shard, use vote_discussion")?;
+
+    let (discussion,): (Option<Discussion>,) = ic_cdk::call(shard, "shard_get_discussion", (discussion_id,))
+        .await
+        .map_err(|(_, message)| format!("Shard call failed: {}", message))?;
+    let discussion = discussion.ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if is_voting_closed(&discussion, time()) {
+        return Err(ErrorCode::VotingClosed.into());
+    }
+
+    if discussion.reveal_ends_at.is_some() {
+        return Err("This discussion uses commit-reveal voting".to_string());
+    }
+
+    // Duplicate votes are checked again by the shard, which holds the votes
+    check_vote_allowed(&discussion, &username, &vote_type)?;
+    check_downvote_reason(&vote_type, &reason)?;
+
+    let (recorded,): (Result<(), String>,) = ic_cdk::call(shard, "shard_record_vote", (discussion_id, username, Some(voter_principal()?), vote_type, reason))
+        .await
+        .map_err(|(_, message)| format!("Shard call failed: {}", message))?;
+    recorded?;
+
+    Ok("Vote recorded for discussion".to_string())
+}
+
+// Function to get a discussion wherever it is held: here, on a shard or in an archive
+#[ic_cdk::query(composite = true)]
+async fn get_sharded_discussion(discussion_id: u64, username: String) -> Result<Discussion, String> {
+    let (canister_id, method) = match (archive_for(discussion_id), shard_for(discussion_id)) {
+        (Some(archive), _) => (archive, "archive_get_discussion"),
+        (None, Some(shard)) => (shard, "shard_get_discussion"),
+        (None, None) => return get_discussion(discussion_id, username),
+    };
+
+    let (discussion,): (Option<Discussion>,) = ic_cdk::call(canister_id, method, (discussion_id,))
+        .await
+        .map_err(|(_, message)| format!("Remote call failed: {}", message))?;
+    let discussion = discussion.ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if !can_access_discussion(&discussion, &resolve_viewer(username)) {
+        return Err(ErrorCode::DiscussionNotFound.into());
+    }
+
+    Ok(redact_tallies(discussion))
+}
+
+// Function to page through forum discussions across this canister and its shards. Shards own
+// increasing ID ranges, so reading them in order continues the same keyset cursor.
+#[ic_cdk::query(composite = true)]
+async fn get_sharded_discussions_page(cursor: Option<String>, limit: Option<u32>) -> Result<Page<Discussion>, String> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let preferences = caller_preferences();
+
+    let mut page = get_discussions_page(cursor.clone(), Some(limit))?;
+    let mut cursor = page.items.last().map(|discussion| encode_cursor(discussion.id)).or(cursor);
+
+    for shard in shards() {
+        let remaining = limit - page.items.len() as u32;
+        if remaining == 0 {
+            break;
+        }
+
+        let (shard_page,): (Result<Page<Discussion>, String>,) = ic_cdk::call(shard.canister_id, "shard_list_discussions", (cursor.clone(), Some(remaining), preferences.clone()))
+            .await
+            .map_err(|(_, message)| format!("Shard call failed: {}", message))?;
+        let shard_page = redact_page(shard_page?);
+
+        cursor = shard_page.items.last().map(|discussion| encode_cursor(discussion.id)).or(cursor);
+        page.items.extend(shard_page.items);
+    }
+
+    page.next_cursor = if page.items.len() == limit as usize { cursor } else { None };
+
+    Ok(page)
+}
+
+// Function called by the router to store a discussion it validated
+#[ic_cdk::update(guard = "caller_is_router")]
+fn shard_store_discussion(discussion: Discussion) -> Result<(), String> {
+    let _meter = metrics::measure("shard_store_discussion");
+
+    check_maintenance()?;
+
+    if DISCUSSIONS_STORAGE.with(|storage| storage.borrow().contains_key(&discussion.id)) {
+        return Err("Discussion already exists".to_string());
+    }
+
+    store_discussion(&discussion);
+
+    Ok(())
+}
+
+// Function called by the router to read a discussion held here
+#[ic_cdk::query(guard = "caller_is_router")]
+fn shard_get_discussion(discussion_id: u64) -> Option<Discussion> {
+    load_discussion(discussion_id)
+}
+
+// Function called by the router to page through the forum discussions held here
+#[ic_cdk::query(guard = "caller_is_router")]
+fn shard_list_discussions(cursor: Option<String>, limit: Option<u32>, preferences: ContentPreferences) -> Result<Page<Discussion>, String> {
+    paginate(&DISCUSSIONS_STORAGE, cursor, limit, |_, discussion| {
+        is_forum_listed(discussion) && matches_preferences(discussion, &preferences)
+    })
+}
+
+// Function called by the router to record a vote it authorized
+#[ic_cdk::update(guard = "caller_is_router")]
+fn shard_record_vote(discussion_id: u64, username: String, voter: Option<Principal>, vote_type: VoteType, reason: Option<DownvoteReason>) -> Result<(), String> {
+    let _meter = metrics::measure("shard_record_vote");
+
+    check_maintenance()?;
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    // The router only sends votes of authenticated callers
+    let voter = voter.ok_or("Voting needs an authenticated caller")?;
+    check_not_voted(discussion_id, &username, voter)?;
+
+    record_vote(discussion, &username, vote_type, Some(voter), reason);
+
+    Ok(())
+}
+
+// Helper function to find the archive canister holding a discussion
+fn archive_for(discussion_id: u64) -> Option<Principal> {
+    let key = ARCHIVED_INDEX.with(|index| index.borrow().get(&discussion_id))?;
+    try_get(&ARCHIVES_STORAGE, key).map(|archive| archive.canister_id)
+}
+
+// Helper function to pick the cold discussions to archive next, with their votes. A discussion is cold
+// once it is older than the archive age, has no open vote and nobody voted on it within that age.
+fn cold_discussion_batch(now: u64) -> (Vec<Discussion>, Vec<Vote>) {
+    let days = settings().archive_after_days;
+    let cutoff = now.saturating_sub(days as u64 * 24 * 60 * 60 * 1_000_000_000);
+
+    let mut last_vote_at: BTreeMap<u64, u64> = BTreeMap::new();
+    VOTES_STORAGE.with(|storage| {
+        for (_, vote) in storage.borrow().iter().filter(decoded) {
+            let last = last_vote_at.entry(vote.discussion_id).or_default();
+            *last = (*last).max(vote.created_at);
+        }
+    });
+
+    let candidates: Vec<Discussion> = DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .map(|(_, discussion)| discussion)
+            .filter(|discussion| {
+                discussion.created_at < cutoff
+                    && (discussion.voting_ends_at.is_none() || discussion.results_final)
+                    && last_vote_at.get(&discussion.id).is_none_or(|at| *at < cutoff)
+            })
+            .take(ARCHIVE_BATCH_DISCUSSIONS)
+            .collect()
+    });
+
+    let mut discussions = Vec::new();
+    let mut votes = Vec::new();
+
+    for discussion in candidates {
+        let discussion_votes: Vec<Vote> = VOTES_STORAGE.with(|storage| {
+            storage.borrow().iter().filter(decoded)
+                .filter(|(_, vote)| vote.discussion_id == discussion.id)
+                .map(|(_, vote)| vote)
+                .collect()
+        });
+
+        // Always send at least one discussion so a heavily voted one can't stall archiving
+        if !discussions.is_empty() && votes.len() + discussion_votes.len() > ARCHIVE_BATCH_VOTES {
+            break;
+        }
+
+        discussions.push(discussion);
+        votes.extend(discussion_votes);
+    }
+
+    (discussions, votes)
+}
+
+// Helper function to start moving one batch of cold discussions to the current archive, spawning a new
+// archive when there is none or the current one is full. Called from the background tick.
+fn archive_cold_discussions(now: u64) {
+    if settings().archive_after_days == 0
+        || canister_role() != CanisterRole::Hub
+        || ARCHIVING_IN_FLIGHT.with(|flag| *flag.borrow())
+    {
+        return;
+    }
+
+    let (discussions, votes) = cold_discussion_batch(now);
+    if discussions.is_empty() {
+        return;
+    }
+
+    ARCHIVING_IN_FLIGHT.with(|flag| *flag.borrow_mut() = true);
+
+    ic_cdk::spawn(async move {
+        if let Err(error) = send_to_archive(discussions, votes).await {
+            logging::log(LogLevel::Error, "archive", format!("Archiving failed: {}", error));
+        }
+        ARCHIVING_IN_FLIGHT.with(|flag| *flag.borrow_mut() = false);
+    });
+}
+
+// Helper function to copy a batch to the archive, then drop the local copies that did not change meanwhile
+async fn send_to_archive(discussions: Vec<Discussion>, votes: Vec<Vote>) -> Result<(), String> {
+    let current = ARCHIVES_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).last())
+        .filter(|(_, archive)| archive.discussions < ARCHIVE_CAPACITY);
+
+    let (key, mut archive) = match current {
+        Some(current) => current,
+        None => {
+            let canister_id = spawn_canister(CanisterRole::Archive { hub: ic_cdk::id() }).await?;
+            let key = ARCHIVES_STORAGE.with(|storage| storage.borrow().len());
+            let archive = ArchiveInfo { canister_id, created_at: time(), discussions: 0 };
+            // Recorded before the first append so a failed call doesn't lose track of the canister
+            ARCHIVES_STORAGE.with(|storage| storage.borrow_mut().insert(key, archive.clone()));
+            (key, archive)
+        }
+    };
+
+    let (appended,): (Result<(), String>,) = ic_cdk::call(archive.canister_id, "archive_append", (discussions.clone(), votes.clone()))
+        .await
+        .map_err(|(_, message)| format!("Archive call failed: {}", message))?;
+    appended?;
+
+    // A discussion edited or voted on while the batch was in flight stays here, the archive copy is unused
+    let mut moved = 0;
+    for discussion in discussions {
+        let unchanged = load_discussion(discussion.id).is_some_and(|current| {
+            current.version == discussion.version
+                && current.upvotes == discussion.upvotes
+                && current.downvotes == discussion.downvotes
+        });
+
+        if unchanged {
+            delete_discussion_and_votes(discussion.id);
+            ARCHIVED_INDEX.with(|index| index.borrow_mut().insert(discussion.id, key));
+            moved += 1;
+        }
+    }
+
+    archive.discussions += moved;
+    ARCHIVES_STORAGE.with(|storage| storage.borrow_mut().insert(key, archive));
+
+    Ok(())
+}
+
+// Function to list the archive canisters of this hub
+#[ic_cdk::query]
+fn get_archives() -> Vec<ArchiveInfo> {
+    ARCHIVES_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).map(|(_, archive)| archive).collect())
+}
+
+// Function called by the hub to store archived discussions and their votes
+#[ic_cdk::update(guard = "caller_is_hub")]
+fn archive_append(discussions: Vec<Discussion>, votes: Vec<Vote>) -> Result<(), String> {
+    let _meter = metrics::measure("archive_append");
+
+    check_maintenance()?;
+
+    for discussion in discussions {
+        save_discussion(discussion.id, discussion);
+    }
+
+    for vote in votes {
+        insert_vote(vote);
+    }
+
+    Ok(())
+}
+
+// Function called by the hub to read an archived discussion
+#[ic_cdk::query(guard = "caller_is_hub")]
+fn archive_get_discussion(discussion_id: u64) -> Option<Discussion> {
+    load_discussion(discussion_id)
+}
+
+// Helper function to read the hub's search canister
+fn search_index_canister() -> Option<Principal> {
+    SEARCH_INDEX.with(|cell| cell.borrow().get().canister_id)
+}
+
+// Helper function to schedule pushing a discussion's current tokens (or its removal) to the search canister
+fn queue_search_update(discussion_id: u64) {
+    if search_index_canister().is_some() {
+        SEARCH_QUEUE.with(|queue| queue.borrow_mut().insert(discussion_id, time()));
+    }
+}
+
+// Function to create the companion search canister and queue every discussion for indexing (controllers only)
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn create_search_index() -> Result<Principal, String> {
+    let _meter = metrics::measure("create_search_index");
+
+    ensure_writable()?;
+
+    if canister_role() != CanisterRole::Hub {
+        return Err("Only the hub can create a search index".to_string());
+    }
+
+    if search_index_canister().is_some() {
+        return Err("A search index already exists".to_string());
+    }
+
+    let canister_id = spawn_canister(CanisterRole::SearchIndex { hub: ic_cdk::id() }).await?;
+
+    SEARCH_INDEX.with(|cell| cell.borrow_mut().set(SearchIndexConfig { canister_id: Some(canister_id) }))
+        .map_err(|_| "Cannot store search index config".to_string())?;
+
+    let ids: Vec<u64> = DISCUSSIONS_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).map(|(id, _)| id).collect());
+    for id in ids {
+        queue_search_update(id);
+    }
+
+    Ok(canister_id)
+}
+
+// Function to get the hub's search canister
+#[ic_cdk::query]
+fn get_search_index() -> Option<Principal> {
+    search_index_canister()
+}
+
+// Helper function to start pushing one batch of queued token updates to the search canister. Called
+// from the background tick; an entry requeued while its batch was in flight stays queued.
+fn push_search_updates() {
+    let Some(canister_id) = search_index_canister() else {
+        return;
+    };
+
+    if SEARCH_PUSH_IN_FLIGHT.with(|flag| *flag.borrow()) {
+        return;
+    }
+
+    let batch: Vec<(u64, u64)> = SEARCH_QUEUE.with(|queue| queue.borrow().iter().take(SEARCH_PUSH_BATCH).collect());
+    if batch.is_empty() {
+        return;
+    }
+
+    // No tokens tells the search canister to drop the discussion
+    let updates: Vec<(u64, Vec<String>)> = batch.iter()
+        .map(|(id, _)| (*id, load_discussion(*id).map(|discussion| search::tokenize(&discussion.topic)).unwrap_or_default()))
+        .collect();
+
+    SEARCH_PUSH_IN_FLIGHT.with(|flag| *flag.borrow_mut() = true);
+
+    ic_cdk::spawn(async move {
+        let result: Result<(), String> = match ic_cdk::call(canister_id, "search_index_update", (updates,)).await {
+            Ok((result,)) => result,
+            Err((_, message)) => Err(message),
+        };
+
+        match result {
+            Ok(()) => SEARCH_QUEUE.with(|queue| {
+                let mut queue = queue.borrow_mut();
+                for (id, queued_at) in batch {
+                    if queue.get(&id) == Some(queued_at) {
+                        queue.remove(&id);
+                    }
+                }
+            }),
+            Err(message) => logging::log(LogLevel::Error, "search_index", format!("Search index push failed: {}", message)),
+        }
+
+        SEARCH_PUSH_IN_FLIGHT.with(|flag| *flag.borrow_mut() = false);
+    });
+}
+
+// Function to search discussions by words in their topic, newest first. Uses the search canister when
+// there is one and otherwise scans this canister; results only include discussions held here.
+#[ic_cdk::query(composite = true)]
+async fn search_discussions(query: String, username: String, limit: Option<u32>) -> Result<Vec<Discussion>, String> {
+    let username = resolve_viewer(username);
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let tokens: Vec<String> = search::tokenize(&query).into_iter().take(search::MAX_QUERY_TOKENS).collect();
+    if tokens.is_empty() {
+        return Err("Search for at least one word of two or more characters".to_string());
+    }
+
+    let preferences = viewer_preferences(&username);
+    let is_match = |discussion: &Discussion| {
+        can_access_discussion(discussion, &username) && !matches!(discussion.visibility, Visibility::Unlisted)
+            && matches_preferences(discussion, &preferences)
+    };
+
+    let Some(canister_id) = search_index_canister() else {
+        let mut matches: Vec<Discussion> = DISCUSSIONS_STORAGE.with(|storage| {
+            storage.borrow().iter().filter(decoded)
+                .map(|(_, discussion)| discussion)
+                .filter(|discussion| {
+                    let words = search::tokenize(&discussion.topic);
+                    tokens.iter().all(|token| words.contains(token)) && is_match(discussion)
+                })
+                .collect()
+        });
+        matches.reverse();
+        matches.truncate(limit as usize);
+        return Ok(matches.into_iter().map(redact_tallies).collect());
+    };
+
+    // Ask for extra IDs since some may be filtered out here
+    let (ids,): (Vec<u64>,) = ic_cdk::call(canister_id, "search_index_query", (tokens, limit.saturating_mul(2)))
+        .await
+        .map_err(|(_, message)| format!("Search index call failed: {}", message))?;
+
+    Ok(ids.into_iter()
+        .filter_map(load_discussion)
+        .filter(|discussion| is_match(discussion))
+        .take(limit as usize)
+        .map(redact_tallies)
+        .collect())
+}
+
+// Function called by the hub to replace the indexed tokens of discussions
+#[ic_cdk::update(guard = "caller_is_hub")]
+fn search_index_update(updates: Vec<(u64, Vec<String>)>) -> Result<(), String> {
+    let _meter = metrics::measure("search_index_update");
+
+    check_maintenance()?;
+
+    for (discussion_id, tokens) in updates {
+        let previous = SEARCH_DOCUMENTS.with(|documents| documents.borrow_mut().remove(&discussion_id));
+
+        SEARCH_POSTINGS.with(|postings| {
+            let mut postings = postings.borrow_mut();
+
+            for token in previous.map(|document| document.tokens).unwrap_or_default() {
+                postings.remove(&(search::token_key(&token), discussion_id));
+            }
+            for token in &tokens {
+                postings.insert((search::token_key(token), discussion_id), 0);
+            }
+        });
+
+        if !tokens.is_empty() {
+            let tokens = tokens.into_iter().map(|token| token.chars().take(search::MAX_TOKEN_LENGTH).collect()).collect();
+            SEARCH_DOCUMENTS.with(|documents| documents.borrow_mut().insert(discussion_id, SearchDocument { tokens }));
+        }
+    }
+
+    Ok(())
+}
+
+// Function called by the hub to find the newest discussions containing every token
+#[ic_cdk::query(guard = "caller_is_hub")]
+fn search_index_query(tokens: Vec<String>, limit: u32) -> Vec<u64> {
+    let postings = |token: &String| -> Vec<u64> {
+        let key = search::token_key(token);
+        SEARCH_POSTINGS.with(|postings| {
+            postings.borrow().range((key, 0)..=(key, u64::MAX)).map(|((_, id), _)| id).collect()
+        })
+    };
+
+    // Intersect starting from the rarest token
+    let mut lists: Vec<Vec<u64>> = tokens.iter().take(search::MAX_QUERY_TOKENS).map(postings).collect();
+    lists.sort_by_key(|list| list.len());
+
+    let Some((first, rest)) = lists.split_first() else {
+        return Vec::new();
+    };
+
+    first.iter().rev()
+        .filter(|id| rest.iter().all(|list| list.binary_search(id).is_ok()))
+        .take(limit.min(MAX_PAGE_SIZE * 2) as usize)
+        .copied()
+        .collect()
+}
+
+// Function to move a group into its own canister running the uploaded wasm (controllers only). The copy
+// takes several calls, so the hub must be in maintenance mode; a failed promotion can be retried and
+// reuses the canister already created for the group.
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn promote_group(group_id: u64) -> Result<GroupDirectoryEntry, String> {
+    let _meter = metrics::measure("promote_group");
+
+    if canister_role() != CanisterRole::Hub {
+        return Err("Only the hub can promote groups".to_string());
+    }
+
+    if check_maintenance().is_ok() {
+        return Err("Enable maintenance mode before promoting a group".to_string());
+    }
+
+    let group = try_get(&GROUPS_STORAGE, group_id).ok_or(ErrorCode::GroupNotFound)?;
+
+    let mut entry = match try_get(&GROUP_DIRECTORY, group_id) {
+        Some(entry) => entry,
+        None => {
+            let canister_id = spawn_canister(CanisterRole::Group { hub: ic_cdk::id(), group_id }).await?;
+            let entry = GroupDirectoryEntry {
+                group_id,
+                name: group.name.clone(),
+                canister_id,
+                promoted_at: time(),
+                migrated: false,
+            };
+            GROUP_DIRECTORY.with(|directory| directory.borrow_mut().insert(group_id, entry.clone()));
+            entry
+        }
+    };
+
+    let batches = group_import_batches(group);
+    let user_ids: Vec<u64> = batches.iter().flat_map(|batch| batch.users.iter().map(|user| user.id)).collect();
+
+    for batch in batches {
+        let (imported,): (Result<(), String>,) = ic_cdk::call(entry.canister_id, "group_import", (batch,))
+            .await
+            .map_err(|(_, message)| format!("Group canister call failed: {}", message))?;
+        imported?;
+    }
+
+    let discussion_ids: Vec<u64> = DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, discussion)| discussion.group_id == Some(group_id))
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for discussion_id in discussion_ids {
+        delete_discussion_and_votes(discussion_id);
+    }
+    remove_where(&GROUP_MEMBERS_STORAGE, |member| member.group_id == group_id);
+    remove_where(&GROUP_INVITES_STORAGE, |invite| invite.group_id == group_id);
+    remove_where(&JOIN_REQUESTS_STORAGE, |request| request.group_id == group_id);
+    GROUPS_STORAGE.with(|storage| storage.borrow_mut().remove(&group_id));
+
+    entry.migrated = true;
+    GROUP_DIRECTORY.with(|directory| directory.borrow_mut().insert(group_id, entry.clone()));
+
+    // The import only copies users and links, restrictions follow with the first sync
+    for user_id in user_ids {
+        queue_account_sync(user_id);
+    }
+
+    Ok(entry)
+}
+
+// Helper function to split everything a group canister needs into import batches: the group, its
+// members, invites, join requests and discussions with their votes, plus the accounts involved
+fn group_import_batches(group: Group) -> Vec<GroupImportBatch> {
+    let group_id = group.id;
+
+    let members: Vec<GroupMember> = GROUP_MEMBERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).filter(|(_, member)| member.group_id == group_id).map(|(_, member)| member).collect()
+    });
+    let invites: Vec<GroupInvite> = GROUP_INVITES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).filter(|(_, invite)| invite.group_id == group_id).map(|(_, invite)| invite).collect()
+    });
+    let join_requests: Vec<JoinRequest> = JOIN_REQUESTS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).filter(|(_, request)| request.group_id == group_id).map(|(_, request)| request).collect()
+    });
+    let discussions: Vec<Discussion> = DISCUSSIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).filter(|(_, discussion)| discussion.group_id == Some(group_id)).map(|(_, discussion)| discussion).collect()
+    });
+    let discussion_ids: Vec<u64> = discussions.iter().map(|discussion| discussion.id).collect();
+    let votes: Vec<Vote> = VOTES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).filter(|(_, vote)| discussion_ids.contains(&vote.discussion_id)).map(|(_, vote)| vote).collect()
+    });
+
+    let mut usernames: Vec<&String> = members.iter().map(|member| &member.username)
+        .chain(discussions.iter().map(|discussion| &discussion.created_by))
+        .chain(votes.iter().map(|vote| &vote.by))
+        .chain(std::iter::once(&group.created_by))
+        .collect();
+    usernames.sort();
+    usernames.dedup();
+
+    let users: Vec<User> = usernames.iter().filter_map(|username| find_user(username)).collect();
+    let principal_links: Vec<PrincipalLink> = PRINCIPAL_LINKS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).filter(|(_, link)| usernames.contains(&&link.username)).map(|(_, link)| link).collect()
+    });
+
+    let mut batches = vec![GroupImportBatch { groups: vec![group], ..Default::default() }];
+    batches.extend(users.chunks(GROUP_IMPORT_BATCH).map(|chunk| GroupImportBatch { users: chunk.to_vec(), ..Default::default() }));
+    batches.extend(principal_links.chunks(GROUP_IMPORT_BATCH).map(|chunk| GroupImportBatch { principal_links: chunk.to_vec(), ..Default::default() }));
+    batches.extend(members.chunks(GROUP_IMPORT_BATCH).map(|chunk| GroupImportBatch { members: chunk.to_vec(), ..Default::default() }));
+    batches.extend(invites.chunks(GROUP_IMPORT_BATCH).map(|chunk| GroupImportBatch { invites: chunk.to_vec(), ..Default::default() }));
+    batches.extend(join_requests.chunks(GROUP_IMPORT_BATCH).map(|chunk| GroupImportBatch { join_requests: chunk.to_vec(), ..Default::default() }));
+    batches.extend(discussions.chunks(GROUP_IMPORT_BATCH).map(|chunk| GroupImportBatch { discussions: chunk.to_vec(), ..Default::default() }));
+    batches.extend(votes.chunks(GROUP_IMPORT_BATCH).map(|chunk| GroupImportBatch { votes: chunk.to_vec(), ..Default::default() }));
+
+    batches
+}
+
+// Function to list the groups that moved to their own canister
+#[ic_cdk::query]
+fn get_group_directory() -> Vec<GroupDirectoryEntry> {
+    GROUP_DIRECTORY.with(|directory| directory.borrow().iter().filter(decoded).map(|(_, entry)| entry).collect())
+}
+
+// Function to find the canister a promoted group lives in
+#[ic_cdk::query]
+fn find_group_canister(group_id: u64) -> Option<Principal> {
+    try_get(&GROUP_DIRECTORY, group_id)
+        .filter(|entry| entry.migrated)
+        .map(|entry| entry.canister_id)
+}
+
+// Function called by the hub to copy one batch of a promoted group's data. Records keep their IDs, so a
+// retried batch overwrites instead of duplicating.
+#[ic_cdk::update(guard = "caller_is_hub")]
+fn group_import(batch: GroupImportBatch) -> Result<(), String> {
+    let _meter = metrics::measure("group_import");
+
+    check_maintenance()?;
+
+    let CanisterRole::Group { group_id, .. } = canister_role() else {
+        return Err("This canister does not run a group".to_string());
+    };
+
+    if batch.groups.iter().any(|group| group.id != group_id)
+        || batch.members.iter().any(|member| member.group_id != group_id)
+        || batch.discussions.iter().any(|discussion| discussion.group_id != Some(group_id))
+    {
+        return Err("Batch contains records of another group".to_string());
+    }
+
+    let mut max_id = 0;
+
+    for group in batch.groups {
+        max_id = max_id.max(group.id);
+        GROUPS_STORAGE.with(|storage| storage.borrow_mut().insert(group.id, group));
+    }
+    for user in batch.users {
+        max_id = max_id.max(user.id);
+        USERS_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, user));
+    }
+    for link in batch.principal_links {
+        max_id = max_id.max(link.id);
+        index_principal_link(&link);
+        PRINCIPAL_LINKS_STORAGE.with(|storage| storage.borrow_mut().insert(link.id, link));
+    }
+    for member in batch.members {
+        max_id = max_id.max(member.id);
+        GROUP_MEMBERS_STORAGE.with(|storage| storage.borrow_mut().insert(member.id, member));
+    }
+    for invite in batch.invites {
+        max_id = max_id.max(invite.id);
+        GROUP_INVITES_STORAGE.with(|storage| storage.borrow_mut().insert(invite.id, invite));
+    }
+    for request in batch.join_requests {
+        max_id = max_id.max(request.id);
+        JOIN_REQUESTS_STORAGE.with(|storage| storage.borrow_mut().insert(request.id, request));
+    }
+    for discussion in batch.discussions {
+        max_id = max_id.max(discussion.id);
+        track_activity(&discussion.created_by, discussion.created_at, |activity| activity.discussions_created += 1);
+        store_discussion(&discussion);
+    }
+    for vote in batch.votes {
+        max_id = max_id.max(vote.id);
+        track_activity(&vote.by, vote.created_at, |activity| activity.votes_cast += 1);
+        if let Some(discussion) = load_discussion(vote.discussion_id) {
+            track_received_vote(&discussion.created_by);
+            if !vote.expired {
+                tally_received_vote(&discussion.created_by, &vote.vote_type, true);
+            }
+        }
+        insert_vote(vote);
+    }
+
+    // New records created here must not reuse an imported ID
+    ID_COUNTER.with(|counter| {
+        let current = *counter.borrow().get();
+        if current <= max_id {
+            counter.borrow_mut().set(max_id + 1).map(|_| ())
+        } else {
+            Ok(())
+        }
+    }).map_err(|_| "Cannot update ID counter".to_string())?;
+
+    Ok(())
+}
+
+// Helper function to schedule sending an account to the group canisters. Only the hub holds the accounts,
+// and only once a group has moved out.
+fn queue_account_sync(user_id: u64) {
+    if canister_role() != CanisterRole::Hub {
+        return;
+    }
+
+    let has_groups = GROUP_DIRECTORY.with(|directory| directory.borrow().iter().filter(decoded).any(|(_, entry)| entry.migrated));
+    if has_groups {
+        ACCOUNT_SYNC_QUEUE.with(|queue| queue.borrow_mut().insert(user_id, time()));
+    }
+}
+
+// Helper function to queue the account of a username, if it exists
+fn queue_account_sync_for(username: &str) {
+    if let Some(user) = find_user(username) {
+        queue_account_sync(user.id);
+    }
+}
+
+// Helper function to start sending one batch of queued accounts to every group canister. Called from the
+// background tick; an entry requeued while its batch was in flight stays queued.
+fn push_account_updates() {
+    if ACCOUNT_SYNC_IN_FLIGHT.with(|flag| *flag.borrow()) {
+        return;
+    }
+
+    let batch: Vec<(u64, u64)> = ACCOUNT_SYNC_QUEUE.with(|queue| queue.borrow().iter().take(ACCOUNT_SYNC_BATCH).collect());
+    if batch.is_empty() {
+        return;
+    }
+
+    let canister_ids: Vec<Principal> = GROUP_DIRECTORY.with(|directory| {
+        directory.borrow().iter().filter(decoded).filter(|(_, entry)| entry.migrated).map(|(_, entry)| entry.canister_id).collect()
+    });
+
+    let mut updates: Vec<AccountSync> = batch.iter()
+        .map(|(user_id, _)| AccountSync {
+            user_id: *user_id,
+            user: try_get(&USERS_STORAGE, *user_id),
+            principal_links: Vec::new(),
+            restrictions: Vec::new(),
+        })
+        .collect();
+
+    // One pass over links and restrictions for the whole batch
+    let positions: BTreeMap<String, usize> = updates.iter().enumerate()
+        .filter_map(|(index, update)| update.user.as_ref().map(|user| (user.username.clone(), index)))
+        .collect();
+    PRINCIPAL_LINKS_STORAGE.with(|storage| {
+        for (_, link) in storage.borrow().iter().filter(decoded) {
+            if let Some(&index) = positions.get(&link.username) {
+                updates[index].principal_links.push(link);
+            }
+        }
+    });
+    RESTRICTIONS_STORAGE.with(|storage| {
+        for (_, restriction) in storage.borrow().iter().filter(decoded) {
+            if let Some(&index) = positions.get(&restriction.username) {
+                updates[index].restrictions.push(restriction);
+            }
+        }
+    });
+
+    ACCOUNT_SYNC_IN_FLIGHT.with(|flag| *flag.borrow_mut() = true);
+
+    ic_cdk::spawn(async move {
+        let mut failed = false;
+
+        for canister_id in canister_ids {
+            let result: Result<(), String> = match ic_cdk::call(canister_id, "group_sync_accounts", (updates.clone(),)).await {
+                Ok((result,)) => result,
+                Err((_, message)) => Err(message),
+            };
+
+            if let Err(message) = result {
+                logging::log(LogLevel::Error, "account_sync", format!("Account sync to {} failed: {}", canister_id, message));
+                failed = true;
+            }
+        }
+
+        // Groups that did take the batch simply get it again
+        if !failed {
+            ACCOUNT_SYNC_QUEUE.with(|queue| {
+                let mut queue = queue.borrow_mut();
+                for (user_id, queued_at) in batch {
+                    if queue.get(&user_id) == Some(queued_at) {
+                        queue.remove(&user_id);
+                    }
+                }
+            });
+        }
+
+        ACCOUNT_SYNC_IN_FLIGHT.with(|flag| *flag.borrow_mut() = false);
+    });
+}
+
+// Function called by the hub to replace this group canister's copies of changed accounts. Only accounts
+// the group already holds are updated; links and the hub's restrictions are replaced as a whole.
+#[ic_cdk::update(guard = "caller_is_hub")]
+fn group_sync_accounts(updates: Vec<AccountSync>) -> Result<(), String> {
+    let _meter = metrics::measure("group_sync_accounts");
+
+    check_maintenance()?;
+
+    if !matches!(canister_role(), CanisterRole::Group { .. }) {
+        return Err("This canister does not run a group".to_string());
+    }
+
+    // Matched by the username held here, which is the old one if the account was renamed
+    let held: Vec<(AccountSync, String)> = updates.into_iter()
+        .filter_map(|update| {
+            let user = try_get(&USERS_STORAGE, update.user_id)?;
+            Some((update, user.username))
+        })
+        .collect();
+    let usernames: BTreeSet<String> = held.iter().map(|(_, username)| username.clone()).collect();
+
+    remove_where(&PRINCIPAL_LINKS_STORAGE, |link| usernames.contains(&link.username));
+    remove_where(&RESTRICTIONS_STORAGE, |restriction| restriction.from_hub && usernames.contains(&restriction.username));
+
+    // Copies get local IDs, the hub's would collide with records created here
+    for (update, _) in held {
+        let Some(user) = update.user else {
+            USERS_STORAGE.with(|storage| storage.borrow_mut().remove(&update.user_id));
+            continue;
+        };
+
+        USERS_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, user));
+        for link in update.principal_links {
+            let link = PrincipalLink { id: next_id(), ..link };
+            index_principal_link(&link);
+            PRINCIPAL_LINKS_STORAGE.with(|storage| storage.borrow_mut().insert(link.id, link));
+        }
+        for restriction in update.restrictions {
+            let id = next_id();
+            insert_restriction(Restriction { id, from_hub: true, ..restriction });
+        }
+    }
+
+    Ok(())
+}
+
+// Function to open a proposal. Anyone registered may ask a question; attaching an action needs an admin
+// caller, and the action runs if option 0 wins.
+#[ic_cdk::update]
+async fn create_proposal(
+    title: String,
+    description: String,
+    options: Vec<String>,
+    rules: ProposalRules,
+    voting_ends_at: u64,
+    action: Option<ProposalAction>,
+    username: String,
+) -> Result<Proposal, String> {
+    let _meter = metrics::measure("create_proposal");
+
+    ensure_writable()?;
+
+    require_feature("proposals")?;
+
+    authenticate(&username)?;
+
+    check_terms_accepted(&username)?;
+
+    let title = sanitize_user_text(&title)?;
+    let description = sanitize_user_text(&description)?;
+
+    permissions::check_permission(&username, Action::PostText(&description))?;
+
+    if title.trim().is_empty() || title.chars().count() > MAX_PROPOSAL_TITLE_LENGTH {
+        return Err(format!("Title must be between 1 and {} characters", MAX_PROPOSAL_TITLE_LENGTH));
+    }
+
+    if description.chars().count() > MAX_PROPOSAL_DESCRIPTION_LENGTH {
+        return Err(format!("Description cannot exceed {} characters", MAX_PROPOSAL_DESCRIPTION_LENGTH));
+    }
+
+    if options.len() < 2 || options.len() > MAX_PROPOSAL_OPTIONS {
+        return Err(format!("A proposal needs between 2 and {} options", MAX_PROPOSAL_OPTIONS));
+    }
+
+    let options = options.iter().map(|option| sanitize_user_text(option)).collect::<Result<Vec<String>, String>>()?;
+    if options.iter().any(|option| option.trim().is_empty() || option.chars().count() > MAX_PROPOSAL_OPTION_LENGTH) {
+        return Err(format!("Options must be between 1 and {} characters", MAX_PROPOSAL_OPTION_LENGTH));
+    }
+
+    if rules.threshold_percent == 0 || rules.threshold_percent > 100 {
+        return Err("threshold_percent must be between 1 and 100".to_string());
+    }
+
+    if rules.max_write_ins.is_some_and(|max| max > MAX_PROPOSAL_WRITE_INS) {
+        return Err(format!("max_write_ins cannot exceed {}", MAX_PROPOSAL_WRITE_INS));
+    }
+
+    let now = time();
+    if voting_ends_at <= now || voting_ends_at > now.saturating_add(MAX_PROPOSAL_WINDOW) {
+        return Err("Voting must end in the future and within 30 days".to_string());
+    }
+
+    if let Some(action) = &action {
+        if !is_admin(&caller()) {
+            return Err("Only admins can attach actions to proposals".to_string());
+        }
+        // While the DAO governs, flags and settings change through execute_dao_action only
+        check_not_dao_governed()?;
+        if let ProposalAction::UpdateSettings(new_settings) = action {
+            validate_settings(new_settings)?;
+        }
+    }
+
+    let (shuffle_seed,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, message)| format!("Cannot seed the option order: {}", message))?;
+
+    ensure_writable()?;
+
+    let id = next_id();
+    let eligible_weight = snapshot_voting_power(id, &rules.weighting);
+
+    let proposal = Proposal {
+        id,
+        title,
+        description,
+        created_by: username,
+        created_at: now,
+        tallies: vec![0; options.len()],
+        options,
+        shuffle_seed,
+        rules,
+        write_ins: 0,
+        eligible_weight,
+        voting_ends_at,
+        action,
+        status: ProposalStatus::Open,
+        winning_option: None,
+        decided_at: None,
+    };
+
+    PROPOSALS_STORAGE.with(|storage| storage.borrow_mut().insert(proposal.id, proposal.clone()));
+    record_randomness(
+        id,
+        RandomnessPurpose::ProposalOptionOrder { proposal_id: id },
+        proposal.shuffle_seed.clone(),
+        OPTION_ORDER_DERIVATION,
+        Vec::new(),
+        Vec::new(),
+    );
+
+    Ok(proposal)
+}
+
+// Function to vote for one option of an open proposal, once per user
+#[ic_cdk::update]
+fn vote_proposal(proposal_id: u64, option: u32, username: String) -> Result<Proposal, String> {
+    let _meter = metrics::measure("vote_proposal");
+
+    ensure_writable()?;
+
+    require_feature("proposals")?;
+
+    authenticate(&username)?;
+
+    let proposal = try_get(&PROPOSALS_STORAGE, proposal_id).ok_or("Proposal not found")?;
+
+    if option as usize >= proposal.options.len() {
+        return Err("Option not found".to_string());
+    }
+
+    cast_proposal_vote(proposal, option, username)
+}
+
+// Function to vote for an option of the user's own wording. Text matching an existing option, ignoring case
+// and spacing, counts for that option; otherwise it is added while the proposal has write-ins left.
+#[ic_cdk::update]
+fn vote_proposal_write_in(proposal_id: u64, text: String, username: String) -> Result<Proposal, String> {
+    let _meter = metrics::measure("vote_proposal_write_in");
+
+    ensure_writable()?;
+
+    require_feature("proposals")?;
+
+    authenticate(&username)?;
+
+    let mut proposal = try_get(&PROPOSALS_STORAGE, proposal_id).ok_or("Proposal not found")?;
+
+    let max_write_ins = proposal.rules.max_write_ins.unwrap_or_default();
+    if max_write_ins == 0 {
+        return Err("This proposal does not accept write-ins".to_string());
+    }
+
+    let text = sanitize_user_text(&text)?.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.is_empty() || text.chars().count() > MAX_PROPOSAL_OPTION_LENGTH {
+        return Err(format!("Options must be between 1 and {} characters", MAX_PROPOSAL_OPTION_LENGTH));
+    }
+
+    permissions::check_permission(&username, Action::PostText(&text))?;
+
+    let key = text.to_lowercase();
+    let existing = proposal.options.iter()
+        .position(|option| option.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase() == key);
+
+    let option = match existing {
+        Some(option) => option as u32,
+        None => {
+            if proposal.write_ins >= max_write_ins {
+                return Err(format!("This proposal already has {} write-ins", max_write_ins));
+            }
+
+            proposal.options.push(text);
+            proposal.tallies.push(0);
+            proposal.write_ins += 1;
+
+            if !Encode!(&proposal).is_ok_and(|bytes| bytes.len() <= Proposal::MAX_SIZE as usize) {
+                return Err("This proposal has no room for more options".to_string());
+            }
+
+            proposal.options.len() as u32 - 1
+        }
+    };
+
+    cast_proposal_vote(proposal, option, username)
+}
+
+// Helper function to record a user's vote for an option of an open proposal and store the new tally
+fn cast_proposal_vote(mut proposal: Proposal, option: u32, username: String) -> Result<Proposal, String> {
+    let proposal_id = proposal.id;
+
+    if proposal.status != ProposalStatus::Open || proposal.voting_ends_at <= time() {
+        return Err("Voting on this proposal has closed".to_string());
+    }
+
+    let already_voted = PROPOSAL_VOTES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).any(|(_, vote)| vote.proposal_id == proposal_id && vote.username == username)
+    });
+    if already_voted {
+        return Err("User has already voted on this proposal".to_string());
+    }
+
+    let weight = voting_power(proposal_id, &username);
+    if weight == 0 {
+        return Err("User has no voting power on this proposal".to_string());
+    }
+
+    let vote = ProposalVote {
+        id: next_id(),
+        proposal_id,
+        username,
+        option,
+        weight,
+        cast_at: time(),
+    };
+
+    PROPOSAL_VOTES_STORAGE.with(|storage| storage.borrow_mut().insert(vote.id, vote));
+
+    proposal.tallies[option as usize] += weight;
+    PROPOSALS_STORAGE.with(|storage| storage.borrow_mut().insert(proposal_id, proposal.clone()));
+
+    Ok(proposal)
+}
+
+// Helper function to record every registered user's weight for a new proposal, returns the total weight
+fn snapshot_voting_power(proposal_id: u64, weighting: &VoteWeighting) -> u64 {
+    let karma = if *weighting == VoteWeighting::Reputation { karma_by_user() } else { BTreeMap::new() };
+
+    let entries: Vec<(u64, VotingPowerEntry)> = USERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .map(|(user_id, user)| {
+                let weight = match weighting {
+                    VoteWeighting::OnePerUser => 1,
+                    VoteWeighting::Reputation => karma.get(&user.username).copied().unwrap_or_default().max(0) as u64,
+                };
+                (user_id, VotingPowerEntry { proposal_id, username: user.username, weight })
+            })
+            .filter(|(_, entry)| entry.weight > 0)
+            .collect()
+    });
+
+    let total = entries.iter().map(|(_, entry)| entry.weight).sum();
+
+    for (user_id, entry) in entries {
+        let id = next_id();
+        VOTING_POWER_INDEX.with(|index| index.borrow_mut().insert((proposal_id, user_id), id));
+        VOTING_POWER_SNAPSHOTS.with(|storage| storage.borrow_mut().insert(id, entry));
+    }
+
+    total
+}
+
+// Helper function to compute every user's karma: net votes received on their discussions plus awards
+fn karma_by_user() -> BTreeMap<String, i64> {
+    let usernames: BTreeMap<u64, String> = USERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(id, user)| (id, user.username)).collect()
+    });
+
+    KARMA_TALLIES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter_map(|(id, tally)| Some((usernames.get(&id)?.clone(), tally_total(&tally))))
+            .collect()
+    })
+}
+
+// Helper function to compute one user's karma
+fn karma(username: &str) -> i64 {
+    find_user(username)
+        .and_then(|user| try_get(&KARMA_TALLIES_STORAGE, user.id))
+        .map(|tally| tally_total(&tally))
+        .unwrap_or(0)
+}
+
+// Helper function to add up the parts of a karma tally
+fn tally_total(tally: &KarmaTally) -> i64 {
+    tally.upvotes_received as i64 - tally.downvotes_received as i64
+        + tally.referrals + tally.moderation_penalties + tally.inactivity_decay + tally.other_awards
+}
+
+// Helper function to count a vote on one of the author's discussions in their karma, or take it back out
+fn tally_received_vote(author: &str, vote_type: &VoteType, counted: bool) {
+    let Some(user) = find_user(author) else {
+        return;
+    };
+
+    let mut tally = try_get(&KARMA_TALLIES_STORAGE, user.id).unwrap_or_default();
+    let count = match vote_type {
+        VoteType::Upvote => &mut tally.upvotes_received,
+        VoteType::Downvote => &mut tally.downvotes_received,
+    };
+    *count = if counted { *count + 1 } else { count.saturating_sub(1) };
+    KARMA_TALLIES_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, tally));
+}
+
+// Helper function to pick the part of a tally an award with this reason counts toward
+fn award_source<'a>(tally: &'a mut KarmaTally, reason: &str) -> &'a mut i64 {
+    match reason {
+        reason if REFERRAL_KARMA_REASONS.contains(&reason) => &mut tally.referrals,
+        WARNING_KARMA_REASON => &mut tally.moderation_penalties,
+        KARMA_DECAY_REASON => &mut tally.inactivity_decay,
+        _ => &mut tally.other_awards,
+    }
+}
+
+// Helper function to count every user's karma again from the discussions and awards held here. Runs after
+// an upgrade while no user has a tally yet, and after a restore replaces what the tallies count. Returns
+// how many users have a tally.
+fn rebuild_karma_tallies() -> u64 {
+    let user_ids: BTreeMap<String, u64> = USERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(id, user)| (user.username, id)).collect()
+    });
+    let mut tallies: BTreeMap<u64, KarmaTally> = BTreeMap::new();
+
+    DISCUSSIONS_STORAGE.with(|storage| {
+        for (_, discussion) in storage.borrow().iter().filter(decoded) {
+            if let Some(id) = user_ids.get(&discussion.created_by) {
+                let tally = tallies.entry(*id).or_default();
+                tally.upvotes_received += discussion.upvotes;
+                tally.downvotes_received += discussion.downvotes;
+            }
+        }
+    });
+    KARMA_AWARDS_STORAGE.with(|storage| {
+        for (_, award) in storage.borrow().iter().filter(decoded) {
+            if let Some(id) = user_ids.get(&award.username) {
+                let tally = tallies.entry(*id).or_default();
+                *award_source(tally, &award.reason) += award.amount;
+                if award.reason == KARMA_DECAY_REASON {
+                    tally.last_decayed_at = tally.last_decayed_at.max(award.awarded_at);
+                }
+            }
+        }
+    });
+
+    remove_where(&KARMA_TALLIES_STORAGE, |_| true);
+    KARMA_TALLIES_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for (id, tally) in tallies {
+            storage.insert(id, tally);
+        }
+        storage.len()
+    })
+}
+
+// Helper function to recompute a user's trust level, storing it when it changed
+fn refresh_trust_level(username: &str) -> TrustLevel {
+    let Some(mut user) = find_user(username) else {
+        return TrustLevel::New;
+    };
+
+    let level = permissions::compute_trust_level(&user);
+    if user.trust_level != level {
+        log_account_action(username, format!("Trust level changed from {:?} to {:?}", user.trust_level, level));
+        user.trust_level = level;
+        queue_account_sync(user.id);
+        USERS_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, user));
+    }
+
+    level
+}
+
+// Function to get the trust level a user has earned
+#[ic_cdk::query]
+fn get_trust_level(username: String) -> Result<TrustLevel, String> {
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    Ok(permissions::compute_trust_level(&user))
+}
+
+// Function to get a user's karma
+#[ic_cdk::query]
+fn get_karma(username: String) -> Result<i64, String> {
+    if !is_user_registered(&username) {
+        return Err(ErrorCode::UserNotFound.into());
+    }
+
+    Ok(karma(&username))
+}
+
+// Function to get a user's karma split by where it came from
+#[ic_cdk::query]
+fn get_karma_breakdown(username: String) -> Result<KarmaBreakdown, String> {
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    let tally = try_get(&KARMA_TALLIES_STORAGE, user.id).unwrap_or_default();
+
+    Ok(KarmaBreakdown {
+        upvotes_received: tally.upvotes_received,
+        downvotes_received: tally.downvotes_received,
+        referrals: tally.referrals,
+        moderation_penalties: tally.moderation_penalties,
+        inactivity_decay: tally.inactivity_decay,
+        other_awards: tally.other_awards,
+        total: tally_total(&tally),
+    })
+}
+
+// Function to get the karma a user lost to inactivity decay, most recent first
+#[ic_cdk::query]
+fn get_karma_decay_history(username: String) -> Result<Vec<KarmaAward>, String> {
+    if !is_user_registered(&username) {
+        return Err(ErrorCode::UserNotFound.into());
+    }
+
+    let mut history: Vec<KarmaAward> = KARMA_AWARDS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .map(|(_, award)| award)
+            .filter(|award| award.username == username && award.reason == KARMA_DECAY_REASON)
+            .collect()
+    });
+    history.sort_by_key(|award| std::cmp::Reverse(award.awarded_at));
+
+    Ok(history)
+}
+
+// Helper function to record a karma award
+fn award_karma(username: &str, amount: i64, reason: &str) {
+    let award_time = time();
+    let award = KarmaAward {
+        username: username.to_string(),
+        amount,
+        reason: reason.to_string(),
+        awarded_at: award_time,
+    };
+    KARMA_AWARDS_STORAGE.with(|storage| storage.borrow_mut().insert(next_id(), award));
+
+    if let Some(user) = find_user(username) {
+        let mut tally = try_get(&KARMA_TALLIES_STORAGE, user.id).unwrap_or_default();
+        *award_source(&mut tally, reason) += amount;
+        if reason == KARMA_DECAY_REASON {
+            tally.last_decayed_at = award_time;
+        }
+        KARMA_TALLIES_STORAGE.with(|storage| storage.borrow_mut().insert(user.id, tally));
+    }
+}
+
+// Helper function to derive a user's referral code from their account ID
+fn referral_code(user: &User) -> String {
+    use sha3::{Digest, Sha3_256};
+
+    let digest = Sha3_256::digest(format!("referral:{}", user.id).as_bytes());
+    digest[..5].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Helper function to find whose referral code this is, rejecting referrers too new to refer
+fn check_referral_code(code: &str) -> Result<String, String> {
+    let referrer = USERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, user)| user).find(|user| referral_code(user) == code.to_lowercase())
+    }).ok_or("Referral code not found")?;
+
+    if referrer.created_at.saturating_add(REFERRER_MIN_ACCOUNT_AGE) > time() {
+        return Err("This referral code is not active yet".to_string());
+    }
+
+    // A principal can't earn a referral for its own second account
+    if linked_principals(&referrer.username).contains(&caller()) || caller() == Principal::anonymous() {
+        return Err("Referral codes need a signed-in caller other than the referrer".to_string());
+    }
+
+    Ok(referrer.username)
+}
+
+// Helper function to credit a user's pending referral once they are active enough. Called after votes and new discussions.
+fn credit_referral(referee: &str) {
+    let Some(mut referral) = REFERRALS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .map(|(_, referral)| referral)
+            .find(|referral| referral.referee == referee && referral.status == ReferralStatus::Pending)
+    }) else {
+        return;
+    };
+
+    let activity = find_user(referee).and_then(|user| try_get(&USER_ACTIVITY_STORAGE, user.id)).unwrap_or_default();
+    if activity.votes_cast < REFERRAL_MIN_VOTES || activity.discussions_created < REFERRAL_MIN_DISCUSSIONS {
+        return;
+    }
+
+    let credited = REFERRALS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, other)| other.referrer == referral.referrer && matches!(other.status, ReferralStatus::Credited { .. }))
+            .count() as u64
+    });
+
+    referral.status = if !is_user_registered(&referral.referrer) {
+        ReferralStatus::Declined("Referrer no longer exists".to_string())
+    } else if credited >= MAX_CREDITED_REFERRALS {
+        ReferralStatus::Declined("Referrer reached the referral cap".to_string())
+    } else {
+        award_karma(&referral.referrer, REFERRAL_KARMA, REFERRAL_KARMA_REASONS[0]);
+        award_karma(referee, REFERRAL_KARMA, REFERRAL_KARMA_REASONS[1]);
+        ReferralStatus::Credited { at: time() }
+    };
+
+    REFERRALS_STORAGE.with(|storage| storage.borrow_mut().insert(referral.id, referral));
+}
+
+// Function to get a user's referral code and how their referrals are doing
+#[ic_cdk::query]
+fn get_referral_stats(username: String) -> Result<ReferralStats, String> {
+    authenticate(&username)?;
+
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+
+    let mut stats = ReferralStats {
+        code: referral_code(&user),
+        pending: 0,
+        credited: 0,
+        declined: 0,
+        karma_earned: 0,
+    };
+
+    REFERRALS_STORAGE.with(|storage| {
+        for (_, referral) in storage.borrow().iter().filter(decoded).filter(|(_, referral)| referral.referrer == username) {
+            match referral.status {
+                ReferralStatus::Pending => stats.pending += 1,
+                ReferralStatus::Credited { .. } => stats.credited += 1,
+                ReferralStatus::Declined(_) => stats.declined += 1,
+            }
+        }
+    });
+
+    stats.karma_earned = KARMA_AWARDS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, award)| award.username == username && award.reason == "referral")
+            .map(|(_, award)| award.amount)
+            .sum()
+    });
+
+    Ok(stats)
+}
+
+// Helper function to look up a user's snapshotted weight on a proposal
+fn voting_power(proposal_id: u64, username: &str) -> u64 {
+    let Some(user) = find_user(username) else {
+        return 0;
+    };
+
+    VOTING_POWER_INDEX.with(|index| index.borrow().get(&(proposal_id, user.id)))
+        .and_then(|id| try_get(&VOTING_POWER_SNAPSHOTS, id))
+        .filter(|entry| entry.proposal_id == proposal_id && entry.username == username)
+        .map(|entry| entry.weight)
+        .unwrap_or_default()
+}
+
+// Helper function to rebuild VOTING_POWER_INDEX from the snapshots. Entries of users since deleted or
+// renamed are left out, as voting_power never matched them.
+fn rebuild_voting_power_index() {
+    let user_ids: BTreeMap<String, u64> = USERS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(id, user)| (user.username, id)).collect()
+    });
+    let entries: Vec<((u64, u64), u64)> = VOTING_POWER_SNAPSHOTS.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter_map(|(id, entry)| user_ids.get(&entry.username).map(|user_id| ((entry.proposal_id, *user_id), id)))
+            .collect()
+    });
+
+    VOTING_POWER_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let keys: Vec<(u64, u64)> = index.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index.remove(&key);
+        }
+        for (key, id) in entries {
+            index.insert(key, id);
+        }
+    });
+}
+
+// Function to get a user's voting power on a proposal
+#[ic_cdk::query]
+fn get_my_voting_power(proposal_id: u64, username: String) -> Result<u64, String> {
+    authenticate(&username)?;
+
+    Ok(voting_power(proposal_id, &username))
+}
+
+// Function to get a proposal
+#[ic_cdk::query]
+fn get_proposal(proposal_id: u64) -> Result<Proposal, String> {
+    try_get(&PROPOSALS_STORAGE, proposal_id).ok_or_else(|| "Proposal not found".to_string())
+}
+
+// Function to get a proposal with its options in the order shown to this voter, to spread position bias
+// across voters. The order is the same on every call and write-ins slot in without moving other options.
+#[ic_cdk::query]
+fn get_proposal_ballot(proposal_id: u64, username: String) -> Result<ProposalBallot, String> {
+    authenticate(&username)?;
+
+    let user = find_user(&username).ok_or(ErrorCode::UserNotFound)?;
+    let proposal = get_proposal(proposal_id)?;
+
+    Ok(ProposalBallot {
+        option_order: shuffled_option_order(&proposal, user.id),
+        proposal,
+    })
+}
+
+// Helper function to order a proposal's options for one voter by SHA3(seed || user ID || option index)
+fn shuffled_option_order(proposal: &Proposal, user_id: u64) -> Vec<u32> {
+    use sha3::{Digest, Sha3_256};
+
+    let mut order: Vec<u32> = (0..proposal.options.len() as u32).collect();
+    order.sort_by_cached_key(|option| {
+        Sha3_256::new()
+            .chain_update(&proposal.shuffle_seed)
+            .chain_update(user_id.to_be_bytes())
+            .chain_update(option.to_be_bytes())
+            .finalize()
+    });
+
+    order
+}
+
+// Function to page through proposals in creation order
+#[ic_cdk::query]
+fn get_proposals_page(cursor: Option<String>, limit: Option<u32>) -> Result<Page<Proposal>, String> {
+    paginate(&PROPOSALS_STORAGE, cursor, limit, |_, _| true)
+}
+
+// Helper function to pick the option that wins under a proposal's rules, if any. A tie for first place passes nothing.
+fn proposal_winner(proposal: &Proposal) -> Option<u32> {
+    let total: u64 = proposal.tallies.iter().sum();
+    if total == 0 || total < proposal.rules.quorum {
+        return None;
+    }
+
+    let top = *proposal.tallies.iter().max()?;
+    if proposal.tallies.iter().filter(|tally| **tally == top).count() > 1 {
+        return None;
+    }
+
+    if top * 100 < total * proposal.rules.threshold_percent as u64 {
+        return None;
+    }
+
+    proposal.tallies.iter().position(|tally| *tally == top).map(|index| index as u32)
+}
+
+// Helper function to decide proposals whose voting window ended and run the actions of those that passed
+fn finalize_proposals(now: u64) {
+    let ended: Vec<Proposal> = PROPOSALS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .filter(|(_, proposal)| proposal.status == ProposalStatus::Open && proposal.voting_ends_at <= now)
+            .map(|(_, proposal)| proposal)
+            .collect()
+    });
+
+    for mut proposal in ended {
+        proposal.winning_option = proposal_winner(&proposal);
+        proposal.decided_at = Some(now);
+        proposal.status = match (proposal.winning_option, &proposal.action) {
+            (None, _) => ProposalStatus::Rejected,
+            (Some(0), Some(action)) => match execute_proposal_action(action.clone()) {
+                Ok(_) => ProposalStatus::Executed,
+                Err(error) => ProposalStatus::ExecutionFailed(error),
+            },
+            (Some(_), Some(_)) => ProposalStatus::Rejected,
+            (Some(_), None) => ProposalStatus::Passed,
+        };
+
+        outbox::publish(EventKind::ProposalDecided { proposal_id: proposal.id });
+        PROPOSALS_STORAGE.with(|storage| storage.borrow_mut().insert(proposal.id, proposal));
+    }
+}
+
+// Helper function to apply the action of a passed proposal; settings stay with the DAO when one governs
+fn execute_proposal_action(action: ProposalAction) -> Result<String, String> {
+    match action {
+        ProposalAction::SetFeatureFlag { name, enabled } => {
+            check_not_dao_governed()?;
+            store_feature_flag(name, enabled)
+        }
+        ProposalAction::UpdateSettings(new_settings) => {
+            check_not_dao_governed()?;
+            validate_settings(&new_settings)?;
+            SETTINGS.with(|settings| settings.borrow_mut().set(*new_settings))
+                .map_err(|_| "Cannot store settings".to_string())?;
+            Ok("Settings updated".to_string())
+        }
+    }
+}
+
+// Function to attach a survey to a discussion, one per discussion (only by the discussion's creator)
+#[ic_cdk::update]
+fn create_survey(discussion_id: u64, questions: Vec<SurveyQuestion>, closes_at: Option<u64>, username: String) -> Result<Survey, String> {
+    let _meter = metrics::measure("create_survey");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    get_owned_discussion(discussion_id, &username)?;
+
+    if survey_of_discussion(discussion_id).is_some() {
+        return Err("The discussion already has a survey".to_string());
+    }
+
+    if questions.is_empty() || questions.len() > MAX_SURVEY_QUESTIONS {
+        return Err(format!("A survey needs between 1 and {} questions", MAX_SURVEY_QUESTIONS));
+    }
+
+    let questions = questions.into_iter().map(validate_survey_question).collect::<Result<Vec<_>, String>>()?;
+
+    let text: Vec<&str> = questions.iter().map(|question| question.prompt.as_str()).collect();
+    permissions::check_permission(&username, Action::PostText(&text.join("\n")))?;
+
+    let now = time();
+    if closes_at.is_some_and(|closes_at| closes_at <= now) {
+        return Err("A survey must close in the future".to_string());
+    }
+
+    let survey = Survey {
+        id: next_id(),
+        discussion_id,
+        created_by: username,
+        created_at: now,
+        questions,
+        closes_at,
+    };
+
+    index_discussion_record(survey.discussion_id, StoredMap::Surveys, survey.id);
+    SURVEYS_STORAGE.with(|storage| storage.borrow_mut().insert(survey.id, survey.clone()));
+
+    Ok(survey)
+}
+
+// Helper function to sanitize a survey question and check its limits
+fn validate_survey_question(mut question: SurveyQuestion) -> Result<SurveyQuestion, String> {
+    question.prompt = sanitize_user_text(&question.prompt)?;
+    if question.prompt.trim().is_empty() || question.prompt.chars().count() > MAX_SURVEY_PROMPT_LENGTH {
+        return Err(format!("Questions must be between 1 and {} characters", MAX_SURVEY_PROMPT_LENGTH));
+    }
+
+    match &mut question.kind {
+        QuestionKind::SingleChoice { options } | QuestionKind::MultiChoice { options } => {
+            if options.len() < 2 || options.len() > MAX_SURVEY_OPTIONS {
+                return Err(format!("A choice question needs between 2 and {} options", MAX_SURVEY_OPTIONS));
+            }
+
+            *options = options.iter().map(|option| sanitize_user_text(option)).collect::<Result<Vec<String>, String>>()?;
+            if options.iter().any(|option| option.trim().is_empty() || option.chars().count() > MAX_SURVEY_OPTION_LENGTH) {
+                return Err(format!("Options must be between 1 and {} characters", MAX_SURVEY_OPTION_LENGTH));
+            }
+        }
+        QuestionKind::Rating { min, max } => {
+            if min >= max || *max > 10 {
+                return Err("A rating scale needs min below max and max of at most 10".to_string());
+            }
+        }
+    }
+
+    Ok(question)
+}
+
+// Function to save answers to a survey. Unanswered questions are None; with `submit` every question must be
+// answered and the response becomes final.
+#[ic_cdk::update]
+fn save_survey_response(survey_id: u64, answers: Vec<Option<SurveyAnswer>>, submit: bool, username: String) -> Result<SurveyResponse, String> {
+    let _meter = metrics::measure("save_survey_response");
+
+    ensure_writable()?;
+
+    authenticate(&username)?;
+
+    let survey = get_visible_survey(survey_id, &username)?;
+
+    if load_discussion(survey.discussion_id).is_some_and(|discussion| discussion.locked) {
+        return Err("The discussion is locked".to_string());
+    }
+
+    if survey.closes_at.is_some_and(|closes_at| time() >= closes_at) {
+        return Err("The survey has closed".to_string());
+    }
+
+    if answers.len() != survey.questions.len() {
+        return Err(format!("Expected {} answers, one per question", survey.questions.len()));
+    }
+
+    for (index, (question, answer)) in survey.questions.iter().zip(&answers).enumerate() {
+        let valid = match (&question.kind, answer) {
+            (_, None) => !submit,
+            (QuestionKind::SingleChoice { options }, Some(SurveyAnswer::Choice(choice))) => (*choice as usize) < options.len(),
+            (QuestionKind::MultiChoice { options }, Some(SurveyAnswer::Choices(choices))) => {
+                !choices.is_empty()
+                    && choices.iter().all(|choice| (*choice as usize) < options.len())
+                    && choices.iter().enumerate().all(|(i, choice)| !choices[..i].contains(choice))
+            }
+            (QuestionKind::Rating { min, max }, Some(SurveyAnswer::Rating(rating))) => (*min..=*max).contains(rating),
+            _ => false,
+        };
+
+        if !valid {
+            return Err(format!("Answer {} does not fit its question", index + 1));
+        }
+    }
+
+    let existing = SURVEY_RESPONSES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, response)| response).find(|response| response.survey_id == survey_id && response.username == username)
+    });
+
+    if existing.as_ref().is_some_and(|response| response.submitted) {
+        return Err("User has already submitted this survey".to_string());
+    }
+
+    let response = SurveyResponse {
+        id: existing.map(|response| response.id).unwrap_or_else(next_id),
+        survey_id,
+        username,
+        answers,
+        submitted: submit,
+        updated_at: time(),
+    };
+
+    index_discussion_record(survey.discussion_id, StoredMap::SurveyResponses, response.id);
+    SURVEY_RESPONSES_STORAGE.with(|storage| storage.borrow_mut().insert(response.id, response.clone()));
+
+    Ok(response)
+}
+
+// Function to get the survey of a discussion together with the user's saved response, if any
+#[ic_cdk::query]
+fn get_survey(discussion_id: u64, username: String) -> Result<(Survey, Option<SurveyResponse>), String> {
+    let survey = survey_of_discussion(discussion_id).ok_or("The discussion has no survey")?;
+    let username = resolve_viewer(username);
+
+    let survey = get_visible_survey(survey.id, &username)?;
+
+    let response = SURVEY_RESPONSES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, response)| response).find(|response| response.survey_id == survey.id && response.username == username)
+    });
+
+    Ok((survey, response))
+}
+
+// Function to get the per-question results of a survey, counting submitted responses only
+#[ic_cdk::query]
+fn get_survey_results(survey_id: u64, username: String) -> Result<SurveyResults, String> {
+    let survey = get_visible_survey(survey_id, &resolve_viewer(username))?;
+
+    let responses: Vec<SurveyResponse> = SURVEY_RESPONSES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .map(|(_, response)| response)
+            .filter(|response| response.survey_id == survey_id && response.submitted)
+            .collect()
+    });
+
+    let questions = survey.questions.iter().enumerate().map(|(index, question)| {
+        let answers = responses.iter().filter_map(|response| response.answers.get(index).cloned().flatten());
+
+        match &question.kind {
+            QuestionKind::SingleChoice { options } | QuestionKind::MultiChoice { options } => {
+                let mut counts = vec![0; options.len()];
+                for answer in answers {
+                    let choices = match answer {
+                        SurveyAnswer::Choice(choice) => vec![choice],
+                        SurveyAnswer::Choices(choices) => choices,
+                        SurveyAnswer::Rating(_) => Vec::new(),
+                    };
+                    for choice in choices {
+                        if let Some(count) = counts.get_mut(choice as usize) {
+                            *count += 1;
+                        }
+                    }
+                }
+                QuestionResults { prompt: question.prompt.clone(), counts, average: None }
+            }
+            QuestionKind::Rating { min, max } => {
+                let ratings: Vec<u8> = answers.filter_map(|answer| match answer {
+                    SurveyAnswer::Rating(rating) => Some(rating),
+                    _ => None,
+                }).collect();
+                let counts = (*min..=*max).map(|value| ratings.iter().filter(|rating| **rating == value).count() as u64).collect();
+                let average = (!ratings.is_empty())
+                    .then(|| ratings.iter().map(|rating| *rating as f64).sum::<f64>() / ratings.len() as f64);
+                QuestionResults { prompt: question.prompt.clone(), counts, average }
+            }
+        }
+    }).collect();
+
+    Ok(SurveyResults { survey, responses: responses.len() as u64, questions })
+}
+
+// Helper function to find the survey attached to a discussion
+fn survey_of_discussion(discussion_id: u64) -> Option<Survey> {
+    discussion_record_keys(discussion_id, StoredMap::Surveys).into_iter()
+        .filter_map(|survey_id| try_get(&SURVEYS_STORAGE, survey_id))
+        .find(|survey| survey.discussion_id == discussion_id)
+}
+
+// Helper function to load a survey whose discussion the user can access
+fn get_visible_survey(survey_id: u64, username: &str) -> Result<Survey, String> {
+    let survey = try_get(&SURVEYS_STORAGE, survey_id).ok_or("Survey not found")?;
+
+    let discussion = load_discussion(survey.discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+    if !can_access_discussion(&discussion, username) {
+        return Err("Survey not found".to_string());
+    }
+
+    Ok(survey)
+}
+
+// Function to draw `winners` random voters of a discussion, e.g. for an engagement campaign (admins only).
+// The seed and a digest of the entrants are kept so the drawing can be checked later.
+#[ic_cdk::update(guard = "caller_is_admin")]
+async fn run_raffle(discussion_id: u64, winners: u32) -> Result<Raffle, String> {
+    let _meter = metrics::measure("run_raffle");
+
+    ensure_writable()?;
+
+    load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if winners == 0 || winners > MAX_RAFFLE_WINNERS {
+        return Err(format!("A raffle draws between 1 and {} winners", MAX_RAFFLE_WINNERS));
+    }
+
+    let (seed,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, message)| format!("Cannot draw the raffle: {}", message))?;
+
+    // Entrants are read after the await, so the drawing uses the votes as they are once the seed is known
+    ensure_writable()?;
+    load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+    let entrants = raffle_entrants(discussion_id);
+    if entrants.len() < winners as usize {
+        return Err(format!("The discussion has {} voters, fewer than {} winners", entrants.len(), winners));
+    }
+
+    let id = next_id();
+
+    let raffle = Raffle {
+        id,
+        discussion_id,
+        winners: draw_raffle(&seed, entrants.clone(), winners as usize),
+        entrant_count: entrants.len() as u64,
+        entrants_digest: {
+            use sha3::{Digest, Sha3_256};
+            Sha3_256::digest(entrants.join("\n").as_bytes()).to_vec()
+        },
+        seed,
+        drawn_by: caller(),
+        drawn_at: time(),
+    };
+
+    index_discussion_record(raffle.discussion_id, StoredMap::Raffles, id);
+    RAFFLES_STORAGE.with(|storage| storage.borrow_mut().insert(id, raffle.clone()));
+    record_randomness(
+        id,
+        RandomnessPurpose::Raffle { discussion_id },
+        raffle.seed.clone(),
+        RAFFLE_DERIVATION,
+        raffle.entrants_digest.clone(),
+        raffle.winners.clone(),
+    );
+    for winner in &raffle.winners {
+        notify(winner, NotificationKind::RaffleWon { raffle_id: id, discussion_id });
+    }
+    logging::log(LogLevel::Info, "run_raffle", format!("Raffle {} drew {} of {} voters of discussion {}", id, winners, entrants.len(), discussion_id));
+
+    Ok(raffle)
+}
+
+// Helper function to list the distinct users with a counted vote on a discussion, sorted by username
+fn raffle_entrants(discussion_id: u64) -> Vec<String> {
+    let mut entrants: Vec<String> = discussion_votes(discussion_id).into_iter()
+        .filter(|vote| !vote.expired)
+        .map(|vote| vote.by)
+        .collect();
+
+    entrants.sort();
+    entrants.dedup();
+    entrants
+}
+
+// Helper function to pick `count` entrants with a partial Fisher-Yates shuffle driven by the seed, as
+// described by RAFFLE_DERIVATION
+fn draw_raffle(seed: &[u8], mut entrants: Vec<String>, count: usize) -> Vec<String> {
+    use sha3::{Digest, Sha3_256};
+
+    for i in 0..count.min(entrants.len()) {
+        let digest = Sha3_256::new().chain_update(seed).chain_update((i as u64).to_be_bytes()).finalize();
+        let n = u64::from_be_bytes(digest[..8].try_into().unwrap());
+        let j = i + (n % (entrants.len() - i) as u64) as usize;
+        entrants.swap(i, j);
+    }
+
+    entrants.truncate(count);
+    entrants
+}
+
+// Helper function to keep the record of a random selection for get_randomness_audit
+fn record_randomness(
+    event_id: u64,
+    purpose: RandomnessPurpose,
+    seed: Vec<u8>,
+    derivation: &str,
+    inputs_digest: Vec<u8>,
+    outputs: Vec<String>,
+) {
+    let audit = RandomnessAudit {
+        event_id,
+        purpose,
+        seed,
+        derivation: derivation.to_string(),
+        inputs_digest,
+        outputs,
+        recorded_at: time(),
+    };
+
+    if let RandomnessPurpose::Raffle { discussion_id } = audit.purpose {
+        index_discussion_record(discussion_id, StoredMap::RandomnessAudit, event_id);
+    }
+    RANDOMNESS_AUDIT_STORAGE.with(|storage| storage.borrow_mut().insert(event_id, audit));
+}
+
+// Function to get the seed and derivation behind a random selection, by the ID of the raffle or proposal
+// it was made for. Raffles on discussions the caller can't see are not shown.
+#[ic_cdk::query]
+fn get_randomness_audit(event_id: u64) -> Result<RandomnessAudit, String> {
+    let audit = try_get(&RANDOMNESS_AUDIT_STORAGE, event_id)
+        .ok_or("Randomness record not found")?;
+
+    if let RandomnessPurpose::Raffle { discussion_id } = audit.purpose {
+        let viewer = linked_username(&caller()).unwrap_or_default();
+        if !load_discussion(discussion_id).is_some_and(|discussion| can_access_discussion(&discussion, &viewer)) {
+            return Err("Randomness record not found".to_string());
+        }
+    }
+
+    Ok(audit)
+}
+
+// Function to get the raffles drawn among a discussion's voters, oldest first
+#[ic_cdk::query]
+fn get_raffles(discussion_id: u64, username: String) -> Result<Vec<Raffle>, String> {
+    let viewer = resolve_viewer(username);
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if !can_access_discussion(&discussion, &viewer) {
+        return Err(ErrorCode::DiscussionNotFound.into());
+    }
+
+    Ok(RAFFLES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded)
+            .map(|(_, raffle)| raffle)
+            .filter(|raffle| raffle.discussion_id == discussion_id)
+            .collect()
+    }))
+}
+
+// Function to get a discussion's final tally signed with the canister's threshold ECDSA key, so third parties
+// can check the result off-chain. The tally no longer changes once final, so it is signed once and kept.
+// Calls made while the signature is being produced are turned away rather than signing again.
+#[ic_cdk::update]
+async fn attest_results(discussion_id: u64) -> Result<ResultAttestation, String> {
+    let _meter = metrics::measure("attest_results");
+
+    ensure_writable()?;
+
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+    let viewer = linked_username(&caller()).unwrap_or_default();
+    if !can_access_discussion(&discussion, &viewer) {
+        return Err(ErrorCode::DiscussionNotFound.into());
+    }
+
+    if let Some(attestation) = try_get(&ATTESTATIONS_STORAGE, discussion_id) {
+        return Ok(attestation);
+    }
+
+    let closed_at = results_final_at(&discussion).filter(|_| discussion.results_final)
+        .ok_or("The results of this discussion are not final yet")?;
+
+    let canister_id = ic_cdk::id();
+    let mut message = RESULTS_ATTESTATION_TAG.to_vec();
+    message.push(canister_id.as_slice().len() as u8);
+    message.extend_from_slice(canister_id.as_slice());
+    for value in [discussion_id, discussion.upvotes, discussion.downvotes, closed_at] {
+        message.extend_from_slice(&value.to_be_bytes());
+    }
+
+    if !ATTESTATIONS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(discussion_id)) {
+        return Err("The results of this discussion are being signed, try again shortly".to_string());
+    }
+
+    let signature = signing::sign(&settings().signing_key_name, RESULTS_ATTESTATION_TAG, &message).await;
+    ATTESTATIONS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&discussion_id));
+    let signature = signature?;
+
+    let attestation = ResultAttestation {
+        discussion_id,
+        upvotes: discussion.upvotes,
+        downvotes: discussion.downvotes,
+        closed_at,
+        message,
+        signature,
+        attested_at: time(),
+    };
+
+    // The discussion may have been deleted while signing
+    if load_discussion(discussion_id).is_some() {
+        ATTESTATIONS_STORAGE.with(|storage| storage.borrow_mut().insert(discussion_id, attestation.clone()));
+    }
+
+    Ok(attestation)
+}
+
+// Function to get the signed result of a discussion, once attest_results has produced it
+#[ic_cdk::query]
+fn get_results_attestation(discussion_id: u64, username: String) -> Result<ResultAttestation, String> {
+    let viewer = resolve_viewer(username);
+    let discussion = load_discussion(discussion_id).ok_or(ErrorCode::DiscussionNotFound)?;
+
+    if !can_access_discussion(&discussion, &viewer) {
+        return Err(ErrorCode::DiscussionNotFound.into());
+    }
+
+    try_get(&ATTESTATIONS_STORAGE, discussion_id)
+        .ok_or_else(|| "The results of this discussion have not been attested".to_string())
+}
+
+// Helper function to store a new vote, index it under its discussion and have its vote root recomputed
+fn insert_vote(vote: Vote) {
+    mark_vote_root_stale(vote.discussion_id);
+    VOTES_BY_DISCUSSION.with(|index| index.borrow_mut().insert((vote.discussion_id, vote.id), 0));
+    VOTES_STORAGE.with(|storage| storage.borrow_mut().insert(vote.id, vote));
+}
+
+// Helper function to read a discussion's votes in vote ID order through the index. Entries whose vote is
+// gone or no longer decodes are skipped; the next rebuild of the vote roots drops them.
+fn discussion_votes(discussion_id: u64) -> Vec<Vote> {
+    let vote_ids: Vec<u64> = VOTES_BY_DISCUSSION.with(|index| {
+        index.borrow().range((discussion_id, 0)..=(discussion_id, u64::MAX)).map(|((_, vote_id), _)| vote_id).collect()
+    });
+
+    VOTES_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        vote_ids.into_iter()
+            .filter_map(|vote_id| storage.get(&vote_id).filter(decoded))
+            .filter(|vote| vote.discussion_id == discussion_id)
+            .collect()
+    })
+}
+
+// Helper function to have the next background tick recompute a discussion's vote root
+fn mark_vote_root_stale(discussion_id: u64) {
+    VOTE_ROOTS_STALE.with(|stale| stale.borrow_mut().insert(discussion_id));
+}
+
+// Helper function to have the next background tick rebuild every vote root, after changes touching many discussions
+fn mark_all_vote_roots_stale() {
+    VOTE_ROOTS_REBUILD.with(|rebuild| *rebuild.borrow_mut() = true);
+}
+
+// Helper function to hash a vote into its leaf, as described on VoteProof
+fn vote_leaf(vote: &Vote) -> merkle::Hash {
+    let mut data = Vec::with_capacity(26 + vote.by.len());
+    data.extend_from_slice(&vote.id.to_be_bytes());
+    data.extend_from_slice(&vote.discussion_id.to_be_bytes());
+    data.extend_from_slice(&vote.created_at.to_be_bytes());
+    data.push(matches!(vote.vote_type, VoteType::Downvote) as u8);
+    data.push(vote.expired as u8);
+    data.extend_from_slice(vote.by.as_bytes());
+
+    merkle::hash_leaf(&data)
+}
+
+// Helper function to list the leaves of the certified tree, one per discussion with votes
+fn discussion_root_leaves() -> Vec<(u64, merkle::Hash)> {
+    VOTE_ROOTS.with(|roots| {
+        roots.borrow().iter()
+            .map(|(discussion_id, root)| {
+                let mut data = discussion_id.to_be_bytes().to_vec();
+                data.extend_from_slice(root);
+                (*discussion_id, merkle::hash_leaf(&data))
+            })
+            .collect()
+    })
+}
+
+// Helper function run by the background tick to recompute the vote roots that changed, then certify the new
+// root over all discussions. Stale discussions read only their own votes through the index; a full rebuild
+// scans every vote once and brings the index back in line with them.
+fn refresh_vote_roots() {
+    let rebuild = VOTE_ROOTS_REBUILD.with(|rebuild| rebuild.replace(false));
+    let stale = VOTE_ROOTS_STALE.with(|stale| std::mem::take(&mut *stale.borrow_mut()));
+    if !rebuild && stale.is_empty() {
+        return;
+    }
+
+    let mut leaves: BTreeMap<u64, Vec<merkle::Hash>> = BTreeMap::new();
+    if rebuild {
+        // Votes are iterated by ID, so each discussion's leaves come out in vote ID order
+        let mut keys: BTreeSet<(u64, u64)> = BTreeSet::new();
+        VOTES_STORAGE.with(|storage| {
+            for (id, vote) in storage.borrow().iter().filter(decoded) {
+                keys.insert((vote.discussion_id, id));
+                leaves.entry(vote.discussion_id).or_default().push(vote_leaf(&vote));
+            }
+        });
+        reindex_votes(keys);
+    } else {
+        for discussion_id in &stale {
+            let votes = discussion_votes(*discussion_id);
+            if !votes.is_empty() {
+                leaves.insert(*discussion_id, votes.iter().map(vote_leaf).collect());
+            }
+        }
+    }
+
+    VOTE_ROOTS.with(|roots| {
+        let mut roots = roots.borrow_mut();
+        if rebuild {
+            roots.clear();
+        }
+        for discussion_id in &stale {
+            roots.remove(discussion_id);
+        }
+        for (discussion_id, leaves) in leaves {
+            roots.insert(discussion_id, merkle::root(&leaves));
+        }
+    });
+
+    let top: Vec<merkle::Hash> = discussion_root_leaves().into_iter().map(|(_, leaf)| leaf).collect();
+    ic_cdk::api::set_certified_data(&merkle::root(&top));
+}
+
+// Helper function to make the vote index hold exactly `keys`, writing only the entries that differ
+fn reindex_votes(keys: BTreeSet<(u64, u64)>) {
+    VOTES_BY_DISCUSSION.with(|index| {
+        let mut index = index.borrow_mut();
+        let dangling: Vec<(u64, u64)> = index.iter().map(|(key, _)| key).filter(|key| !keys.contains(key)).collect();
+        for key in dangling {
+            index.remove(&key);
+        }
+        for key in keys {
+            if !index.contains_key(&key) {
+                index.insert(key, 0);
+            }
+        }
+    });
+}
+
+// Function to prove that a vote is counted in the certified vote tree, so voters can check their ballot
+// independently. Follows the same visibility as the voter's vote history.
+#[ic_cdk::query]
+fn prove_vote(vote_id: u64, username: String) -> Result<VoteProof, String> {
+    let viewer = resolve_viewer(username);
+    let vote = try_get(&VOTES_STORAGE, vote_id).ok_or("Vote not found")?;
+
+    if check_vote_history_access(&vote.by, &viewer).is_err() || !is_visible_vote(&vote, &vote.by, &viewer) {
+        return Err("Vote not found".to_string());
+    }
+
+    let pending = VOTE_ROOTS_REBUILD.with(|rebuild| *rebuild.borrow())
+        || VOTE_ROOTS_STALE.with(|stale| stale.borrow().contains(&vote.discussion_id));
+    let discussion_root = VOTE_ROOTS.with(|roots| roots.borrow().get(&vote.discussion_id).copied());
+    let Some(discussion_root) = discussion_root.filter(|_| !pending) else {
+        return Err("The vote tree is being updated, try again in a few seconds".to_string());
+    };
+
+    let votes = discussion_votes(vote.discussion_id);
+    let leaves: Vec<merkle::Hash> = votes.iter().map(vote_leaf).collect();
+    let index = votes.iter().position(|other| other.id == vote_id).ok_or("Vote not found")?;
+
+    let top = discussion_root_leaves();
+    let discussion_index = top.iter().position(|(discussion_id, _)| *discussion_id == vote.discussion_id)
+        .ok_or("Vote not found")?;
+    let top: Vec<merkle::Hash> = top.into_iter().map(|(_, leaf)| leaf).collect();
+
+    Ok(VoteProof {
+        vote_path: merkle::path(&leaves, index),
+        discussion_root: discussion_root.to_vec(),
+        discussion_path: merkle::path(&top, discussion_index),
+        certified_root: merkle::root(&top).to_vec(),
+        certificate: ic_cdk::api::data_certificate(),
+        vote,
+    })
+}
+
+// Function to subscribe a webhook or canister to forum events (admins only). `events` takes names from
+// outbox::EVENT_NAMES, empty for every event.
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn add_event_subscription(target: SubscriberTarget, events: Vec<String>) -> Result<Subscription, String> {
+    let _meter = metrics::measure("add_event_subscription");
+
+    ensure_writable()?;
+
+    match &target {
+        SubscriberTarget::Webhook { url } if !url.starts_with("https://") || url.len() > 300 => {
+            return Err("Webhook URLs must use https and be at most 300 characters".to_string());
+        }
+        SubscriberTarget::Canister { method, .. } if method.is_empty() || method.len() > 100 => {
+            return Err("Method names must be between 1 and 100 characters".to_string());
+        }
+        _ => {}
+    }
+
+    if let Some(unknown) = events.iter().find(|name| !outbox::EVENT_NAMES.contains(&name.as_str())) {
+        return Err(format!("Unknown event {}, expected one of {}", unknown, outbox::EVENT_NAMES.join(", ")));
+    }
+
+    let subscription = Subscription {
+        id: next_id(),
+        target,
+        events,
+        created_at: time(),
+    };
+
+    EVENT_SUBSCRIPTIONS_STORAGE.with(|storage| storage.borrow_mut().insert(subscription.id, subscription.clone()));
+
+    Ok(subscription)
+}
+
+// Function to remove an event subscription and drop its undelivered events (admins only)
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn remove_event_subscription(subscription_id: u64) -> Result<String, String> {
+    let _meter = metrics::measure("remove_event_subscription");
+
+    ensure_writable()?;
+
+    EVENT_SUBSCRIPTIONS_STORAGE.with(|storage| storage.borrow_mut().remove(&subscription_id)).ok_or("Subscription not found")?;
+
+    remove_where(&OUTBOX_STORAGE, |entry| entry.subscription_id == subscription_id);
+    remove_where(&DEAD_LETTERS_STORAGE, |entry| entry.subscription_id == subscription_id);
+
+    Ok("Subscription removed".to_string())
+}
+
+// Function to list the event subscriptions (admins only)
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_event_subscriptions() -> Vec<Subscription> {
+    EVENT_SUBSCRIPTIONS_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).map(|(_, subscription)| subscription).collect())
+}
+
+// Function to page through the events that could not be delivered (admins only)
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_dead_letters(cursor: Option<String>, limit: Option<u32>) -> Result<Page<OutboxEntry>, String> {
+    paginate(&DEAD_LETTERS_STORAGE, cursor, limit, |_, _| true)
+}
+
+// Function to put a dead letter back in the outbox with a fresh set of attempts (admins only)
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn retry_dead_letter(entry_id: u64) -> Result<String, String> {
+    let _meter = metrics::measure("retry_dead_letter");
+
+    ensure_writable()?;
+
+    let mut entry = DEAD_LETTERS_STORAGE.with(|storage| storage.borrow_mut().remove(&entry_id)).ok_or("Dead letter not found")?;
+
+    entry.attempts = 0;
+    entry.next_attempt_at = time();
+    outbox::enqueue(entry);
+
+    Ok("Event queued for delivery".to_string())
+}
+
+// Function to drop a dead letter for good (admins only)
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn discard_dead_letter(entry_id: u64) -> Result<String, String> {
+    let _meter = metrics::measure("discard_dead_letter");
+
+    ensure_writable()?;
+
+    DEAD_LETTERS_STORAGE.with(|storage| storage.borrow_mut().remove(&entry_id)).ok_or("Dead letter not found")?;
+
+    Ok("Dead letter discarded".to_string())
+}
+
+// Function used by webhook outcalls to keep only the status, so replicas agree on the response
+#[ic_cdk::query]
+fn transform_webhook_response(raw: ic_cdk::api::management_canister::http_request::TransformArgs) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    ic_cdk::api::management_canister::http_request::HttpResponse {
+        status: raw.response.status,
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+// Function to define a discussion that is started automatically on a schedule (admins only)
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn create_recurrence(topic_template: String, author: String, group_id: Option<u64>, schedule: Schedule, lock_previous: bool) -> Result<Recurrence, String> {
+    let _meter = metrics::measure("create_recurrence");
+
+    ensure_writable()?;
+
+    let now = time();
+    validate_topic(&render_topic_template(&topic_template, now))?;
+
+    find_user(&author).ok_or(ErrorCode::UserNotFound)?;
+
+    if let Some(group_id) = group_id {
+        try_get(&GROUPS_STORAGE, group_id).ok_or(ErrorCode::GroupNotFound)?;
+    }
+
+    let valid = match schedule {
+        Schedule::Daily { hour, minute } => hour < 24 && minute < 60,
+        Schedule::Weekly { weekday, hour, minute } => weekday < 7 && hour < 24 && minute < 60,
+    };
+    if !valid {
+        return Err("Schedules take a weekday from 0 to 6, an hour from 0 to 23 and a minute from 0 to 59".to_string());
+    }
+
+    let recurrence = Recurrence {
+        id: next_id(),
+        topic_template,
+        author,
+        group_id,
+        next_run_at: next_occurrence(&schedule, now),
+        schedule,
+        lock_previous,
+        last_discussion_id: None,
+        created_at: now,
+    };
+
+    RECURRENCES_STORAGE.with(|storage| storage.borrow_mut().insert(recurrence.id, recurrence.clone()));
+
+    Ok(recurrence)
+}
+
+// Function to stop a recurrence; discussions it already started stay (admins only)
+#[ic_cdk::update(guard = "caller_is_admin")]
+fn delete_recurrence(recurrence_id: u64) -> Result<String, String> {
+    let _meter = metrics::measure("delete_recurrence");
+
+    ensure_writable()?;
+
+    RECURRENCES_STORAGE.with(|storage| storage.borrow_mut().remove(&recurrence_id)).ok_or("Recurrence not found")?;
+
+    Ok("Recurrence deleted".to_string())
+}
+
+// Function to list the recurrences (admins only)
+#[ic_cdk::query(guard = "caller_is_admin")]
+fn get_recurrences() -> Vec<Recurrence> {
+    RECURRENCES_STORAGE.with(|storage| storage.borrow().iter().filter(decoded).map(|(_, recurrence)| recurrence).collect())
+}
+
+// Helper function run by the background tick to start the discussions of recurrences that are due. Runs missed
+// while the canister was stopped are not made up, the next one is scheduled from now.
+fn run_recurrences(now: u64) {
+    let due: Vec<Recurrence> = RECURRENCES_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, recurrence)| recurrence).filter(|recurrence| recurrence.next_run_at <= now).collect()
+    });
+
+    for mut recurrence in due {
+        recurrence.next_run_at = next_occurrence(&recurrence.schedule, now);
+
+        let instance = validate_topic(&render_topic_template(&recurrence.topic_template, now))
+            .map(|topic| discussion_record(topic, recurrence.author.clone(), recurrence.group_id))
+            .and_then(publish_discussion);
+
+        match instance {
+            Ok(discussion) => {
+                if recurrence.lock_previous {
+                    if let Some(mut previous) = recurrence.last_discussion_id.and_then(load_discussion) {
+                        previous.locked = true;
+                        previous.version += 1;
+                        save_discussion(previous.id, previous);
+                    }
+                }
+                recurrence.last_discussion_id = Some(discussion.id);
+            }
+            Err(message) => logging::log(LogLevel::Warn, "recurrence", format!("Recurrence {} did not start a discussion: {}", recurrence.id, message)),
+        }
+
+        RECURRENCES_STORAGE.with(|storage| storage.borrow_mut().insert(recurrence.id, recurrence));
+    }
+}
+
+// Helper function to find the first time after `after` a schedule runs
+fn next_occurrence(schedule: &Schedule, after: u64) -> u64 {
+    const DAY: u64 = 86_400_000_000_000;
+
+    let (weekday, hour, minute) = match *schedule {
+        Schedule::Daily { hour, minute } => (None, hour, minute),
+        Schedule::Weekly { weekday, hour, minute } => (Some(weekday as u64), hour, minute),
+    };
+    let time_of_day = (hour as u64 * 60 + minute as u64) * 60_000_000_000;
+
+    // 1970-01-01 was a Thursday, day 3 counting from Monday
+    let mut day = after / DAY;
+    loop {
+        let candidate = day * DAY + time_of_day;
+        if candidate > after && weekday.is_none_or(|weekday| (day + 3) % 7 == weekday) {
+            return candidate;
+        }
+        day += 1;
+    }
+}
+
+// Helper function to fill in the date of a recurrence instance
+fn render_topic_template(template: &str, at: u64) -> String {
+    // Days since 1970-01-01 to a civil date (Howard Hinnant's algorithm)
+    let days = (at / 86_400_000_000_000) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    template.replace("{date}", &format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+// Entries moved per background tick out of a retired map
+const RETIRED_MOVE_BATCH: usize = 200;
+
+// A stored value kept as its bytes, to read a map without decoding it. N must be the bound the map was
+// created with, which fixes the layout of its nodes.
+struct RawValue<const N: u32>(Vec<u8>);
+
+impl<const N: u32> Storable for RawValue<N> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RawValue(bytes.into_owned())
+    }
+}
+
+impl<const N: u32> BoundedStorable for RawValue<N> {
+    const MAX_SIZE: u32 = N;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Helper function to read the value bound of the map stored in a MemoryId, None if it holds no map
+fn stored_value_bound(memory_id: u8) -> Option<u32> {
+    use ic_stable_structures::Memory as _;
+
+    let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id)));
+    if memory.size() == 0 {
+        return None;
+    }
+
+    // The map header: "BTR", layout version, max key size, max value size
+    let mut header = [0u8; 12];
+    memory.read(0, &mut header);
+    (&header[0..3] == b"BTR").then(|| u32::from_le_bytes([header[8], header[9], header[10], header[11]]))
+}
+
+// Helper function to load the map retired to `memory_id`. N must be the bound it was created with.
+fn retired_map<const N: u32>(memory_id: u8) -> StableBTreeMap<u64, RawValue<N>, Memory> {
+    StableBTreeMap::load(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id))))
+}
+
+// Helper function to move up to RETIRED_MOVE_BATCH entries of the map retired to `memory_id` into `storage`,
+// decoding each in the current shape. An entry that doesn't decode is quarantined instead of being moved as
+// a placeholder. Returns how many were moved, 0 once the retired map is empty.
+fn move_retired_batch<const N: u32, V: StoredRecord>(
+    memory_id: u8,
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+) -> u64 {
+    let mut retired = retired_map::<N>(memory_id);
+    let batch: Vec<(u64, RawValue<N>)> = retired.iter().take(RETIRED_MOVE_BATCH).collect();
+
+    for (id, value) in &batch {
+        let record = V::from_bytes(Cow::Borrowed(&value.0));
+        match take_decode_failure() {
+            Some(failure) => store_quarantined(V::MAP, *id, failure),
+            None => {
+                storage.with(|storage| storage.borrow_mut().insert(*id, record));
+            }
+        }
+    }
+    for (id, _) in &batch {
+        retired.remove(id);
+    }
+
+    batch.len() as u64
+}
+
+// Helper function to check whether the maps discussions and groups were first stored in still hold entries. A map
+// can't raise its bound, and both types outgrew theirs: discussions were created at 512 bytes and groups at 1024.
+fn retired_maps_hold_entries() -> bool {
+    (stored_value_bound(2) == Some(512) && !retired_map::<512>(2).is_empty())
+        || (stored_value_bound(5) == Some(1024) && !retired_map::<1024>(5).is_empty())
+}
+
+// Helper function to move one batch of discussions and groups out of their retired maps, while an upgrade left
+// entries in them. Returns how many were moved, 0 once every retired map is empty.
+fn move_retired_maps() -> u64 {
+    if !RETIRED_MAPS_PENDING.with(|pending| *pending.borrow()) {
+        return 0;
+    }
+
+    let mut moved = 0;
+    if stored_value_bound(2) == Some(512) {
+        moved += move_retired_batch::<512, _>(2, &DISCUSSIONS_STORAGE);
+    }
+    if stored_value_bound(5) == Some(1024) {
+        moved += move_retired_batch::<1024, _>(5, &GROUPS_STORAGE);
+    }
+
+    if moved == 0 {
+        RETIRED_MAPS_PENDING.with(|pending| *pending.borrow_mut() = false);
+        finish_upgrade();
+    }
+    moved
+}
+
+// Helper function to rewrite the records of one batch that are stored in an older shape, so they decode
+// without schema::decode filling them in. A record that doesn't decode at all is quarantined rather than
+// rewritten as a placeholder. Returns how many were rewritten and where the next batch starts.
+fn rewrite_outdated<const N: u32, V: StoredRecord + candid::CandidType + serde::de::DeserializeOwned>(
+    memory_id: u8,
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+    cursor: u64,
+    upgrade: impl Fn(&mut V),
+) -> (u64, Option<u64>) {
+    let raw: StableBTreeMap<u64, RawValue<N>, Memory> =
+        StableBTreeMap::load(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id))));
+
+    let mut outdated = Vec::new();
+    let mut undecodable = Vec::new();
+    let mut next = None;
+    for (scanned, (id, value)) in raw.range(cursor..).enumerate() {
+        if scanned == SCHEMA_REWRITE_BATCH {
+            next = Some(id);
+            break;
+        }
+        if !schema::is_current::<V>(&value.0) {
+            let mut record = V::from_bytes(Cow::Owned(value.0));
+            // Checked before upgrade, which may decode other records
+            if let Some(failure) = take_decode_failure() {
+                undecodable.push((id, failure));
+                continue;
+            }
+            upgrade(&mut record);
+            outdated.push((id, record));
+        }
+    }
+
+    let rewritten = outdated.len() as u64;
+    storage.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for (id, record) in outdated {
+            storage.insert(id, record);
+        }
+        for (id, _) in &undecodable {
+            storage.remove(id);
+        }
+    });
+    // Removing decodes the old values
+    take_decode_failure();
+    for (id, failure) in undecodable {
+        store_quarantined(V::MAP, id, failure);
+    }
+
+    (rewritten, next)
+}
+
+// Helper function to give members stored before roles existed their role: the group's creator owns it
+fn assign_legacy_role(member: &mut GroupMember) {
+    let created_by = GROUPS_STORAGE.with(|storage| storage.borrow().get(&member.group_id).filter(decoded).map(|group| group.created_by));
+    if created_by.as_deref() == Some(member.username.as_str()) {
+        member.role = GroupRole::Owner;
+    }
+}
+
+// Runs one batch of rewrite_outdated over the map at `index` of the rewrite order
+macro_rules! rewrite_map {
+    ($index:expr, $cursor:expr) => {
+        match $index {
+            0 => Some(rewrite_outdated::<{ User::MAX_SIZE }, _>(1, &USERS_STORAGE, $cursor, |_| ())),
+            1 => Some(rewrite_outdated::<{ Discussion::MAX_SIZE }, _>(91, &DISCUSSIONS_STORAGE, $cursor, |_| ())),
+            2 => Some(rewrite_outdated::<{ Vote::MAX_SIZE }, _>(4, &VOTES_STORAGE, $cursor, |_| ())),
+            3 => Some(rewrite_outdated::<{ Group::MAX_SIZE }, _>(92, &GROUPS_STORAGE, $cursor, |_| ())),
+            4 => Some(rewrite_outdated::<{ GroupMember::MAX_SIZE }, _>(6, &GROUP_MEMBERS_STORAGE, $cursor, assign_legacy_role)),
+            5 => Some(rewrite_outdated::<{ TrashedDiscussion::MAX_SIZE }, _>(72, &TRASH_STORAGE, $cursor, |_| ())),
+            6 => Some(rewrite_outdated::<{ Restriction::MAX_SIZE }, _>(74, &RESTRICTIONS_STORAGE, $cursor, |_| ())),
+            7 => Some(rewrite_outdated::<{ Proposal::MAX_SIZE }, _>(53, &PROPOSALS_STORAGE, $cursor, |_| ())),
+            8 => Some(rewrite_outdated::<{ UserActivity::MAX_SIZE }, _>(87, &USER_ACTIVITY_STORAGE, $cursor, |_| ())),
+            9 => Some(rewrite_outdated::<{ ContentPreferences::MAX_SIZE }, _>(66, &CONTENT_PREFERENCES_STORAGE, $cursor, |_| ())),
+            _ => None,
+        }
+    };
+}
+
+// Helper function to rewrite one batch of records stored in an older shape, moving on to the next map once
+// one is done. Runs from the background tick until every map is current.
+fn rewrite_outdated_records() {
+    let Some((index, cursor)) = SCHEMA_REWRITE_CURSOR.with(|cursor| *cursor.borrow()) else {
+        return;
+    };
+
+    let next = match rewrite_map!(index, cursor) {
+        Some((rewritten, next)) => {
+            if rewritten > 0 {
+                logging::log(LogLevel::Info, "schema", format!("Rewrote {} records of map {} in the current shape", rewritten, index));
+            }
+            Some(next.map_or((index + 1, 0), |cursor| (index, cursor)))
+        }
+        None => None,
+    };
+
+    SCHEMA_REWRITE_CURSOR.with(|cursor| *cursor.borrow_mut() = next);
 }
 
 ic_cdk::export_candid!();
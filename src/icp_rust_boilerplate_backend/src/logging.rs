@@ -0,0 +1,88 @@
+use std::borrow::Cow;
+
+use candid::{CandidType, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+
+use crate::{decode_stored, decoded, StoredMap, StoredRecord, LOG_SEQUENCE, LOG_STORAGE};
+
+// Entries kept in the ring buffer; the oldest is overwritten once it is full
+pub(crate) const LOG_CAPACITY: u64 = 4096;
+
+// Longer messages are cut so every entry fits its slot
+const MAX_LOG_MESSAGE_LENGTH: usize = 400;
+
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd)]
+pub(crate) enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LogEntry {
+    // Increases by one per entry, gaps mean entries were overwritten
+    sequence: u64,
+    level: LogLevel,
+    timestamp: u64,
+    endpoint: String,
+    message: String,
+}
+
+impl Storable for LogEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_stored(bytes.as_ref())
+    }
+}
+
+impl BoundedStorable for LogEntry {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl StoredRecord for LogEntry {
+    const MAP: StoredMap = StoredMap::Log;
+}
+
+// Helper function to append an entry to the stable ring buffer and echo it to the replica log.
+// Entries written during query calls are dropped with the rest of the query's state.
+pub(crate) fn log(level: LogLevel, endpoint: &str, message: impl Into<String>) {
+    let message: String = message.into().chars().take(MAX_LOG_MESSAGE_LENGTH).collect();
+    ic_cdk::println!("[{}] {}", endpoint, message);
+
+    let sequence = LOG_SEQUENCE.with(|cell| {
+        let sequence = *cell.borrow().get();
+        cell.borrow_mut().set(sequence + 1).expect("Cannot advance log sequence");
+        sequence
+    });
+
+    let entry = LogEntry {
+        sequence,
+        level,
+        timestamp: time(),
+        endpoint: endpoint.chars().take(64).collect(),
+        message,
+    };
+
+    LOG_STORAGE.with(|storage| storage.borrow_mut().insert(sequence % LOG_CAPACITY, entry));
+}
+
+// Helper function to read entries at or after `since` and at least as severe as `min_level`, oldest first
+pub(crate) fn entries(since: u64, min_level: LogLevel, limit: usize) -> Vec<LogEntry> {
+    let mut entries: Vec<LogEntry> = LOG_STORAGE.with(|storage| {
+        storage.borrow().iter()
+            .filter(decoded)
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.timestamp >= since && entry.level >= min_level)
+            .collect()
+    });
+
+    entries.sort_by_key(|entry| entry.sequence);
+    entries.truncate(limit);
+    entries
+}
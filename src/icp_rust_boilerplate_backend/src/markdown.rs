@@ -0,0 +1,85 @@
+// Link destinations kept in normalized Markdown; any other link is reduced to its text
+const ALLOWED_LINK_SCHEMES: &[&str] = &["https://", "http://", "mailto:"];
+
+// Helper function to produce the canonical Markdown stored next to raw user text, so every frontend
+// renders the same thing: unified line endings and list markers, no trailing spaces or runs of blank
+// lines, raw HTML escaped, and links restricted to safe schemes
+pub(crate) fn normalize_markdown(text: &str) -> String {
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut blank_run = 0;
+
+    for line in text.lines() {
+        let line = line.trim_end();
+
+        if line.is_empty() {
+            blank_run += 1;
+            if blank_run > 1 || lines.is_empty() {
+                continue;
+            }
+        } else {
+            blank_run = 0;
+        }
+
+        lines.push(normalize_line(line));
+    }
+
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+
+    lines.join("\n")
+}
+
+fn normalize_line(line: &str) -> String {
+    let indent = &line[..line.len() - line.trim_start().len()];
+    let content = line.trim_start();
+
+    // "* item" and "+ item" become "- item"
+    let content = match content.strip_prefix("* ").or_else(|| content.strip_prefix("+ ")) {
+        Some(item) => format!("- {}", item),
+        None => content.to_string(),
+    };
+
+    // A leading ">" is a blockquote marker, every other angle bracket is escaped so no HTML survives
+    let (quote, rest) = match content.find(|c: char| c != '>' && c != ' ') {
+        Some(index) if content.starts_with('>') => content.split_at(index),
+        _ => ("", content.as_str()),
+    };
+
+    let escaped = rest.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+
+    format!("{}{}{}", indent, quote, strip_unsafe_links(&escaped))
+}
+
+// Helper function to replace [text](destination) links whose destination is not allowed with their text
+fn strip_unsafe_links(line: &str) -> String {
+    let mut output = String::with_capacity(line.len());
+    let mut rest = line;
+
+    while let Some(open) = rest.find('[') {
+        let Some(close) = rest[open..].find("](").map(|index| open + index) else {
+            break;
+        };
+        let Some(end) = rest[close + 2..].find(')').map(|index| close + 2 + index) else {
+            break;
+        };
+
+        let label = &rest[open + 1..close];
+        let destination = rest[close + 2..end].trim();
+        let allowed = ALLOWED_LINK_SCHEMES.iter()
+            .any(|scheme| destination.to_ascii_lowercase().starts_with(scheme));
+
+        output.push_str(&rest[..open]);
+        if allowed {
+            output.push_str(&rest[open..=end]);
+        } else {
+            output.push_str(label);
+        }
+        rest = &rest[end + 1..];
+    }
+
+    output.push_str(rest);
+    output
+}
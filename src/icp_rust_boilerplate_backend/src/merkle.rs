@@ -0,0 +1,65 @@
+use candid::CandidType;
+use k256::sha2::{Digest, Sha256};
+
+pub(crate) type Hash = [u8; 32];
+
+// One level of an inclusion proof: hash the running value with `sibling`, on the side it is given
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct MerkleStep {
+    pub(crate) sibling: Vec<u8>,
+    pub(crate) sibling_on_left: bool,
+}
+
+// Helper function to hash a leaf as SHA-256(0x00 || data), so a leaf can never pass for an inner node
+pub(crate) fn hash_leaf(data: &[u8]) -> Hash {
+    Sha256::new().chain_update([0u8]).chain_update(data).finalize().into()
+}
+
+// Inner nodes are SHA-256(0x01 || left || right)
+fn hash_node(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new().chain_update([1u8]).chain_update(left).chain_update(right).finalize().into()
+}
+
+// Helper function to compute the root over leaves in order. Levels pair neighbours; an odd last node moves up
+// unchanged. The root of no leaves is SHA-256 of nothing.
+pub(crate) fn root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return Sha256::digest([]).into();
+    }
+
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+
+    level[0]
+}
+
+// Helper function to list the siblings from the leaf at `index` up to the root, skipping the levels where
+// the node moves up unpaired
+pub(crate) fn path(leaves: &[Hash], mut index: usize) -> Vec<MerkleStep> {
+    let mut steps = Vec::new();
+    let mut level = leaves.to_vec();
+
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            steps.push(MerkleStep { sibling: level[sibling].to_vec(), sibling_on_left: sibling < index });
+        }
+
+        level = next_level(&level);
+        index /= 2;
+    }
+
+    steps
+}
+
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    level.chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
@@ -0,0 +1,99 @@
+use std::borrow::Cow;
+
+use candid::{CandidType, Encode};
+use ic_cdk::api::performance_counter;
+use ic_stable_structures::{BoundedStorable, Storable};
+
+use crate::{decode_stored, decoded, StoredMap, StoredRecord, ENDPOINT_METRICS_STORAGE};
+
+// Upper bounds of the instruction histogram buckets; a last bucket counts everything above
+const BUCKET_BOUNDS: [u64; 5] = [1_000_000, 10_000_000, 100_000_000, 1_000_000_000, 10_000_000_000];
+
+// Instructions used by one endpoint across the calls that completed
+#[derive(CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct EndpointMetrics {
+    endpoint: String,
+    calls: u64,
+    total_instructions: u64,
+    max_instructions: u64,
+    // Calls per bucket: up to 1M, 10M, 100M, 1B, 10B instructions, then above
+    histogram: Vec<u64>,
+}
+
+impl Storable for EndpointMetrics {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_stored(bytes.as_ref())
+    }
+}
+
+impl BoundedStorable for EndpointMetrics {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl StoredRecord for EndpointMetrics {
+    const MAP: StoredMap = StoredMap::EndpointMetrics;
+}
+
+// Records the instructions an entrypoint used when it goes out of scope, whichever way the call returns
+pub(crate) struct InstructionMeter {
+    endpoint: &'static str,
+    start: u64,
+}
+
+// Helper function to start measuring an entrypoint; keep the meter alive until the call ends.
+// Only update calls keep their measurements, a query's writes are discarded with its state.
+// The call context counter keeps counting across awaits, so an async endpoint is measured as a whole
+// rather than from its last await.
+pub(crate) fn measure(endpoint: &'static str) -> InstructionMeter {
+    InstructionMeter { endpoint, start: performance_counter(1) }
+}
+
+impl Drop for InstructionMeter {
+    fn drop(&mut self) {
+        record(self.endpoint, performance_counter(1).saturating_sub(self.start));
+    }
+}
+
+fn record(endpoint: &str, instructions: u64) {
+    let key = endpoint_key(endpoint);
+
+    ENDPOINT_METRICS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        // Counts that no longer decode start over
+        let mut metrics = storage.get(&key).filter(decoded).unwrap_or_else(|| EndpointMetrics {
+            endpoint: endpoint.to_string(),
+            histogram: vec![0; BUCKET_BOUNDS.len() + 1],
+            ..Default::default()
+        });
+
+        let bucket = BUCKET_BOUNDS.iter().position(|bound| instructions <= *bound).unwrap_or(BUCKET_BOUNDS.len());
+        metrics.histogram[bucket] += 1;
+        metrics.calls += 1;
+        metrics.total_instructions = metrics.total_instructions.saturating_add(instructions);
+        metrics.max_instructions = metrics.max_instructions.max(instructions);
+
+        storage.insert(key, metrics);
+    });
+}
+
+// Helper function to list the measured endpoints, heaviest single call first
+pub(crate) fn endpoint_metrics() -> Vec<EndpointMetrics> {
+    let mut metrics: Vec<EndpointMetrics> = ENDPOINT_METRICS_STORAGE.with(|storage| {
+        storage.borrow().iter().filter(decoded).map(|(_, metrics)| metrics).collect()
+    });
+
+    metrics.sort_by_key(|metrics| std::cmp::Reverse(metrics.max_instructions));
+    metrics
+}
+
+fn endpoint_key(endpoint: &str) -> u64 {
+    use sha3::{Digest, Sha3_256};
+
+    let digest = Sha3_256::digest(endpoint.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
@@ -0,0 +1,274 @@
+use std::borrow::Cow;
+
+use candid::{CandidType, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+
+use crate::logging::{self, LogLevel};
+use crate::{decode_stored, decoded, next_id, try_get, StoredMap, StoredRecord, DEAD_LETTERS_STORAGE, EVENT_SUBSCRIPTIONS_STORAGE, OUTBOX_DUE_INDEX, OUTBOX_IN_FLIGHT, OUTBOX_STORAGE};
+
+// Entries delivered per dispatcher run, one at a time
+const DISPATCH_BATCH: usize = 10;
+
+// A failed delivery is retried after 30 seconds, doubling up to 6 hours, and moved to the dead letters
+// once MAX_ATTEMPTS deliveries have failed
+const BASE_BACKOFF: u64 = 30 * 1_000_000_000;
+const MAX_BACKOFF: u64 = 6 * 60 * 60 * 1_000_000_000;
+const MAX_ATTEMPTS: u32 = 8;
+
+const WEBHOOK_OUTCALL_CYCLES: u128 = 2_000_000_000;
+const WEBHOOK_MAX_RESPONSE_BYTES: u64 = 2048;
+
+// Names subscriptions use to pick the events they receive
+pub(crate) const EVENT_NAMES: &[&str] = &["discussion_created", "discussion_edited", "discussion_deleted", "vote_cast", "proposal_decided"];
+
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum EventKind {
+    DiscussionCreated { discussion_id: u64 },
+    DiscussionEdited { discussion_id: u64, version: u64 },
+    DiscussionDeleted { discussion_id: u64 },
+    VoteCast { discussion_id: u64, upvote: bool },
+    ProposalDecided { proposal_id: u64 },
+}
+
+impl EventKind {
+    fn name(&self) -> &'static str {
+        match self {
+            EventKind::DiscussionCreated { .. } => "discussion_created",
+            EventKind::DiscussionEdited { .. } => "discussion_edited",
+            EventKind::DiscussionDeleted { .. } => "discussion_deleted",
+            EventKind::VoteCast { .. } => "vote_cast",
+            EventKind::ProposalDecided { .. } => "proposal_decided",
+        }
+    }
+}
+
+// What a subscriber receives. Delivery is at least once and `id` stays the same across retries, so
+// receivers drop events they have already seen.
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Event {
+    id: u64,
+    kind: EventKind,
+    occurred_at: u64,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum SubscriberTarget {
+    // Receives each event as a JSON POST with the event ID in an Idempotency-Key header
+    Webhook { url: String },
+    // Receives a call to `method` with the Event as its only argument
+    Canister { canister_id: Principal, method: String },
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Subscription {
+    pub(crate) id: u64,
+    pub(crate) target: SubscriberTarget,
+    // Names from EVENT_NAMES to deliver, empty for every event
+    pub(crate) events: Vec<String>,
+    pub(crate) created_at: u64,
+}
+
+// One event waiting to be delivered to one subscription
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct OutboxEntry {
+    pub(crate) id: u64,
+    pub(crate) subscription_id: u64,
+    event: Event,
+    pub(crate) attempts: u32,
+    pub(crate) next_attempt_at: u64,
+    last_error: Option<String>,
+}
+
+impl Storable for Subscription {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_stored(bytes.as_ref())
+    }
+}
+
+impl BoundedStorable for Subscription {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl StoredRecord for Subscription {
+    const MAP: StoredMap = StoredMap::EventSubscriptions;
+}
+
+impl Storable for OutboxEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_stored(bytes.as_ref())
+    }
+}
+
+impl BoundedStorable for OutboxEntry {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Helper function to queue an event for every subscription that wants it. Called in the same update as the
+// state change, so the event is stored exactly when the change is.
+pub(crate) fn publish(kind: EventKind) {
+    let subscriptions: Vec<u64> = EVENT_SUBSCRIPTIONS_STORAGE.with(|storage| {
+        storage.borrow().iter()
+            .filter(decoded)
+            .filter(|(_, subscription)| subscription.events.is_empty() || subscription.events.iter().any(|name| name == kind.name()))
+            .map(|(id, _)| id)
+            .collect()
+    });
+    if subscriptions.is_empty() {
+        return;
+    }
+
+    let now = time();
+    let event = Event { id: next_id(), kind, occurred_at: now };
+
+    for subscription_id in subscriptions {
+        enqueue(OutboxEntry {
+            id: next_id(),
+            subscription_id,
+            event: event.clone(),
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+        });
+    }
+}
+
+// Helper function to store an entry and index it by when it is due
+pub(crate) fn enqueue(entry: OutboxEntry) {
+    OUTBOX_DUE_INDEX.with(|index| index.borrow_mut().insert((entry.next_attempt_at, entry.id), 0));
+    OUTBOX_STORAGE.with(|outbox| outbox.borrow_mut().insert(entry.id, entry));
+}
+
+// Helper function to take an entry out of the outbox and its due index
+fn dequeue(id: u64) -> Option<OutboxEntry> {
+    let entry = OUTBOX_STORAGE.with(|outbox| outbox.borrow_mut().remove(&id))?;
+    OUTBOX_DUE_INDEX.with(|index| index.borrow_mut().remove(&(entry.next_attempt_at, id)));
+    Some(entry)
+}
+
+// Helper function to index every entry in the outbox again, after an upgrade or a bulk change of the map
+pub(crate) fn rebuild_due_index() {
+    let keys: Vec<(u64, u64)> = OUTBOX_STORAGE.with(|outbox| {
+        outbox.borrow().iter()
+            .filter(decoded)
+            .map(|(id, entry)| (entry.next_attempt_at, id))
+            .collect()
+    });
+
+    OUTBOX_DUE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let stale: Vec<(u64, u64)> = index.iter().map(|(key, _)| key).collect();
+        for key in stale {
+            index.remove(&key);
+        }
+        for key in keys {
+            index.insert(key, 0);
+        }
+    });
+}
+
+// Helper function run by the background tick to deliver the entries that are due, unless a run is still going
+pub(crate) fn dispatch(now: u64) {
+    if OUTBOX_IN_FLIGHT.with(|in_flight| *in_flight.borrow()) {
+        return;
+    }
+
+    let keys: Vec<(u64, u64)> = OUTBOX_DUE_INDEX.with(|index| {
+        index.borrow().range(..=(now, u64::MAX)).take(DISPATCH_BATCH).map(|(key, _)| key).collect()
+    });
+
+    let mut due = Vec::new();
+    for (next_attempt_at, id) in keys {
+        match OUTBOX_STORAGE.with(|outbox| outbox.borrow().get(&id)).filter(decoded) {
+            Some(entry) if entry.next_attempt_at == next_attempt_at => due.push(entry),
+            // Left behind by an entry that was removed or rescheduled without going through dequeue
+            _ => {
+                OUTBOX_DUE_INDEX.with(|index| index.borrow_mut().remove(&(next_attempt_at, id)));
+            }
+        }
+    }
+    if due.is_empty() {
+        return;
+    }
+
+    OUTBOX_IN_FLIGHT.with(|in_flight| *in_flight.borrow_mut() = true);
+
+    ic_cdk::spawn(async move {
+        for entry in due {
+            let result = deliver(&entry).await;
+            settle(entry, result);
+        }
+
+        OUTBOX_IN_FLIGHT.with(|in_flight| *in_flight.borrow_mut() = false);
+    });
+}
+
+async fn deliver(entry: &OutboxEntry) -> Result<(), String> {
+    let subscription = try_get(&EVENT_SUBSCRIPTIONS_STORAGE, entry.subscription_id).ok_or("Subscription was removed")?;
+
+    match subscription.target {
+        SubscriberTarget::Webhook { url } => post_webhook(&url, &entry.event).await,
+        SubscriberTarget::Canister { canister_id, method } => ic_cdk::call(canister_id, &method, (entry.event.clone(),))
+            .await
+            .map_err(|(code, message)| format!("Call rejected ({:?}): {}", code, message)),
+    }
+}
+
+async fn post_webhook(url: &str, event: &Event) -> Result<(), String> {
+    use ic_cdk::api::management_canister::http_request::{http_request as outcall, CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformContext};
+
+    let body = serde_json::to_vec(event).map_err(|e| format!("Cannot encode event: {}", e))?;
+
+    let request = CanisterHttpRequestArgument {
+        url: url.to_string(),
+        max_response_bytes: Some(WEBHOOK_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader { name: "Content-Type".to_string(), value: "application/json".to_string() },
+            HttpHeader { name: "Idempotency-Key".to_string(), value: event.id.to_string() },
+        ],
+        body: Some(body),
+        transform: Some(TransformContext::from_name("transform_webhook_response".to_string(), vec![])),
+    };
+
+    let (response,) = outcall(request, WEBHOOK_OUTCALL_CYCLES)
+        .await
+        .map_err(|(_, message)| format!("Outcall failed: {}", message))?;
+
+    match u64::try_from(&response.status.0) {
+        Ok(200..=299) => Ok(()),
+        _ => Err(format!("Webhook returned status {}", response.status)),
+    }
+}
+
+// Helper function to drop a delivered entry, or schedule the next attempt of a failed one
+fn settle(mut entry: OutboxEntry, result: Result<(), String>) {
+    dequeue(entry.id);
+
+    let Err(error) = result else {
+        return;
+    };
+
+    entry.attempts += 1;
+    entry.last_error = Some(error.chars().take(200).collect());
+
+    if entry.attempts >= MAX_ATTEMPTS {
+        logging::log(LogLevel::Warn, "outbox", format!("Event {} to subscription {} moved to the dead letters: {}", entry.event.id, entry.subscription_id, error));
+        DEAD_LETTERS_STORAGE.with(|storage| storage.borrow_mut().insert(entry.id, entry));
+        return;
+    }
+
+    let backoff = BASE_BACKOFF.saturating_mul(1 << (entry.attempts - 1)).min(MAX_BACKOFF);
+    entry.next_attempt_at = time().saturating_add(backoff);
+    enqueue(entry);
+}
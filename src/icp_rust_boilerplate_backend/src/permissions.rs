@@ -0,0 +1,199 @@
+use candid::CandidType;
+use ic_cdk::api::{caller, time};
+
+use crate::{
+    active_strikes, discussions_started_since, find_user, guards, karma, refresh_trust_level, sanitize, settings, try_get,
+    user_restrictions, Restriction, RestrictionKind, Settings, User, USER_ACTIVITY_STORAGE,
+};
+
+// Actions gated by trust level, and by karma once the matching threshold in Settings is set
+pub(crate) enum Action<'a> {
+    Downvote,
+    // Publishing user text, gated only when it contains a link
+    PostText(&'a str),
+    CreateDiscussion,
+    AttachImage,
+    SendDirectMessage,
+}
+
+// Levels earned through tenure and activity, each unlocking more of the forum
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+pub(crate) enum TrustLevel {
+    #[default]
+    New,
+    Basic,
+    Member,
+    Regular,
+}
+
+// Requirements of each level above New: account age in days, discussions started, votes cast, karma.
+// Regular additionally needs a clean record, no strikes inside the strike window.
+const TRUST_REQUIREMENTS: [(TrustLevel, u64, u64, u64, i64); 3] = [
+    (TrustLevel::Basic, 1, 0, 5, 0),
+    (TrustLevel::Member, 15, 3, 30, 5),
+    (TrustLevel::Regular, 60, 10, 100, 25),
+];
+
+// Discussions per 24 hours by trust level, Regular users are not capped
+const DAILY_DISCUSSIONS_BY_TRUST: [(TrustLevel, u32); 3] = [
+    (TrustLevel::New, 2),
+    (TrustLevel::Basic, 5),
+    (TrustLevel::Member, 15),
+];
+
+// Helper function to work out the level a user has earned right now. Discussions and votes are the lifetime
+// counts kept in the user's activity counters.
+pub(crate) fn compute_trust_level(user: &User) -> TrustLevel {
+    let now = time();
+    let age_days = now.saturating_sub(user.created_at) / 86_400_000_000_000;
+    let activity = try_get(&USER_ACTIVITY_STORAGE, user.id).unwrap_or_default();
+    let (discussions, votes) = (activity.discussions_created, activity.votes_cast);
+    let karma = karma(&user.username);
+
+    let mut level = TrustLevel::New;
+    for (next, min_age_days, min_discussions, min_votes, min_karma) in TRUST_REQUIREMENTS {
+        let earned = age_days >= min_age_days && discussions >= min_discussions && votes >= min_votes && karma >= min_karma;
+        if !earned || (next == TrustLevel::Regular && active_strikes(&user.username, now) > 0) {
+            break;
+        }
+        level = next;
+    }
+
+    level
+}
+
+// Helper function consulted by update entrypoints before a karma-gated action
+pub(crate) fn check_permission(username: &str, action: Action) -> Result<(), String> {
+    if matches!(action, Action::PostText(_) | Action::CreateDiscussion) {
+        match active_restriction(username, RestrictionKind::Mute) {
+            Some(mute) if mute.pending_review => return Err("Account is muted until moderators review the reports against it".to_string()),
+            Some(mute) => return Err(format!("Account is muted for another {} hours", hours_left(&mute))),
+            None => {}
+        }
+    }
+
+    let settings = settings();
+    check_account_age(username, &action, &settings)?;
+
+    let trust_level = refresh_trust_level(username);
+    check_trust_level(username, trust_level, &action)?;
+
+    let (required, what) = match action {
+        Action::Downvote => (settings.min_karma_to_downvote, "downvote"),
+        Action::PostText(text) if sanitize::contains_link(text) => (settings.min_karma_to_post_links, "post links"),
+        Action::CreateDiscussion if discussions_today(username) >= settings.discussions_per_day_before_karma => {
+            (settings.min_karma_for_extra_discussions, "start more discussions today")
+        }
+        Action::PostText(_) | Action::CreateDiscussion | Action::AttachImage | Action::SendDirectMessage => (None, ""),
+    };
+
+    match required {
+        Some(minimum) if karma(username) < minimum => Err(format!("At least {} karma is needed to {}", minimum, what)),
+        _ => Ok(()),
+    }
+}
+
+// Helper function to keep accounts younger than new_account_days from downvoting and sending direct messages,
+// and to cap how many discussions they start, so freshly created sock puppets are of little use
+fn check_account_age(username: &str, action: &Action, settings: &Settings) -> Result<(), String> {
+    if settings.new_account_days == 0 || guards::is_admin(&caller()) {
+        return Ok(());
+    }
+
+    let Some(user) = find_user(username) else {
+        return Ok(());
+    };
+    if time().saturating_sub(user.created_at) >= settings.new_account_days as u64 * 86_400_000_000_000 {
+        return Ok(());
+    }
+
+    let days = settings.new_account_days;
+    match action {
+        Action::Downvote => Err(format!("Accounts younger than {} days cannot downvote", days)),
+        Action::SendDirectMessage => Err(format!("Accounts younger than {} days cannot send direct messages", days)),
+        Action::CreateDiscussion if discussions_today(username) >= settings.new_account_discussions_per_day => Err(format!(
+            "Accounts younger than {} days can start {} discussions per day",
+            days, settings.new_account_discussions_per_day
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Helper function to hold back what a user's trust level has not unlocked yet
+fn check_trust_level(username: &str, level: TrustLevel, action: &Action) -> Result<(), String> {
+    let (required, what) = match action {
+        Action::Downvote => (TrustLevel::Basic, "downvote"),
+        Action::PostText(text) if sanitize::contains_link(text) => (TrustLevel::Basic, "post links"),
+        Action::AttachImage => (TrustLevel::Basic, "attach images"),
+        Action::CreateDiscussion => {
+            let cap = DAILY_DISCUSSIONS_BY_TRUST.iter().find(|(capped, _)| *capped == level).map(|(_, cap)| *cap);
+            if cap.is_some_and(|cap| discussions_today(username) >= cap) {
+                return Err(format!("Trust level {:?} allows {} discussions per day", level, cap.unwrap_or_default()));
+            }
+            return Ok(());
+        }
+        Action::PostText(_) | Action::SendDirectMessage => return Ok(()),
+    };
+
+    if level < required {
+        return Err(format!("Trust level {:?} is needed to {}", required, what));
+    }
+
+    Ok(())
+}
+
+// Helper function called by authenticate, suspended users cannot act on their account
+pub(crate) fn check_not_suspended(username: &str) -> Result<(), String> {
+    match active_restriction(username, RestrictionKind::Suspension) {
+        Some(suspension) => Err(format!("Account is suspended for another {} hours: {}", hours_left(&suspension), suspension.reason)),
+        None => Ok(()),
+    }
+}
+
+// Helper function to find the restriction of a kind in force on a user, the one ending last if several overlap
+pub(crate) fn active_restriction(username: &str, kind: RestrictionKind) -> Option<Restriction> {
+    let now = time();
+
+    user_restrictions(username, kind).into_iter()
+        .filter(|restriction| !restriction.lifted && restriction.ends_at > now)
+        .max_by_key(|restriction| restriction.ends_at)
+}
+
+fn hours_left(restriction: &Restriction) -> u64 {
+    restriction.ends_at.saturating_sub(time()).div_ceil(3_600_000_000_000)
+}
+
+// Helper function to make users wait between consecutive discussions; trusted users and admins are exempt
+pub(crate) fn check_cooldown(username: &str) -> Result<(), String> {
+    let settings = settings();
+
+    if settings.discussion_cooldown_seconds == 0 || guards::is_admin(&caller()) {
+        return Ok(());
+    }
+
+    if settings.cooldown_waiver_karma.is_some_and(|waiver| karma(username) >= waiver) {
+        return Ok(());
+    }
+
+    let cooldown = settings.discussion_cooldown_seconds.saturating_mul(1_000_000_000);
+    let last_created_at = discussions_started_since(username, time().saturating_sub(cooldown)).into_iter().max();
+
+    let Some(last_created_at) = last_created_at else {
+        return Ok(());
+    };
+
+    let ready_at = last_created_at.saturating_add(cooldown);
+    let now = time();
+    if now < ready_at {
+        return Err(format!("Wait {} more seconds before starting another discussion", (ready_at - now).div_ceil(1_000_000_000)));
+    }
+
+    Ok(())
+}
+
+// Helper function to count the discussions a user started in the last 24 hours
+fn discussions_today(username: &str) -> u32 {
+    let since = time().saturating_sub(24 * 3_600_000_000_000);
+
+    discussions_started_since(username, since).len() as u32
+}
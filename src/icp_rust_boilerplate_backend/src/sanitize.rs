@@ -0,0 +1,101 @@
+// Schemes a link in user-supplied text may use
+const ALLOWED_SCHEMES: &[&str] = &["https", "http", "mailto"];
+
+// Schemes that run code or embed content when clicked, rejected even without "//"
+const BLOCKED_SCHEMES: &[&str] = &["javascript", "vbscript", "data", "file"];
+
+// Helper function to clean user-supplied text before it is stored: strips control and bidi override
+// characters, rejects links outside the scheme allowlist, and optionally routes web links through a
+// redirect endpoint (`<prefix><percent-encoded url>`)
+pub(crate) fn sanitize_text(text: &str, redirect_prefix: Option<&str>) -> Result<String, String> {
+    let cleaned: String = text.chars()
+        .filter(|c| *c == '\n' || *c == '\t' || !(c.is_control() || is_bidi_override(*c)))
+        .collect();
+
+    let mut output = String::with_capacity(cleaned.len());
+
+    for piece in cleaned.split_inclusive(char::is_whitespace) {
+        let word = piece.trim_end_matches(char::is_whitespace);
+        let spacing = &piece[word.len()..];
+
+        // Leading punctuation such as "(" or "<" is not part of the link
+        let link_start = word.find(|c: char| c.is_ascii_alphanumeric()).unwrap_or(word.len());
+        let (lead, candidate) = word.split_at(link_start);
+
+        match link_scheme(candidate) {
+            Some(scheme) if !ALLOWED_SCHEMES.contains(&scheme.as_str()) => {
+                return Err(format!("Links with the {} scheme are not allowed", scheme));
+            }
+            Some(scheme) if scheme != "mailto" && redirect_prefix.is_some() => {
+                let prefix = redirect_prefix.unwrap_or_default();
+                output.push_str(lead);
+                output.push_str(prefix);
+                output.push_str(&percent_encode(candidate));
+            }
+            _ => output.push_str(word),
+        }
+
+        output.push_str(spacing);
+    }
+
+    Ok(output)
+}
+
+// Helper function to check if text contains anything sanitize_text treats as a link
+pub(crate) fn contains_link(text: &str) -> bool {
+    text.split_whitespace().any(|word| {
+        let link_start = word.find(|c: char| c.is_ascii_alphanumeric()).unwrap_or(word.len());
+        link_scheme(&word[link_start..]).is_some()
+    })
+}
+
+// Helper function to recover the original link from one rewritten through the redirect endpoint
+pub(crate) fn unwrap_redirect(url: &str, redirect_prefix: &str) -> Option<String> {
+    url.strip_prefix(redirect_prefix).and_then(percent_decode)
+}
+
+// Helper function to get the lowercase scheme of a word that looks like a link
+fn link_scheme(word: &str) -> Option<String> {
+    let (scheme, rest) = word.split_once(':')?;
+
+    if scheme.is_empty() || !scheme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.')) {
+        return None;
+    }
+
+    let scheme = scheme.to_ascii_lowercase();
+    let is_link = rest.starts_with("//") || scheme == "mailto" || BLOCKED_SCHEMES.contains(&scheme.as_str());
+
+    is_link.then_some(scheme)
+}
+
+fn is_bidi_override(c: char) -> bool {
+    matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = text.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
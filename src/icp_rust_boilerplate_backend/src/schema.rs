@@ -0,0 +1,158 @@
+use candid::types::internal::TypeId;
+use candid::types::value::{IDLField, IDLValue, VariantValue};
+use candid::types::{Serializer, Type, TypeInner};
+use candid::{CandidType, Decode, Encode, IDLArgs, Int, Nat, Principal, TypeEnv};
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+
+// Values that fields added to a stored type take in records written before them, keyed by the field's type.
+// For a record type, its fields are looked up instead, so the struct's Default fills in its new fields.
+pub(crate) type Defaults = Vec<(Type, IDLValue)>;
+
+// Helper function to list `value` as the default for its type
+pub(crate) fn default_of<T: CandidType>(value: T) -> (Type, IDLValue) {
+    let bytes = Encode!(&value).expect("Cannot encode a default value");
+    let value = IDLArgs::from_bytes(&bytes).expect("Cannot decode a default value").args.remove(0);
+    (T::ty(), value)
+}
+
+// Helper function to decode a stored value, filling in the record fields added to its type since it was
+// written. A missing field takes its value from `defaults`, otherwise None, false, 0, "" or an empty vector.
+// Fields without such a value, e.g. a principal, leave the record undecodable.
+pub(crate) fn decode<T: CandidType + DeserializeOwned>(bytes: &[u8], defaults: impl FnOnce() -> Defaults) -> Result<T, String> {
+    let error = match Decode!(bytes, T) {
+        Ok(value) => return Ok(value),
+        Err(error) => error.to_string(),
+    };
+
+    let Ok(mut stored) = IDLArgs::from_bytes(bytes) else {
+        return Err(error);
+    };
+    if stored.args.len() != 1 {
+        return Err(error);
+    }
+
+    let ty = T::ty();
+    let filled = fill(&stored.args.remove(0), &ty, &defaults());
+    let annotated = filled.annotate_type(false, &TypeEnv::new(), &ty).map_err(|_| error.clone())?;
+    let bytes = Encode!(&Typed::<T>(annotated, PhantomData)).map_err(|_| error.clone())?;
+
+    Decode!(&bytes, T).map_err(|_| error)
+}
+
+// Helper function to build a T with every field empty, standing in for a record that no longer decodes.
+// Variants take their first case and principals the anonymous one. None if T holds a function or service.
+pub(crate) fn placeholder<T: CandidType + DeserializeOwned>() -> Option<T> {
+    let ty = T::ty();
+    let annotated = empty_value(&ty)?.annotate_type(false, &TypeEnv::new(), &ty).ok()?;
+    let bytes = Encode!(&Typed::<T>(annotated, PhantomData)).ok()?;
+
+    Decode!(&bytes, T).ok()
+}
+
+fn empty_value(ty: &Type) -> Option<IDLValue> {
+    Some(match ty.as_ref() {
+        TypeInner::Record(fields) => IDLValue::Record(
+            fields.iter()
+                .map(|field| Some(IDLField { id: (*field.id).clone(), val: empty_value(&field.ty)? }))
+                .collect::<Option<_>>()?,
+        ),
+        TypeInner::Variant(fields) => {
+            let field = fields.first()?;
+            IDLValue::Variant(VariantValue(Box::new(IDLField { id: (*field.id).clone(), val: empty_value(&field.ty)? }), 0))
+        }
+        TypeInner::Principal => IDLValue::Principal(Principal::anonymous()),
+        _ => default_value(ty, &Vec::new())?,
+    })
+}
+
+// Helper function to check whether bytes decode as T without filling in any field
+pub(crate) fn is_current<T: CandidType + DeserializeOwned>(bytes: &[u8]) -> bool {
+    Decode!(bytes, T).is_ok()
+}
+
+// Helper function to add the fields `ty` expects but `value` lacks, at every depth
+fn fill(value: &IDLValue, ty: &Type, defaults: &Defaults) -> IDLValue {
+    match (value, ty.as_ref()) {
+        (IDLValue::Record(stored), TypeInner::Record(fields)) => {
+            let listed = listed_default(ty, defaults);
+            let fields = fields.iter()
+                .filter_map(|field| {
+                    let id = field.id.get_id();
+                    let val = match stored.iter().find(|stored| stored.id.get_id() == id) {
+                        Some(stored) => fill(&stored.val, &field.ty, defaults),
+                        None => match listed {
+                            Some(IDLValue::Record(listed)) => listed.iter().find(|listed| listed.id.get_id() == id)?.val.clone(),
+                            _ => default_value(&field.ty, defaults)?,
+                        },
+                    };
+                    Some(IDLField { id: (*field.id).clone(), val })
+                })
+                .collect();
+            IDLValue::Record(fields)
+        }
+        (IDLValue::Opt(inner), TypeInner::Opt(ty)) => IDLValue::Opt(Box::new(fill(inner, ty, defaults))),
+        (IDLValue::Vec(items), TypeInner::Vec(ty)) => IDLValue::Vec(items.iter().map(|item| fill(item, ty, defaults)).collect()),
+        (IDLValue::Variant(VariantValue(stored, index)), TypeInner::Variant(fields)) => {
+            match fields.iter().find(|field| field.id.get_id() == stored.id.get_id()) {
+                Some(field) => IDLValue::Variant(VariantValue(
+                    Box::new(IDLField { id: stored.id.clone(), val: fill(&stored.val, &field.ty, defaults) }),
+                    *index,
+                )),
+                None => value.clone(),
+            }
+        }
+        _ => value.clone(),
+    }
+}
+
+fn listed_default<'a>(ty: &Type, defaults: &'a Defaults) -> Option<&'a IDLValue> {
+    defaults.iter().find(|(listed, _)| listed == ty).map(|(_, value)| value)
+}
+
+// Helper function to get the value of a field missing from a stored record
+fn default_value(ty: &Type, defaults: &Defaults) -> Option<IDLValue> {
+    if let Some(value) = listed_default(ty, defaults) {
+        return Some(value.clone());
+    }
+
+    Some(match ty.as_ref() {
+        TypeInner::Null => IDLValue::Null,
+        TypeInner::Reserved => IDLValue::Reserved,
+        TypeInner::Bool => IDLValue::Bool(false),
+        TypeInner::Nat => IDLValue::Nat(Nat::from(0u8)),
+        TypeInner::Int => IDLValue::Int(Int::from(0)),
+        TypeInner::Nat8 => IDLValue::Nat8(0),
+        TypeInner::Nat16 => IDLValue::Nat16(0),
+        TypeInner::Nat32 => IDLValue::Nat32(0),
+        TypeInner::Nat64 => IDLValue::Nat64(0),
+        TypeInner::Int8 => IDLValue::Int8(0),
+        TypeInner::Int16 => IDLValue::Int16(0),
+        TypeInner::Int32 => IDLValue::Int32(0),
+        TypeInner::Int64 => IDLValue::Int64(0),
+        TypeInner::Float32 => IDLValue::Float32(0.0),
+        TypeInner::Float64 => IDLValue::Float64(0.0),
+        TypeInner::Text => IDLValue::Text(String::new()),
+        TypeInner::Opt(_) => IDLValue::None,
+        TypeInner::Vec(_) => IDLValue::Vec(Vec::new()),
+        TypeInner::Record(_) => fill(&IDLValue::Record(Vec::new()), ty, defaults),
+        _ => return None,
+    })
+}
+
+// A value annotated with T's type, encoded with T's type table so it decodes as T
+struct Typed<T>(IDLValue, PhantomData<T>);
+
+impl<T: CandidType> CandidType for Typed<T> {
+    fn _ty() -> Type {
+        T::ty()
+    }
+
+    fn id() -> TypeId {
+        TypeId::of::<Typed<T>>()
+    }
+
+    fn idl_serialize<S: Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        self.0.idl_serialize(serializer)
+    }
+}
@@ -0,0 +1,35 @@
+// Most distinct tokens kept per document and per query
+const MAX_DOCUMENT_TOKENS: usize = 64;
+pub(crate) const MAX_QUERY_TOKENS: usize = 8;
+
+// Longest token kept, longer words are cut
+pub(crate) const MAX_TOKEN_LENGTH: usize = 32;
+
+// Helper function to split text into the lowercase word tokens used by the search index. Hashtags and
+// links are split like any other text, and one-character words are dropped.
+pub(crate) fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+
+    for word in text.split(|c: char| !c.is_alphanumeric()) {
+        let token: String = word.to_lowercase().chars().take(MAX_TOKEN_LENGTH).collect();
+
+        if token.chars().count() < 2 || tokens.contains(&token) {
+            continue;
+        }
+
+        tokens.push(token);
+        if tokens.len() == MAX_DOCUMENT_TOKENS {
+            break;
+        }
+    }
+
+    tokens
+}
+
+// Helper function to map a token to its posting-list key
+pub(crate) fn token_key(token: &str) -> u64 {
+    use sha3::{Digest, Sha3_256};
+
+    let digest = Sha3_256::digest(token.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap_or_default())
+}
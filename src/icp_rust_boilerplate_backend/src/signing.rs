@@ -0,0 +1,50 @@
+use candid::CandidType;
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument, SignWithEcdsaArgument,
+};
+use k256::sha2::{Digest, Sha256};
+
+// A signature by the canister's threshold ECDSA key, with what a verifier needs to check it off-chain
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CanisterSignature {
+    // SHA-256 of the signed message
+    pub(crate) message_hash: Vec<u8>,
+    // 64 bytes, r followed by s
+    pub(crate) signature: Vec<u8>,
+    // SEC1 compressed secp256k1 key of this canister for `derivation_path`
+    pub(crate) public_key: Vec<u8>,
+    pub(crate) key_name: String,
+    pub(crate) derivation_path: Vec<Vec<u8>>,
+}
+
+// Helper function to sign the SHA-256 of `message` with the key derived for `purpose`, so signatures made
+// for one purpose can't be passed off as another's
+pub(crate) async fn sign(key_name: &str, purpose: &[u8], message: &[u8]) -> Result<CanisterSignature, String> {
+    let key_id = EcdsaKeyId { curve: EcdsaCurve::Secp256k1, name: key_name.to_string() };
+    let derivation_path = vec![purpose.to_vec()];
+    let message_hash = Sha256::digest(message).to_vec();
+
+    let (public_key,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: derivation_path.clone(),
+        key_id: key_id.clone(),
+    })
+    .await
+    .map_err(|(_, message)| format!("Cannot get the signing key: {}", message))?;
+
+    let (signature,) = sign_with_ecdsa(SignWithEcdsaArgument {
+        message_hash: message_hash.clone(),
+        derivation_path: derivation_path.clone(),
+        key_id,
+    })
+    .await
+    .map_err(|(_, message)| format!("Cannot sign: {}", message))?;
+
+    Ok(CanisterSignature {
+        message_hash,
+        signature: signature.signature,
+        public_key: public_key.public_key,
+        key_name: key_name.to_string(),
+        derivation_path,
+    })
+}